@@ -0,0 +1,321 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! Keyframe animation of cameras, lights, and scene graph nodes.
+//!
+//! A track is a list of time-stamped keyframes of one property, such as a
+//! position. At a given time, the track interpolates between the keyframes
+//! around it. Before the first keyframe and after the last one, the value is
+//! held constant.
+
+use graph::{NodeId, SceneGraph};
+use quaternion::SQuaternion;
+use scene::Scene;
+use vector3::SVector3;
+
+/// How a track interpolates between keyframes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Straight lines between keyframes, with a kink at every keyframe.
+    Linear,
+
+    /// A Catmull-Rom spline through the keyframes, which is smooth at the
+    /// keyframes. The tangent at a keyframe is the difference between its
+    /// neighbours.
+    CatmullRom,
+}
+
+/// A value that can be animated with keyframes.
+pub trait Keyframe: Copy {
+    /// Interpolates on the cubic Hermite curve from `p1` to `p2` with tangents
+    /// `m1` and `m2`, at parameter `t` in [0, 1].
+    fn hermite(p1: Self, m1: Self, p2: Self, m2: Self, t: f32) -> Self;
+
+    /// Returns the tangent at a keyframe, given the neighbouring keyframes
+    /// and the relative durations of the segments on both sides.
+    fn tangent(prev: Self, next: Self, scale: f32) -> Self;
+
+    /// Returns the tangent of a straight line from `p1` to `p2`.
+    fn linear_tangent(p1: Self, p2: Self) -> Self;
+}
+
+/// Returns the weights of p1, m1, p2, and m2 on the cubic Hermite curve at
+/// parameter t.
+fn hermite_weights(t: f32) -> (f32, f32, f32, f32) {
+    let (t2, t3) = (t * t, t * t * t);
+    (2.0 * t3 - 3.0 * t2 + 1.0, t3 - 2.0 * t2 + t, -2.0 * t3 + 3.0 * t2, t3 - t2)
+}
+
+impl Keyframe for SVector3 {
+    fn hermite(p1: SVector3, m1: SVector3, p2: SVector3, m2: SVector3, t: f32) -> SVector3 {
+        let (h0, h1, h2, h3) = hermite_weights(t);
+        p1 * h0 + m1 * h1 + p2 * h2 + m2 * h3
+    }
+
+    fn tangent(prev: SVector3, next: SVector3, scale: f32) -> SVector3 {
+        (next - prev) * scale
+    }
+
+    fn linear_tangent(p1: SVector3, p2: SVector3) -> SVector3 {
+        p2 - p1
+    }
+}
+
+impl Keyframe for SQuaternion {
+    fn hermite(p1: SQuaternion, m1: SQuaternion, p2: SQuaternion, m2: SQuaternion, t: f32) -> SQuaternion {
+        // Interpolate the components, and project back onto the unit sphere.
+        let (h0, h1, h2, h3) = hermite_weights(t);
+        let a = p1.a * h0 + m1.a * h1 + p2.a * h2 + m2.a * h3;
+        let b = p1.b * h0 + m1.b * h1 + p2.b * h2 + m2.b * h3;
+        let c = p1.c * h0 + m1.c * h1 + p2.c * h2 + m2.c * h3;
+        let d = p1.d * h0 + m1.d * h1 + p2.d * h2 + m2.d * h3;
+        let norm = (a * a + b * b + c * c + d * d).sqrt();
+        SQuaternion::new(a / norm, b / norm, c / norm, d / norm)
+    }
+
+    fn tangent(prev: SQuaternion, next: SQuaternion, scale: f32) -> SQuaternion {
+        SQuaternion::new((next.a - prev.a) * scale, (next.b - prev.b) * scale,
+                         (next.c - prev.c) * scale, (next.d - prev.d) * scale)
+    }
+
+    fn linear_tangent(p1: SQuaternion, p2: SQuaternion) -> SQuaternion {
+        SQuaternion::tangent(p1, p2, 1.0)
+    }
+}
+
+/// Time-stamped keyframes of one property.
+pub struct Track<T: Keyframe> {
+    /// Keyframes ordered by time.
+    keyframes: Vec<(f32, T)>,
+    interpolation: Interpolation,
+}
+
+impl<T: Keyframe> Track<T> {
+    pub fn new(interpolation: Interpolation) -> Track<T> {
+        Track {
+            keyframes: Vec::new(),
+            interpolation: interpolation,
+        }
+    }
+
+    /// Adds a keyframe with the value at the given time in seconds.
+    pub fn add_keyframe(&mut self, time: f32, value: T) {
+        let index = self.keyframes.iter().position(|&(t, _)| t > time).unwrap_or(self.keyframes.len());
+        self.keyframes.insert(index, (time, value));
+    }
+
+    /// Returns the value of the track at the given time. The track must have
+    /// at least one keyframe.
+    pub fn sample(&self, time: f32) -> T {
+        let n = self.keyframes.len();
+        assert!(n > 0, "track must have at least one keyframe");
+
+        // Find the segment [i, i + 1] that contains the time.
+        let i = match self.keyframes.iter().position(|&(t, _)| t > time) {
+            Some(0) => return self.keyframes[0].1,
+            None => return self.keyframes[n - 1].1,
+            Some(next) => next - 1,
+        };
+
+        let (t1, p1) = self.keyframes[i];
+        let (t2, p2) = self.keyframes[i + 1];
+        let t = (time - t1) / (t2 - t1);
+
+        let (m1, m2) = match self.interpolation {
+            Interpolation::Linear => (T::linear_tangent(p1, p2), T::linear_tangent(p1, p2)),
+            Interpolation::CatmullRom => {
+                // The tangents are scaled to the duration of this segment, so
+                // keyframes need not be evenly spaced. At the ends of the
+                // track, the missing neighbour is the keyframe itself.
+                let (t0, p0) = if i > 0 { self.keyframes[i - 1] } else { (t1, p1) };
+                let (t3, p3) = if i + 2 < n { self.keyframes[i + 2] } else { (t2, p2) };
+                (T::tangent(p0, p2, (t2 - t1) / (t2 - t0)),
+                 T::tangent(p1, p3, (t2 - t1) / (t3 - t1)))
+            }
+        };
+
+        T::hermite(p1, m1, p2, m2, t)
+    }
+}
+
+/// The object that a track animates.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Target {
+    /// A camera of the scene, by index.
+    Camera(usize),
+
+    /// An area light of the scene, by index. Only the position of a light
+    /// can be animated.
+    Light(usize),
+
+    /// A node of a scene graph.
+    Node(NodeId),
+}
+
+/// A collection of tracks that animate a scene.
+pub struct Animation {
+    positions: Vec<(Target, Track<SVector3>)>,
+    orientations: Vec<(Target, Track<SQuaternion>)>,
+}
+
+impl Animation {
+    pub fn new() -> Animation {
+        Animation {
+            positions: Vec::new(),
+            orientations: Vec::new(),
+        }
+    }
+
+    /// Animates the position of the target. For a light this is the center.
+    pub fn add_position_track(&mut self, target: Target, track: Track<SVector3>) {
+        self.positions.push((target, track));
+    }
+
+    /// Animates the orientation of a camera or node.
+    pub fn add_orientation_track(&mut self, target: Target, track: Track<SQuaternion>) {
+        if let Target::Light(..) = target {
+            panic!("lights have no orientation");
+        }
+        self.orientations.push((target, track));
+    }
+
+    /// Moves the cameras and lights of the scene to where they are at the
+    /// given time. Cameras move on during the frame, which takes `time_delta`
+    /// seconds, for motion blur.
+    pub fn apply(&self, scene: &mut Scene, time: f32, time_delta: f32) {
+        for &(target, ref track) in &self.positions {
+            let position = track.sample(time);
+            match target {
+                Target::Camera(i) => {
+                    let delta = track.sample(time + time_delta) - position;
+                    scene.cameras_mut()[i].set_position(position, delta);
+                }
+                Target::Light(i) => {
+                    let offset = position - scene.light_center(i);
+                    scene.move_light(i, offset);
+                }
+                Target::Node(..) => {}
+            }
+        }
+        for &(target, ref track) in &self.orientations {
+            if let Target::Camera(i) = target {
+                let q = track.sample(time);
+                let end = track.sample(time + time_delta);
+                let delta = SQuaternion::new(end.a - q.a, end.b - q.b, end.c - q.c, end.d - q.d);
+                scene.cameras_mut()[i].set_orientation(q, delta);
+            }
+        }
+    }
+
+    /// Updates the transforms of the animated scene graph nodes for the given
+    /// time. A position track sets the translation of a node relative to its
+    /// parent, an orientation track replaces the rotation of the linear part,
+    /// but keeps the scale along each axis.
+    pub fn apply_to_graph(&self, graph: &mut SceneGraph, time: f32) {
+        use transform::SAffine;
+        for &(target, ref track) in &self.positions {
+            if let Target::Node(id) = target {
+                let transform = SAffine { translation: track.sample(time), ..graph.get(id).transform };
+                graph.set_transform(id, transform);
+            }
+        }
+        for &(target, ref track) in &self.orientations {
+            if let Target::Node(id) = target {
+                let current = graph.get(id).transform;
+                let rotation = SAffine::rotation(track.sample(time));
+                let transform = SAffine {
+                    x: rotation.x * current.x.norm_squared().sqrt(),
+                    y: rotation.y * current.y.norm_squared().sqrt(),
+                    z: rotation.z * current.z.norm_squared().sqrt(),
+                    translation: current.translation,
+                };
+                graph.set_transform(id, transform);
+            }
+        }
+    }
+}
+
+#[test]
+fn two_keyframes_give_midpoint_halfway() {
+    let a = SVector3::new(1.0, 2.0, 3.0);
+    let b = SVector3::new(5.0, -2.0, 7.0);
+    for &interpolation in &[Interpolation::Linear, Interpolation::CatmullRom] {
+        let mut track = Track::new(interpolation);
+        track.add_keyframe(3.0, b);
+        track.add_keyframe(1.0, a);
+        let mid = track.sample(2.0);
+        assert!((mid - (a + b) * 0.5).norm_squared() < 1e-10, "{:?}: {:?}", interpolation, mid);
+
+        // Outside the keyframes the value is held.
+        assert!((track.sample(0.0) - a).norm_squared() < 1e-10);
+        assert!((track.sample(4.0) - b).norm_squared() < 1e-10);
+    }
+}
+
+#[test]
+fn catmull_rom_passes_through_keyframes_smoothly() {
+    let mut track = Track::new(Interpolation::CatmullRom);
+    let points = [(0.0, 0.0), (1.0, 1.0), (2.0, 0.0), (4.0, 2.0)];
+    for &(t, y) in &points {
+        track.add_keyframe(t, SVector3::new(0.0, y, 0.0));
+    }
+    for &(t, y) in &points {
+        assert!((track.sample(t).y - y).abs() < 1e-6);
+    }
+
+    // The curve is smooth at the keyframes: the slope is the same on both
+    // sides, unlike with linear interpolation.
+    let h = 1e-3;
+    let slope = |t: f32| (track.sample(t + h).y - track.sample(t).y) / h;
+    assert!((slope(1.0 - 2.0 * h) - slope(1.0 + h)).abs() < 0.05);
+    assert!((slope(2.0 - 2.0 * h) - slope(2.0 + h)).abs() < 0.05);
+}
+
+#[test]
+fn animation_moves_light_and_node() {
+    use bench;
+    use graph::NodeContent;
+    use light::{AreaLight, EmissionMap};
+    use transform::SAffine;
+
+    let mut scene = bench::caustic_scene();
+    let light = AreaLight::new(SVector3::zero(),
+                               SVector3::new(1.0, 0.0, 0.0),
+                               SVector3::new(0.0, 1.0, 0.0),
+                               EmissionMap::constant(SVector3::one()));
+    scene.add_area_light(light);
+    let mut graph = SceneGraph::new();
+    let node = graph.add_root(NodeContent::Mesh(0), SAffine::scale(2.0));
+
+    let mut track = Track::new(Interpolation::Linear);
+    track.add_keyframe(0.0, SVector3::new(0.0, 0.0, 0.0));
+    track.add_keyframe(1.0, SVector3::new(2.0, 4.0, 0.0));
+    let mut animation = Animation::new();
+    animation.add_position_track(Target::Light(0), track);
+    let mut track = Track::new(Interpolation::Linear);
+    track.add_keyframe(0.0, SVector3::new(0.0, 0.0, 0.0));
+    track.add_keyframe(1.0, SVector3::new(0.0, 0.0, 8.0));
+    animation.add_position_track(Target::Node(node), track);
+
+    animation.apply(&mut scene, 0.25, 0.0);
+    animation.apply_to_graph(&mut graph, 0.25);
+    assert!((scene.light_center(0) - SVector3::new(0.5, 1.0, 0.0)).norm_squared() < 1e-10);
+    let transform = graph.get(node).transform;
+    assert_eq!(transform.translation, SVector3::new(0.0, 0.0, 2.0));
+    assert_eq!(transform.x, SVector3::new(2.0, 0.0, 0.0));
+
+    // Rotating the node a quarter turn about the z-axis keeps its scale.
+    let half_angle = ::std::f32::consts::PI / 4.0;
+    let mut track = Track::new(Interpolation::Linear);
+    track.add_keyframe(0.0, SQuaternion::new(half_angle.cos(), 0.0, 0.0, half_angle.sin()));
+    animation.add_orientation_track(Target::Node(node), track);
+    animation.apply_to_graph(&mut graph, 0.25);
+    let transform = graph.get(node).transform;
+    assert!((transform.x - SVector3::new(0.0, 2.0, 0.0)).norm_squared() < 1e-10, "x is {:?}", transform.x);
+    assert!((transform.z - SVector3::new(0.0, 0.0, 2.0)).norm_squared() < 1e-10, "z is {:?}", transform.z);
+    assert_eq!(transform.translation, SVector3::new(0.0, 0.0, 2.0));
+}
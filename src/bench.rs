@@ -14,10 +14,14 @@ use rand;
 use rand::Rng;
 use rand::distributions::{IndependentSample, Range};
 use ray::{MRay, SRay};
+use scene::Scene;
 use simd::Mf32;
+use std::collections::HashMap;
 use std::f32::consts;
+use std::u32;
 use triangle::Triangle;
 use vector3::{MVector3, SVector3};
+use wavefront::{self, Mesh};
 
 /// Generates n random Mf32s in the range [0, 1).
 pub fn mf32_unit(n: usize) -> Vec<Mf32> {
@@ -222,6 +226,151 @@ pub fn mrays_inward_coherent(n: usize) -> Vec<MRay> {
         .collect()
 }
 
+/// Builds a mesh out of quads, where the vertices of every quad are listed in
+/// counterclockwise order.
+pub fn mesh_from_quads(quads: &[([SVector3; 4], SMaterial)]) -> Mesh {
+    let mut vertices = Vec::with_capacity(quads.len() * 4);
+    let mut triangles = Vec::with_capacity(quads.len() * 2);
+    for &(ref vs, material) in quads {
+        let i = vertices.len() as u32;
+        vertices.extend(vs.iter().cloned());
+        for &(a, b, c) in &[(i, i + 1, i + 2), (i, i + 2, i + 3)] {
+            triangles.push(wavefront::Triangle {
+                vertices: (a, b, c),
+                tex_coords: None,
+                material: material,
+            });
+        }
+    }
+    Mesh {
+        vertices: vertices,
+        tex_coords: Vec::new(),
+        colors: Vec::new(),
+        triangles: triangles,
+        light_links: u32::MAX,
+    }
+}
+
+/// Returns the six faces of an axis-aligned box, with outward-facing normals,
+/// in the format accepted by `mesh_from_quads()`.
+pub fn box_quads(min: SVector3, max: SVector3, material: SMaterial) -> Vec<([SVector3; 4], SMaterial)> {
+    let v = SVector3::new;
+    let (x0, y0, z0) = (min.x, min.y, min.z);
+    let (x1, y1, z1) = (max.x, max.y, max.z);
+    vec![
+        ([v(x0, y1, z1), v(x1, y1, z1), v(x1, y1, z0), v(x0, y1, z0)], material),
+        ([v(x0, y0, z0), v(x1, y0, z0), v(x1, y0, z1), v(x0, y0, z1)], material),
+        ([v(x0, y0, z1), v(x1, y0, z1), v(x1, y1, z1), v(x0, y1, z1)], material),
+        ([v(x1, y0, z0), v(x0, y0, z0), v(x0, y1, z0), v(x1, y1, z0)], material),
+        ([v(x1, y0, z1), v(x1, y0, z0), v(x1, y1, z0), v(x1, y1, z1)], material),
+        ([v(x0, y0, z0), v(x0, y0, z1), v(x0, y1, z1), v(x0, y1, z0)], material),
+    ]
+}
+
+/// Builds a closed sphere mesh with outward-facing normals, out of the given
+/// number of rings from pole to pole and segments around the vertical axis.
+pub fn sphere_mesh(center: SVector3, radius: f32, material: SMaterial, rings: u32, segments: u32) -> Mesh {
+    let pi = consts::PI;
+    let mut vertices = vec![center + SVector3::new(0.0, radius, 0.0)];
+    for r in 1..rings {
+        let theta = pi * r as f32 / rings as f32;
+        for s in 0..segments {
+            let phi = 2.0 * pi * s as f32 / segments as f32;
+            let dir = SVector3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+            vertices.push(center + dir * radius);
+        }
+    }
+    vertices.push(center - SVector3::new(0.0, radius, 0.0));
+
+    let bottom = vertices.len() as u32 - 1;
+    let ring = |r: u32, s: u32| 1 + (r - 1) * segments + s % segments;
+    let mut faces = Vec::new();
+    for s in 0..segments {
+        faces.push((0, ring(1, s), ring(1, s + 1)));
+        faces.push((bottom, ring(rings - 1, s), ring(rings - 1, s + 1)));
+        for r in 1..(rings - 1) {
+            faces.push((ring(r, s), ring(r + 1, s), ring(r + 1, s + 1)));
+            faces.push((ring(r, s), ring(r + 1, s + 1), ring(r, s + 1)));
+        }
+    }
+
+    let triangles = faces.into_iter().map(|(a, b, c)| {
+        // Orient every triangle such that its normal points outward.
+        let (va, vb, vc) = (vertices[a as usize], vertices[b as usize], vertices[c as usize]);
+        let outward = (vb - va).cross(vc - va).dot(va - center) > 0.0;
+        wavefront::Triangle {
+            vertices: if outward { (a, b, c) } else { (a, c, b) },
+            tex_coords: None,
+            material: material,
+        }
+    }).collect();
+
+    Mesh {
+        vertices: vertices,
+        tex_coords: Vec::new(),
+        colors: Vec::new(),
+        triangles: triangles,
+        light_links: u32::MAX,
+    }
+}
+
+/// Builds a small scene with a caustic: a diffuse floor, a glossy wall, and an
+/// emissive ceiling of 8 triangles. The camera is at the origin looking along
+/// the negative z-axis, so the bottom half of the screen shows the floor.
+pub fn caustic_scene() -> Scene {
+    let v = SVector3::new;
+    let floor = SMaterial::white();
+    let wall = SMaterial::diffuse(0.9, 0.9, 0.9).with_glossiness(5);
+    let light = SMaterial::sky();
+    let mut quads = vec![
+        ([v(-4.0, -1.0, 2.0), v(4.0, -1.0, 2.0), v(4.0, -1.0, -6.0), v(-4.0, -1.0, -6.0)], floor),
+        ([v(-4.0, -1.0, -4.0), v(4.0, -1.0, -4.0), v(4.0, 3.0, -4.0), v(-4.0, 3.0, -4.0)], wall),
+    ];
+    // Four quads, so the light consists of 8 triangles for direct sampling.
+    for i in 0..4 {
+        let x0 = -2.0 + i as f32;
+        let x1 = x0 + 1.0;
+        quads.push(([v(x0, 3.0, -3.0), v(x0, 3.0, -2.0), v(x1, 3.0, -2.0), v(x1, 3.0, -3.0)], light));
+    }
+    Scene::from_meshes(&[mesh_from_quads(&quads)])
+}
+
+/// Builds a closed diffuse box around the camera, with a small emissive patch
+/// under the ceiling and a sphere inside. Paths bounce many times before they
+/// find the light, and they diverge after the first bounce, which makes this
+/// the worst case for packet tracing.
+pub fn diffuse_box_scene() -> Scene {
+    let v = SVector3::new;
+    let mut quads = box_quads(v(-3.0, -2.0, -6.0), v(3.0, 2.0, 2.0), SMaterial::diffuse(0.8, 0.8, 0.8));
+    for quad in &mut quads {
+        // Turn the faces inward.
+        quad.0.reverse();
+    }
+    for i in 0..2 {
+        let x0 = -0.5 + i as f32 * 0.5;
+        let x1 = x0 + 0.5;
+        quads.push(([v(x0, 1.9, -3.5), v(x0, 1.9, -2.5), v(x1, 1.9, -2.5), v(x1, 1.9, -3.5)], SMaterial::sky()));
+    }
+    let sphere = sphere_mesh(v(0.5, -1.0, -3.0), 1.0, SMaterial::diffuse(0.9, 0.4, 0.3), 12, 24);
+    Scene::from_meshes(&[mesh_from_quads(&quads), sphere])
+}
+
+/// Loads the indoor scene with a fixed set of materials, for full-frame
+/// benchmarks. This mirrors the scene of the interactive application, but it
+/// does not change when that one is tweaked, so timings remain comparable.
+pub fn indoor_scene() -> Scene {
+    let mut materials = HashMap::new();
+    materials.insert("baseboard", SMaterial::white().with_glossiness(4));
+    materials.insert("ceiling", SMaterial::white().with_glossiness(1));
+    materials.insert("fauteuil", SMaterial::diffuse(1.0, 0.1, 0.4));
+    materials.insert("floor", SMaterial::diffuse(0.569, 0.494, 0.345).with_glossiness(4).with_texture(1));
+    materials.insert("glass", SMaterial::sky());
+    materials.insert("wall", SMaterial::diffuse(0.65, 0.7, 0.9).with_glossiness(1));
+    materials.insert("wood_light", SMaterial::diffuse(0.6, 0.533, 0.455).with_glossiness(3).with_texture(2));
+    let indoor = Mesh::load_with_materials("models/indoor.obj", &materials);
+    Scene::from_meshes(&[indoor])
+}
+
 #[test]
 fn aabb_with_srays_respects_probability() {
     let (aabb, rays) = aabb_with_srays(4096, 2048);
@@ -0,0 +1,92 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! Measures interactive rendering performance.
+//!
+//! In benchmark mode, frames are rendered as fast as possible and discarded.
+//! Nothing is displayed, so the frame time is not bounded by vsync, and the
+//! result is a reproducible measure of how fast the renderer is in realtime
+//! mode, as opposed to how fast an accumulated image converges.
+
+use renderer::{RenderBuffer, Renderer};
+use scoped_threadpool::Pool;
+use std::cmp;
+use std::sync::atomic::AtomicBool;
+use time::PreciseTime;
+
+#[cfg(test)]
+use bench;
+
+/// Frame times of a benchmark run, in microseconds.
+pub struct BenchmarkReport {
+    pub num_frames: u32,
+    pub min_us: u32,
+    pub mean_us: u32,
+    pub max_us: u32,
+}
+
+impl BenchmarkReport {
+    /// Returns the number of frames per second, based on the mean frame time.
+    pub fn fps(&self) -> f32 {
+        1.0 / (self.mean_us as f32 * 1e-6)
+    }
+
+    pub fn print(&self) {
+        println!("rendered {} frames", self.num_frames);
+        println!("frame time: min {} us, mean {} us, max {} us -> {:0.1} fps",
+                 self.min_us,
+                 self.mean_us,
+                 self.max_us,
+                 self.fps());
+    }
+}
+
+/// Renders `num_frames` frames with the current scene and settings of the
+/// renderer, and reports the frame times. The number of frames must be
+/// positive.
+pub fn run(renderer: &Renderer, pool: &mut Pool, patch_width: u32, num_frames: u32) -> BenchmarkReport {
+    assert!(num_frames > 0);
+
+    let mut bitmap = RenderBuffer::new(renderer.width(), renderer.height());
+    let mut gbuffer = RenderBuffer::new(renderer.width(), renderer.height());
+    let cancel = AtomicBool::new(false);
+
+    let mut min_us = u32::max_value();
+    let mut max_us = 0;
+    let mut total_us = 0u64;
+
+    for frame_number in 0..num_frames {
+        let begin = PreciseTime::now();
+        renderer.render_frame_parallel(pool, &mut bitmap, &mut gbuffer, patch_width, frame_number, &cancel);
+        let duration = begin.to(PreciseTime::now());
+
+        // Round up, so a frame always takes a measurable amount of time.
+        let ns = duration.num_nanoseconds().unwrap();
+        let us = cmp::max((ns + 999) / 1000, 1) as u32;
+        min_us = cmp::min(min_us, us);
+        max_us = cmp::max(max_us, us);
+        total_us += us as u64;
+    }
+
+    BenchmarkReport {
+        num_frames: num_frames,
+        min_us: min_us,
+        mean_us: (total_us / num_frames as u64) as u32,
+        max_us: max_us,
+    }
+}
+
+#[test]
+fn benchmark_renders_all_frames() {
+    let renderer = Renderer::new(bench::caustic_scene(), 64, 32);
+    let mut pool = Pool::new(2);
+    let report = run(&renderer, &mut pool, 32, 5);
+    assert_eq!(report.num_frames, 5);
+    assert!(report.min_us > 0);
+    assert!(report.min_us <= report.mean_us && report.mean_us <= report.max_us);
+    assert!(report.fps() > 0.0 && report.fps().is_finite());
+}
@@ -8,8 +8,11 @@
 //! Implements a bounding volume hierarchy.
 
 use aabb::Aabb;
-use ray::{MIntersection, MRay};
-use triangle::Triangle;
+use ray::{MIntersection, MRay, NRay, triangle_bits};
+use std::cell::Cell;
+use std::cmp;
+use std::f32;
+use triangle::{Triangle, TriangleSoa};
 use util;
 use vector3::{Axis, SVector3};
 use wavefront::Mesh;
@@ -34,6 +37,18 @@ pub struct Bvh {
     nodes: Vec<BvhNode>,
     pub triangles: Vec<Triangle>,
 
+    /// For every triangle, the index of the triangle that it was copied from
+    /// in the slice that the BVH was built from.
+    pub sources: Vec<u32>,
+
+    /// The vertices of the triangles of every leaf, in structure-of-arrays
+    /// form. Every leaf starts at a new group.
+    leaf_vertices: TriangleSoa,
+
+    /// For the first triangle of every leaf, the index of the first group of
+    /// the leaf in `leaf_vertices`. The other elements are unused.
+    leaf_groups: Vec<u32>,
+
     /// Average ratio of bounding box surface area to parent surface area.
     avg_area_ratio: f32,
 
@@ -41,6 +56,58 @@ pub struct Bvh {
     avg_tris_per_leaf: f32,
 }
 
+/// Counters of the work done by BVH traversal, for tuning the BVH.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraversalStats {
+    /// The number of ray packets traced.
+    pub num_packets: u64,
+
+    /// The number of nodes whose bounding box was intersected.
+    pub nodes_visited: u64,
+
+    /// The number of triangles intersected.
+    pub triangles_tested: u64,
+}
+
+impl TraversalStats {
+    pub fn add(&mut self, other: &TraversalStats) {
+        self.num_packets += other.num_packets;
+        self.nodes_visited += other.nodes_visited;
+        self.triangles_tested += other.triangles_tested;
+    }
+
+    pub fn print(&self) {
+        let n = cmp::max(self.num_packets, 1) as f64;
+        println!("bvh traversal: {} packets, {:0.1} nodes and {:0.1} triangles per packet",
+                 self.num_packets,
+                 self.nodes_visited as f64 / n,
+                 self.triangles_tested as f64 / n);
+    }
+}
+
+thread_local! {
+    /// Traversal counters of the current thread, only updated when the
+    /// `traversal-stats` feature is enabled.
+    static TRAVERSAL_STATS: Cell<TraversalStats> = Cell::new(TraversalStats::default());
+}
+
+fn record_traversal(numi_aabb: u32, numi_tri: u32) {
+    TRAVERSAL_STATS.with(|cell| {
+        let mut stats = cell.get();
+        stats.num_packets += 1;
+        stats.nodes_visited += numi_aabb as u64;
+        stats.triangles_tested += numi_tri as u64;
+        cell.set(stats);
+    });
+}
+
+/// Returns the traversal counters accumulated on the current thread since the
+/// last call, and resets them. The counters are always zero unless the
+/// `traversal-stats` feature is enabled.
+pub fn take_thread_traversal_stats() -> TraversalStats {
+    TRAVERSAL_STATS.with(|cell| cell.replace(TraversalStats::default()))
+}
+
 /// Reference to a triangle used during BVH construction.
 #[derive(Clone, Debug)]
 struct TriangleRef {
@@ -390,6 +457,7 @@ impl InterimNode {
                    source_triangles: &[Triangle],
                    nodes: &mut Vec<BvhNode>,
                    sorted_triangles: &mut Vec<Triangle>,
+                   sources: &mut Vec<u32>,
                    into_index: usize) {
         // Nodes must always be pushed in pairs to keep siblings on the same
         // cache line.
@@ -408,9 +476,9 @@ impl InterimNode {
 
             // Recursively crystallize the child nodes.
             self.children[0]
-                .crystallize(source_triangles, nodes, sorted_triangles, child_index + 0);
+                .crystallize(source_triangles, nodes, sorted_triangles, sources, child_index + 0);
             self.children[1]
-                .crystallize(source_triangles, nodes, sorted_triangles, child_index + 1);
+                .crystallize(source_triangles, nodes, sorted_triangles, sources, child_index + 1);
 
             nodes[into_index].index = child_index as u32;
             nodes[into_index].len = 0;
@@ -424,6 +492,7 @@ impl InterimNode {
             // Copy the triangles into the triangle buffer.
             let tris = self.triangles.iter().map(|triref| source_triangles[triref.index].clone());
             sorted_triangles.extend(tris);
+            sources.extend(self.triangles.iter().map(|triref| triref.index as u32));
         }
     }
 }
@@ -521,6 +590,7 @@ impl Bvh {
         let num_nodes = root.count_nodes();
         let mut nodes = util::cache_line_aligned_vec(num_nodes);
         let mut sorted_triangles = Vec::with_capacity(num_tris);
+        let mut sources = Vec::with_capacity(num_tris);
 
         // Write the tree of interim nodes that is all over the heap currently,
         // neatly packed into the buffers that we just allocated.
@@ -529,21 +599,39 @@ impl Bvh {
         nodes.push(BvhNode::new());
         nodes.push(BvhNode::new());
 
-        left.crystallize(&source_triangles, &mut nodes, &mut sorted_triangles, 0);
-        right.crystallize(&source_triangles, &mut nodes, &mut sorted_triangles, 1);
+        left.crystallize(&source_triangles, &mut nodes, &mut sorted_triangles, &mut sources, 0);
+        right.crystallize(&source_triangles, &mut nodes, &mut sorted_triangles, &mut sources, 1);
 
         // Gather some statistics.
         let num_leaves = root.count_leaves();
         let area_ratio_sum = root.summed_area_ratio();
 
+        let (leaf_vertices, leaf_groups) = Bvh::build_leaf_vertices(&nodes, &sorted_triangles);
+
         Bvh {
             nodes: nodes,
             triangles: sorted_triangles,
+            sources: sources,
+            leaf_vertices: leaf_vertices,
+            leaf_groups: leaf_groups,
             avg_area_ratio: area_ratio_sum / (num_nodes as f32),
             avg_tris_per_leaf: (num_tris as f32) / (num_leaves as f32),
         }
     }
 
+    /// Copies the vertices of the triangles of every leaf into
+    /// structure-of-arrays form, and returns them with the index of the first
+    /// group of every leaf.
+    fn build_leaf_vertices(nodes: &[BvhNode], triangles: &[Triangle]) -> (TriangleSoa, Vec<u32>) {
+        let mut leaf_vertices = TriangleSoa::new();
+        let mut leaf_groups = vec![0; triangles.len()];
+        for node in nodes.iter().filter(|node| node.len > 0) {
+            let (index, len) = (node.index as usize, node.len as usize);
+            leaf_groups[index] = leaf_vertices.push_triangles(&triangles[index..index + len]);
+        }
+        (leaf_vertices, leaf_groups)
+    }
+
     pub fn from_meshes(meshes: &[Mesh]) -> Bvh {
         let mut triangles = Vec::new();
 
@@ -554,11 +642,17 @@ impl Bvh {
                 let v1 = mesh.vertices[i1 as usize];
                 let v2 = mesh.vertices[i2 as usize];
                 let mut triangle = Triangle::new(v0, v1, v2, tri.material);
+                triangle.light_links = mesh.light_links;
                 if let Some((tx0, tx1, tx2)) = tri.tex_coords {
                     triangle.uv0 = mesh.tex_coords[tx0 as usize];
                     triangle.uv1 = mesh.tex_coords[tx1 as usize];
                     triangle.uv2 = mesh.tex_coords[tx2 as usize];
                 }
+                if !mesh.colors.is_empty() {
+                    triangle.color0 = mesh.colors[i0 as usize];
+                    triangle.color1 = mesh.colors[i1 as usize];
+                    triangle.color2 = mesh.colors[i2 as usize];
+                }
                 triangle
             });
             triangles.extend(mesh_triangles);
@@ -567,6 +661,41 @@ impl Bvh {
         Bvh::build(&triangles)
     }
 
+    /// Recomputes the bounding boxes, and the per-triangle degenerate bounds,
+    /// after triangles have moved.
+    ///
+    /// The tree is not restructured, so triangles keep their indices, but the
+    /// BVH becomes less efficient when triangles move far.
+    pub fn refit(&mut self) {
+        for triangle in &mut self.triangles {
+            triangle.update_degenerate_bound();
+        }
+
+        // Children are always stored after their parent, so in reverse order
+        // every node is visited after its children.
+        for i in (0..self.nodes.len()).rev() {
+            let index = self.nodes[i].index as usize;
+            let len = self.nodes[i].len as usize;
+            let aabb = if len > 0 {
+                let tris = &self.triangles[index..index + len];
+                let points: Vec<SVector3> = tris.iter().flat_map(|t| vec![t.v0, t.v1, t.v2]).collect();
+                Aabb::enclose_points(&points)
+            } else {
+                Aabb::enclose_aabbs(&[self.nodes[index].aabb.clone(), self.nodes[index + 1].aabb.clone()])
+            };
+            self.nodes[i].aabb = aabb;
+        }
+
+        let (leaf_vertices, leaf_groups) = Bvh::build_leaf_vertices(&self.nodes, &self.triangles);
+        self.leaf_vertices = leaf_vertices;
+        self.leaf_groups = leaf_groups;
+    }
+
+    /// Returns the bounding box of all triangles, the union of the two roots.
+    pub fn bounds(&self) -> Aabb {
+        Aabb::enclose_aabbs(&[self.nodes[0].aabb.clone(), self.nodes[1].aabb.clone()])
+    }
+
     pub fn print_stats(&self) {
         use std::mem;
         println!("bvh statistics:");
@@ -645,22 +774,191 @@ impl Bvh {
                     if child_isect_0.any_masked(ray.active) { stack.push((child_isect_0, child_0)); }
                 }
             } else {
-                for i in node.index..node.index + node.len {
-                    let triangle = unsafe { self.triangles.get_unchecked(i as usize) };
-                    isect = triangle.intersect(ray, isect);
-                    numi_tri += 1;
-                }
+                isect = self.intersect_leaf(node, ray, isect);
+                numi_tri += node.len;
             }
         }
 
         (isect, numi_aabb, numi_tri)
     }
 
+    /// Returns the nearest intersection with the triangles of a leaf node that
+    /// is closer than the provided intersection.
+    ///
+    /// The vertices of the leaf are intersected in groups of eight from
+    /// `leaf_vertices`, and only the nearest triangle of every ray is then
+    /// intersected in full to fill in the surface attributes. The result is
+    /// identical to that of `intersect_leaf_aos()`. The watertight test has no
+    /// structure-of-arrays version, so with the `watertight` feature the
+    /// triangles are intersected one by one.
+    #[inline(always)]
+    fn intersect_leaf(&self, node: &BvhNode, ray: &MRay, mut isect: MIntersection) -> MIntersection {
+        if cfg!(feature = "watertight") {
+            return self.intersect_leaf_aos(node, ray, isect)
+        }
+
+        let first_group = unsafe { *self.leaf_groups.get_unchecked(node.index as usize) } as usize;
+        let num_groups = (node.len as usize + 7) / 8;
+        let mut indices = self.leaf_vertices.nearest_indices(first_group..first_group + num_groups, ray, &isect);
+
+        // Several rays may share the nearest triangle, intersect it only once.
+        // Triangles are intersected in order of increasing index, so for
+        // equal distances the same triangle wins as in `intersect_leaf_aos()`.
+        indices.sort();
+        for (k, &index) in indices.iter().enumerate() {
+            let is_repeated = k > 0 && indices[k - 1] == index;
+            if let (Some(j), false) = (index, is_repeated) {
+                let i = node.index + j;
+                let triangle = unsafe { self.triangles.get_unchecked(i as usize) };
+                let distance = isect.distance;
+                isect = triangle.intersect(ray, isect);
+                isect.triangle = triangle_bits(i).pick(isect.triangle, isect.distance.geq(distance));
+            }
+        }
+
+        isect
+    }
+
+    /// Returns the nearest intersection with the triangles of a leaf node that
+    /// is closer than the provided intersection, intersecting the triangles
+    /// one by one.
+    #[inline(always)]
+    fn intersect_leaf_aos(&self, node: &BvhNode, ray: &MRay, mut isect: MIntersection) -> MIntersection {
+        for i in node.index..node.index + node.len {
+            let triangle = unsafe { self.triangles.get_unchecked(i as usize) };
+            let distance = isect.distance;
+            isect = if cfg!(feature = "watertight") {
+                triangle.intersect_watertight(ray, isect)
+            } else {
+                triangle.intersect(ray, isect)
+            };
+            isect.triangle = triangle_bits(i).pick(isect.triangle, isect.distance.geq(distance));
+        }
+        isect
+    }
+
     pub fn intersect_nearest(&self, ray: &MRay, isect: MIntersection) -> MIntersection {
-        let (isect, _, _) = self.intersect_nearest_impl(ray, isect);
+        let (isect, numi_aabb, numi_tri) = self.intersect_nearest_impl(ray, isect);
+        if cfg!(feature = "traversal-stats") {
+            record_traversal(numi_aabb, numi_tri);
+        }
+        isect
+    }
+
+    /// Returns the nearest intersection closer than the provided intersection,
+    /// traversing the BVH with a narrow packet if at most four rays are
+    /// active.
+    ///
+    /// After a few bounces most lanes of a packet are inactive, but the
+    /// bounding boxes are still tested for all eight lanes. The narrow
+    /// traversal tests only the active rays. Triangles are still intersected
+    /// with the full packet, so the intersection is the same as that of
+    /// `intersect_nearest()`, except that for triangles at exactly the same
+    /// distance, a different one may be found first.
+    pub fn intersect_nearest_narrow(&self, ray: &MRay, isect: MIntersection) -> MIntersection {
+        match NRay::compact(ray) {
+            Some(nray) => self.intersect_nearest_narrow_impl(ray, &nray, isect),
+            None => self.intersect_nearest(ray, isect),
+        }
+    }
+
+    /// See `intersect_nearest_narrow()`. The narrow ray must contain the
+    /// active rays of the packet.
+    fn intersect_nearest_narrow_impl(&self,
+                                     ray: &MRay,
+                                     nray: &NRay,
+                                     mut isect: MIntersection)
+                                     -> MIntersection {
+        let mut stack = Vec::with_capacity(32);
+        let mut distance = nray.gather_mf32(isect.distance);
+
+        let root_0 = unsafe { self.nodes.get_unchecked(0) };
+        let root_1 = unsafe { self.nodes.get_unchecked(1) };
+        let root_isect_0 = root_0.aabb.intersect_narrow(nray);
+        let root_isect_1 = root_1.aabb.intersect_narrow(nray);
+
+        if root_isect_0.should_try_before(&root_isect_1) {
+            if root_isect_0.any() { stack.push((root_isect_0, root_0)); }
+            if root_isect_1.any() { stack.push((root_isect_1, root_1)); }
+        } else {
+            if root_isect_1.any() { stack.push((root_isect_1, root_1)); }
+            if root_isect_0.any() { stack.push((root_isect_0, root_0)); }
+        }
+
+        while let Some((aabb_isect, node)) = stack.pop() {
+            if aabb_isect.is_further_away_than(distance) {
+                continue;
+            }
+
+            if node.len == 0 {
+                let child_0 = unsafe { self.nodes.get_unchecked(node.index as usize + 0) };
+                let child_1 = unsafe { self.nodes.get_unchecked(node.index as usize + 1) };
+                let child_isect_0 = child_0.aabb.intersect_narrow(nray);
+                let child_isect_1 = child_1.aabb.intersect_narrow(nray);
+
+                if child_isect_0.should_try_before(&child_isect_1) {
+                    if child_isect_0.any() { stack.push((child_isect_0, child_0)); }
+                    if child_isect_1.any() { stack.push((child_isect_1, child_1)); }
+                } else {
+                    if child_isect_1.any() { stack.push((child_isect_1, child_1)); }
+                    if child_isect_0.any() { stack.push((child_isect_0, child_0)); }
+                }
+            } else {
+                isect = self.intersect_leaf(node, ray, isect);
+                distance = nray.gather_mf32(isect.distance);
+            }
+        }
+
         isect
     }
 
+    /// Returns the point on any triangle that is closest to the given point,
+    /// the index of that triangle, and the distance to the point.
+    ///
+    /// Nodes are visited nearest first, and nodes further away than the
+    /// closest point so far are skipped.
+    pub fn closest_point(&self, point: SVector3) -> (SVector3, u32, f32) {
+        let mut best = (point, 0, f32::INFINITY);
+        let mut stack = vec![(self.nodes[0].aabb.distance_to(point), 0),
+                             (self.nodes[1].aabb.distance_to(point), 1)];
+        if stack[0].0 < stack[1].0 {
+            stack.swap(0, 1);
+        }
+
+        while let Some((node_distance, index)) = stack.pop() {
+            if node_distance >= best.2 {
+                continue;
+            }
+
+            let node = &self.nodes[index];
+            if node.len == 0 {
+                let i0 = node.index as usize;
+                let d0 = self.nodes[i0 + 0].aabb.distance_to(point);
+                let d1 = self.nodes[i0 + 1].aabb.distance_to(point);
+
+                // Push the furthest child first, so the nearest one is
+                // visited first.
+                if d0 < d1 {
+                    stack.push((d1, i0 + 1));
+                    stack.push((d0, i0 + 0));
+                } else {
+                    stack.push((d0, i0 + 0));
+                    stack.push((d1, i0 + 1));
+                }
+            } else {
+                for i in node.index..node.index + node.len {
+                    let closest = self.triangles[i as usize].closest_point(point);
+                    let distance = closest.distance(point);
+                    if distance < best.2 {
+                        best = (closest, i, distance);
+                    }
+                }
+            }
+        }
+
+        best
+    }
+
     /// Returns the number of AABBs and the number of triangles intersected to
     /// find the closest intersection.
     pub fn intersect_debug(&self, ray: &MRay, isect: MIntersection) -> (u32, u32) {
@@ -678,6 +976,89 @@ impl Drop for Bvh {
     }
 }
 
+#[test]
+fn intersect_debug_counts_quad_traversal() {
+    use material::SMaterial;
+    use ray::{MIntersection, MRay};
+    use vector3::{MVector3, SVector3};
+
+    // A tiny BVH: two quads far apart, each in its own leaf. (The heuristic
+    // does not split a single quad, and the BVH needs two roots.)
+    let quad = [SVector3::new(-1.0, -1.0, -2.0), SVector3::new(1.0, -1.0, -2.0),
+                SVector3::new(1.0, 1.0, -2.0), SVector3::new(-1.0, 1.0, -2.0)];
+    let far = [SVector3::new(9.0, -1.0, -2.0), SVector3::new(11.0, -1.0, -2.0),
+               SVector3::new(11.0, 1.0, -2.0), SVector3::new(9.0, 1.0, -2.0)];
+    let mesh = bench::mesh_from_quads(&[(quad, SMaterial::white()), (far, SMaterial::white())]);
+    let bvh = Bvh::from_meshes(&[mesh]);
+
+    let origin = MVector3::broadcast(SVector3::new(0.5, -0.5, 0.0));
+    let towards = MVector3::broadcast(SVector3::new(0.0, 0.0, -1.0));
+    let away = MVector3::broadcast(SVector3::new(0.0, 0.0, 1.0));
+    // A ray that hits the quad intersects both root nodes, and the two
+    // triangles in the leaf of the quad.
+    let ray = MRay::new(origin, towards);
+    let (isect, numi_aabb, numi_tri) = bvh.intersect_nearest_impl(&ray, MIntersection::with_max_distance(1e5));
+    assert!(isect.distance.get_coord(0) < 3.0);
+    assert_eq!((numi_aabb, numi_tri), (2, 2));
+
+    // A ray that misses only intersects the root nodes.
+    let ray = MRay::new(origin, away);
+    assert_eq!(bvh.intersect_debug(&ray, MIntersection::with_max_distance(1e5)), (2, 0));
+
+    take_thread_traversal_stats();
+    record_traversal(2, 2);
+    record_traversal(2, 0);
+    let stats = take_thread_traversal_stats();
+    assert_eq!(stats, TraversalStats { num_packets: 2, nodes_visited: 4, triangles_tested: 2 });
+    assert_eq!(take_thread_traversal_stats(), TraversalStats::default());
+}
+
+#[test]
+fn intersect_leaf_agrees_with_aos() {
+    use simd::Mf32;
+    use wavefront::Mesh;
+
+    let suzanne = Mesh::load("models/suzanne.obj");
+    let bvh = Bvh::from_meshes(&[suzanne]);
+    let mut rays = bench::mrays_inward_coherent(32);
+    rays.extend(bench::mrays_inward(32));
+    // Also test packets where some rays are inactive.
+    for ray in rays.iter_mut().filter(|ray| ray.origin.x.get_coord(0) > 0.0) {
+        ray.active = Mf32::generate(|i| if i % 3 == 0 { -1.0 } else { 0.0 });
+    }
+
+    for node in bvh.nodes.iter().filter(|node| node.len > 0) {
+        for ray in &rays {
+            let expected = bvh.intersect_leaf_aos(node, ray, MIntersection::with_max_distance(1e5));
+            let actual = bvh.intersect_leaf(node, ray, MIntersection::with_max_distance(1e5));
+            for i in 0..8 {
+                assert_eq!(expected.distance.get_coord(i), actual.distance.get_coord(i));
+                assert_eq!(expected.normal.x.get_coord(i), actual.normal.x.get_coord(i));
+                assert_eq!(expected.tex_coords.0.get_coord(i), actual.tex_coords.0.get_coord(i));
+                assert_eq!(expected.triangle_index(i), actual.triangle_index(i));
+            }
+        }
+    }
+}
+
+#[test]
+fn intersect_nearest_agrees_with_all_triangles() {
+    use wavefront::Mesh;
+
+    let suzanne = Mesh::load("models/suzanne.obj");
+    let bvh = Bvh::from_meshes(&[suzanne]);
+    for ray in &bench::mrays_inward(64) {
+        let mut expected = MIntersection::with_max_distance(1e5);
+        for triangle in &bvh.triangles {
+            expected = triangle.intersect(ray, expected);
+        }
+        let actual = bvh.intersect_nearest(ray, MIntersection::with_max_distance(1e5));
+        for i in 0..8 {
+            assert_eq!(expected.distance.get_coord(i), actual.distance.get_coord(i));
+        }
+    }
+}
+
 #[bench]
 fn bench_intersect_decoherent_mray_suzanne(b: &mut test::Bencher) {
     use wavefront::Mesh;
@@ -737,3 +1118,37 @@ fn bench_intersect_coherent_mray_bunny(b: &mut test::Bencher) {
         test::black_box(isect);
     });
 }
+
+#[bench]
+fn bench_intersect_leaves_mray_suzanne(b: &mut test::Bencher) {
+    use wavefront::Mesh;
+    let suzanne = Mesh::load("models/suzanne.obj");
+    let bvh = Bvh::from_meshes(&[suzanne]);
+    let leaves: Vec<&BvhNode> = bvh.nodes.iter().filter(|node| node.len > 0).collect();
+    let rays = bench::mrays_inward_coherent(4096 / 8);
+    let mut rays_it = rays.iter().cycle();
+    let mut leaves_it = leaves.iter().cycle();
+    b.iter(|| {
+        let ray = rays_it.next().unwrap();
+        let leaf = leaves_it.next().unwrap();
+        let isect_far = MIntersection::with_max_distance(1e5);
+        test::black_box(bvh.intersect_leaf(leaf, ray, isect_far));
+    });
+}
+
+#[bench]
+fn bench_intersect_leaves_aos_mray_suzanne(b: &mut test::Bencher) {
+    use wavefront::Mesh;
+    let suzanne = Mesh::load("models/suzanne.obj");
+    let bvh = Bvh::from_meshes(&[suzanne]);
+    let leaves: Vec<&BvhNode> = bvh.nodes.iter().filter(|node| node.len > 0).collect();
+    let rays = bench::mrays_inward_coherent(4096 / 8);
+    let mut rays_it = rays.iter().cycle();
+    let mut leaves_it = leaves.iter().cycle();
+    b.iter(|| {
+        let ray = rays_it.next().unwrap();
+        let leaf = leaves_it.next().unwrap();
+        let isect_far = MIntersection::with_max_distance(1e5);
+        test::black_box(bvh.intersect_leaf_aos(leaf, ray, isect_far));
+    });
+}
@@ -0,0 +1,78 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! Helpers to specify colors in other ways than linear RGB.
+
+use vector3::SVector3;
+
+/// Returns the chromaticity (x, y) of a Planckian radiator at the given
+/// temperature, using the cubic approximation of the Planckian locus by Kim et
+/// al. (2002). The approximation is valid from 1667 K to 25000 K.
+fn planckian_locus(kelvin: f32) -> (f32, f32) {
+    let t = kelvin.max(1667.0).min(25000.0);
+    let (t1, t2, t3) = (1.0e3 / t, 1.0e6 / (t * t), 1.0e9 / (t * t * t));
+
+    let x = if t <= 4000.0 {
+        -0.2661239 * t3 - 0.2343589 * t2 + 0.8776956 * t1 + 0.179910
+    } else {
+        -3.0258469 * t3 + 2.1070379 * t2 + 0.2226347 * t1 + 0.240390
+    };
+
+    let (x2, x3) = (x * x, x * x * x);
+    let y = if t <= 2222.0 {
+        -1.1063814 * x3 - 1.34811020 * x2 + 2.18555832 * x - 0.20219683
+    } else if t <= 4000.0 {
+        -0.9549476 * x3 - 1.37418593 * x2 + 2.09137015 * x - 0.16748867
+    } else {
+        3.0817580 * x3 - 5.87338670 * x2 + 3.75112997 * x - 0.37001483
+    };
+
+    (x, y)
+}
+
+/// Returns the color of a blackbody at the given temperature in Kelvin, as
+/// linear RGB (with the sRGB primaries), scaled such that the largest
+/// component is 1. Multiply by an intensity to get the radiance of a light.
+///
+/// Temperatures are clamped to the range from 1667 K to 25000 K. Low
+/// temperatures are red, 6500 K is close to white, and high temperatures are
+/// blue.
+pub fn blackbody(kelvin: f32) -> SVector3 {
+    let (x, y) = planckian_locus(kelvin);
+
+    // Chromaticity to XYZ with Y = 1, then XYZ to linear sRGB.
+    let (cx, cy, cz) = (x / y, 1.0, (1.0 - x - y) / y);
+    let r = 3.2404542 * cx - 1.5371385 * cy - 0.4985314 * cz;
+    let g = -0.9692660 * cx + 1.8760108 * cy + 0.0415560 * cz;
+    let b = 0.0556434 * cx - 0.2040259 * cy + 1.0572252 * cz;
+
+    // Very warm colors lie outside of the sRGB gamut, clip them.
+    let rgb = SVector3::new(r.max(0.0), g.max(0.0), b.max(0.0));
+    rgb * (1.0 / rgb.x.max(rgb.y).max(rgb.z))
+}
+
+#[test]
+fn blackbody_6500_is_near_white() {
+    let c = blackbody(6500.0);
+    assert!(c.x > 0.9 && c.y > 0.9 && c.z > 0.9, "6500 K should be near white, got {}", c);
+}
+
+#[test]
+fn blackbody_skews_warm_and_cool() {
+    let warm = blackbody(2700.0);
+    let cool = blackbody(12000.0);
+    assert!(warm.x > warm.y && warm.y > warm.z, "2700 K should be orange, got {}", warm);
+    assert!(cool.z > cool.y && cool.y > cool.x, "12000 K should be blue, got {}", cool);
+
+    // The hue changes monotonically with temperature.
+    let mut prev = blackbody(1667.0);
+    for i in 1..24 {
+        let c = blackbody(1667.0 + i as f32 * 1000.0);
+        assert!(c.z / c.x >= prev.z / prev.x);
+        prev = c;
+    }
+}
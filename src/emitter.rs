@@ -0,0 +1,294 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! Implements a bounding volume hierarchy over emissive triangles, for picking
+//! a light source with a probability proportional to its importance.
+//!
+//! With many emissive triangles, picking one uniformly wastes most samples on
+//! triangles that are far away. The hierarchy groups nearby emitters into
+//! clusters, and picking descends the tree, choosing a child with probability
+//! proportional to its flux divided by the squared distance to the shading
+//! point. The probability of an emitter is the product of the probabilities of
+//! the choices along its path.
+//!
+//! All emissive surfaces emit the radiance of the sky, so the flux of an
+//! emitter is proportional to its area. The orientation of the emitters is not
+//! taken into account.
+
+use aabb::Aabb;
+use std::cmp::Ordering;
+use triangle::Triangle;
+use vector3::{Axis, SVector3};
+
+/// One node in the emitter hierarchy.
+struct EmitterNode {
+    aabb: Aabb,
+
+    /// The sum of the flux of the emitters in the node.
+    flux: f32,
+
+    /// The index of the first emitter in the node. The node contains `len`
+    /// consecutive emitters.
+    first: u32,
+    len: u32,
+
+    /// For internal nodes, the index of the first child. The second child is
+    /// at `child + 1`. Zero for leaf nodes.
+    child: u32,
+}
+
+/// An emissive triangle, before it is put in the hierarchy.
+struct Emitter {
+    triangle: u32,
+    aabb: Aabb,
+    centroid: SVector3,
+    flux: f32,
+}
+
+/// A bounding volume hierarchy over emissive triangles.
+pub struct EmitterBvh {
+    nodes: Vec<EmitterNode>,
+
+    /// Triangle indices of the emitters, in the order of the leaves.
+    triangles: Vec<u32>,
+
+    /// Pairs of a triangle index and the position of its leaf, sorted by
+    /// triangle index, to look up the leaf of a triangle in `pdf()`.
+    leaves: Vec<(u32, u32)>,
+}
+
+impl EmitterNode {
+    /// Returns the importance of the node for a shading point: the flux over
+    /// the squared distance to the center. Within the bounding sphere of the
+    /// node, the distance is clamped to its radius so the importance is
+    /// bounded.
+    fn importance(&self, point: SVector3) -> f32 {
+        let center = (self.aabb.origin + self.aabb.far) * 0.5;
+        let radius_sqr = self.aabb.size().norm_squared() * 0.25;
+        let dist_sqr = (center - point).norm_squared();
+        self.flux / dist_sqr.max(radius_sqr).max(1e-20)
+    }
+}
+
+/// Returns the probability of picking the first of two children.
+fn probability_first(first: &EmitterNode, second: &EmitterNode, point: SVector3) -> f32 {
+    let i0 = first.importance(point);
+    let i1 = second.importance(point);
+    if i0 + i1 > 0.0 { i0 / (i0 + i1) } else { 0.5 }
+}
+
+impl EmitterBvh {
+    /// Builds the hierarchy over the triangles with the given indices.
+    pub fn build(triangles: &[Triangle], indices: &[u32]) -> EmitterBvh {
+        let mut emitters: Vec<Emitter> = indices.iter().map(|&i| {
+            let tri = &triangles[i as usize];
+            Emitter {
+                triangle: i,
+                aabb: Aabb::enclose_points(&[tri.v0, tri.v1, tri.v2]),
+                centroid: (tri.v0 + tri.v1 + tri.v2) * (1.0 / 3.0),
+                flux: (tri.v1 - tri.v0).cross(tri.v2 - tri.v0).norm_squared().sqrt() * 0.5,
+            }
+        }).collect();
+
+        let mut bvh = EmitterBvh {
+            nodes: Vec::new(),
+            triangles: Vec::with_capacity(emitters.len()),
+            leaves: Vec::new(),
+        };
+
+        if !emitters.is_empty() {
+            bvh.nodes.push(EmitterBvh::leaf(&emitters, 0));
+            bvh.split(0, &mut emitters);
+        }
+
+        bvh.leaves = bvh.triangles.iter().enumerate().map(|(i, &t)| (t, i as u32)).collect();
+        bvh.leaves.sort();
+
+        bvh
+    }
+
+    fn leaf(emitters: &[Emitter], first: u32) -> EmitterNode {
+        EmitterNode {
+            aabb: Aabb::enclose_aabbs(emitters.iter().map(|e| &e.aabb)),
+            flux: emitters.iter().map(|e| e.flux).sum(),
+            first: first,
+            len: emitters.len() as u32,
+            child: 0,
+        }
+    }
+
+    /// Splits the node at the median along the longest axis of the centroids,
+    /// recursively, until every leaf contains one emitter.
+    fn split(&mut self, node: usize, emitters: &mut [Emitter]) {
+        if emitters.len() == 1 {
+            self.triangles.push(emitters[0].triangle);
+            return
+        }
+
+        let centroids: Vec<SVector3> = emitters.iter().map(|e| e.centroid).collect();
+        let size = Aabb::enclose_points(&centroids).size();
+        let axis = if size.x >= size.y && size.x >= size.z {
+            Axis::X
+        } else if size.y >= size.z {
+            Axis::Y
+        } else {
+            Axis::Z
+        };
+        let key = |e: &Emitter| e.centroid.get_coord(axis);
+        emitters.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap_or(Ordering::Equal));
+
+        let first = self.nodes[node].first;
+        let mid = emitters.len() / 2;
+        let child = self.nodes.len();
+        self.nodes[node].child = child as u32;
+        {
+            let (left, right) = emitters.split_at_mut(mid);
+            self.nodes.push(EmitterBvh::leaf(left, first));
+            self.nodes.push(EmitterBvh::leaf(right, first + mid as u32));
+            self.split(child, left);
+            self.split(child + 1, right);
+        }
+    }
+
+    /// Returns the number of emitters in the hierarchy.
+    pub fn len(&self) -> usize {
+        self.triangles.len()
+    }
+
+    /// Picks an emitter for the shading point, given a uniform random number
+    /// in [0, 1). Returns the index of the triangle and the probability with
+    /// which it was picked, or `None` if there are no emitters.
+    pub fn sample(&self, point: SVector3, u: f32) -> Option<(u32, f32)> {
+        if self.nodes.is_empty() {
+            return None
+        }
+
+        let mut node = &self.nodes[0];
+        let mut pdf = 1.0;
+        let mut u = u;
+
+        while node.child != 0 {
+            let first = &self.nodes[node.child as usize];
+            let second = &self.nodes[node.child as usize + 1];
+            let p = probability_first(first, second, point);
+
+            // Reuse the random number for the next level, by rescaling the
+            // part that selected the child to [0, 1).
+            if u < p {
+                u = u / p;
+                pdf = pdf * p;
+                node = first;
+            } else {
+                u = (u - p) / (1.0 - p);
+                pdf = pdf * (1.0 - p);
+                node = second;
+            }
+            u = u.min(0.99999994);
+        }
+
+        Some((self.triangles[node.first as usize], pdf))
+    }
+
+    /// Returns the probability that `sample()` picks the given triangle for
+    /// the shading point, which is zero if the triangle is not an emitter.
+    pub fn pdf(&self, point: SVector3, triangle: u32) -> f32 {
+        let leaf = match self.leaves.binary_search_by_key(&triangle, |&(t, _)| t) {
+            Ok(i) => self.leaves[i].1,
+            Err(..) => return 0.0,
+        };
+
+        // Descend into the child that contains the leaf.
+        let mut node = &self.nodes[0];
+        let mut pdf = 1.0;
+        while node.child != 0 {
+            let first = &self.nodes[node.child as usize];
+            let second = &self.nodes[node.child as usize + 1];
+            let p = probability_first(first, second, point);
+            if leaf < first.first + first.len {
+                pdf = pdf * p;
+                node = first;
+            } else {
+                pdf = pdf * (1.0 - p);
+                node = second;
+            }
+        }
+
+        pdf
+    }
+}
+
+/// Returns a square of two triangles with the given center and size, in the
+/// plane z = 0.
+#[cfg(test)]
+fn square(center: SVector3, size: f32) -> Vec<Triangle> {
+    use material::SMaterial;
+    let h = size * 0.5;
+    let v = |x: f32, y: f32| SVector3::new(center.x + x, center.y + y, center.z);
+    let tri = |v0, v1, v2| Triangle {
+        v0: v0,
+        v1: v1,
+        v2: v2,
+        uv0: (0.0, 0.0),
+        uv1: (0.0, 0.0),
+        uv2: (0.0, 0.0),
+        color0: SVector3::one(),
+        color1: SVector3::one(),
+        color2: SVector3::one(),
+        material: SMaterial::sky(),
+        light_links: !0,
+        degenerate_bound: 0.0,
+    };
+    vec![tri(v(-h, -h), v(h, -h), v(h, h)), tri(v(-h, -h), v(h, h), v(-h, h))]
+}
+
+#[test]
+fn emitter_bvh_prefers_nearby_cluster_and_is_unbiased() {
+    use random::Rng;
+
+    // Two clusters of 8 small lights each, far apart.
+    let mut triangles = Vec::new();
+    for i in 0..4 {
+        triangles.extend(square(SVector3::new(i as f32 * 0.1, 0.0, 0.0), 0.05));
+        triangles.extend(square(SVector3::new(10.0 + i as f32 * 0.1, 0.0, 0.0), 0.05));
+    }
+    let indices: Vec<u32> = (0..triangles.len() as u32).collect();
+    let bvh = EmitterBvh::build(&triangles, &indices);
+    assert_eq!(bvh.len(), 16);
+
+    let point = SVector3::new(0.1, 0.0, 1.0);
+    let near = |t: u32| triangles[t as usize].v0.x < 5.0;
+
+    // The probabilities form a distribution, and `pdf()` agrees with the
+    // probability that `sample()` returns.
+    let total: f32 = indices.iter().map(|&t| bvh.pdf(point, t)).sum();
+    assert!((total - 1.0).abs() < 1e-4, "probabilities sum to {}", total);
+    let near_probability: f32 = indices.iter().filter(|&&t| near(t)).map(|&t| bvh.pdf(point, t)).sum();
+    assert!(near_probability > 0.95, "near cluster probability is {}", near_probability);
+
+    // Estimate the sum of a function over all emitters. With the probability
+    // of every sample taken into account, the estimate converges to the sum.
+    let f = |t: u32| triangles[t as usize].v0.x * 0.01 + 1.0;
+    let exact: f32 = indices.iter().map(|&t| f(t)).sum();
+    let mut rng = Rng::with_seed(2, 3, 5);
+    let n = 1 << 14;
+    let mut estimate = 0.0;
+    let mut num_near = 0;
+    for _ in 0..n {
+        for i in 0..8 {
+            let u = rng.sample_unit().get_coord(i);
+            let (t, pdf) = bvh.sample(point, u).unwrap();
+            assert!((pdf - bvh.pdf(point, t)).abs() < 1e-6);
+            estimate += f(t) / pdf;
+            if near(t) {
+                num_near += 1;
+            }
+        }
+    }
+    let estimate = estimate / (8 * n) as f32;
+    assert!(num_near as f32 > 0.95 * (8 * n) as f32);
+    assert!((estimate - exact).abs() < 0.05 * exact, "estimate {} versus exact {}", estimate, exact);
+}
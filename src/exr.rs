@@ -0,0 +1,210 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module writes OpenEXR files. Like the Wavefront OBJ reader, it is
+//! reinvented here rather than pulled in as a dependency.
+//!
+//! Only the bare minimum is supported: a single-part scanline image with three
+//! 32-bit float channels and no compression. That is not the most compact
+//! file, but every EXR reader can open it, and it preserves the radiance
+//! exactly.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::mem::transmute;
+use std::path::Path;
+
+/// Pixel type identifier for 32-bit floats in a channel list.
+const PIXEL_TYPE_FLOAT: i32 = 2;
+
+fn write_i32<W: Write>(w: &mut W, x: i32) -> io::Result<()> {
+    let bytes = [x as u8, (x >> 8) as u8, (x >> 16) as u8, (x >> 24) as u8];
+    w.write_all(&bytes)
+}
+
+fn write_u64<W: Write>(w: &mut W, x: u64) -> io::Result<()> {
+    try!(write_i32(w, x as i32));
+    write_i32(w, (x >> 32) as i32)
+}
+
+fn write_f32<W: Write>(w: &mut W, x: f32) -> io::Result<()> {
+    let bits: i32 = unsafe { transmute(x) };
+    write_i32(w, bits)
+}
+
+/// Writes the attribute name, type, and size. The value must follow.
+fn write_attribute_header<W: Write>(w: &mut W, name: &str, kind: &str, size: i32) -> io::Result<()> {
+    try!(w.write_all(name.as_bytes()));
+    try!(w.write_all(&[0]));
+    try!(w.write_all(kind.as_bytes()));
+    try!(w.write_all(&[0]));
+    write_i32(w, size)
+}
+
+fn write_box2i<W: Write>(w: &mut W, name: &str, width: u32, height: u32) -> io::Result<()> {
+    try!(write_attribute_header(w, name, "box2i", 16));
+    try!(write_i32(w, 0));
+    try!(write_i32(w, 0));
+    try!(write_i32(w, width as i32 - 1));
+    write_i32(w, height as i32 - 1)
+}
+
+/// Writes an image as an OpenEXR file.
+///
+/// The pixels are linear RGB, three floats per pixel, stored row by row with
+/// the bottom row first, as returned by `HdrBuffer::into_hdr_f32()`. EXR
+/// stores the top row first, so the rows are flipped while writing.
+pub fn write_exr<P: AsRef<Path>>(path: P, width: u32, height: u32, rgbs: &[f32]) -> io::Result<()> {
+    assert_eq!(rgbs.len(), width as usize * height as usize * 3);
+
+    // Build the header in memory first, its size is needed for the offsets.
+    let mut h = Vec::new();
+
+    // Magic number, followed by version 2 with no flags set (single-part
+    // scanline file).
+    try!(h.write_all(&[0x76, 0x2f, 0x31, 0x01]));
+    try!(h.write_all(&[2, 0, 0, 0]));
+
+    // Channels must be sorted alphabetically. Every channel is the name, the
+    // pixel type, the linear flag, three reserved bytes, and the x and y
+    // sampling rate. The list is terminated by a zero byte.
+    let channels = ["B", "G", "R"];
+    try!(write_attribute_header(&mut h, "channels", "chlist", 3 * 18 + 1));
+    for name in &channels {
+        try!(h.write_all(name.as_bytes()));
+        try!(h.write_all(&[0]));
+        try!(write_i32(&mut h, PIXEL_TYPE_FLOAT));
+        try!(h.write_all(&[0, 0, 0, 0]));
+        try!(write_i32(&mut h, 1));
+        try!(write_i32(&mut h, 1));
+    }
+    try!(h.write_all(&[0]));
+
+    try!(write_attribute_header(&mut h, "compression", "compression", 1));
+    try!(h.write_all(&[0])); // No compression.
+
+    try!(write_box2i(&mut h, "dataWindow", width, height));
+    try!(write_box2i(&mut h, "displayWindow", width, height));
+
+    try!(write_attribute_header(&mut h, "lineOrder", "lineOrder", 1));
+    try!(h.write_all(&[0])); // Increasing y.
+
+    try!(write_attribute_header(&mut h, "pixelAspectRatio", "float", 4));
+    try!(write_f32(&mut h, 1.0));
+
+    try!(write_attribute_header(&mut h, "screenWindowCenter", "v2f", 8));
+    try!(write_f32(&mut h, 0.0));
+    try!(write_f32(&mut h, 0.0));
+
+    try!(write_attribute_header(&mut h, "screenWindowWidth", "float", 4));
+    try!(write_f32(&mut h, 1.0));
+
+    // End of the header.
+    try!(h.write_all(&[0]));
+
+    let file = try!(File::create(path));
+    let mut w = BufWriter::new(file);
+    try!(w.write_all(&h));
+
+    // The offset table points at every scanline block, which consists of the
+    // y coordinate, the data size, and the data.
+    let line_data_size = width as u64 * 3 * 4;
+    let table_size = height as u64 * 8;
+    for y in 0..(height as u64) {
+        let offset = h.len() as u64 + table_size + y * (8 + line_data_size);
+        try!(write_u64(&mut w, offset));
+    }
+
+    for y in 0..height {
+        try!(write_i32(&mut w, y as i32));
+        try!(write_i32(&mut w, line_data_size as i32));
+
+        // Within a line, all values of one channel are stored together.
+        let row = (height - 1 - y) as usize;
+        let line = &rgbs[row * width as usize * 3..(row + 1) * width as usize * 3];
+        for &c in &[2, 1, 0] {
+            for rgb in line.chunks(3) {
+                try!(write_f32(&mut w, rgb[c]));
+            }
+        }
+    }
+
+    w.flush()
+}
+
+/// Reads back an uncompressed float EXR file as written by `write_exr()`.
+/// Returns the width, the height, and the pixels in the same order as the
+/// input of `write_exr()`.
+#[cfg(test)]
+pub fn read_exr<P: AsRef<Path>>(path: P) -> (u32, u32, Vec<f32>) {
+    use std::io::Read;
+
+    let mut bytes = Vec::new();
+    File::open(path).unwrap().read_to_end(&mut bytes).unwrap();
+
+    let read_i32 = |at: usize| -> i32 {
+        (bytes[at] as i32) | (bytes[at + 1] as i32) << 8 |
+        (bytes[at + 2] as i32) << 16 | (bytes[at + 3] as i32) << 24
+    };
+    let read_cstr = |at: usize| -> (String, usize) {
+        let end = at + bytes[at..].iter().position(|&b| b == 0).unwrap();
+        (String::from_utf8(bytes[at..end].to_vec()).unwrap(), end + 1)
+    };
+
+    assert_eq!(&bytes[0..4], &[0x76, 0x2f, 0x31, 0x01]);
+
+    // Walk over the attributes to find the data window.
+    let mut at = 8;
+    let (mut width, mut height) = (0, 0);
+    loop {
+        let (name, next) = read_cstr(at);
+        if name.is_empty() {
+            at = next;
+            break;
+        }
+        let (_kind, next) = read_cstr(next);
+        let size = read_i32(next) as usize;
+        if name == "dataWindow" {
+            width = (read_i32(next + 12) - read_i32(next + 4) + 1) as u32;
+            height = (read_i32(next + 16) - read_i32(next + 8) + 1) as u32;
+        }
+        at = next + 4 + size;
+    }
+
+    let mut rgbs = vec![0.0; width as usize * height as usize * 3];
+    for y in 0..height as usize {
+        let offset = read_i32(at + y * 8) as usize;
+        let line_y = read_i32(offset) as usize;
+        let row = height as usize - 1 - line_y;
+        for (i, &c) in [2, 1, 0].iter().enumerate() {
+            for x in 0..width as usize {
+                let p = offset + 8 + (i * width as usize + x) * 4;
+                rgbs[(row * width as usize + x) * 3 + c] = unsafe { transmute(read_i32(p)) };
+            }
+        }
+    }
+
+    (width, height, rgbs)
+}
+
+#[test]
+fn write_exr_round_trip() {
+    use std::env;
+    use std::fs;
+
+    let (width, height) = (7, 3);
+    let rgbs: Vec<f32> = (0..width * height * 3).map(|i| (i as f32) * 0.37 - 2.0).collect();
+
+    let path = env::temp_dir().join("convector_write_exr_round_trip.exr");
+    write_exr(&path, width, height, &rgbs).unwrap();
+    let (read_width, read_height, read_rgbs) = read_exr(&path);
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!((width, height), (read_width, read_height));
+    assert_eq!(rgbs, read_rgbs);
+}
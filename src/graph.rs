@@ -0,0 +1,209 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! A scene graph for articulated objects.
+//!
+//! Nodes form a forest: every node has an optional parent and a transform
+//! relative to that parent. Before rendering, the graph is flattened into a
+//! list of instances with world-space transforms.
+
+use transform::SAffine;
+use wavefront::Mesh;
+
+/// What a node in the scene graph refers to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NodeContent {
+    /// A pure grouping node that only contributes its transform.
+    Empty,
+
+    /// An index into the list of meshes.
+    Mesh(usize),
+
+    /// An index into the list of lights.
+    Light(usize),
+}
+
+/// Index of a node in the scene graph.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct NodeId(usize);
+
+pub struct Node {
+    pub parent: Option<NodeId>,
+    pub content: NodeContent,
+
+    /// The transform from node space into the space of the parent.
+    pub transform: SAffine,
+}
+
+/// A node content together with its transform into world space.
+#[derive(Copy, Clone, Debug)]
+pub struct Instance {
+    pub content: NodeContent,
+    pub world: SAffine,
+}
+
+pub struct SceneGraph {
+    /// The nodes, stored such that a parent always precedes its children.
+    /// This allows computing world transforms in a single pass.
+    nodes: Vec<Node>,
+
+    /// The node to world transforms of the previous frame, see
+    /// `retain_transforms()`.
+    previous: Vec<SAffine>,
+}
+
+impl SceneGraph {
+    pub fn new() -> SceneGraph {
+        SceneGraph {
+            nodes: Vec::new(),
+            previous: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, parent: Option<NodeId>, content: NodeContent, transform: SAffine) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        let node = Node {
+            parent: parent,
+            content: content,
+            transform: transform,
+        };
+        self.nodes.push(node);
+        id
+    }
+
+    pub fn add_root(&mut self, content: NodeContent, transform: SAffine) -> NodeId {
+        self.push(None, content, transform)
+    }
+
+    pub fn add_child(&mut self, parent: NodeId, content: NodeContent, transform: SAffine) -> NodeId {
+        assert!(parent.0 < self.nodes.len(), "parent node does not exist");
+        self.push(Some(parent), content, transform)
+    }
+
+    pub fn get(&self, id: NodeId) -> &Node {
+        &self.nodes[id.0]
+    }
+
+    /// Replaces the transform of a node relative to its parent.
+    ///
+    /// All descendants of the node move along with it.
+    pub fn set_transform(&mut self, id: NodeId, transform: SAffine) {
+        self.nodes[id.0].transform = transform;
+    }
+
+    /// Returns the node to world transform for every node, indexed like the
+    /// nodes.
+    pub fn world_transforms(&self) -> Vec<SAffine> {
+        let mut world: Vec<SAffine> = Vec::with_capacity(self.nodes.len());
+        for node in &self.nodes {
+            let transform = match node.parent {
+                // Parents precede children, so the parent transform is known.
+                Some(NodeId(p)) => world[p].compose(&node.transform),
+                None => node.transform,
+            };
+            world.push(transform);
+        }
+        world
+    }
+
+    /// Returns the world-space instances of all non-empty nodes.
+    pub fn flatten(&self) -> Vec<Instance> {
+        self.nodes.iter()
+            .zip(self.world_transforms())
+            .filter(|&(node, _)| node.content != NodeContent::Empty)
+            .map(|(node, world)| Instance { content: node.content, world: world })
+            .collect()
+    }
+
+    /// Remembers the current world transforms as those of the previous frame.
+    /// Call this before updating the transforms for a new frame.
+    pub fn retain_transforms(&mut self) {
+        self.previous = self.world_transforms();
+    }
+
+    /// Returns for every mesh instance, in the order of `bake_meshes()`, the
+    /// transform from its current world-space position to its position in the
+    /// previous frame. Nodes that did not exist in the previous frame did not
+    /// move.
+    pub fn motion_transforms(&self) -> Vec<SAffine> {
+        self.nodes.iter()
+            .zip(self.world_transforms())
+            .enumerate()
+            .filter(|&(_, (node, _))| match node.content {
+                NodeContent::Mesh(_) => true,
+                _ => false,
+            })
+            .map(|(i, (_, world))| match self.previous.get(i) {
+                Some(previous) => previous.compose(&world.inverse()),
+                None => SAffine::identity(),
+            })
+            .collect()
+    }
+
+    /// Returns a copy of every mesh instance with its vertices transformed
+    /// into world space, ready to be passed to `Scene::from_meshes()`.
+    pub fn bake_meshes(&self, meshes: &[Mesh]) -> Vec<Mesh> {
+        let mut baked = Vec::new();
+        for instance in self.flatten() {
+            if let NodeContent::Mesh(i) = instance.content {
+                let mesh = &meshes[i];
+                baked.push(Mesh {
+                    vertices: mesh.vertices.iter().map(|&v| instance.world.apply_point(v)).collect(),
+                    tex_coords: mesh.tex_coords.clone(),
+                    colors: mesh.colors.clone(),
+                    triangles: mesh.triangles.clone(),
+                    light_links: mesh.light_links,
+                });
+            }
+        }
+        baked
+    }
+}
+
+#[test]
+fn translating_parent_moves_child() {
+    use vector3::SVector3;
+
+    let mut graph = SceneGraph::new();
+    let parent = graph.add_root(NodeContent::Empty, SAffine::identity());
+    let child_offset = SAffine::translation(SVector3::new(0.0, 2.0, 0.0));
+    graph.add_child(parent, NodeContent::Mesh(0), child_offset);
+
+    let origin = SVector3::zero();
+    let before = graph.flatten()[0].world.apply_point(origin);
+
+    let offset = SVector3::new(3.0, -1.0, 0.5);
+    graph.set_transform(parent, SAffine::translation(offset));
+    let after = graph.flatten()[0].world.apply_point(origin);
+
+    assert_eq!(before, SVector3::new(0.0, 2.0, 0.0));
+    assert!((after - before - offset).norm_squared() < 1e-10);
+}
+
+#[test]
+fn motion_transforms_map_back_to_previous_frame() {
+    use vector3::SVector3;
+
+    let mut graph = SceneGraph::new();
+    let parent = graph.add_root(NodeContent::Empty, SAffine::identity());
+    graph.add_child(parent, NodeContent::Mesh(0), SAffine::translation(SVector3::new(0.0, 2.0, 0.0)));
+    graph.add_root(NodeContent::Mesh(1), SAffine::identity());
+
+    // Nothing moved yet.
+    let p = SVector3::new(1.0, 2.0, 3.0);
+    assert!(graph.motion_transforms().iter().all(|m| m.apply_point(p) == p));
+
+    // Moving the parent moves the child along, but not the other mesh.
+    graph.retain_transforms();
+    let offset = SVector3::new(3.0, -1.0, 0.5);
+    graph.set_transform(parent, SAffine::scale(2.0).compose(&SAffine::translation(offset)));
+    let motion = graph.motion_transforms();
+    assert_eq!(motion.len(), 2);
+    let now = graph.flatten()[0].world.apply_point(p);
+    assert!((motion[0].apply_point(now) - (p + SVector3::new(0.0, 2.0, 0.0))).norm_squared() < 1e-8);
+    assert!((motion[1].apply_point(p) - p).norm_squared() < 1e-10);
+}
@@ -0,0 +1,205 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module reads Radiance `.hdr` files. Like the OpenEXR writer, it is
+//! reinvented here rather than pulled in as a dependency.
+//!
+//! The format stores a pixel as four bytes: a mantissa per channel and a
+//! shared exponent (RGBE). Only the `32-bit_rle_rgbe` format with the usual
+//! `-Y height +X width` orientation is supported, with flat or run-length
+//! encoded scanlines.
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use vector3::SVector3;
+
+/// An image with linear RGB texels.
+pub struct HdrImage {
+    pub width: u32,
+    pub height: u32,
+
+    /// The texels row by row, with the top row first.
+    pub texels: Vec<SVector3>,
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Converts a pixel with a shared exponent into linear RGB.
+fn rgbe_to_rgb(rgbe: [u8; 4]) -> SVector3 {
+    if rgbe[3] == 0 {
+        return SVector3::zero()
+    }
+
+    // The mantissas are fractions of 256, so the exponent is offset by 8.
+    let f = 2.0f32.powi(rgbe[3] as i32 - (128 + 8));
+    SVector3::new(rgbe[0] as f32 * f, rgbe[1] as f32 * f, rgbe[2] as f32 * f)
+}
+
+/// Returns the next line of the header without the newline, and advances
+/// past it.
+fn read_line<'a>(bytes: &'a [u8], at: &mut usize) -> io::Result<&'a str> {
+    let rest = &bytes[*at..];
+    let len = match rest.iter().position(|&b| b == b'\n') {
+        Some(n) => n,
+        None => return Err(invalid_data("unexpected end of header")),
+    };
+    *at += len + 1;
+    match ::std::str::from_utf8(&rest[..len]) {
+        Ok(line) => Ok(line),
+        Err(..) => Err(invalid_data("header is not valid text")),
+    }
+}
+
+/// Decodes one run-length encoded scanline into the pixels.
+///
+/// The four components are stored one after another. Every run starts with a
+/// count byte: above 128 it is a run of count - 128 copies of the next byte,
+/// otherwise it is followed by count literal bytes.
+fn decode_rle_scanline(bytes: &[u8], at: &mut usize, line: &mut [[u8; 4]]) -> io::Result<()> {
+    let width = line.len();
+    for c in 0..4 {
+        let mut x = 0;
+        while x < width {
+            if *at >= bytes.len() {
+                return Err(invalid_data("unexpected end of pixel data"))
+            }
+            let count = bytes[*at] as usize;
+            *at += 1;
+            if count > 128 {
+                let n = count - 128;
+                if x + n > width || *at >= bytes.len() {
+                    return Err(invalid_data("run exceeds scanline"))
+                }
+                let value = bytes[*at];
+                *at += 1;
+                for pixel in &mut line[x..x + n] {
+                    pixel[c] = value;
+                }
+                x += n;
+            } else {
+                if count == 0 || x + count > width || *at + count > bytes.len() {
+                    return Err(invalid_data("run exceeds scanline"))
+                }
+                for (pixel, &value) in line[x..x + count].iter_mut().zip(&bytes[*at..*at + count]) {
+                    pixel[c] = value;
+                }
+                *at += count;
+                x += count;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decodes the contents of a `.hdr` file.
+pub fn decode_hdr(bytes: &[u8]) -> io::Result<HdrImage> {
+    let mut at = 0;
+
+    let magic = try!(read_line(bytes, &mut at));
+    if magic != "#?RADIANCE" && magic != "#?RGBE" {
+        return Err(invalid_data("not a Radiance HDR file"))
+    }
+
+    // Header variables follow until an empty line.
+    loop {
+        let line = try!(read_line(bytes, &mut at));
+        if line.is_empty() {
+            break
+        }
+        if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+            return Err(invalid_data("unsupported pixel format"))
+        }
+    }
+
+    let resolution = try!(read_line(bytes, &mut at));
+    let parts: Vec<&str> = resolution.split_whitespace().collect();
+    if parts.len() != 4 || parts[0] != "-Y" || parts[2] != "+X" {
+        return Err(invalid_data("unsupported image orientation"))
+    }
+    let (height, width) = match (parts[1].parse::<u32>(), parts[3].parse::<u32>()) {
+        (Ok(h), Ok(w)) if w > 0 && h > 0 => (h, w),
+        _ => return Err(invalid_data("invalid image size")),
+    };
+
+    let mut texels = Vec::with_capacity(width as usize * height as usize);
+    let mut line = vec![[0u8; 4]; width as usize];
+    for _ in 0..height {
+        // A new-style run-length encoded scanline starts with 2, 2, and the
+        // width as a big-endian 16-bit integer. Anything else is flat.
+        let is_rle = width >= 8 && width < 32768 && bytes.len() >= at + 4 &&
+                     bytes[at] == 2 && bytes[at + 1] == 2 &&
+                     ((bytes[at + 2] as u32) << 8 | bytes[at + 3] as u32) == width;
+        if is_rle {
+            at += 4;
+            try!(decode_rle_scanline(bytes, &mut at, &mut line));
+        } else {
+            if bytes.len() < at + 4 * width as usize {
+                return Err(invalid_data("unexpected end of pixel data"))
+            }
+            for pixel in line.iter_mut() {
+                pixel.copy_from_slice(&bytes[at..at + 4]);
+                at += 4;
+            }
+        }
+        texels.extend(line.iter().map(|&rgbe| rgbe_to_rgb(rgbe)));
+    }
+
+    Ok(HdrImage {
+        width: width,
+        height: height,
+        texels: texels,
+    })
+}
+
+/// Reads a `.hdr` file.
+pub fn read_hdr<P: AsRef<Path>>(path: P) -> io::Result<HdrImage> {
+    let mut bytes = Vec::new();
+    let mut file = try!(File::open(path));
+    try!(file.read_to_end(&mut bytes));
+    decode_hdr(&bytes)
+}
+
+#[test]
+fn decode_hdr_flat_pixels() {
+    let mut bytes = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 2\n".to_vec();
+    bytes.extend_from_slice(&[128, 64, 32, 129, 0, 0, 0, 0]);
+    let image = decode_hdr(&bytes).unwrap();
+    assert_eq!((image.width, image.height), (2, 1));
+    assert_eq!(image.texels[0], SVector3::new(1.0, 0.5, 0.25));
+    assert_eq!(image.texels[1], SVector3::zero());
+}
+
+#[test]
+fn decode_hdr_rle_scanline() {
+    let mut bytes = b"#?RGBE\n\n-Y 1 +X 8\n".to_vec();
+    bytes.extend_from_slice(&[2, 2, 0, 8]);
+    // Red: a run of 8 times 200. Green: 8 literal values. Blue: two runs.
+    // Exponent: a run of 8 times 136, so the scale is 1.
+    bytes.extend_from_slice(&[128 + 8, 200]);
+    bytes.extend_from_slice(&[8, 0, 1, 2, 3, 4, 5, 6, 7]);
+    bytes.extend_from_slice(&[128 + 4, 10, 128 + 4, 20]);
+    bytes.extend_from_slice(&[128 + 8, 136]);
+    let image = decode_hdr(&bytes).unwrap();
+    assert_eq!(image.texels.len(), 8);
+    for (x, texel) in image.texels.iter().enumerate() {
+        let blue = if x < 4 { 10.0 } else { 20.0 };
+        assert_eq!(*texel, SVector3::new(200.0, x as f32, blue));
+    }
+}
+
+#[test]
+fn decode_hdr_rejects_malformed_header() {
+    assert!(decode_hdr(b"P6\n2 1\n255\n").is_err());
+    assert!(decode_hdr(b"#?RADIANCE\nFORMAT=32-bit_rle_xyze\n\n-Y 1 +X 1\n\0\0\0\0").is_err());
+    assert!(decode_hdr(b"#?RADIANCE\n\n+Y 1 +X 1\n\0\0\0\0").is_err());
+    assert!(decode_hdr(b"#?RADIANCE\n\n-Y 1 +X 2\n\0\0\0\0").is_err());
+    assert!(decode_hdr(b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n").is_err());
+}
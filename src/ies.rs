@@ -0,0 +1,215 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! This module reads IES photometric profiles (the IESNA LM-63 format). Like
+//! the other file formats, it is reinvented here.
+//!
+//! A profile is a table of luminous intensities in candela, indexed by a
+//! vertical angle (0 degrees along the axis of the light) and a horizontal
+//! angle around the axis. Only type C photometry is supported, and the tilt
+//! must be `NONE`. The horizontal angles may cover a single angle (the light
+//! is rotationally symmetric), a quadrant, a half, or the full circle.
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+/// Luminous intensity as a function of the emission direction.
+pub struct IesProfile {
+    /// Vertical angles in degrees, ascending.
+    vertical_angles: Vec<f32>,
+
+    /// Horizontal angles in degrees, ascending.
+    horizontal_angles: Vec<f32>,
+
+    /// Intensities in candela, for every horizontal angle all vertical ones.
+    candela: Vec<f32>,
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Returns the index i such that xs[i] <= x <= xs[i + 1], and the fraction of
+/// the way from xs[i] to xs[i + 1]. The slice must contain at least one value
+/// and x must be within range.
+fn locate(xs: &[f32], x: f32) -> (usize, f32) {
+    if xs.len() == 1 {
+        return (0, 0.0)
+    }
+    let mut i = 0;
+    while i + 2 < xs.len() && xs[i + 1] < x {
+        i += 1;
+    }
+    let span = xs[i + 1] - xs[i];
+    let t = if span > 0.0 { (x - xs[i]) / span } else { 0.0 };
+    (i, t.max(0.0).min(1.0))
+}
+
+impl IesProfile {
+    /// Returns the largest intensity in the table.
+    pub fn max_candela(&self) -> f32 {
+        self.candela.iter().fold(0.0, |m, &c| m.max(c))
+    }
+
+    /// Returns the intensity in candela at the given vertical and horizontal
+    /// angle in degrees, interpolated linearly between the table entries.
+    /// Outside of the vertical range the intensity is zero.
+    pub fn intensity(&self, vertical: f32, horizontal: f32) -> f32 {
+        let vs = &self.vertical_angles;
+        let hs = &self.horizontal_angles;
+        if vertical < vs[0] || vertical > vs[vs.len() - 1] {
+            return 0.0
+        }
+
+        // Fold the horizontal angle into the range that the table covers,
+        // using the symmetry that the range implies.
+        let h_max = hs[hs.len() - 1];
+        let mut h = horizontal % 360.0;
+        if h < 0.0 {
+            h += 360.0;
+        }
+        if h_max <= 90.0 {
+            h = if h > 180.0 { 360.0 - h } else { h };
+            h = if h > 90.0 { 180.0 - h } else { h };
+        } else if h_max <= 180.0 && h > 180.0 {
+            h = 360.0 - h;
+        }
+        let h = h.min(h_max);
+
+        let nv = vs.len();
+        let (i, s) = locate(vs, vertical);
+        let (j, t) = locate(hs, h);
+        let i1 = if nv > 1 { i + 1 } else { i };
+        let j1 = if hs.len() > 1 { j + 1 } else { j };
+        let at = |i: usize, j: usize| self.candela[j * nv + i];
+        let c0 = at(i, j) * (1.0 - s) + at(i1, j) * s;
+        let c1 = at(i, j1) * (1.0 - s) + at(i1, j1) * s;
+        c0 * (1.0 - t) + c1 * t
+    }
+}
+
+/// Parses the contents of an `.ies` file.
+pub fn parse_ies(text: &str) -> io::Result<IesProfile> {
+    // The header consists of free-form keyword lines, and ends with the tilt.
+    let mut lines = text.lines();
+    loop {
+        let line = match lines.next() {
+            Some(line) => line.trim(),
+            None => return Err(invalid_data("missing TILT line")),
+        };
+        if line.starts_with("TILT=") {
+            if line != "TILT=NONE" {
+                return Err(invalid_data("only TILT=NONE is supported"))
+            }
+            break
+        }
+    }
+
+    // After the tilt, the file is just a sequence of numbers, regardless of
+    // how they are divided over lines.
+    let mut numbers = Vec::new();
+    for word in lines.flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ',')) {
+        if word.is_empty() {
+            continue
+        }
+        match word.parse::<f32>() {
+            Ok(x) => numbers.push(x),
+            Err(..) => return Err(invalid_data("invalid number")),
+        }
+    }
+
+    // Ten values describe the lamp, three more describe the ballast.
+    if numbers.len() < 13 {
+        return Err(invalid_data("unexpected end of file"))
+    }
+    let multiplier = numbers[2];
+    let num_vertical = numbers[3] as usize;
+    let num_horizontal = numbers[4] as usize;
+    let photometric_type = numbers[5] as u32;
+    if photometric_type != 1 {
+        return Err(invalid_data("only type C photometry is supported"))
+    }
+    if num_vertical == 0 || num_horizontal == 0 {
+        return Err(invalid_data("profile has no angles"))
+    }
+
+    let start = 13;
+    let num_candela = num_vertical * num_horizontal;
+    if numbers.len() < start + num_vertical + num_horizontal + num_candela {
+        return Err(invalid_data("unexpected end of file"))
+    }
+    let (vertical, rest) = numbers[start..].split_at(num_vertical);
+    let (horizontal, rest) = rest.split_at(num_horizontal);
+    let candela = rest[..num_candela].iter().map(|&c| c * multiplier).collect();
+
+    let ascending = |xs: &[f32]| xs.windows(2).all(|w| w[0] <= w[1]);
+    if !ascending(vertical) || !ascending(horizontal) {
+        return Err(invalid_data("angles must be ascending"))
+    }
+
+    Ok(IesProfile {
+        vertical_angles: vertical.to_vec(),
+        horizontal_angles: horizontal.to_vec(),
+        candela: candela,
+    })
+}
+
+/// Reads an `.ies` file.
+pub fn read_ies<P: AsRef<Path>>(path: P) -> io::Result<IesProfile> {
+    let mut text = String::new();
+    let mut file = try!(File::open(path));
+    try!(file.read_to_string(&mut text));
+    parse_ies(&text)
+}
+
+/// A rotationally symmetric profile that is bright along the axis and dim to
+/// the side.
+#[cfg(test)]
+pub fn spot_profile() -> IesProfile {
+    parse_ies("IESNA:LM-63-2002\n\
+               [TEST] spot\n\
+               TILT=NONE\n\
+               1 1000 2 3 1 1 2 0 0 0\n\
+               1 1 100\n\
+               0 45 90\n\
+               0\n\
+               500 250 50\n").unwrap()
+}
+
+#[test]
+fn parse_ies_reads_table() {
+    let profile = spot_profile();
+    // The multiplier of 2 applies to all values.
+    assert_eq!(profile.max_candela(), 1000.0);
+    assert_eq!(profile.intensity(0.0, 0.0), 1000.0);
+    assert_eq!(profile.intensity(90.0, 123.0), 100.0);
+    assert_eq!(profile.intensity(22.5, 0.0), 750.0);
+    assert_eq!(profile.intensity(120.0, 0.0), 0.0);
+}
+
+#[test]
+fn ies_intensity_uses_horizontal_symmetry() {
+    // A quadrant from 0 to 90 degrees, with a different value at the ends.
+    let profile = parse_ies("TILT=NONE\n1 1 1 1 2 1 2 0 0 0 1 1 100\n0\n0 90\n10 30\n").unwrap();
+    assert_eq!(profile.intensity(0.0, 0.0), 10.0);
+    assert_eq!(profile.intensity(0.0, 45.0), 20.0);
+    assert_eq!(profile.intensity(0.0, 90.0), 30.0);
+    assert_eq!(profile.intensity(0.0, 180.0), 10.0);
+    assert_eq!(profile.intensity(0.0, 270.0), 30.0);
+    assert_eq!(profile.intensity(0.0, -45.0), 20.0);
+}
+
+#[test]
+fn parse_ies_rejects_malformed_files() {
+    assert!(parse_ies("IESNA:LM-63-2002\n1 1 1").is_err());
+    assert!(parse_ies("TILT=INCLUDE\n").is_err());
+    assert!(parse_ies("TILT=NONE\n1 1 1 2 1 1 2 0 0 0 1 1 100\n0 90\n0\n1\n").is_err());
+    assert!(parse_ies("TILT=NONE\n1 1 1 2 1 3 2 0 0 0 1 1 100\n0 90\n0\n1 2\n").is_err());
+    assert!(parse_ies("TILT=NONE\n1 1 1 2 1 1 2 0 0 0 1 1 100\n90 0\n0\n1 2\n").is_err());
+}
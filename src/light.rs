@@ -0,0 +1,674 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! Rectangular area lights with spatially varying emission.
+//!
+//! The emission of a light is modulated by an emission map, for instance a
+//! window with a sky image behind it. Points on the light are sampled
+//! proportional to the luminance of the map, so bright parts of the light
+//! receive more samples than dark parts.
+//!
+//! A light can also have an IES profile, which modulates its intensity by the
+//! direction of emission.
+
+use hdr::HdrImage;
+use ies::IesProfile;
+use random::Rng;
+use simd::Mf32;
+use std::cmp;
+use std::f32::consts;
+use util::generate_slice8;
+use vector3::{MVector3, SVector3};
+
+/// A grid of radiance values, used to modulate the emission of a light.
+pub struct EmissionMap {
+    width: u32,
+    height: u32,
+
+    /// Radiance per texel, row by row, where rows run along the first edge of
+    /// the light.
+    texels: Vec<SVector3>,
+
+    /// The cumulative probability of picking every texel, in the same order
+    /// as the texels. The last value is 1.
+    cdf: Vec<f32>,
+}
+
+/// A parallelogram that emits light to the side its normal points to.
+pub struct AreaLight {
+    origin: SVector3,
+    edge_u: SVector3,
+    edge_v: SVector3,
+    normal: SVector3,
+    area: f32,
+    emission: EmissionMap,
+
+    /// Directional intensity, with the normal as the axis. Without a profile
+    /// the light is Lambertian.
+    profile: Option<IesProfile>,
+
+    /// The wrap and the exponent of the cosine at the receiver, see
+    /// `set_wrap()`.
+    wrap: f32,
+    wrap_exponent: f32,
+}
+
+/// Radiance arriving from infinitely far away, stored as an equirectangular
+/// image.
+///
+/// The z-axis points up. The top row of the image is the zenith, the bottom
+/// row the nadir, and the horizontal image coordinate is the azimuth.
+pub struct EnvironmentMap {
+    width: u32,
+    height: u32,
+
+    /// Radiance per texel, row by row, with the top row first.
+    texels: Vec<SVector3>,
+}
+
+/// 8 points on light sources.
+pub struct MLightSample {
+    pub position: MVector3,
+    pub normal: MVector3,
+
+    /// The radiance emitted at the sampled point.
+    pub radiance: MVector3,
+
+    /// The probability density of the point, with respect to area.
+    pub pdf: Mf32,
+
+    /// The index of the sampled light in the scene, per lane.
+    pub lights: [usize; 8],
+}
+
+fn luminance(color: SVector3) -> f32 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+impl EmissionMap {
+    /// Creates an emission map from a width times height grid of texels.
+    pub fn new(width: u32, height: u32, texels: Vec<SVector3>) -> EmissionMap {
+        assert_eq!(texels.len(), (width * height) as usize);
+
+        let mut cdf = Vec::with_capacity(texels.len());
+        let mut total = 0.0;
+        for &texel in &texels {
+            total += luminance(texel).max(0.0);
+            cdf.push(total);
+        }
+
+        // If the map is black everywhere, sample uniformly. There will be no
+        // contribution anyway.
+        if total > 0.0 {
+            for c in &mut cdf {
+                *c = *c / total;
+            }
+        } else {
+            let n = cdf.len() as f32;
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = (i + 1) as f32 / n;
+            }
+        }
+
+        EmissionMap {
+            width: width,
+            height: height,
+            texels: texels,
+            cdf: cdf,
+        }
+    }
+
+    /// Creates an emission map that emits the same radiance everywhere.
+    pub fn constant(radiance: SVector3) -> EmissionMap {
+        EmissionMap::new(1, 1, vec![radiance])
+    }
+
+    /// Returns the largest radiance of any texel in any channel.
+    pub fn max_radiance(&self) -> f32 {
+        self.texels.iter().fold(0.0, |m, t| m.max(t.x).max(t.y).max(t.z))
+    }
+
+    /// Returns the average radiance over the map.
+    pub fn mean_radiance(&self) -> SVector3 {
+        let sum = self.texels.iter().fold(SVector3::zero(), |s, &t| s + t);
+        sum * (1.0 / self.texels.len() as f32)
+    }
+
+    /// Returns the radiance at texture coordinates (s, t) in [0, 1).
+    pub fn lookup(&self, s: f32, t: f32) -> SVector3 {
+        let i = cmp::min((s * self.width as f32) as u32, self.width - 1);
+        let j = cmp::min((t * self.height as f32) as u32, self.height - 1);
+        self.texels[(j * self.width + i) as usize]
+    }
+
+    /// Picks a texel with probability proportional to its luminance, given a
+    /// uniform random number in [0, 1). Returns the texel index and the
+    /// probability of picking it.
+    fn pick_texel(&self, xi: f32) -> (usize, f32) {
+        // Find the first texel whose cumulative probability exceeds xi.
+        let index = match self.cdf.binary_search_by(|c| c.partial_cmp(&xi).unwrap()) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        };
+        let index = cmp::min(index, self.cdf.len() - 1);
+        let prev = if index == 0 { 0.0 } else { self.cdf[index - 1] };
+        (index, self.cdf[index] - prev)
+    }
+}
+
+impl EnvironmentMap {
+    pub fn from_image(image: HdrImage) -> EnvironmentMap {
+        assert_eq!(image.texels.len(), (image.width * image.height) as usize);
+        EnvironmentMap {
+            width: image.width,
+            height: image.height,
+            texels: image.texels,
+        }
+    }
+
+    /// Returns the radiance arriving from the given direction, which must have
+    /// unit length.
+    pub fn lookup(&self, direction: SVector3) -> SVector3 {
+        let (x, y) = self.texel_coords(direction);
+        self.texel(x as i32, y as i32)
+    }
+
+    /// Returns the position of the direction in the image, in texels. Texel
+    /// (i, j) covers [i, i + 1) by [j, j + 1).
+    fn texel_coords(&self, direction: SVector3) -> (f32, f32) {
+        let phi = direction.y.atan2(direction.x);
+        let theta = direction.z.max(-1.0).min(1.0).acos();
+        let s = phi * (0.5 / consts::PI) + 0.5;
+        let t = theta * (1.0 / consts::PI);
+        (s * self.width as f32, t * self.height as f32)
+    }
+
+    /// Returns texel (i, j). The azimuth wraps around, and rows beyond the
+    /// poles are clamped.
+    fn texel(&self, i: i32, j: i32) -> SVector3 {
+        let (w, h) = (self.width as i32, self.height as i32);
+        let i = ((i % w) + w) % w;
+        let j = cmp::max(0, cmp::min(j, h - 1));
+        self.texels[(j * w + i) as usize]
+    }
+
+    /// Returns the radiance arriving from the given direction, interpolated
+    /// bilinearly between the four nearest texel centers.
+    pub fn lookup_bilinear(&self, direction: SVector3) -> SVector3 {
+        let (x, y) = self.texel_coords(direction);
+        let (x, y) = (x - 0.5, y - 0.5);
+        let (i, j) = (x.floor(), y.floor());
+        let (fx, fy) = (x - i, y - j);
+        let (i, j) = (i as i32, j as i32);
+        let top = self.texel(i, j) * (1.0 - fx) + self.texel(i + 1, j) * fx;
+        let bottom = self.texel(i, j + 1) * (1.0 - fx) + self.texel(i + 1, j + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+
+    /// Returns the radiance of the nearest texel, after moving the lookup by
+    /// up to half a texel in both directions, given uniform random numbers
+    /// in [0, 1).
+    ///
+    /// A texel is picked with probability equal to its bilinear weight, so
+    /// the mean over many samples is the bilinear interpolation, for the cost
+    /// of a single texel. Where the map is smooth, this turns the banding of
+    /// the texels into noise that averages out.
+    pub fn lookup_jittered(&self, direction: SVector3, xi_s: f32, xi_t: f32) -> SVector3 {
+        let (x, y) = self.texel_coords(direction);
+        self.texel((x - 0.5 + xi_s).floor() as i32, (y - 0.5 + xi_t).floor() as i32)
+    }
+
+    /// Returns the radiance arriving from 8 directions.
+    pub fn lookup_8(&self, directions: MVector3) -> MVector3 {
+        MVector3::generate(|i| {
+            self.lookup(SVector3::new(directions.x.get_coord(i),
+                                      directions.y.get_coord(i),
+                                      directions.z.get_coord(i)))
+        })
+    }
+
+    /// Returns the radiance arriving from 8 directions, with the lookups
+    /// jittered within a texel, see `lookup_jittered()`.
+    pub fn lookup_8_jittered(&self, directions: MVector3, rng: &mut Rng) -> MVector3 {
+        let xi_s = rng.sample_unit();
+        let xi_t = rng.sample_unit();
+        MVector3::generate(|i| {
+            let direction = SVector3::new(directions.x.get_coord(i),
+                                          directions.y.get_coord(i),
+                                          directions.z.get_coord(i));
+            self.lookup_jittered(direction, xi_s.get_coord(i), xi_t.get_coord(i))
+        })
+    }
+}
+
+impl AreaLight {
+    /// Creates a light spanned by two edges from the origin. The light emits
+    /// to the side of `edge_u` cross `edge_v`. The first texture coordinate of
+    /// the emission map runs along `edge_u`, the second one along `edge_v`.
+    pub fn new(origin: SVector3, edge_u: SVector3, edge_v: SVector3, emission: EmissionMap) -> AreaLight {
+        let cross = edge_u.cross(edge_v);
+        AreaLight {
+            origin: origin,
+            edge_u: edge_u,
+            edge_v: edge_v,
+            normal: cross.normalized(),
+            area: cross.norm_squared().sqrt(),
+            emission: emission,
+            profile: None,
+            wrap: 0.0,
+            wrap_exponent: 1.0,
+        }
+    }
+
+    pub fn area(&self) -> f32 {
+        self.area
+    }
+
+    /// Returns the normal, the direction that the light emits to.
+    pub fn normal(&self) -> SVector3 {
+        self.normal
+    }
+
+    /// Returns the center of the parallelogram.
+    pub fn center(&self) -> SVector3 {
+        self.origin + (self.edge_u + self.edge_v) * 0.5
+    }
+
+    /// Returns the radius of the smallest sphere around the center that
+    /// contains the light.
+    pub fn bounding_radius(&self) -> f32 {
+        let d0 = (self.edge_u + self.edge_v).norm_squared();
+        let d1 = (self.edge_u - self.edge_v).norm_squared();
+        d0.max(d1).sqrt() * 0.5
+    }
+
+    /// Returns an upper bound on the radiant intensity of the light in any
+    /// direction: the peak radiance times the area.
+    pub fn max_intensity(&self) -> f32 {
+        self.emission.max_radiance() * self.area
+    }
+
+    /// Returns the distance from the center beyond which the irradiance due
+    /// to the light is less than `cutoff`.
+    ///
+    /// The irradiance at distance d from the light is at most the intensity
+    /// divided by d squared, measured from the nearest point of the light.
+    pub fn effective_radius(&self, cutoff: f32) -> f32 {
+        (self.max_intensity() / cutoff).sqrt() + self.bounding_radius()
+    }
+
+    /// Sets the photometric profile of the light. The vertical angle of the
+    /// profile is measured from the normal of the light, the horizontal angle
+    /// from the first edge.
+    pub fn set_profile(&mut self, profile: Option<IesProfile>) {
+        self.profile = profile;
+    }
+
+    /// Returns whether the light has a photometric profile.
+    pub fn has_profile(&self) -> bool {
+        self.profile.is_some()
+    }
+
+    /// Sets the wrap lighting of receiving surfaces, for stylized shading and
+    /// thin foliage.
+    ///
+    /// The cosine at the receiver is remapped to ((cos + wrap) / (1 + wrap))
+    /// to the power `exponent`, so surfaces that face slightly away from the
+    /// light still receive some of it. A wrap of 0 is Lambertian (and then
+    /// the exponent is ignored), a wrap of 1 with exponent 2 is the
+    /// half-Lambert of Valve, (cos * 0.5 + 0.5)^2. This is not physically
+    /// based: it adds energy.
+    pub fn set_wrap(&mut self, wrap: f32, exponent: f32) {
+        assert!(wrap >= 0.0, "wrap must not be negative");
+        assert!(exponent > 0.0, "wrap exponent must be positive");
+        self.wrap = wrap;
+        self.wrap_exponent = exponent;
+    }
+
+    /// Returns whether the light has wrap lighting, see `set_wrap()`.
+    pub fn has_wrap(&self) -> bool {
+        self.wrap != 0.0
+    }
+
+    /// Returns the cosine term at a receiver, with wrap lighting applied.
+    pub fn receiver_factor(&self, cos_theta: f32) -> f32 {
+        if self.wrap == 0.0 {
+            cos_theta.max(0.0)
+        } else {
+            ((cos_theta + self.wrap) / (1.0 + self.wrap)).max(0.0).powf(self.wrap_exponent)
+        }
+    }
+
+    /// Returns the intensity in the given direction relative to the intensity
+    /// along the normal, a value in [0, 1] for a Lambertian light or a profile
+    /// whose peak lies on the axis. The direction must be normalized.
+    pub fn directional_factor(&self, direction: SVector3) -> f32 {
+        let cos_theta = self.normal.dot(direction);
+        match self.profile {
+            None => cos_theta.max(0.0),
+            Some(ref profile) => {
+                let u = self.edge_u.normalized();
+                let v = self.normal.cross(u);
+                let vertical = cos_theta.max(-1.0).min(1.0).acos().to_degrees();
+                let horizontal = v.dot(direction).atan2(u.dot(direction)).to_degrees();
+                let peak = profile.max_candela();
+                if peak > 0.0 { profile.intensity(vertical, horizontal) / peak } else { 0.0 }
+            }
+        }
+    }
+
+    /// Returns the radiance that the light emits in the given direction,
+    /// relative to the radiance of its emission map. This is 1 for a
+    /// Lambertian light, a profile redistributes the intensity over the
+    /// directions. The direction must be normalized.
+    pub fn radiance_factor(&self, direction: SVector3) -> f32 {
+        let cos_theta = self.normal.dot(direction);
+        match self.profile {
+            None => 1.0,
+            Some(_) if cos_theta <= 0.0 => 0.0,
+            Some(_) => self.directional_factor(direction) / cos_theta,
+        }
+    }
+
+    /// Returns the irradiance at a point on a surface with the given normal,
+    /// treating the light as a point source at its center. The cosine at the
+    /// receiver is subject to wrap lighting, see `set_wrap()`.
+    ///
+    /// This is an approximation that holds when the point is far away
+    /// compared to the size of the light. It ignores occlusion.
+    pub fn get_irradiance(&self, point: SVector3, normal: SVector3) -> SVector3 {
+        let center = self.center();
+        let dist = center.distance(point);
+        let direction = (point - center) * (1.0 / dist);
+        let cos_receiver = self.receiver_factor((-direction).dot(normal));
+        let intensity = self.emission.mean_radiance() * (self.area * self.directional_factor(direction));
+        intensity * (cos_receiver / (dist * dist))
+    }
+
+    /// Intersects a ray with the emitting side of the light. Returns the
+    /// distance along the ray and the radiance emitted there, or `None` if the
+    /// ray misses the light or approaches it from the back.
+    pub fn intersect(&self, origin: SVector3, direction: SVector3) -> Option<(f32, SVector3)> {
+        let denom = self.normal.dot(direction);
+        if denom >= 0.0 {
+            return None
+        }
+
+        let t = self.normal.dot(self.origin - origin) / denom;
+        if t <= 0.0 {
+            return None
+        }
+
+        // Express the point in terms of the edges: q = s * edge_u + t * edge_v.
+        // Then q cross edge_v = s * area * normal, and similarly for t.
+        let q = origin + direction * t - self.origin;
+        let s = q.cross(self.edge_v).dot(self.normal) / self.area;
+        let r = self.edge_u.cross(q).dot(self.normal) / self.area;
+        if s < 0.0 || s >= 1.0 || r < 0.0 || r >= 1.0 {
+            return None
+        }
+
+        Some((t, self.emission.lookup(s, r) * self.radiance_factor(-direction)))
+    }
+
+    /// Moves the light by the given offset.
+    pub fn translate(&mut self, offset: SVector3) {
+        self.origin = self.origin + offset;
+    }
+
+    /// Samples a point on the light given three uniform random numbers in
+    /// [0, 1). Returns the position, the radiance, and the probability
+    /// density with respect to area.
+    pub fn sample_point(&self, xi: f32, a: f32, b: f32) -> (SVector3, SVector3, f32) {
+        let map = &self.emission;
+        let (index, p_texel) = map.pick_texel(xi);
+        let i = index as u32 % map.width;
+        let j = index as u32 / map.width;
+
+        // Pick a point uniformly within the texel.
+        let s = (i as f32 + a) / map.width as f32;
+        let t = (j as f32 + b) / map.height as f32;
+        let position = self.origin + self.edge_u * s + self.edge_v * t;
+
+        let texel_area = self.area / (map.width * map.height) as f32;
+        (position, map.texels[index], p_texel / texel_area)
+    }
+
+    /// Returns 8 points on the light. The light index of the sample is 0, the
+    /// scene fills it in when it has more lights.
+    pub fn sample(&self, rng: &mut Rng) -> MLightSample {
+        let xi = rng.sample_unit();
+        let (a, b) = rng.sample_unit_pair();
+        let samples = generate_slice8(|i| self.sample_point(xi.get_coord(i), a.get_coord(i), b.get_coord(i)));
+        MLightSample {
+            position: MVector3::generate(|i| samples[i].0),
+            normal: MVector3::broadcast(self.normal),
+            radiance: MVector3::generate(|i| samples[i].1),
+            pdf: Mf32::generate(|i| samples[i].2),
+            lights: [0; 8],
+        }
+    }
+}
+
+#[cfg(test)]
+fn half_black_light() -> AreaLight {
+    // A 2x2 light at height 2, facing down. The part at negative x is black,
+    // the part at positive x is white.
+    let black = SVector3::zero();
+    let white = SVector3::new(1.0, 1.0, 1.0);
+    AreaLight::new(SVector3::new(-1.0, 2.0, -1.0),
+                   SVector3::new(2.0, 0.0, 0.0),
+                   SVector3::new(0.0, 0.0, 2.0),
+                   EmissionMap::new(2, 1, vec![black, white]))
+}
+
+#[test]
+fn area_light_normal_and_area() {
+    let light = half_black_light();
+    assert_eq!(light.normal, SVector3::new(0.0, -1.0, 0.0));
+    assert_eq!(light.area(), 4.0);
+}
+
+#[test]
+fn area_light_pdf_integrates_to_one() {
+    // For a constant map the pdf is uniform, so 1/pdf is the area.
+    let light = AreaLight::new(SVector3::zero(),
+                               SVector3::new(3.0, 0.0, 0.0),
+                               SVector3::new(0.0, 0.5, 0.0),
+                               EmissionMap::constant(SVector3::new(2.0, 1.0, 0.5)));
+    let mut rng = Rng::with_seed(2, 5, 7);
+    for _ in 0..64 {
+        let sample = light.sample(&mut rng);
+        for i in 0..8 {
+            assert!((1.0 / sample.pdf.get_coord(i) - 1.5).abs() < 1e-5);
+        }
+    }
+}
+
+#[test]
+fn area_light_emission_map_biases_illumination() {
+    let light = half_black_light();
+    let mut rng = Rng::with_seed(2, 5, 7);
+
+    // Estimate the irradiance on the floor below either half of the light.
+    let irradiance = |rng: &mut Rng, x: f32| {
+        let receiver = SVector3::new(x, 0.0, 0.0);
+        let n = 512;
+        let mut total = 0.0;
+        for _ in 0..n {
+            let sample = light.sample(rng);
+            for i in 0..8 {
+                let position = SVector3::new(sample.position.x.get_coord(i),
+                                             sample.position.y.get_coord(i),
+                                             sample.position.z.get_coord(i));
+
+                // Importance sampling never picks the black half.
+                assert!(position.x >= 0.0);
+
+                let dist = receiver.distance(position);
+                let dist_sqr = dist * dist;
+                let dir = (position - receiver) * (1.0 / dist);
+                let cos_receiver = dir.y;
+                let cos_light = -dir.y;
+                let radiance = sample.radiance.y.get_coord(i);
+                total += radiance * cos_receiver * cos_light / (dist_sqr * sample.pdf.get_coord(i));
+            }
+        }
+        total / (n * 8) as f32
+    };
+
+    let dark_side = irradiance(&mut rng, -0.5);
+    let bright_side = irradiance(&mut rng, 0.5);
+    assert!(bright_side > 2.0 * dark_side,
+            "irradiance below the bright half ({}) should exceed that below the dark half ({})",
+            bright_side, dark_side);
+}
+
+#[test]
+fn area_light_intersect_hits_emitting_side_only() {
+    let light = half_black_light();
+    let down = SVector3::new(0.0, -1.0, 0.0);
+    let up = SVector3::new(0.0, 1.0, 0.0);
+
+    // From below, the light is hit at height 2, and the map is looked up at
+    // the hit point.
+    let (t, radiance) = light.intersect(SVector3::new(0.5, 0.0, 0.5), up).unwrap();
+    assert!((t - 2.0).abs() < 1e-5);
+    assert_eq!(radiance, SVector3::new(1.0, 1.0, 1.0));
+    let (_, radiance) = light.intersect(SVector3::new(-0.5, 0.0, 0.5), up).unwrap();
+    assert_eq!(radiance, SVector3::zero());
+
+    // The light is invisible from the back, and it does not extend beyond
+    // its edges.
+    assert!(light.intersect(SVector3::new(0.5, 3.0, 0.5), down).is_none());
+    assert!(light.intersect(SVector3::new(1.5, 0.0, 0.5), up).is_none());
+    assert!(light.intersect(SVector3::new(0.5, 0.0, -1.5), up).is_none());
+    assert!(light.intersect(SVector3::new(0.5, 3.0, 0.5), up).is_none());
+}
+
+#[test]
+fn area_light_profile_modulates_irradiance() {
+    use ies;
+
+    // A small light at height 2, facing down, with a profile that is bright
+    // downwards and dim to the side.
+    let mut light = AreaLight::new(SVector3::new(-0.05, 2.0, -0.05),
+                                   SVector3::new(0.1, 0.0, 0.0),
+                                   SVector3::new(0.0, 0.0, 0.1),
+                                   EmissionMap::constant(SVector3::new(1.0, 1.0, 1.0)));
+    let up = SVector3::new(0.0, 1.0, 0.0);
+    let center = light.center();
+    let below = SVector3::new(0.0, 0.0, 0.0);
+    let side = center + SVector3::new(1.0, 0.0, 0.0);
+    let oblique = center + SVector3::new(3.0f32.sqrt(), -1.0, 0.0) * 0.5;
+
+    let lambertian = [light.get_irradiance(below, up), light.get_irradiance(oblique, up)];
+    light.set_profile(Some(ies::spot_profile()));
+
+    let down = SVector3::new(0.0, -1.0, 0.0);
+    assert_eq!(light.directional_factor(down), 1.0);
+    assert!((light.directional_factor(SVector3::new(1.0, 0.0, 0.0)) - 0.1).abs() < 1e-5);
+
+    // Along the axis the profile and the Lambertian light agree. At 60
+    // degrees from the axis, the profile has 0.367 of its peak intensity,
+    // where a Lambertian light has 0.5.
+    assert!((light.get_irradiance(below, up) - lambertian[0]).norm_squared() < 1e-10);
+    let ratio = light.get_irradiance(oblique, up).x / lambertian[1].x;
+    assert!((ratio - (0.3667 / 0.5)).abs() < 1e-3, "ratio is {}", ratio);
+
+    // Sideways the light is dim but not zero, a surface facing it receives
+    // light.
+    let facing = SVector3::new(-1.0, 0.0, 0.0);
+    assert!(light.get_irradiance(side, facing).x > 0.0);
+
+    // Seen from the oblique point, the light is less bright than along the
+    // axis, by the intensity ratio over the projected area.
+    assert_eq!(light.radiance_factor(down), 1.0);
+    let (_, radiance) = light.intersect(oblique, (center - oblique).normalized()).unwrap();
+    assert!((radiance.x - 0.3667 / 0.5).abs() < 1e-3, "radiance is {}", radiance.x);
+}
+
+#[test]
+fn environment_map_lookup_maps_zenith_to_top_row() {
+    let top = SVector3::new(1.0, 0.0, 0.0);
+    let bottom = SVector3::new(0.0, 0.0, 1.0);
+    let map = EnvironmentMap::from_image(HdrImage {
+        width: 2,
+        height: 2,
+        texels: vec![top, top, bottom, bottom],
+    });
+    assert_eq!(map.lookup(SVector3::new(0.0, 0.0, 1.0)), top);
+    assert_eq!(map.lookup(SVector3::new(0.6, 0.0, 0.8)), top);
+    assert_eq!(map.lookup(SVector3::new(0.0, 0.6, -0.8)), bottom);
+    assert_eq!(map.lookup(SVector3::new(0.0, 0.0, -1.0)), bottom);
+}
+
+#[test]
+fn environment_map_jittered_lookup_averages_to_bilinear() {
+    let v = SVector3::new;
+    let blue = v(0.0, 0.0, 4.0);
+    let map = EnvironmentMap::from_image(HdrImage {
+        width: 4,
+        height: 2,
+        texels: vec![v(1.0, 0.0, 0.0), v(0.0, 1.0, 0.0), v(0.0, 0.0, 1.0), v(1.0, 1.0, 1.0),
+                     blue, blue, blue, blue],
+    });
+
+    // A direction at texel coordinates (1.3, 0.75). Relative to the texel
+    // centers, that is 80% of the way from texel 0 to 1, and 25% of the way
+    // from the top row to the bottom one.
+    let phi = (1.3 / 4.0 - 0.5) * 2.0 * consts::PI;
+    let theta = 0.375 * consts::PI;
+    let direction = v(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+    let bilinear = map.lookup_bilinear(direction);
+    assert!((bilinear - v(0.15, 0.6, 1.0)).norm_squared() < 1e-6, "bilinear lookup is {}", bilinear);
+
+    let mut rng = Rng::with_seed(3, 5, 7);
+    let directions = MVector3::broadcast(direction);
+    let n = 4096;
+    let mut sum = MVector3::zero();
+    for _ in 0..n {
+        sum = sum + map.lookup_8_jittered(directions, &mut rng);
+    }
+    let mean = SVector3::new(sum.x.hsum(), sum.y.hsum(), sum.z.hsum()) * (1.0 / (8 * n) as f32);
+    assert!((mean - bilinear).norm_squared() < 0.05 * 0.05, "mean is {}, expected {}", mean, bilinear);
+}
+
+#[test]
+fn area_light_wrap_lights_surfaces_facing_away() {
+    // A small light at height 2, facing down.
+    let mut light = AreaLight::new(SVector3::new(-0.05, 2.0, -0.05),
+                                   SVector3::new(0.1, 0.0, 0.0),
+                                   SVector3::new(0.0, 0.0, 0.1),
+                                   EmissionMap::constant(SVector3::new(1.0, 1.0, 1.0)));
+    let below = SVector3::zero();
+    let up = SVector3::new(0.0, 1.0, 0.0);
+    let tilted = SVector3::new(1.0, 1.0, 0.0).normalized();
+    let away = SVector3::new(1.0, -0.2, 0.0).normalized();
+    let lambertian = [light.get_irradiance(below, up), light.get_irradiance(below, tilted)];
+    assert_eq!(light.get_irradiance(below, away), SVector3::zero());
+
+    // Without wrap the light is Lambertian, whatever the exponent.
+    light.set_wrap(0.0, 3.0);
+    assert_eq!(light.get_irradiance(below, up), lambertian[0]);
+    assert_eq!(light.get_irradiance(below, tilted), lambertian[1]);
+
+    // With wrap, a surface that faces slightly away receives light, and
+    // surfaces that face the light head-on receive as much as before.
+    light.set_wrap(1.0, 2.0);
+    assert!(light.get_irradiance(below, away).x > 0.0);
+    assert!(light.get_irradiance(below, tilted).x > lambertian[1].x);
+    assert!((light.get_irradiance(below, up) - lambertian[0]).norm_squared() < 1e-10);
+
+    // A wider wrap brightens the back side more.
+    let half_wrap = {
+        light.set_wrap(0.5, 2.0);
+        light.get_irradiance(below, away).x
+    };
+    light.set_wrap(1.0, 2.0);
+    assert!(light.get_irradiance(below, away).x > half_wrap);
+}
@@ -26,16 +26,30 @@ extern crate time;
 extern crate glium;
 
 mod aabb;
+mod animation;
+mod benchmark;
 mod bvh;
+mod color;
+mod emitter;
+mod exr;
+mod graph;
+mod hdr;
+mod ies;
+mod light;
 mod material;
+mod photon;
 mod quaternion;
 mod random;
 mod ray;
 mod renderer;
 mod scene;
 mod simd;
+mod sky;
+mod splat;
 mod stats;
+mod subsurface;
 mod trace;
+mod transform;
 mod triangle;
 mod ui;
 mod util;
@@ -46,11 +60,17 @@ mod wavefront;
 mod bench;
 
 use material::SMaterial;
+use photon::PhotonMap;
+use random::Rng;
 use renderer::{RenderBuffer, Renderer};
-use scene::Scene;
+use scene::{LightDrag, Scene};
 use stats::GlobalStats;
 use std::collections::HashMap;
+use std::env;
 use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
 use time::PreciseTime;
 use ui::{Action, Window};
 use wavefront::Mesh;
@@ -70,7 +90,7 @@ fn load_textures() -> Vec<Vec<u8>> {
 fn build_scene() -> Scene {
     println!("loading geometry");
     let mut materials = HashMap::new();
-    materials.insert("baseboard", SMaterial::white().with_glossiness(4));
+    materials.insert("baseboard", SMaterial::white().with_ggx());
     materials.insert("ceiling", SMaterial::white().with_glossiness(1));
     materials.insert("fauteuil", SMaterial::diffuse(1.0, 0.1, 0.4));
     materials.insert("floor", SMaterial::diffuse(0.569, 0.494, 0.345).with_glossiness(4).with_texture(1));
@@ -87,6 +107,13 @@ fn build_scene() -> Scene {
     scene
 }
 
+/// Traces the photons for the caustics of the scene. This has to be done again
+/// when the lights move.
+fn trace_photon_map(scene: &Scene) -> PhotonMap {
+    let mut rng = Rng::with_seed(2, 5, 7);
+    PhotonMap::trace(scene, 16 * 1024, 0.05, &mut rng)
+}
+
 fn main() {
     // The patch size has been tuned for 8 cores. With a resolution of 1280x736 there are 920
     // patches to be rendered by the worker pool. Increasing the patch size to 64 results in 230
@@ -97,17 +124,45 @@ fn main() {
     let height = 736;
     let patch_width = 32;
 
+    // With `--benchmark [frames]`, render a number of frames without a window
+    // and report the frame times.
+    let args: Vec<String> = env::args().collect();
+    if let Some(i) = args.iter().position(|arg| arg == "--benchmark") {
+        let num_frames = args.get(i + 1).and_then(|n| n.parse().ok()).unwrap_or(100);
+        let renderer = Renderer::new(build_scene(), width, height);
+        let mut threadpool = scoped_threadpool::Pool::new(renderer::available_threads());
+        println!("rendering {} frames", num_frames);
+        benchmark::run(&renderer, &mut threadpool, patch_width, num_frames).print();
+        return
+    }
+
     let mut window = Window::new(width, height, "Convector interactive path tracer");
     let mut renderer = Renderer::new(build_scene(), width, height);
+    let photon_map = trace_photon_map(renderer.scene());
+    renderer.set_photon_map(Some(photon_map));
     let mut stats = GlobalStats::new();
     let mut trace_log = trace::TraceLog::with_limit(6 * 1024);
-    let mut threadpool = scoped_threadpool::Pool::new(num_cpus::get() as u32);
+    let mut threadpool = scoped_threadpool::Pool::new(renderer::available_threads());
     let mut backbuffer = RenderBuffer::new(width, height);
     let mut backbuffer_g = RenderBuffer::new(width, height);
-    let mut f32_buffer = renderer.new_buffer_f32(); // TODO: Consistency.
-    let mut f32_buffer_samples = 0;
+    let mut f32_buffer = renderer.new_buffer_f32();
     let mut should_continue = true;
     let mut render_realtime = true;
+    let mut light_drag = None;
+
+    // After a change, the next frame is rendered at a reduced resolution, so
+    // there is feedback immediately. Subsequent frames refine it.
+    let mut resolution_scale = 1;
+    let mut showing_preview = false;
+
+    // Whether the converged accumulation buffer has been displayed already.
+    let mut presented_converged = false;
+
+    // An event that arrived while a frame was being rendered, and whether it
+    // cancelled that frame. A cancelled frame is incomplete, so it is not
+    // displayed.
+    let mut pending_action = None;
+    let mut frame_cancelled = false;
 
     for texture in load_textures() {
         window.upload_texture(texture);
@@ -128,18 +183,64 @@ fn main() {
         let time = epoch.to(PreciseTime::now()).num_milliseconds() as f32 * 1e-3;
         let time_delta = (stats.frame_us.median() as f32) * 1e-6;
 
-        match window.handle_events() {
+        let action = match pending_action.take() {
+            Some(action) => action,
+            None => window.handle_events(),
+        };
+        match action {
+            Action::BeginDrag(x, y) => {
+                light_drag = LightDrag::begin(renderer.scene(), x, y);
+                // The scene no longer animates once the user moves a light,
+                // otherwise the animation would move it back.
+                if light_drag.is_some() {
+                    renderer.take_control();
+                }
+            }
+            Action::Drag(x, y) => {
+                if let Some(ref mut drag) = light_drag {
+                    drag.update(renderer.scene_mut(), x, y);
+                    resolution_scale = 2;
+                    // The accumulated samples are no longer valid.
+                    if !render_realtime {
+                        f32_buffer = renderer.new_buffer_f32();
+                    }
+                }
+            }
+            Action::EndDrag => {
+                if light_drag.take().is_some() {
+                    let photon_map = trace_photon_map(renderer.scene());
+                    renderer.set_photon_map(Some(photon_map));
+                    // The caustics changed, so start accumulating anew.
+                    if !render_realtime {
+                        f32_buffer = renderer.new_buffer_f32();
+                        resolution_scale = 2;
+                    }
+                }
+            }
             Action::DumpTrace => {
                 trace_log.export_to_file("trace.json").expect("failed to write trace");
                 println!("wrote trace to trace.json");
             }
             Action::Quit => should_continue = false,
-            Action::PrintStats => stats.print(),
+            Action::PrintStats => {
+                stats.print();
+                renderer.warn_if_bounce_cap_hit();
+            }
+            Action::SaveScreenshot => {
+                if render_realtime {
+                    println!("screenshots can only be taken in accumulation mode, press 'r' first");
+                } else {
+                    match renderer.save_screenshot(&f32_buffer, None, true) {
+                        Ok(path) => println!("saved screenshot to {}", path.display()),
+                        Err(err) => println!("failed to save screenshot: {}", err),
+                    }
+                }
+            }
             Action::ToggleDebugView => renderer.toggle_debug_view(),
             Action::ToggleRealtime => {
                 render_realtime = !render_realtime;
+                resolution_scale = 2;
                 f32_buffer = renderer.new_buffer_f32();
-                f32_buffer_samples = 0;
                 // In accumulative mode the time is fixed and there is no motion
                 // blur.
                 renderer.set_time(time, 0.0);
@@ -152,67 +253,140 @@ fn main() {
         }
         renderer.update_scene();
 
+        // In accumulation mode, a camera that moved invalidates the samples
+        // like a moved light does. In real-time mode every frame is rendered
+        // anew anyway, and the camera moves every frame while it animates, so
+        // there only discrete changes get a preview.
+        if !render_realtime && renderer.camera_moved() {
+            f32_buffer = renderer.new_buffer_f32();
+            resolution_scale = 2;
+        }
+
+        // Once the accumulated image has converged, nothing changes any more,
+        // so there is no need to render anything. The samples of the final
+        // batch have not been displayed yet though, so present the buffer once
+        // more, and after that save power, but keep handling events.
+        if !render_realtime && renderer.is_converged(&f32_buffer) {
+            if !presented_converged {
+                renderer.buffer_f32_into_render_buffer(&f32_buffer, &mut backbuffer);
+                let frontbuffer = mem::replace(&mut backbuffer, RenderBuffer::new(width, height));
+                let frontbuffer_g = mem::replace(&mut backbuffer_g, RenderBuffer::new(width, height));
+                window.display_buffer(frontbuffer.into_bitmap(),
+                                      frontbuffer_g.into_bitmap(),
+                                      &mut stats);
+                presented_converged = true;
+            } else {
+                thread::sleep(Duration::from_millis(16));
+            }
+            continue;
+        }
+
+        presented_converged = false;
+
         // When rendering in accumulation mode, first copy the current state
         // into the backbuffer (which will immediately after this become the new
-        // front buffer) so we can display it later.
-        if !render_realtime {
-            let n = if f32_buffer_samples > 0 { f32_buffer_samples } else { 1 };
-            renderer.buffer_f32_into_render_buffer(&f32_buffer, &mut backbuffer, n);
-            f32_buffer_samples += 1;
+        // front buffer) so we can display it later. A preview in the backbuffer
+        // is shown instead of the freshly cleared accumulation buffer, and
+        // while the preview renders, the previous image stays on screen rather
+        // than the cleared buffer.
+        let preview = resolution_scale > 1;
+        if !render_realtime && !showing_preview && !preview {
+            renderer.buffer_f32_into_render_buffer(&f32_buffer, &mut backbuffer);
         }
+        let display_front = !frame_cancelled && (render_realtime || !preview);
 
         let new_backbuffer = RenderBuffer::new(width, height);
         let new_backbuffer_g = RenderBuffer::new(width, height);
-        let frontbuffer = mem::replace(&mut backbuffer, new_backbuffer);
-        let frontbuffer_g = mem::replace(&mut backbuffer_g, new_backbuffer_g);
-        let renderer_ref = &renderer;
-        let trace_log_ref = &trace_log;
-        let backbuffer_ref = &backbuffer;
-        let backbuffer_g_ref = &backbuffer_g;
-        let f32_buffer_ref = &f32_buffer[..];
-
-        threadpool.scoped(|scope| {
-
-            let w = width / patch_width;
-            let h = height / patch_width;
-
-            // Queue tasks for the worker threads to render patches.
-            for i in 0..w {
-                for j in 0..h {
-                    scope.execute(move || {
-                        let x = i * patch_width;
-                        let y = j * patch_width;
-
-                        // Multiple threads mutably borrow the buffer below,
-                        // which could cause races, but all of the patches are
-                        // disjoint, hence it is safe.
-
-                        if render_realtime {
-                            let _stw = trace_log_ref.scoped("render_patch_u8", j * w + i);
-                            let bitmap = unsafe { backbuffer_ref.get_mut_slice() };
-                            let gbuffer = unsafe { backbuffer_g_ref.get_mut_slice() };
-                            renderer_ref.render_patch_u8(bitmap, gbuffer, patch_width, x, y, frame_number);
-                        } else {
-                            let _stw = trace_log_ref.scoped("accumulate_patch_f32", j * w + i);
-                            let buffer = unsafe { util::make_mutable(f32_buffer_ref) };
-                            let gbuffer = unsafe { backbuffer_g_ref.get_mut_slice() };
-                            renderer_ref.accumulate_patch_f32(buffer, gbuffer, patch_width, x, y, frame_number);
-                        }
-                    });
+        let mut frontbuffer = mem::replace(&mut backbuffer, new_backbuffer);
+        let mut frontbuffer_g = mem::replace(&mut backbuffer_g, new_backbuffer_g);
+        if let Some(ref drag) = light_drag {
+            renderer.draw_handle(&mut frontbuffer, &mut frontbuffer_g, drag.position());
+        }
+        let cancel = AtomicBool::new(false);
+        {
+            let cancel_ref = &cancel;
+            let renderer_ref = &renderer;
+            let trace_log_ref = &trace_log;
+            let backbuffer_ref = &backbuffer;
+            let backbuffer_g_ref = &backbuffer_g;
+            let f32_buffer_ref = &f32_buffer;
+
+            threadpool.scoped(|scope| {
+
+                let w = width / patch_width;
+                let h = height / patch_width;
+
+                // Queue tasks for the worker threads to render patches.
+                for i in 0..w {
+                    for j in 0..h {
+                        scope.execute(move || {
+                            if cancel_ref.load(Ordering::Relaxed) {
+                                return
+                            }
+
+                            let x = i * patch_width;
+                            let y = j * patch_width;
+
+                            // Multiple threads mutably borrow the buffer below,
+                            // which could cause races, but all of the patches are
+                            // disjoint, hence it is safe.
+
+                            if render_realtime || preview {
+                                let _stw = trace_log_ref.scoped("render_patch_u8", j * w + i);
+                                let bitmap = unsafe { backbuffer_ref.get_mut_slice() };
+                                let gbuffer = unsafe { backbuffer_g_ref.get_mut_slice() };
+                                renderer_ref.render_patch_u8_scaled(bitmap, gbuffer, patch_width, x, y,
+                                                                    resolution_scale, frame_number);
+                            } else {
+                                let _stw = trace_log_ref.scoped("accumulate_patch_f32", j * w + i);
+                                unsafe {
+                                    let gbuffer = backbuffer_g_ref.get_mut_slice();
+                                    renderer_ref.accumulate_patch_f32(f32_buffer_ref, gbuffer, patch_width, x, y,
+                                                                      frame_number);
+                                }
+                            }
+                        });
+                    }
                 }
-            }
 
-            // In the mean time upload the previous frame to the GPU
-            // and display it.
-            let _stw_display = trace_log.scoped("display_buffer", 0);
-            window.display_buffer(frontbuffer.into_bitmap(),
-                                  frontbuffer_g.into_bitmap(),
-                                  &mut stats);
+                // In the mean time upload the previous frame to the GPU
+                // and display it.
+                if display_front {
+                    let _stw_display = trace_log.scoped("display_buffer", 0);
+                    window.display_buffer(frontbuffer.into_bitmap(),
+                                          frontbuffer_g.into_bitmap(),
+                                          &mut stats);
+                }
+
+                // Moving a light or switching modes makes the frame that is
+                // being rendered obsolete. Skip its remaining patches, and
+                // handle the event at the start of the next frame.
+                let action = window.handle_events();
+                let invalidates = match action {
+                    Action::Drag(..) => light_drag.is_some(),
+                    Action::ToggleRealtime => true,
+                    _ => false,
+                };
+                if invalidates {
+                    cancel_ref.store(true, Ordering::Relaxed);
+                }
+                pending_action = Some(action);
+
+                // The scope automatically waits for all tasks to complete
+                // before the loop continues.
+            });
+        }
+
+        // All patches have been accumulated now, the samples are complete.
+        frame_cancelled = cancel.load(Ordering::Relaxed);
+        if !render_realtime && !preview && !frame_cancelled {
+            f32_buffer.inc_num_samples();
+        }
 
-            // The scope automatically waits for all tasks to complete
-            // before the loop continues.
-        });
+        showing_preview = preview;
+        resolution_scale = 1;
 
+        renderer.print_traversal_stats();
         stats.frame_us.insert_time_us(stw_frame.take_duration());
     }
 }
@@ -18,11 +18,17 @@
 //!  * Bit 30: if 1, a primitive with this material is eligible for direct
 //!    sampling.
 //!
-//!  * Bit 29: if 1, this material is a glass material.
+//!  * Bit 29: if 1, this material is a glass material. Glass reflects and
+//!    refracts specularly, and the color is the fraction of light per channel
+//!    that is transmitted over a unit of distance inside the glass. Shadow
+//!    rays pass straight through glass.
 //!
 //!  * Bits 26-28: the 2-log of the exponent for the Blinn-Phong BRDF plus one.
 //!    Must be between 0 and 6 (inclusive), so the exponent can be 0, 1, 2, 4,
-//!    8, or 16.
+//!    8, or 16. The value 7 selects an isotropic GGX lobe with roughness
+//!    `GGX_ROUGHNESS` instead. Glass has no Blinn-Phong lobe, for glass these
+//!    bits are a roughness between 0 (perfectly smooth) and 5, which only path
+//!    regularization sets.
 //!
 //!  * Bits 24-25: the texture index, ranging from 0 to 3.
 //!
@@ -67,10 +73,20 @@ use random::Rng;
 use ray::{MIntersection, MRay};
 use scene::Scene;
 use simd::{Mask, Mf32, Mi32};
+use std::cmp;
 use std::f32::consts;
-use vector3::MVector3;
+use vector3::{MVector3, SVector3};
 
-#[derive(Copy, Clone, Debug)]
+/// The index of refraction of glass.
+pub const GLASS_IOR: f32 = 1.5;
+
+/// The glossiness bits that select the GGX lobe, see `SMaterial::with_ggx()`.
+const GGX_GLOSS_INDEX: u32 = 7;
+
+/// The roughness of the GGX lobe.
+pub const GGX_ROUGHNESS: f32 = 0.3;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct SMaterial(u32);
 
 pub type MMaterial = Mf32;
@@ -100,9 +116,23 @@ impl SMaterial {
         SMaterial(mat)
     }
 
-    /// A transparent and reflective material.
+    /// A transparent and reflective material. The glass is clear, it absorbs
+    /// no light, see `colored_glass()` for glass that does.
     pub fn glass() -> SMaterial {
-        let mat = 0b0110_0000_00000000_00000000_00000000_u32;
+        let mat = 0b0110_0000_11111111_11111111_11111111_u32;
+        SMaterial(mat)
+    }
+
+    /// Glass that absorbs light as it travels through it.
+    ///
+    /// The color is the fraction of light per channel that remains after
+    /// traveling through one unit of glass. Light is absorbed exponentially
+    /// with distance (the Beer-Lambert law), so a thicker slab of glass casts
+    /// a darker and more saturated shadow. The surfaces of the glass must form
+    /// a closed mesh with outward-facing normals.
+    pub fn colored_glass(r: f32, g: f32, b: f32) -> SMaterial {
+        let SMaterial(color) = SMaterial::diffuse(r, g, b);
+        let mat = 0b0010_0000_00000000_00000000_00000000_u32 | color;
         SMaterial(mat)
     }
 
@@ -117,6 +147,15 @@ impl SMaterial {
         SMaterial(mat)
     }
 
+    /// Replaces the Blinn-Phong lobe of the material by a GGX lobe with
+    /// roughness `GGX_ROUGHNESS`. GGX has a sharper peak and a longer tail than
+    /// Blinn-Phong, which looks more like real glossy surfaces.
+    pub fn with_ggx(self) -> SMaterial {
+        let SMaterial(mat) = self;
+        let no_gloss = 0b11100011_11111111_11111111_11111111_u32;
+        SMaterial((mat & no_gloss) | (GGX_GLOSS_INDEX << 26))
+    }
+
     /// Sets the texture index of the material. Valid values are 0 through 3.
     pub fn with_texture(self, texture_index: u32) -> SMaterial {
         assert!(texture_index <= 3);
@@ -135,6 +174,106 @@ impl SMaterial {
     }
 }
 
+/// Physical parameters of a real-world material, for authoring scenes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Material {
+    /// Whether the material is a conductor. Metals have no diffuse part, their
+    /// color is the specular reflectance.
+    pub metallic: bool,
+
+    /// Reflectance at normal incidence per channel. For dielectrics this
+    /// follows from the index of refraction, and is the same for all channels.
+    pub f0: SVector3,
+
+    /// The color of the diffuse part, unused for metals.
+    pub albedo: SVector3,
+
+    /// Index of refraction. Only meaningful for dielectrics.
+    pub ior: f32,
+
+    /// Roughness of the GGX microfacet distribution, 0 is a perfect mirror.
+    pub roughness: f32,
+
+    /// Whether light is transmitted through the material, as for glass.
+    pub transparent: bool,
+
+    /// For translucent materials such as skin and wax, the mean free path of
+    /// light below the surface per channel, in world units. See
+    /// `Scene::add_material()`.
+    pub subsurface: Option<SVector3>,
+}
+
+impl Material {
+    /// Returns the parameters of a common material, or `None` if the name is
+    /// unknown. Known names are gold, copper, silver, aluminium, glass, water,
+    /// plastic, and rough_plastic.
+    ///
+    /// The reflectances of metals are the linear RGB values at normal
+    /// incidence, from "Real-Time Rendering", 3rd edition, table 7.3.
+    pub fn preset(name: &str) -> Option<Material> {
+        let v = SVector3::new;
+        let metal = |f0: SVector3, roughness: f32| Material {
+            metallic: true,
+            f0: f0,
+            albedo: f0,
+            // Conductors have a complex index of refraction, which is not
+            // modeled; f0 describes their reflectance instead.
+            ior: 1.0,
+            roughness: roughness,
+            transparent: false,
+            subsurface: None,
+        };
+        let dielectric = |ior: f32, albedo: SVector3, roughness: f32, transparent: bool| {
+            let r = (ior - 1.0) / (ior + 1.0);
+            Material {
+                metallic: false,
+                f0: v(r * r, r * r, r * r),
+                albedo: albedo,
+                ior: ior,
+                roughness: roughness,
+                transparent: transparent,
+                subsurface: None,
+            }
+        };
+
+        let material = match name {
+            "gold" => metal(v(1.000, 0.766, 0.336), 0.2),
+            "copper" => metal(v(0.955, 0.638, 0.538), 0.25),
+            "silver" => metal(v(0.972, 0.960, 0.915), 0.15),
+            "aluminium" => metal(v(0.913, 0.922, 0.924), 0.3),
+            "glass" => dielectric(1.5, SVector3::one(), 0.0, true),
+            "water" => dielectric(1.333, SVector3::one(), 0.0, true),
+            "plastic" => dielectric(1.46, v(0.8, 0.8, 0.8), 0.3, false),
+            "rough_plastic" => dielectric(1.46, v(0.8, 0.8, 0.8), 0.7, false),
+            _ => return None,
+        };
+        Some(material)
+    }
+
+    /// Approximates the material in the packed encoding.
+    ///
+    /// The packed encoding has no notion of metals or of an index of
+    /// refraction: transparent materials become glass, and other materials
+    /// get the specular color of metals or the albedo of dielectrics, with the
+    /// Blinn-Phong exponent that matches the roughness.
+    pub fn to_smaterial(&self) -> SMaterial {
+        if self.transparent {
+            return SMaterial::glass()
+        }
+
+        let color = if self.metallic { self.f0 } else { self.albedo };
+
+        // The Blinn-Phong exponent 2 / a^2 - 2 has roughly the same highlight
+        // as GGX with roughness a. The glossiness is the 2-log of the exponent
+        // plus one.
+        let a = self.roughness.max(1e-3);
+        let exponent = (2.0 / (a * a) - 2.0).max(0.0);
+        let glossiness = if exponent < 1.0 { 0 } else { cmp::min(exponent.log2().round() as u32 + 1, 6) };
+
+        SMaterial::diffuse(color.x, color.y, color.z).with_glossiness(glossiness)
+    }
+}
+
 impl MMaterial {
     pub fn broadcast_material(material: SMaterial) -> MMaterial {
         use std::mem::transmute;
@@ -177,6 +316,69 @@ impl MMaterial {
         exponent & Mi32::broadcast(0b111)
     }
 
+    /// Sets the sign bit to 1 if the material is diffuse, that is, if the
+    /// Blinn-Phong exponent is 0 or 1, and the material is not glass.
+    pub fn is_diffuse(&self) -> Mask {
+        // The exponent index is at most 1 if and only if it is less than 1.5,
+        // so subtracting 1.5 makes the sign bit 1 exactly for diffuse lanes.
+        let low_gloss = self.get_glossiness().into_mf32() - Mf32::broadcast(1.5);
+        low_gloss & self.is_glass().neg_xor()
+    }
+
+    /// Sets the sign bit to 1 if the material has a GGX lobe rather than a
+    /// Blinn-Phong lobe.
+    pub fn is_ggx(&self) -> Mask {
+        use std::mem::transmute;
+
+        // All three glossiness bits are set for GGX. Move each of them into
+        // the sign bit, and take the bitwise and.
+        let mati: Mi32 = unsafe { transmute(*self) };
+        let ggx = mati.map(|x| x << 3) & mati.map(|x| x << 4) & mati.map(|x| x << 5);
+        let ggx: Mask = unsafe { transmute(ggx) };
+        ggx & self.is_glass().neg_xor()
+    }
+
+    /// Sets the sign bit to 1 if the material is glass.
+    pub fn is_glass(&self) -> Mask {
+        use std::mem::transmute;
+
+        // Move the glass bit into the sign bit.
+        let mati: Mi32 = unsafe { transmute(*self) };
+        unsafe { transmute(mati.map(|x| x << 2)) }
+    }
+
+    /// Returns the fraction of light that is transmitted per channel, after
+    /// traveling the given distance through a glass material.
+    pub fn get_transmittance(&self, distance: Mf32) -> MVector3 {
+        let color = self.get_color();
+        MVector3::new(color.x.pow(distance), color.y.pow(distance), color.z.pow(distance))
+    }
+
+    /// Returns a rougher version of the material, for path regularization.
+    ///
+    /// For the lanes where the sign bit of `mask` is 1, the glossiness exponent
+    /// index is reduced by the fraction `amount` of its value. An amount of 0
+    /// leaves the material untouched, an amount of 1 makes it fully diffuse.
+    /// Glass gets the fraction `amount` of the maximum roughness, so a chain of
+    /// specular refractions is blurred as well. The GGX lobe is rough already,
+    /// it is left untouched.
+    pub fn regularize(&self, amount: f32, mask: Mask) -> MMaterial {
+        use std::mem::transmute;
+
+        let gloss = self.get_glossiness();
+        let rough = gloss.map(|g| g - (g as f32 * amount).round() as i32);
+        let rough_glass = Mi32::broadcast((amount * 5.0).round() as i32);
+
+        // Mask that resets the glossiness to 0.
+        let no_gloss = Mi32::broadcast(0b11100011_11111111_11111111_11111111_u32 as i32);
+        let mati: Mi32 = unsafe { transmute(*self) };
+        let regularized: MMaterial = unsafe { transmute((mati & no_gloss) | rough.map(|g| g << 26)) };
+        let regularized_glass: MMaterial = unsafe { transmute((mati & no_gloss) | rough_glass.map(|g| g << 26)) };
+        let regularized = regularized.pick(regularized_glass, self.is_glass());
+
+        self.pick(regularized, mask & self.is_ggx().neg_xor())
+    }
+
     /// Unpacks the texture index.
     pub fn get_texture(&self) -> Mi32 {
         use std::mem::transmute;
@@ -199,6 +401,12 @@ impl MMaterial {
 
         unsafe { transmute(has_tex) }
     }
+
+    /// Returns the material of one of the lanes.
+    pub fn get_material(&self, lane: usize) -> SMaterial {
+        use std::mem::transmute;
+        SMaterial(unsafe { transmute(self.get_coord(lane)) })
+    }
 }
 
 /// Returns the sky color for a ray in the given direction.
@@ -231,9 +439,9 @@ fn continue_path_brdf(isect: &MIntersection, rng: &mut Rng) -> MRay {
     let dir_z = rng.sample_hemisphere_vector();
     let direction = dir_z.rotate_hemisphere(isect.normal);
 
-    // Build a new ray, offset by an epsilon from the intersection so we
-    // don't intersect the same surface again.
-    let origin = direction.mul_add(Mf32::epsilon(), isect.position);
+    // Build a new ray, offset from the intersection so we don't intersect the
+    // same surface again.
+    let origin = isect.offset_origin(direction);
     MRay {
         origin: origin,
         direction: direction,
@@ -251,12 +459,12 @@ fn pd_brdf(isect: &MIntersection, ray: &MRay) -> Mf32 {
 
 /// Continues the path of a photon by sampling a point on a surface.
 fn continue_path_direct_sample(scene: &Scene, isect: &MIntersection, rng: &mut Rng) -> MRay {
-    let ds = scene.get_direct_sample(rng);
+    let ds = scene.get_emitter_sample(&isect.position, rng);
     let direction = (ds.position - isect.position).normalized();
 
-    // Build a new ray, offset by an epsilon from the intersection so we
-    // don't intersect the same surface again.
-    let origin = direction.mul_add(Mf32::epsilon(), isect.position);
+    // Build a new ray, offset from the intersection so we don't intersect the
+    // same surface again.
+    let origin = isect.offset_origin(direction);
     MRay {
         origin: origin,
         direction: direction,
@@ -264,6 +472,58 @@ fn continue_path_direct_sample(scene: &Scene, isect: &MIntersection, rng: &mut R
     }
 }
 
+/// Continues the path at a glass surface, by reflecting or refracting the ray.
+///
+/// The ray is reflected with a probability equal to the Fresnel reflectance,
+/// and refracted otherwise, so the color modulation is one either way. The
+/// reflectance is Schlick's approximation, with the cosine of the angle on the
+/// side of the air. Glass that was roughened by path regularization tilts the
+/// direction randomly, see `MMaterial::regularize()`.
+fn continue_path_glass(material: MMaterial, ray: &MRay, isect: &MIntersection, rng: &mut Rng) -> MRay {
+    // Use the normal on the side that the ray comes from, and the inverse
+    // ratio of indices of refraction when the ray exits the glass.
+    let n = isect.facing_normal();
+    let eta = isect.relative_ior(GLASS_IOR);
+    let cos_i = n.dot(ray.direction).abs();
+
+    // Snell's law. If k is negative, there is no refracted direction, and all
+    // light is reflected (total internal reflection).
+    let sin2_t = eta * eta * cos_i.neg_mul_add(cos_i, Mf32::one());
+    let k = Mf32::one() - sin2_t;
+    let cos_t = k.max(Mf32::zero()).sqrt();
+
+    let r0 = (GLASS_IOR - 1.0) / (GLASS_IOR + 1.0);
+    let r0 = Mf32::broadcast(r0 * r0);
+    let ct = Mf32::one() - cos_i.pick(cos_t, isect.front_face.neg_xor());
+    let ct2 = ct * ct;
+    let ct5 = (ct2 * ct2 * ct).abs();
+    let reflectance = ct5.mul_add(Mf32::one() - r0, r0);
+    let reflect = (rng.sample_unit() - reflectance) | k;
+
+    let refracted = n.mul_add(eta.mul_sub(cos_i, cos_t), ray.direction * eta);
+    let reflected = n.mul_add(cos_i + cos_i, ray.direction);
+    let direction = refracted.pick(reflected, reflect).normalized();
+
+    // Tilt the direction by up to a tenth of the roughness, but keep the
+    // sharp direction where tilting would cross the surface.
+    let spread = material.get_glossiness().into_mf32() * Mf32::broadcast(0.1);
+    let direction = if spread.hsum() > 0.0 {
+        let (dx, dy) = rng.sample_disk();
+        let tilt = MVector3::new(dx * spread, dy * spread, Mf32::one());
+        let tilted = tilt.rotate_hemisphere(direction).normalized();
+        let crossed = tilted.dot(n) * direction.dot(n);
+        tilted.pick(direction, crossed)
+    } else {
+        direction
+    };
+
+    MRay {
+        origin: isect.offset_origin(direction),
+        direction: direction,
+        active: Mf32::zero(),
+    }
+}
+
 /// Asserts that the values where active has sign bit 0 (positive) are nonzero.
 fn debug_assert_all_nonzero(x: Mf32, active: Mf32, tag: &str) {
     debug_assert!(x.0 != 0.0 || active.0.is_sign_negative(), "{} {:?} must be nonzero", tag, x);
@@ -276,11 +536,11 @@ fn debug_assert_all_nonzero(x: Mf32, active: Mf32, tag: &str) {
     debug_assert!(x.7 != 0.0 || active.7.is_sign_negative(), "{} {:?} must be nonzero", tag, x);
 }
 
-/// Returns the probability density for the given ray, for the direct sampling
-/// distribution.
-fn pd_direct_sample(scene: &Scene, ray: &MRay) -> Mf32 {
+/// Returns the probability density for the given ray from the intersection,
+/// for the direct sampling distribution.
+fn pd_direct_sample(scene: &Scene, isect: &MIntersection, ray: &MRay) -> Mf32 {
     let mut pd_total = Mf32::zero();
-    scene.foreach_direct_sample(|triangle| {
+    scene.foreach_direct_sample(|index, triangle| {
         // The probability density for the point on the triangle is simply
         // 1/area, but we want to know the probability of the ray direction, not
         // the probability of the point. The conversion factor is cos(phi)/r^2,
@@ -299,6 +559,10 @@ fn pd_direct_sample(scene: &Scene, ray: &MRay) -> Mf32 {
         let dot_emissive = sample_isect.normal.dot(ray.direction).abs() + Mf32::broadcast(0.0001);
         let pd = distance_sqr * (sample_isect.area * dot_emissive).recip_fast();
 
+        // The triangle was not picked uniformly, but with a probability that
+        // depends on the shading point.
+        let pd = pd * scene.emitter_probability(&isect.position, index);
+
         debug_assert_all_nonzero(sample_isect.area, ray.active, "area");
         debug_assert_all_nonzero(dot_emissive, ray.active, "dot_emissive");
 
@@ -312,11 +576,7 @@ fn pd_direct_sample(scene: &Scene, ray: &MRay) -> Mf32 {
                       "probability density must be positive");
     });
 
-    // So far we computed the probability density per triangle, but we picked
-    // one triangle uniformly, so compensate for that too.
-    // TODO: I can make my pick non-uniform but take the angle into account.
-    // That should reduce variance.
-    pd_total * Mf32::broadcast(1.0 / scene.direct_sample_num() as f32)
+    pd_total
 }
 
 /// Continues the path of a photon.
@@ -358,7 +618,7 @@ pub fn continue_path(material: MMaterial,
         active: Mf32::zero(),
     };
     let pd_brdf = pd_brdf(isect, &new_ray);
-    let pd_direct = pd_direct_sample(scene, &new_ray);
+    let pd_direct = pd_direct_sample(scene, isect, &new_ray);
     // Add a small constant to avoid division by zero later on.
     let weight_denom = pd_brdf + pd_direct + Mf32::broadcast(0.01);
 
@@ -381,7 +641,8 @@ pub fn continue_path(material: MMaterial,
     // angle.
     let modulation = (weight_denom * p).recip_fast();
     let cos_theta = isect.normal.dot(new_ray.direction).max(Mf32::zero());
-    let (brdf_term, fresnel) = microfacet_brdf(material, &new_ray, ray, isect, ignore_fresnel);
+    let ggx_albedo = scene.ggx_albedo();
+    let (brdf_term, fresnel) = microfacet_brdf(material, ggx_albedo, &new_ray, ray, isect, ignore_fresnel);
     let color_mod = brdf_term * (modulation * cos_theta);
 
     debug_assert!(modulation.all_finite());
@@ -398,21 +659,56 @@ pub fn continue_path(material: MMaterial,
         z: color_mod.z.min(Mf32::broadcast(2.0)),
     };
 
+    // Glass does not scatter light diffusely, it reflects or refracts.
+    let white = MVector3::new(Mf32::one(), Mf32::one(), Mf32::one());
+    let glass = material.is_glass();
+    let (new_ray, color_mod, fresnel) = if glass.all_sign_bits_positive() {
+        (new_ray, color_mod, fresnel)
+    } else {
+        let ray_glass = continue_path_glass(material, ray, isect, rng);
+        let new_ray = MRay {
+            origin: new_ray.origin.pick(ray_glass.origin, glass),
+            direction: new_ray.direction.pick(ray_glass.direction, glass),
+            active: Mf32::zero(),
+        };
+        (new_ray, color_mod.pick(white, glass), fresnel.pick(Mf32::zero(), glass))
+    };
+
     let new_ray = MRay {
         origin: new_ray.origin.pick(ray.origin, active),
         direction: new_ray.direction.pick(ray.direction, active),
         active: active,
     };
 
-    let white = MVector3::new(Mf32::one(), Mf32::one(), Mf32::one());
     let color_mod = color_mod.pick(white, active);
 
+    new_ray.debug_assert_invariants();
+
     (new_ray, color_mod, fresnel)
 }
 
+/// Returns the BRDF for light that arrives from `direction` at the surface
+/// and leaves towards the origin of `ray`, the same BRDF that
+/// `continue_path()` uses. This is for sampling light sources explicitly.
+pub fn eval_brdf(material: MMaterial,
+                 ggx_albedo: &GgxAlbedoTable,
+                 ray: &MRay,
+                 isect: &MIntersection,
+                 direction: MVector3,
+                 ignore_fresnel: bool)
+                 -> MVector3 {
+    let ray_light = MRay {
+        origin: isect.position,
+        direction: direction,
+        active: Mf32::zero(),
+    };
+    microfacet_brdf(material, ggx_albedo, &ray_light, ray, isect, ignore_fresnel).0
+}
+
 /// Returns the color modulation for the microfacet BRDF and also the raw
 /// Fresnel factor.
 fn microfacet_brdf(material: MMaterial,
+                   ggx_albedo: &GgxAlbedoTable,
                    ray_in: &MRay,
                    ray_out: &MRay,
                    isect: &MIntersection,
@@ -423,7 +719,31 @@ fn microfacet_brdf(material: MMaterial,
     let color = material.get_color();
     let gloss = material.get_glossiness();
     let h = (ray_in.direction - ray_out.direction).normalized();
-    let d = microfacet_normal_dist(h, isect, gloss);
+    let ggx = material.is_ggx();
+    let d = if ggx.all_sign_bits_positive() {
+        microfacet_normal_dist(h, isect, gloss)
+    } else {
+        // Keep the Blinn-Phong table lookup in bounds for the GGX lanes. The
+        // GGX lobe is isotropic, so any tangent will do.
+        let d_blinn_phong = microfacet_normal_dist(h, isect, gloss.map(|g| cmp::min(g, 5)));
+        let roughness = Mf32::broadcast(GGX_ROUGHNESS);
+        let d_ggx = MGgx::new(isect.normal, MVector3::zero(), roughness, roughness).normal_dist(h);
+
+        // Add back the light that scatters between microfacets more than
+        // once. The compensation lobe is tinted by the same Fresnel factor as
+        // the single-scattering lobe, rather than by the average Fresnel
+        // factor of Kulla and Conty.
+        let mu_o = -ray_out.direction.dot(isect.normal);
+        let mu_i = ray_in.direction.dot(isect.normal);
+        let d_multi = Mf32::generate(|i| {
+            if ggx.get_coord(i).is_sign_negative() {
+                ggx_albedo.multiscatter(mu_o.get_coord(i), mu_i.get_coord(i), GGX_ROUGHNESS)
+            } else {
+                0.0
+            }
+        });
+        d_blinn_phong.pick(d_ggx + d_multi, ggx)
+    };
     let (f_color, f_raw) = microfacet_fresnel(ray_in.direction, h, color);
 
     let white = MVector3::new(Mf32::one(), Mf32::one(), Mf32::one());
@@ -511,3 +831,598 @@ fn microfacet_normal_dist(half_way: MVector3,
         unsafe { values.get_unchecked(index as usize).get_coord(i) }
     })
 }
+
+/// The GGX (Trowbridge-Reitz) microfacet distribution with a roughness that
+/// can differ along the tangent and the bitangent.
+///
+/// Unequal roughness stretches the highlight along the rougher direction, as
+/// on brushed metal. The packed material has no room for two roughness values
+/// and a tangent, so these are passed in explicitly.
+pub struct MGgx {
+    normal: MVector3,
+    tangent: MVector3,
+    bitangent: MVector3,
+    roughness_u: Mf32,
+    roughness_v: Mf32,
+}
+
+impl MGgx {
+    /// Creates the distribution around the normal, with roughness
+    /// `roughness_u` along the tangent and `roughness_v` perpendicular to it.
+    ///
+    /// The tangent (for instance the direction in which the first texture
+    /// coordinate increases) need not be perpendicular to the normal or have
+    /// unit length; it is orthonormalized here. The normal must have unit
+    /// length.
+    pub fn new(normal: MVector3, tangent: MVector3, roughness_u: Mf32, roughness_v: Mf32) -> MGgx {
+        let tangent = tangent.orthonormalize(normal);
+        MGgx {
+            normal: normal,
+            tangent: tangent,
+            bitangent: normal.cross(tangent),
+            roughness_u: roughness_u,
+            roughness_v: roughness_v,
+        }
+    }
+
+    /// Returns the density of microfacet normals in the direction of the
+    /// half-way vector, which must have unit length.
+    pub fn normal_dist(&self, half_way: MVector3) -> Mf32 {
+        let ht = half_way.dot(self.tangent);
+        let hb = half_way.dot(self.bitangent);
+        let hn = half_way.dot(self.normal);
+
+        // The direction within the tangent plane only determines how the two
+        // roughness values are weighted. Take the length of the projection
+        // from the normal component, so for equal roughness this reduces to
+        // the isotropic formula exactly, also when the half-way vector is not
+        // perfectly normalized. The small constant avoids division by zero
+        // when the half-way vector is the normal.
+        let au = self.roughness_u;
+        let av = self.roughness_v;
+        let ht2 = ht * ht;
+        let hb2 = hb * hb;
+        let stretched = ht2 * (au * au).recip_precise() + hb2 * (av * av).recip_precise();
+        let weight = stretched * (ht2 + hb2 + Mf32::broadcast(1e-30)).recip_precise();
+        let tangential = hn.neg_mul_add(hn, Mf32::one());
+        let d = tangential.mul_add(weight, hn * hn);
+        let denom = Mf32::broadcast(consts::PI) * au * av * d * d;
+        let density = denom.recip_precise();
+
+        // Microfacets never face away from the surface.
+        density.pick(Mf32::zero(), hn)
+    }
+
+    /// Returns a random half-way vector with density `normal_dist(h)` times
+    /// dot(h, normal) with respect to solid angle.
+    pub fn sample_half_way(&self, rng: &mut Rng) -> MVector3 {
+        let phi = rng.sample_angle();
+        let u = rng.sample_unit();
+
+        // Sample the slope of the microfacet for unit roughness, and then
+        // stretch it along the tangent and bitangent. For GGX the squared slope
+        // is u / (1 - u).
+        let slope = (u * (Mf32::one() - u).recip_precise()).sqrt();
+        let x = self.roughness_u * slope * phi.cos();
+        let y = self.roughness_v * slope * phi.sin();
+        let h = self.normal.mul_add(Mf32::one(), self.tangent.mul_add(x, self.bitangent * y));
+        h.normalized()
+    }
+}
+
+/// The isotropic GGX (Trowbridge-Reitz) microfacet distribution. See `MGgx`
+/// for the anisotropic version.
+pub fn ggx_normal_dist(half_way: MVector3, normal: MVector3, roughness: Mf32) -> Mf32 {
+    let a2 = roughness * roughness;
+    let hn = half_way.dot(normal);
+    let d = (hn * hn).mul_add(a2 - Mf32::one(), Mf32::one());
+    let density = a2 * (Mf32::broadcast(consts::PI) * d * d).recip_precise();
+    density.pick(Mf32::zero(), hn)
+}
+
+/// The Smith masking term of GGX for a direction at `cos_theta` from the
+/// normal, in the form Lambda that the height-correlated shadowing-masking
+/// function G2 = 1 / (1 + Lambda(o) + Lambda(i)) uses.
+fn ggx_smith_lambda(cos_theta: f32, roughness: f32) -> f32 {
+    let cos2 = cos_theta * cos_theta;
+    let tan2 = (1.0 - cos2).max(0.0) / cos2;
+    ((1.0 + roughness * roughness * tan2).sqrt() - 1.0) * 0.5
+}
+
+/// Evaluates the single-scattering GGX BRDF with a Fresnel factor of 1, for
+/// unit directions in a frame where the normal is the z-axis.
+pub fn ggx_single_scatter(wo: SVector3, wi: SVector3, roughness: f32) -> f32 {
+    if wo.z <= 0.0 || wi.z <= 0.0 {
+        return 0.0
+    }
+    let h = (wo + wi).normalized();
+    let a2 = roughness * roughness;
+    let d = (h.z * h.z) * (a2 - 1.0) + 1.0;
+    let dist = a2 / (consts::PI * d * d);
+    let g = 1.0 / (1.0 + ggx_smith_lambda(wo.z, roughness) + ggx_smith_lambda(wi.z, roughness));
+    dist * g / (4.0 * wo.z * wi.z)
+}
+
+/// Returns the fraction of light that single-scattering GGX reflects for
+/// light arriving at cosine `mu` with the normal. This integrates the BRDF
+/// over the hemisphere with an n by n grid of half-way vectors drawn from the
+/// distribution of normals.
+fn ggx_directional_albedo(mu: f32, roughness: f32, n: u32) -> f32 {
+    let wo = SVector3::new((1.0 - mu * mu).max(0.0).sqrt(), 0.0, mu);
+    let a2 = roughness * roughness;
+    let mut total = 0.0;
+    for i in 0..n {
+        for j in 0..n {
+            let u1 = (i as f32 + 0.5) / n as f32;
+            let u2 = (j as f32 + 0.5) / n as f32;
+            let cos2_h = (1.0 - u1) / (u1 * (a2 - 1.0) + 1.0);
+            let cos_h = cos2_h.sqrt();
+            let sin_h = (1.0 - cos2_h).max(0.0).sqrt();
+            let phi = 2.0 * consts::PI * u2;
+            let h = SVector3::new(sin_h * phi.cos(), sin_h * phi.sin(), cos_h);
+            let oh = wo.dot(h);
+            let wi = h * (2.0 * oh) - wo;
+            if wi.z <= 0.0 || oh <= 0.0 {
+                continue
+            }
+
+            // With the pdf D(h) (n . h) / (4 (o . h)) of the reflected
+            // direction, the BRDF times cosine over the pdf reduces to this.
+            let g = 1.0 / (1.0 + ggx_smith_lambda(mu, roughness) + ggx_smith_lambda(wi.z, roughness));
+            total += g * oh / (mu * cos_h);
+        }
+    }
+    total / (n * n) as f32
+}
+
+/// Number of roughness and cosine entries in `GgxAlbedoTable`.
+const GGX_TABLE_SIZE: usize = 16;
+
+/// The directional albedo of single-scattering GGX, to compensate for the
+/// energy that is lost because light that scatters between microfacets more
+/// than once is not accounted for.
+///
+/// At high roughness, a sizable fraction of the light is lost this way, so
+/// rough surfaces look too dark. The compensation lobe of Kulla and Conty
+/// ("Revisiting Physically Based Shading at Imageworks", 2017) adds back the
+/// missing energy as a diffuse-like lobe, based on the albedo table.
+pub struct GgxAlbedoTable {
+    /// Albedo per roughness (rows) and cosine (columns), both from 0 to 1.
+    albedo: Vec<f32>,
+
+    /// The cosine-weighted average of the albedo over the hemisphere, per
+    /// roughness.
+    average: Vec<f32>,
+}
+
+impl GgxAlbedoTable {
+    /// Computes the table by numerical integration. This takes a moment.
+    pub fn new() -> GgxAlbedoTable {
+        let n = GGX_TABLE_SIZE;
+        let step = 1.0 / (n - 1) as f32;
+        let mut albedo = Vec::with_capacity(n * n);
+        let mut average = Vec::with_capacity(n);
+
+        for i in 0..n {
+            let roughness = (i as f32 * step).max(1e-3);
+            let row: Vec<f32> = (0..n).map(|j| {
+                let mu = (j as f32 * step).max(1e-3);
+                ggx_directional_albedo(mu, roughness, 32).min(1.0)
+            }).collect();
+
+            // Integrate 2 E(mu) mu over [0, 1] with the trapezoidal rule.
+            let mut avg = 0.0;
+            for j in 0..n - 1 {
+                let (mu0, mu1) = (j as f32 * step, (j + 1) as f32 * step);
+                avg += (row[j] * mu0 + row[j + 1] * mu1) * step;
+            }
+            average.push(avg.min(1.0));
+            albedo.extend(row);
+        }
+
+        GgxAlbedoTable {
+            albedo: albedo,
+            average: average,
+        }
+    }
+
+    /// Returns the directional albedo, interpolated bilinearly.
+    pub fn albedo(&self, mu: f32, roughness: f32) -> f32 {
+        let n = GGX_TABLE_SIZE;
+        let x = mu.max(0.0).min(1.0) * (n - 1) as f32;
+        let y = roughness.max(0.0).min(1.0) * (n - 1) as f32;
+        let (i, j) = (cmp::min(y as usize, n - 2), cmp::min(x as usize, n - 2));
+        let (fy, fx) = (y - i as f32, x - j as f32);
+        let at = |i: usize, j: usize| self.albedo[i * n + j];
+        let low = at(i, j) * (1.0 - fx) + at(i, j + 1) * fx;
+        let high = at(i + 1, j) * (1.0 - fx) + at(i + 1, j + 1) * fx;
+        low * (1.0 - fy) + high * fy
+    }
+
+    /// Returns the average albedo over the hemisphere, interpolated linearly.
+    pub fn average_albedo(&self, roughness: f32) -> f32 {
+        let n = GGX_TABLE_SIZE;
+        let y = roughness.max(0.0).min(1.0) * (n - 1) as f32;
+        let i = cmp::min(y as usize, n - 2);
+        let fy = y - i as f32;
+        self.average[i] * (1.0 - fy) + self.average[i + 1] * fy
+    }
+
+    /// Evaluates the compensation lobe for the cosines of the outgoing and
+    /// incoming directions with the normal.
+    ///
+    /// The lobe is (1 - E(o)) (1 - E(i)) / (pi (1 - E_avg)), which reflects
+    /// exactly the fraction 1 - E(o) that single scattering misses.
+    pub fn multiscatter(&self, mu_o: f32, mu_i: f32, roughness: f32) -> f32 {
+        if mu_o <= 0.0 || mu_i <= 0.0 {
+            return 0.0
+        }
+        let missing_o = 1.0 - self.albedo(mu_o, roughness);
+        let missing_i = 1.0 - self.albedo(mu_i, roughness);
+        let missing_avg = (1.0 - self.average_albedo(roughness)).max(1e-4);
+        missing_o * missing_i / (consts::PI * missing_avg)
+    }
+
+    /// Evaluates GGX with energy compensation, with the same conventions as
+    /// `ggx_single_scatter()`.
+    pub fn compensated_brdf(&self, wo: SVector3, wi: SVector3, roughness: f32) -> f32 {
+        ggx_single_scatter(wo, wi, roughness) + self.multiscatter(wo.z, wi.z, roughness)
+    }
+}
+
+/// A grid of heights, used to add detail to flat surfaces by perturbing the
+/// shading normal.
+///
+/// Triangles carry no tangent vectors, so the normal is derived on the fly:
+/// the gradient of the height in texture space is found by finite
+/// differences, and mapped onto the surface with the derivatives of the
+/// position with respect to the texture coordinates. This is the surface
+/// gradient formulation from "Bump Mapping Unparametrized Surfaces on the GPU"
+/// by Mikkelsen (2010). Intersections do not record these derivatives, so
+/// the caller has to supply them.
+pub struct HeightMap {
+    width: u32,
+    height: u32,
+
+    /// Height per texel, row by row, in world units.
+    heights: Vec<f32>,
+}
+
+impl HeightMap {
+    /// Creates a height map from a width times height grid of heights.
+    pub fn new(width: u32, height: u32, heights: Vec<f32>) -> HeightMap {
+        assert_eq!(heights.len(), (width * height) as usize);
+        HeightMap {
+            width: width,
+            height: height,
+            heights: heights,
+        }
+    }
+
+    fn texel(&self, i: i32, j: i32) -> f32 {
+        // Wrap around, like textures on the GPU.
+        let (w, h) = (self.width as i32, self.height as i32);
+        let i = ((i % w) + w) % w;
+        let j = ((j % h) + h) % h;
+        self.heights[(j * w + i) as usize]
+    }
+
+    /// Returns the bilinearly interpolated height at texture coordinates
+    /// (u, v), where texel centers are at half-integer multiples of the texel
+    /// size.
+    pub fn sample(&self, u: f32, v: f32) -> f32 {
+        let x = u * self.width as f32 - 0.5;
+        let y = v * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (i, j) = (x0 as i32, y0 as i32);
+        let bottom = self.texel(i, j) * (1.0 - fx) + self.texel(i + 1, j) * fx;
+        let top = self.texel(i, j + 1) * (1.0 - fx) + self.texel(i + 1, j + 1) * fx;
+        bottom * (1.0 - fy) + top * fy
+    }
+
+    /// Returns the normal of the surface displaced by the height map.
+    ///
+    /// The vectors `dp_du` and `dp_dv` are the derivatives of the position
+    /// with respect to the texture coordinates; they need not be orthogonal
+    /// or normalized. The normal must have unit length.
+    pub fn height_to_normal(&self,
+                            normal: MVector3,
+                            dp_du: MVector3,
+                            dp_dv: MVector3,
+                            tex_coords: (Mf32, Mf32))
+                            -> MVector3 {
+        // Central differences over one texel.
+        let du = 1.0 / self.width as f32;
+        let dv = 1.0 / self.height as f32;
+        let (us, vs) = tex_coords;
+        let dh_du = Mf32::generate(|i| {
+            let (u, v) = (us.get_coord(i), vs.get_coord(i));
+            (self.sample(u + du, v) - self.sample(u - du, v)) * (0.5 / du)
+        });
+        let dh_dv = Mf32::generate(|i| {
+            let (u, v) = (us.get_coord(i), vs.get_coord(i));
+            (self.sample(u, v + dv) - self.sample(u, v - dv)) * (0.5 / dv)
+        });
+
+        // The surface gradient is the gradient of the height in texture space
+        // transformed to the tangent plane. The determinant accounts for the
+        // scale and orientation of the texture mapping.
+        let r1 = dp_dv.cross(normal);
+        let r2 = normal.cross(dp_du);
+        let det = dp_du.dot(r1);
+        let gradient = r1.mul_add(dh_du, r2 * dh_dv);
+        let signed_gradient = gradient.pick(-gradient, det);
+        normal.mul_sub(det.abs(), signed_gradient).normalized()
+    }
+}
+
+#[test]
+fn ggx_equal_roughness_is_isotropic() {
+    let mut rng = Rng::with_seed(2, 5, 7);
+    for &roughness in &[0.05, 0.3, 0.8] {
+        let a = Mf32::broadcast(roughness);
+        for _ in 0..64 {
+            let normal = rng.sample_hemisphere_vector();
+            let tangent = rng.sample_hemisphere_vector();
+            let half_way = rng.sample_hemisphere_vector().rotate_hemisphere(normal).normalized();
+            let ggx = MGgx::new(normal, tangent, a, a);
+            let aniso = ggx.normal_dist(half_way);
+            let iso = ggx_normal_dist(half_way, normal, a);
+            for i in 0..8 {
+                let (d_aniso, d_iso) = (aniso.get_coord(i), iso.get_coord(i));
+                assert!((d_aniso - d_iso).abs() <= 1e-4 * d_iso.max(1.0),
+                        "anisotropic {} differs from isotropic {}", d_aniso, d_iso);
+            }
+        }
+    }
+}
+
+#[test]
+fn ggx_anisotropy_stretches_along_tangent() {
+    let normal = MVector3::new(Mf32::zero(), Mf32::zero(), Mf32::one());
+    let tangent = MVector3::new(Mf32::one(), Mf32::zero(), Mf32::zero());
+    let ggx = MGgx::new(normal, tangent, Mf32::broadcast(0.5), Mf32::broadcast(0.1));
+
+    // Tilting the half-way vector towards the tangent loses less density than
+    // tilting it by the same angle towards the bitangent.
+    let (s, c) = (0.2f32.sin(), 0.2f32.cos());
+    let towards_u = MVector3::broadcast(SVector3::new(s, 0.0, c));
+    let towards_v = MVector3::broadcast(SVector3::new(0.0, s, c));
+    assert!(ggx.normal_dist(towards_u).get_coord(0) > 10.0 * ggx.normal_dist(towards_v).get_coord(0));
+
+    // Sampled half-way vectors spread out further along the tangent.
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let mut spread_u = 0.0;
+    let mut spread_v = 0.0;
+    for _ in 0..256 {
+        let h = ggx.sample_half_way(&mut rng);
+        assert!(h.z.all_sign_bits_positive());
+        spread_u += h.x.abs().hsum();
+        spread_v += h.y.abs().hsum();
+    }
+    assert!(spread_u > 3.0 * spread_v, "spread along tangent {} vs bitangent {}", spread_u, spread_v);
+}
+
+#[test]
+fn height_to_normal_flat_map_keeps_normal() {
+    let map = HeightMap::new(4, 4, vec![0.3; 16]);
+    let normal = MVector3::broadcast(SVector3::new(0.0, 1.0, 0.0));
+    let dp_du = MVector3::broadcast(SVector3::new(2.0, 0.0, 0.0));
+    let dp_dv = MVector3::broadcast(SVector3::new(0.0, 0.0, -2.0));
+    let uvs = (Mf32::generate(|i| i as f32 * 0.125), Mf32::broadcast(0.4));
+    let n = map.height_to_normal(normal, dp_du, dp_dv, uvs);
+    for i in 0..8 {
+        assert!(n.x.get_coord(i).abs() < 1e-6 && n.z.get_coord(i).abs() < 1e-6);
+        assert!((n.y.get_coord(i) - 1.0).abs() < 1e-3);
+    }
+}
+
+#[test]
+fn height_to_normal_ramp_tilts_normal() {
+    // A ramp that rises by 0.1 per texel along u, on a floor where u runs
+    // along x, with 8 texels per unit of distance.
+    let heights = (0..64).map(|i| (i % 8) as f32 * 0.1).collect();
+    let map = HeightMap::new(8, 8, heights);
+    let normal = MVector3::broadcast(SVector3::new(0.0, 1.0, 0.0));
+    let dp_du = MVector3::broadcast(SVector3::new(1.0, 0.0, 0.0));
+    let dp_dv = MVector3::broadcast(SVector3::new(0.0, 0.0, -1.0));
+
+    // Stay away from the edges, where the ramp wraps around.
+    let uvs = (Mf32::broadcast(0.5), Mf32::generate(|i| i as f32 * 0.125));
+    let n = map.height_to_normal(normal, dp_du, dp_dv, uvs);
+
+    // The surface y = 0.8 x has normal (-0.8, 1, 0), normalized.
+    let norm = (1.0f32 + 0.64).sqrt();
+    for i in 0..8 {
+        assert!((n.x.get_coord(i) + 0.8 / norm).abs() < 1e-3, "normal x is {}", n.x.get_coord(i));
+        assert!((n.y.get_coord(i) - 1.0 / norm).abs() < 1e-3, "normal y is {}", n.y.get_coord(i));
+        assert!(n.z.get_coord(i).abs() < 1e-5);
+    }
+}
+
+#[test]
+fn ggx_energy_compensation_passes_white_furnace() {
+    let table = GgxAlbedoTable::new();
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let wo = SVector3::new(0.6, 0.0, 0.8);
+
+    for &roughness in &[0.1, 0.4, 0.7, 1.0] {
+        // Estimate the reflected fraction with cosine-weighted directions, for
+        // which the estimator is pi times the BRDF.
+        let (mut single, mut compensated) = (0.0, 0.0);
+        let n = 4096;
+        for _ in 0..n {
+            let v = rng.sample_hemisphere_vector();
+            for i in 0..8 {
+                let wi = SVector3::new(v.x.get_coord(i), v.y.get_coord(i), v.z.get_coord(i));
+                single += ggx_single_scatter(wo, wi, roughness) * consts::PI;
+                compensated += table.compensated_brdf(wo, wi, roughness) * consts::PI;
+            }
+        }
+        let single = single / (n * 8) as f32;
+        let compensated = compensated / (n * 8) as f32;
+
+        assert!((compensated - 1.0).abs() < 0.05,
+                "compensated GGX with roughness {} reflects {}", roughness, compensated);
+        if roughness == 1.0 {
+            assert!(single < 0.6, "single scattering with roughness 1 reflects {}", single);
+        }
+    }
+}
+
+#[test]
+fn material_preset_gold_is_warm_metal() {
+    let gold = Material::preset("gold").unwrap();
+    assert!(gold.metallic);
+    assert!(!gold.transparent);
+    // Gold reflects red more than green, and green more than blue.
+    assert!(gold.f0.x > gold.f0.y && gold.f0.y > gold.f0.z, "f0 of gold is {}", gold.f0);
+    assert!(gold.f0.z < 0.5);
+
+    // Glass reflects 4% at normal incidence.
+    let glass = Material::preset("glass").unwrap();
+    assert!(!glass.metallic && glass.transparent);
+    assert!((glass.f0.x - 0.04).abs() < 1e-6);
+
+    assert!(Material::preset("unobtainium").is_none());
+}
+
+#[test]
+fn glass_reflects_with_fresnel_probability_and_refracts_otherwise() {
+    // Clear glass must not absorb light.
+    let white = MMaterial::broadcast_material(SMaterial::glass()).get_color();
+    assert_eq!((white.x.0, white.y.0, white.z.0), (1.0, 1.0, 1.0));
+
+    // Rays that hit a glass surface head-on, from the front.
+    let normal = MVector3::broadcast(SVector3::new(0.0, 0.0, 1.0));
+    let down = MVector3::broadcast(SVector3::new(0.0, 0.0, -1.0));
+    let mut isect = MIntersection::with_max_distance(1.0);
+    isect.normal = normal;
+    isect.front_face = normal.dot(down);
+    let ray = MRay::new(MVector3::broadcast(SVector3::new(0.0, 0.0, 1.0)), down);
+
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let mut num_reflected = 0;
+    let n = 1024;
+    for _ in 0..n {
+        let new_ray = continue_path_glass(MMaterial::broadcast_material(SMaterial::glass()), &ray, &isect, &mut rng);
+        for i in 0..8 {
+            let z = new_ray.direction.z.get_coord(i);
+            if z > 0.0 {
+                assert!((z - 1.0).abs() < 1e-3, "reflection should be in the mirror direction");
+                num_reflected += 1;
+            } else {
+                assert!((z + 1.0).abs() < 1e-3, "refraction at normal incidence should not bend");
+            }
+        }
+    }
+
+    // Glass reflects 4% at normal incidence.
+    let fraction = num_reflected as f32 / (n * 8) as f32;
+    assert!((fraction - 0.04).abs() < 0.01, "glass reflected {} of the rays", fraction);
+}
+
+#[test]
+fn regularize_roughens_glossy_and_glass_lobes() {
+    let glossy = MMaterial::broadcast_material(SMaterial::white().with_glossiness(5));
+    let glass = MMaterial::broadcast_material(SMaterial::glass());
+    let all = Mf32::broadcast(-1.0);
+
+    // Without the mask, nothing changes.
+    assert_eq!(glass.regularize(1.0, Mf32::zero()).get_glossiness().get_coord(0), 0);
+
+    assert_eq!(glossy.regularize(1.0, all).get_glossiness().get_coord(0), 0);
+    assert_eq!(glass.regularize(1.0, all).get_glossiness().get_coord(0), 5);
+    assert!(glass.regularize(1.0, all).is_glass().all_sign_bits_negative());
+    assert_eq!(glass.regularize(0.4, all).get_glossiness().get_coord(0), 2);
+}
+
+#[test]
+fn regularization_blurs_caustic_path_through_glass() {
+    // The tail of a caustic path: after a diffuse bounce off the floor, the
+    // path goes up through a glass slab between the planes z = 1 and z = 2,
+    // towards a light above. Returns the direction after the slab, and the
+    // z-component of the direction inside, which is negative for the lanes
+    // that were reflected instead.
+    let up = MVector3::broadcast(SVector3::new(0.0, 0.0, 1.0));
+    let incoming = MVector3::broadcast(SVector3::new(0.3, 0.0, 1.0).normalized());
+    let through_slab = |material: MMaterial, rng: &mut Rng| {
+        let ray = MRay::new(MVector3::zero(), incoming);
+        let mut isect = MIntersection::with_max_distance(1.0);
+        isect.distance = incoming.z.recip_precise();
+        isect.position = incoming * isect.distance;
+        isect.normal = -up;
+        isect.front_face = isect.normal.dot(ray.direction);
+        let inside = continue_path_glass(material, &ray, &isect, rng);
+
+        let mut isect = MIntersection::with_max_distance(1.0);
+        isect.distance = (Mf32::broadcast(2.0) - inside.origin.z) * inside.direction.z.recip_precise();
+        isect.position = inside.direction.mul_add(isect.distance, inside.origin);
+        isect.normal = up;
+        isect.front_face = isect.normal.dot(inside.direction);
+        let outside = continue_path_glass(material, &inside, &isect, rng);
+        (outside.direction, inside.direction.z)
+    };
+
+    let glass = MMaterial::broadcast_material(SMaterial::glass());
+    let after_diffuse = Mf32::broadcast(-1.0);
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let (mut num_refracted, mut num_blurred) = (0, 0);
+    for _ in 0..64 {
+        // A slab with parallel faces does not change the direction.
+        let (sharp, reflected) = through_slab(glass.regularize(0.0, after_diffuse), &mut rng);
+        for i in 0..8 {
+            if reflected.get_coord(i) < 0.0 || sharp.z.get_coord(i) < 0.0 { continue }
+            let deviation = (sharp - incoming).norm_squared().get_coord(i).sqrt();
+            assert!(deviation < 5e-3, "unregularized glass deviated by {}", deviation);
+        }
+
+        // Regularized glass blurs the direction, but it stays within a cone
+        // around the sharp direction, and on the other side of the slab.
+        let (blurred, reflected) = through_slab(glass.regularize(1.0, after_diffuse), &mut rng);
+        for i in 0..8 {
+            if reflected.get_coord(i) < 0.0 || blurred.z.get_coord(i) < 0.0 { continue }
+            let deviation = (blurred - incoming).norm_squared().get_coord(i).sqrt();
+            num_refracted += 1;
+            if deviation > 2e-2 { num_blurred += 1 }
+            assert!(deviation < 1.0, "regularized glass deviated by {}", deviation);
+        }
+    }
+    assert!(num_blurred * 10 > num_refracted * 9,
+            "only {} of {} regularized paths were blurred", num_blurred, num_refracted);
+}
+
+#[test]
+fn ggx_material_selects_ggx_lobe() {
+    let blinn_phong = MMaterial::broadcast_material(SMaterial::white().with_glossiness(3));
+    let ggx = MMaterial::broadcast_material(SMaterial::white().with_glossiness(3).with_ggx());
+    assert!(blinn_phong.is_ggx().all_sign_bits_positive());
+    assert!(ggx.is_ggx().all_sign_bits_negative());
+    assert!(ggx.is_diffuse().all_sign_bits_positive());
+    assert!(MMaterial::broadcast_material(SMaterial::glass()).is_ggx().all_sign_bits_positive());
+
+    // Regularization leaves the GGX lobe alone.
+    let all = Mf32::broadcast(-1.0);
+    assert!(ggx.regularize(1.0, all).is_ggx().all_sign_bits_negative());
+
+    // Light arrives at 30 degrees from the normal. For a white material the
+    // Fresnel factor is 1, so the BRDF is the GGX distribution plus the energy
+    // compensation lobe, which peaks in the mirror direction.
+    let normal = SVector3::new(0.0, 0.0, 1.0);
+    let incoming = SVector3::new(0.5, 0.0, -0.75f32.sqrt());
+    let mut isect = MIntersection::with_max_distance(1.0);
+    isect.normal = MVector3::broadcast(normal);
+    let ray = MRay::new(MVector3::broadcast(-incoming), MVector3::broadcast(incoming));
+    let mirror = SVector3::new(0.5, 0.0, 0.75f32.sqrt());
+    let table = GgxAlbedoTable::new();
+    let brdf = |direction: SVector3| {
+        eval_brdf(ggx, &table, &ray, &isect, MVector3::broadcast(direction), true).x.get_coord(0)
+    };
+    let roughness = Mf32::broadcast(GGX_ROUGHNESS);
+    let peak = ggx_normal_dist(MVector3::broadcast(normal), MVector3::broadcast(normal), roughness).get_coord(0);
+    let mu = 0.75f32.sqrt();
+    let peak = peak + table.multiscatter(mu, mu, GGX_ROUGHNESS);
+    assert!((brdf(mirror) - peak).abs() < 1e-3 * peak, "brdf is {}, expected {}", brdf(mirror), peak);
+    assert!(brdf(normal) < 0.5 * brdf(mirror));
+}
@@ -0,0 +1,337 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! A caustic photon map.
+//!
+//! Light that is focused by glass onto a diffuse surface is very hard to find
+//! for the path tracer: a path would have to pass through the glass in exactly
+//! the right direction to reach the light, and small lights are practically
+//! never hit. Instead, photons are emitted from the emissive triangles and the
+//! area lights, refracted by glass, and stored where they land on a diffuse
+//! surface. During rendering, the photons near a point estimate the irradiance
+//! there, and the renderer drops the paths that would find the same light.
+
+use random::Rng;
+use ray::{MIntersection, MRay};
+use scene::Scene;
+use simd::Mf32;
+use std::f32::consts;
+use vector3::{Axis, MVector3, SVector3};
+
+#[cfg(test)]
+use bench;
+
+#[cfg(test)]
+use material::SMaterial;
+
+/// The index of refraction of glass.
+const GLASS_IOR: f32 = 1.5;
+
+/// The maximum number of glass surfaces that a photon interacts with.
+const MAX_PHOTON_BOUNCES: u32 = 8;
+
+struct Photon {
+    position: SVector3,
+
+    /// The flux that the photon carries.
+    power: SVector3,
+
+    /// The axis along which the kd-tree node of this photon splits.
+    axis: Axis,
+}
+
+/// Photons that passed through glass before landing on a diffuse surface,
+/// stored in a kd-tree.
+pub struct PhotonMap {
+    /// The photons in a balanced kd-tree: the median of every range is the
+    /// node, the part before it the left subtree, and the part after it the
+    /// right subtree.
+    photons: Vec<Photon>,
+
+    /// The radius in which photons are gathered.
+    radius: f32,
+}
+
+/// Refracts the direction through the glass surface of the intersection, or
+/// reflects it in case of total internal reflection.
+fn refract_glass(direction: MVector3, isect: &MIntersection) -> MVector3 {
+    // Use the normal on the side that the ray comes from, and the inverse
+    // ratio of indices of refraction when the ray exits the glass.
+    let n = isect.facing_normal();
+    let eta = isect.relative_ior(GLASS_IOR);
+    let cos_i = n.dot(direction).abs();
+
+    // Snell's law. If k is negative, there is no refracted direction.
+    let sin2_t = eta * eta * cos_i.neg_mul_add(cos_i, Mf32::one());
+    let k = Mf32::one() - sin2_t;
+    let cos_t = k.max(Mf32::zero()).sqrt();
+    let refracted = n.mul_add(eta.mul_sub(cos_i, cos_t), direction * eta);
+    let reflected = n.mul_add(cos_i + cos_i, direction);
+
+    refracted.pick(reflected, k).normalized()
+}
+
+/// Follows 8 photons through glass, and stores the ones that land on a
+/// diffuse surface after passing through glass.
+fn trace_photons(scene: &Scene, mut ray: MRay, mut power: MVector3, photons: &mut Vec<Photon>) {
+    // Sign bit 1 indicates that the photon has passed through glass.
+    let mut specular = Mf32::zero();
+
+    for _ in 0..MAX_PHOTON_BOUNCES {
+        let isect = scene.intersect_nearest(&ray);
+        let glass = isect.material.is_glass();
+
+        // Store photons that land on a diffuse surface after glass. Hitting
+        // the sky or a light source absorbs the photon.
+        let store = ray.active | isect.material | glass | specular.neg_xor();
+        for i in 0..8 {
+            if !store.get_coord(i).is_sign_negative() {
+                photons.push(Photon {
+                    position: SVector3::new(isect.position.x.get_coord(i),
+                                            isect.position.y.get_coord(i),
+                                            isect.position.z.get_coord(i)),
+                    power: SVector3::new(power.x.get_coord(i),
+                                         power.y.get_coord(i),
+                                         power.z.get_coord(i)),
+                    axis: Axis::X,
+                });
+            }
+        }
+
+        // Only photons that hit glass continue.
+        let done = ray.active | glass.neg_xor();
+        if done.all_sign_bits_negative() {
+            break
+        }
+        specular = specular | glass;
+
+        // Glass absorbs light along the way inside, like in
+        // `Scene::intersect_nearest_opaque()`.
+        let absorbed = power.mul_coords(isect.material.get_transmittance(isect.distance));
+        power = absorbed.pick(power, isect.front_face | done);
+
+        let direction = refract_glass(ray.direction, &isect).pick(ray.direction, done);
+        ray = MRay {
+            origin: isect.offset_origin(direction).pick(ray.origin, done),
+            direction: direction,
+            active: done,
+        };
+    }
+}
+
+impl PhotonMap {
+    /// Emits 8 times `num_packets` photons from the emissive triangles of the
+    /// scene, and as many from the area lights, and stores the ones that land
+    /// on a diffuse surface after passing through glass.
+    pub fn trace(scene: &Scene, num_packets: u32, radius: f32, rng: &mut Rng) -> PhotonMap {
+        let mut photons = Vec::new();
+        let num_photons = Mf32::broadcast((num_packets * 8) as f32);
+
+        // Emissive triangles emit to both sides, with the radiance that the
+        // path tracer finds when it hits them: the sky radiance in the
+        // direction opposite to the photon. Pick a side, and then a
+        // cosine-weighted direction. The flux on one side is pi times the
+        // radiance integrated over the area.
+        if scene.direct_sample_num() > 0 {
+            for _ in 0..num_packets {
+                let sample = scene.get_direct_sample(rng);
+                let normal = sample.normal.pick(-sample.normal, rng.sample_biunit());
+                let direction = rng.sample_hemisphere_vector().rotate_hemisphere(normal);
+                let num_triangles = Mf32::broadcast(scene.direct_sample_num() as f32);
+                let scale = Mf32::broadcast(2.0 * consts::PI) * sample.area * num_triangles * num_photons.recip_precise();
+                let power = scene.sky_intensity(-direction) * scale;
+                let ray = MRay {
+                    origin: direction.mul_add(Mf32::epsilon(), sample.position),
+                    direction: direction,
+                    active: Mf32::zero(),
+                };
+                trace_photons(scene, ray, power, &mut photons);
+            }
+        }
+
+        // Area lights emit to the side of their normal only.
+        if scene.num_lights() > 0 {
+            for _ in 0..num_packets {
+                let sample = scene.sample_light(rng);
+                let direction = rng.sample_hemisphere_vector().rotate_hemisphere(sample.normal);
+                let scale = Mf32::broadcast(consts::PI) * (sample.pdf * num_photons).recip_precise();
+                let scale = scale * scene.light_radiance_factor(&sample, direction);
+                let power = sample.radiance * scale;
+                let ray = MRay {
+                    origin: direction.mul_add(Mf32::epsilon(), sample.position),
+                    direction: direction,
+                    active: Mf32::zero(),
+                };
+                trace_photons(scene, ray, power, &mut photons);
+            }
+        }
+
+        build_kd_tree(&mut photons[..]);
+
+        PhotonMap {
+            photons: photons,
+            radius: radius,
+        }
+    }
+
+    /// Returns the number of stored photons.
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    /// Estimates the irradiance at a point on a diffuse surface, from the
+    /// photons within the gather radius.
+    pub fn irradiance(&self, position: SVector3) -> SVector3 {
+        let mut power = SVector3::zero();
+        let r2 = self.radius * self.radius;
+        gather(&self.photons[..], position, r2, &mut |photon| power = power + photon.power);
+        power * (1.0 / (consts::PI * r2))
+    }
+
+    /// Estimates the irradiance at 8 points, see `irradiance()`.
+    pub fn irradiance_8(&self, positions: MVector3) -> MVector3 {
+        MVector3::generate(|i| {
+            self.irradiance(SVector3::new(positions.x.get_coord(i),
+                                          positions.y.get_coord(i),
+                                          positions.z.get_coord(i)))
+        })
+    }
+}
+
+/// Reorders the photons into a balanced kd-tree, splitting along the axis of
+/// largest extent at every level.
+fn build_kd_tree(photons: &mut [Photon]) {
+    if photons.len() <= 1 {
+        return
+    }
+
+    let first = photons[0].position;
+    let (min, max) = photons.iter().fold((first, first), |(min, max), p| {
+        (min.min(p.position), max.max(p.position))
+    });
+    let size = max - min;
+    let axis = if size.x > size.y && size.x > size.z {
+        Axis::X
+    } else if size.y > size.z {
+        Axis::Y
+    } else {
+        Axis::Z
+    };
+
+    photons.sort_by(|a, b| {
+        a.position.get_coord(axis).partial_cmp(&b.position.get_coord(axis)).unwrap()
+    });
+
+    let mid = photons.len() / 2;
+    photons[mid].axis = axis;
+    let (left, right) = photons.split_at_mut(mid);
+    build_kd_tree(left);
+    build_kd_tree(&mut right[1..]);
+}
+
+/// Calls `f` for every photon within distance sqrt(r2) of the position.
+fn gather<F: FnMut(&Photon)>(photons: &[Photon], position: SVector3, r2: f32, f: &mut F) {
+    if photons.is_empty() {
+        return
+    }
+
+    let mid = photons.len() / 2;
+    let node = &photons[mid];
+    if (node.position - position).norm_squared() <= r2 {
+        f(node);
+    }
+
+    // Visit the side that contains the position, and the other side only if
+    // the gather sphere crosses the splitting plane.
+    let delta = position.get_coord(node.axis) - node.position.get_coord(node.axis);
+    let (near, far) = if delta < 0.0 {
+        (&photons[..mid], &photons[mid + 1..])
+    } else {
+        (&photons[mid + 1..], &photons[..mid])
+    };
+    gather(near, position, r2, f);
+    if delta * delta <= r2 {
+        gather(far, position, r2, f);
+    }
+}
+
+#[test]
+fn refract_glass_bends_towards_normal_when_entering() {
+    let normal = MVector3::broadcast(SVector3::new(0.0, 1.0, 0.0));
+    let (s, c) = (0.5f32.sin(), 0.5f32.cos());
+    let incoming = MVector3::broadcast(SVector3::new(s, -c, 0.0));
+    let mut isect = MIntersection::with_max_distance(1.0);
+    isect.normal = normal;
+    isect.front_face = normal.dot(incoming);
+    let inside = refract_glass(incoming, &isect);
+    let sin_t = inside.x.get_coord(0);
+    assert!((sin_t - s / GLASS_IOR).abs() < 1e-3);
+    assert!(inside.y.get_coord(0) < 0.0);
+
+    // Leaving the glass again restores the original direction.
+    isect.normal = -normal;
+    isect.front_face = isect.normal.dot(inside);
+    let outside = refract_glass(inside, &isect);
+    assert!((outside.x.get_coord(0) - s).abs() < 1e-3);
+    assert!((outside.y.get_coord(0) + c).abs() < 1e-3);
+}
+
+#[test]
+fn kd_tree_gather_finds_all_photons_in_radius() {
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let mut photons = Vec::new();
+    for _ in 0..64 {
+        let v = rng.sample_hemisphere_vector();
+        for i in 0..8 {
+            let position = SVector3::new(v.x.get_coord(i), v.y.get_coord(i), v.z.get_coord(i));
+            photons.push(Photon { position: position, power: SVector3::one(), axis: Axis::X });
+        }
+    }
+    let positions: Vec<SVector3> = photons.iter().map(|p| p.position).collect();
+    build_kd_tree(&mut photons[..]);
+
+    let query = SVector3::new(0.2, 0.1, 0.7);
+    let r2 = 0.3 * 0.3;
+    let expected = positions.iter().filter(|&&p| (p - query).norm_squared() <= r2).count();
+    let mut found = 0;
+    gather(&photons[..], query, r2, &mut |_| found += 1);
+    assert!(expected > 0);
+    assert_eq!(found, expected);
+}
+
+#[test]
+fn glass_sphere_focuses_light() {
+    use light::{AreaLight, EmissionMap};
+
+    // A clear glass ball lens of radius 1 focuses parallel light at half the
+    // radius behind it, so the floor is in focus 1.5 below the center. The
+    // light is small and far away, so its light is nearly parallel.
+    let v = SVector3::new;
+    let floor = ([v(-10.0, 0.0, 10.0), v(10.0, 0.0, 10.0), v(10.0, 0.0, -10.0), v(-10.0, 0.0, -10.0)],
+                 SMaterial::white());
+    let glass = SMaterial::colored_glass(1.0, 1.0, 1.0);
+    let meshes = [bench::mesh_from_quads(&[floor]),
+                  bench::sphere_mesh(v(0.0, 1.5, 0.0), 1.0, glass, 24, 48)];
+    let mut scene = Scene::from_meshes(&meshes);
+    let radiance = SVector3::new(1.0, 1.0, 1.0);
+    scene.add_area_light(AreaLight::new(v(-0.5, 10.0, -0.5),
+                                        v(1.0, 0.0, 0.0),
+                                        v(0.0, 0.0, 1.0),
+                                        EmissionMap::constant(radiance)));
+
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let photon_map = PhotonMap::trace(&scene, 8192, 0.1, &mut rng);
+    assert!(photon_map.len() > 0);
+
+    // Without the sphere, the small light directly above at distance 10
+    // contributes about radiance * area / distance^2 to the irradiance.
+    let direct = radiance.y * 1.0 / (10.0 * 10.0);
+    let focus = photon_map.irradiance(v(0.0, 0.0, 0.0)).y;
+    let shadow = photon_map.irradiance(v(0.9, 0.0, 0.0)).y;
+    assert!(focus > 5.0 * direct, "caustic irradiance {} should exceed direct irradiance {}", focus, direct);
+    assert!(focus > 5.0 * shadow, "caustic irradiance {} should be focused, but is {} next to it", focus, shadow);
+}
@@ -37,6 +37,12 @@ impl SQuaternion {
             d: d,
         }
     }
+
+    /// Returns the conjugate, which is the inverse rotation for a unit
+    /// quaternion.
+    pub fn conjugate(self) -> SQuaternion {
+        SQuaternion::new(self.a, -self.b, -self.c, -self.d)
+    }
 }
 
 impl MQuaternion {
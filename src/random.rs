@@ -15,6 +15,7 @@
 use simd::{Mf32, Mi32, Mu64};
 use std::f32::consts;
 use std::i32;
+use util::generate_slice8;
 use vector3::MVector3;
 
 #[cfg(test)]
@@ -34,6 +35,24 @@ pub struct Rng {
     state: Mu64,
 }
 
+/// Basic quality metrics of a stream of `Rng::sample_unit()` values, see
+/// `Rng::diagnose()`.
+#[derive(Debug)]
+pub struct Diagnostics {
+    /// The mean of the values, 1/2 for a uniform distribution.
+    pub mean: f64,
+
+    /// The variance of the values, 1/12 for a uniform distribution.
+    pub variance: f64,
+
+    /// The correlation between consecutive values of the same lane, which
+    /// should be close to zero.
+    pub serial_correlation: f64,
+
+    /// Whether the state of any lane reached zero, where it stays forever.
+    pub degenerate: bool,
+}
+
 impl Rng {
     /// Creates a new random number generator.
     ///
@@ -54,6 +73,25 @@ impl Rng {
         let c = (i as u64).wrapping_mul(2268244495640532043);
         let seed = a.wrapping_add(b).wrapping_add(c);
 
+        Rng::from_mixed_seed(seed)
+    }
+
+    /// Creates a new random number generator from a single 64-bit seed.
+    ///
+    /// Unlike `with_seed()`, this is not tied to pixel coordinates, so it is
+    /// useful to reproduce a stream of numbers in isolation. The same seed
+    /// always produces the same sequence.
+    pub fn from_u64(seed: u64) -> Rng {
+        // Seeds are often small numbers, so spread their bits over the full
+        // range first. A state of zero would remain zero forever, so force the
+        // seed to be odd. After the mixing below, the state can then never be
+        // zero.
+        let seed = seed.wrapping_mul(12276630456901467871);
+        let seed = seed ^ (seed >> 32);
+        Rng::from_mixed_seed(seed | 1)
+    }
+
+    fn from_mixed_seed(seed: u64) -> Rng {
         // If I only use the above scheme, the seed has a severe bias modulo
         // small powers of two. (For instance, x and y are always multiples of
         // 16 and 4, so modulo 8, a + b is always 0 or 4.) To avoid this, take
@@ -113,6 +151,45 @@ impl Rng {
         mi32.into_mf32().mul_add(range, half)
     }
 
+    /// Returns 8 random numbers distributed uniformly over the open interval
+    /// (0, 1).
+    ///
+    /// Use this instead of `sample_unit()` when taking the logarithm or the
+    /// reciprocal of the number. The values at the ends of the interval, which
+    /// `sample_unit()` can produce due to rounding, are moved inwards by a
+    /// tiny amount, so the distribution is still uniform.
+    pub fn sample_unit_excl_zero(&mut self) -> Mf32 {
+        let min = Mf32::broadcast(1.0 / 4294967296.0); // 2^-32
+        let max = Mf32::broadcast(1.0 - 1.0 / 16777216.0); // 1 - 2^-24
+        self.sample_unit().max(min).min(max)
+    }
+
+    /// Returns two sets of 8 random numbers distributed uniformly over the
+    /// half-open interval [0, 1).
+    ///
+    /// Both sets are built from the high 16 bits of every random 32-bit
+    /// integer, each from its own step of the generator, so they have a
+    /// resolution of only 2^-16. That is plenty for picking points on a disk
+    /// or a triangle. The low bits of the state are not used: the lowest bit
+    /// of the state is always set, and the bit above it alternates, so they
+    /// would produce visible patterns.
+    pub fn sample_unit_pair(&mut self) -> (Mf32, Mf32) {
+        use std::mem::transmute;
+
+        let mi32_a: Mi32 = unsafe { transmute(self.next()) };
+        let mi32_b: Mi32 = unsafe { transmute(self.next()) };
+
+        // With the low bits zero, the signed values are multiples of 2^16 in
+        // [-2^31, 2^31), which map exactly onto multiples of 2^-16 in [0, 1).
+        let high_bits = Mi32::broadcast(0xffff0000_u32 as i32);
+        let a = mi32_a & high_bits;
+        let b = mi32_b & high_bits;
+        let range = Mf32::broadcast(1.0 / 4294967296.0);
+        let half = Mf32::broadcast(0.5);
+
+        (a.into_mf32().mul_add(range, half), b.into_mf32().mul_add(range, half))
+    }
+
     /// Returns 8 random numbers distributed uniformly over the half-open
     /// interval [-1, 1).
     pub fn sample_biunit(&mut self) -> Mf32 {
@@ -135,6 +212,47 @@ impl Rng {
         mi32.into_mf32() * range
     }
 
+    /// Returns 8 points distributed uniformly over the unit disk, as x and y
+    /// coordinates.
+    pub fn sample_disk(&mut self) -> (Mf32, Mf32) {
+        let phi = self.sample_angle();
+        let r = self.sample_unit().sqrt();
+        (phi.cos() * r, phi.sin() * r)
+    }
+
+    /// Returns 8 points distributed uniformly over a regular polygon with the
+    /// given number of sides, inscribed in the unit circle, as x and y
+    /// coordinates. One of the corners lies on the positive x-axis.
+    ///
+    /// This is the shape of a lens aperture with straight blades. As the
+    /// number of blades grows, the polygon approaches the unit disk.
+    pub fn sample_polygon(&mut self, blades: u32) -> (Mf32, Mf32) {
+        assert!(blades >= 3, "a polygon needs at least three sides");
+
+        // The polygon consists of equal triangles between the center and an
+        // edge, so pick one uniformly, then a point uniformly in the triangle.
+        // Taking the square root of the distance along the median makes the
+        // density uniform, like for the disk.
+        let n = blades as f32;
+        let sector = self.sample_unit() * Mf32::broadcast(n);
+        let (a, b) = self.sample_unit_pair();
+        let r = a.sqrt();
+
+        // The corners must be exact, or the points could lie slightly outside
+        // of the polygon, so use the scalar sine and cosine here.
+        let points = generate_slice8(|i| {
+            let k = sector.get_coord(i).floor().min(n - 1.0);
+            let angle_0 = k * (2.0 * consts::PI / n);
+            let angle_1 = (k + 1.0) * (2.0 * consts::PI / n);
+            let (s, t) = (r.get_coord(i), b.get_coord(i));
+            let x = s * ((1.0 - t) * angle_0.cos() + t * angle_1.cos());
+            let y = s * ((1.0 - t) * angle_0.sin() + t * angle_1.sin());
+            (x, y)
+        });
+
+        (Mf32::generate(|i| points[i].0), Mf32::generate(|i| points[i].1))
+    }
+
     /// Returns a random unit vector in the hemisphere around the positive
     /// z-axis, drawn from a cosine-weighted distribution.
     pub fn sample_hemisphere_vector(&mut self) -> MVector3 {
@@ -150,14 +268,68 @@ impl Rng {
         // of cycles will have passed. Pipelining to the rescue here.
         let r = r_sqr.sqrt();
         let x = phi.sin() * r;
-        let y = phi.cos() * r; // TODO: cos is a bottleneck, do I need the precision?
+        // Reconstructing the cosine from the sine is not faster, see
+        // `sample_hemisphere_vector_fast()`.
+        let y = phi.cos() * r;
         let z = (Mf32::one() - r_sqr).sqrt();
 
-        // TODO: Perhaps it would be faster to use a less precise sin and cos,
-        // but normalize the vector in the end?
         MVector3::new(x, y, z)
     }
 
+    /// Returns a random unit vector in the hemisphere around the positive
+    /// z-axis, drawn from a cosine-weighted distribution.
+    ///
+    /// Like `sample_hemisphere_vector()`, but the cosine is reconstructed from
+    /// the sine as sqrt(1 - sin^2), with the sign of the quadrant of the
+    /// angle, instead of evaluating its polynomial. The sine and cosine then
+    /// lie on the unit circle exactly, so the vector has unit norm up to
+    /// rounding, and renormalization is not needed.
+    ///
+    /// Benchmarks show that it is not faster though: the square root has a
+    /// longer latency than the polynomial. Near phi = pi/2 the square root also
+    /// amplifies the error of the sine, so the direction is off by up to 0.1.
+    /// Therefore it is not the default.
+    pub fn sample_hemisphere_vector_fast(&mut self) -> MVector3 {
+        let phi = self.sample_angle();
+        let r_sqr = self.sample_unit();
+
+        let r = r_sqr.sqrt();
+
+        // The approximation of the sine can exceed 1 slightly, so clamp it
+        // before taking the square root. The cosine is negative where
+        // |phi| > pi/2.
+        let sin_phi = phi.sin().max(-Mf32::one()).min(Mf32::one());
+        let cos_abs = sin_phi.neg_mul_add(sin_phi, Mf32::one()).max(Mf32::zero()).sqrt();
+        let cos_phi = cos_abs.copysign(Mf32::broadcast(consts::FRAC_PI_2) - phi.abs());
+
+        let x = sin_phi * r;
+        let y = cos_phi * r;
+        let z = (Mf32::one() - r_sqr).sqrt();
+
+        MVector3::new(x, y, z)
+    }
+
+    /// Returns a random unit vector in the hemisphere around the positive
+    /// z-axis, drawn from a distribution proportional to cos(theta)^n.
+    ///
+    /// This is the lobe of the classic Phong model. For n = 1 it coincides
+    /// with the distribution of `sample_hemisphere_vector()`. The density with
+    /// respect to solid angle is given by `cosine_power_pdf()`.
+    pub fn sample_cosine_power(&mut self, n: Mf32) -> MVector3 {
+        let phi = self.sample_angle();
+        let u = self.sample_unit();
+
+        // Inverting the cumulative distribution of cos(theta) gives
+        // cos(theta) = u^(1 / (n + 1)). The power is approximate, so clamp the
+        // sine to avoid taking the square root of a tiny negative number.
+        let cos_theta = u.pow((n + Mf32::one()).recip_precise());
+        let sin_theta = cos_theta.neg_mul_add(cos_theta, Mf32::one()).max(Mf32::zero()).sqrt();
+        let x = phi.sin() * sin_theta;
+        let y = phi.cos() * sin_theta;
+
+        MVector3::new(x, y, cos_theta)
+    }
+
     /// Returns a random unit vector in the hemisphere around the positive
     /// z-axis, drawn from a cosine-weighted distribution.
     ///
@@ -192,6 +364,288 @@ impl Rng {
 
         MVector3::new(x, y, z)
     }
+
+    /// Draws n times 8 values from `sample_unit()`, and measures how uniform
+    /// and independent they are.
+    ///
+    /// This is a development tool to check that a change to the generator
+    /// (such as a different multiplier in `next()`) did not ruin it. It is not
+    /// meant to be fast.
+    pub fn diagnose(&mut self, n: u32) -> Diagnostics {
+        let (mut s, mut ss, mut sp) = (0.0f64, 0.0f64, 0.0f64);
+        let mut prev = [0.0f64; 8];
+        let mut degenerate = false;
+
+        for j in 0..n {
+            let x = self.sample_unit();
+            let state = self.state;
+            degenerate = degenerate || state.0 == 0 || state.1 == 0 || state.2 == 0 || state.3 == 0;
+            for i in 0..8 {
+                let x = x.get_coord(i) as f64;
+                s += x;
+                ss += x * x;
+                if j > 0 {
+                    sp += prev[i] * x;
+                }
+                prev[i] = x;
+            }
+        }
+
+        // For the serial correlation, approximate the mean and variance of
+        // the pairs with those of all values, which is fine for large n.
+        let m = (n as f64) * 8.0;
+        let mean = s / m;
+        let variance = ss / m - mean * mean;
+        let covariance = sp / ((n as f64 - 1.0) * 8.0) - mean * mean;
+
+        Diagnostics {
+            mean: mean,
+            variance: variance,
+            serial_correlation: covariance / variance,
+            degenerate: degenerate,
+        }
+    }
+}
+
+/// Returns the probability density of `Rng::sample_cosine_power()` with
+/// respect to solid angle, for a direction at an angle theta to the z-axis.
+pub fn cosine_power_pdf(n: Mf32, cos_theta: Mf32) -> Mf32 {
+    let normalization = (n + Mf32::one()) * Mf32::broadcast(0.5 / consts::PI);
+    normalization * cos_theta.max(Mf32::zero()).pow(n)
+}
+
+/// Returns element `index` of the Halton sequence with the given base.
+///
+/// This is not a random number: the sequence is deterministic, but the values
+/// fill the unit interval evenly. Element 0 is 0, so it is common to start at
+/// index 1.
+pub fn halton(index: u32, base: u32) -> f32 {
+    let mut index = index;
+    let mut f = 1.0;
+    let mut result = 0.0;
+    while index > 0 {
+        f = f / base as f32;
+        result = result + f * (index % base) as f32;
+        index = index / base;
+    }
+    result
+}
+
+/// A source of sample values for Monte Carlo integration.
+///
+/// The renderer does not care whether the values are random or come from a
+/// low-discrepancy sequence, as long as they are uniformly distributed. A
+/// sample covers the 8 pixels that are traced together in one mf32. Every
+/// call to `next_1d()` or `next_2d()` after `start_pixel()` consumes the next
+/// dimension(s) of the sample, and the renderer always draws them in the same
+/// order: first the offset in the pixel (two dimensions), then the time. The
+/// dimensions for which the source does not matter much (such as the bounces
+/// of a path) are drawn from `rng()`.
+pub trait Sampler {
+    /// Starts a new sample for the 4x2 pixels whose bottom-left pixel is at
+    /// (x, y), in the lane order of an mf32 in a 16x4 block. The sample index
+    /// is usually the frame number.
+    fn start_pixel(&mut self, x: u32, y: u32, sample: u32);
+
+    /// Returns the next dimension of the sample, 8 values in [0, 1).
+    fn next_1d(&mut self) -> Mf32;
+
+    /// Returns the next two dimensions of the sample, values in [0, 1).
+    fn next_2d(&mut self) -> (Mf32, Mf32);
+
+    /// Returns a random number generator for the remaining dimensions.
+    fn rng(&mut self) -> &mut Rng;
+}
+
+impl Sampler for Rng {
+    fn start_pixel(&mut self, x: u32, y: u32, sample: u32) {
+        *self = Rng::with_seed(x, y, sample);
+    }
+
+    fn next_1d(&mut self) -> Mf32 {
+        self.sample_unit()
+    }
+
+    fn next_2d(&mut self) -> (Mf32, Mf32) {
+        let a = self.sample_unit();
+        let b = self.sample_unit();
+        (a, b)
+    }
+
+    fn rng(&mut self) -> &mut Rng {
+        self
+    }
+}
+
+/// Bases for the dimensions of the Halton sequence. Dimensions beyond these
+/// wrap around, and rely on the scrambling to be decorrelated.
+const HALTON_PRIMES: [u32; 16] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53];
+
+/// A sampler that takes every dimension from the Halton sequence, indexed by
+/// the sample number.
+///
+/// All pixels would see the same point of the sequence, so every pixel and
+/// dimension is offset by a random rotation (Cranley-Patterson rotation). The
+/// rotation is a hash of the pixel coordinates and the dimension, so
+/// consecutive samples of a pixel fill the unit interval evenly, and every
+/// lane gets the rotation of its own pixel.
+pub struct HaltonSampler {
+    /// Generator for the remaining dimensions.
+    rng: Rng,
+
+    /// Coordinates of the bottom-left pixel of the 4x2 pixels.
+    x: u32,
+    y: u32,
+
+    sample: u32,
+    dimension: u32,
+}
+
+impl HaltonSampler {
+    pub fn new() -> HaltonSampler {
+        HaltonSampler {
+            rng: Rng::from_u64(0),
+            x: 0,
+            y: 0,
+            sample: 0,
+            dimension: 0,
+        }
+    }
+
+    /// Returns the rotation of the pixel at (x, y) for the given dimension,
+    /// a value in [0, 1).
+    #[inline(always)]
+    fn rotation(x: u32, y: u32, dimension: u32) -> f32 {
+        // Hash the values with different primes, as in `Rng::with_seed()`,
+        // and use the high bits, which are mixed best.
+        let a = (x as u64).wrapping_mul(12276630456901467871);
+        let b = (y as u64).wrapping_mul(7661526868048087387);
+        let c = (dimension as u64 + 1).wrapping_mul(2268244495640532043);
+        let h = a.wrapping_add(b).wrapping_add(c);
+        let h = (h ^ (h >> 32)).wrapping_mul(14491630826648200009);
+        (h >> 40) as f32 * (1.0 / (1u64 << 24) as f32)
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel(&mut self, x: u32, y: u32, sample: u32) {
+        self.rng = Rng::with_seed(x, y, sample);
+        self.x = x;
+        self.y = y;
+        self.sample = sample;
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> Mf32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        let base = HALTON_PRIMES[dimension as usize % HALTON_PRIMES.len()];
+        let h = halton(self.sample, base);
+        let (x, y) = (self.x, self.y);
+        Mf32::generate(|i| {
+            let offset = HaltonSampler::rotation(x + i as u32 % 4, y + i as u32 / 4, dimension);
+            let v = h + offset;
+            if v >= 1.0 { v - 1.0 } else { v }
+        })
+    }
+
+    fn next_2d(&mut self) -> (Mf32, Mf32) {
+        let a = self.next_1d();
+        let b = self.next_1d();
+        (a, b)
+    }
+
+    fn rng(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
+}
+
+#[test]
+fn halton_known_values() {
+    let base_2 = [0.0, 0.5, 0.25, 0.75, 0.125, 0.625];
+    let base_3 = [0.0, 1.0 / 3.0, 2.0 / 3.0, 1.0 / 9.0, 4.0 / 9.0, 7.0 / 9.0];
+    for i in 0..6 {
+        assert!((halton(i as u32, 2) - base_2[i]).abs() < 1e-6);
+        assert!((halton(i as u32, 3) - base_3[i]).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn from_u64_is_reproducible() {
+    let mut rng_a = Rng::from_u64(42);
+    let mut rng_b = Rng::from_u64(42);
+    for _ in 0..1024 {
+        assert_eq!(rng_a.sample_u32(), rng_b.sample_u32());
+    }
+}
+
+#[test]
+fn from_u64_different_seeds_diverge() {
+    for &(seed_a, seed_b) in &[(0, 1), (42, 43), (1 << 32, 1 << 33), (7, 7 << 32)] {
+        let mut rng_a = Rng::from_u64(seed_a);
+        let mut rng_b = Rng::from_u64(seed_b);
+        for _ in 0..16 {
+            let xs = rng_a.sample_u32();
+            let ys = rng_b.sample_u32();
+            for i in 0..8 {
+                assert!(xs[i] != ys[i], "seeds {} and {} collide in lane {}", seed_a, seed_b, i);
+                assert!(xs[i] != 0 || ys[i] != 0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn assert_sampler_in_interval<S: Sampler>(sampler: &mut S) {
+    let in_interval = |x: Mf32| x.all_sign_bits_positive() && (Mf32::one() - x).all_sign_bits_positive();
+    for sample in 0..64 {
+        sampler.start_pixel(16, 4, sample);
+        for _ in 0..24 {
+            let x = sampler.next_1d();
+            assert!(in_interval(x), "{:?} should be in [0, 1)", x);
+            let (a, b) = sampler.next_2d();
+            assert!(in_interval(a) && in_interval(b), "{:?} should be in [0, 1)", (a, b));
+        }
+        let x = sampler.rng().sample_unit();
+        assert!(in_interval(x), "{:?} should be in [0, 1)", x);
+    }
+}
+
+#[test]
+fn samplers_are_in_interval() {
+    assert_sampler_in_interval(&mut Rng::with_seed(2, 5, 7));
+    assert_sampler_in_interval(&mut HaltonSampler::new());
+}
+
+#[test]
+fn halton_sampler_stratifies_pixel_samples() {
+    // The first 16 samples of a pixel cover all 16 strata of base 2, whatever
+    // the rotation.
+    let mut sampler = HaltonSampler::new();
+    let mut strata = [0; 16];
+    for sample in 0..16 {
+        sampler.start_pixel(32, 8, sample);
+        let x = sampler.next_1d();
+        strata[(x.get_coord(3) * 16.0) as usize] += 1;
+    }
+    assert_eq!(strata, [1; 16]);
+}
+
+#[test]
+fn halton_sampler_depends_on_pixel_sample_and_dimension_only() {
+    // Lane 1 of the pixels at (16, 4) is the pixel at (17, 4), which is lane
+    // 0 when the pixels start there.
+    let mut a = HaltonSampler::new();
+    let mut b = HaltonSampler::new();
+    a.start_pixel(16, 4, 9);
+    b.start_pixel(17, 4, 9);
+    let (a0, a1) = a.next_2d();
+    let (b0, b1) = b.next_2d();
+    assert_eq!(a0.get_coord(1), b0.get_coord(0));
+    assert_eq!(a1.get_coord(1), b1.get_coord(0));
+
+    // Different pixels are rotated differently.
+    assert!(a0.get_coord(0) != a0.get_coord(1));
 }
 
 #[test]
@@ -205,6 +659,145 @@ fn sample_unit_is_in_interval() {
     }
 }
 
+#[test]
+fn sample_unit_excl_zero_is_in_open_interval() {
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let mut buckets = [0u32; 16];
+    let n = 1 << 16;
+
+    for _ in 0..n {
+        let x = rng.sample_unit_excl_zero();
+        for i in 0..8 {
+            let xi = x.get_coord(i);
+            assert!(xi > 0.0 && xi < 1.0, "{} should be in (0, 1)", xi);
+            assert!(xi.ln().is_finite() && (1.0 / xi).is_finite());
+            buckets[(xi * 16.0) as usize] += 1;
+        }
+    }
+
+    // Every bucket should receive about 1/16 of the samples.
+    let expected = (n * 8 / 16) as f32;
+    for &count in &buckets {
+        assert!((count as f32 - expected).abs() < 0.02 * expected, "bucket counts: {:?}", buckets);
+    }
+}
+
+#[test]
+fn sample_unit_pair_is_in_interval() {
+    let mut rng = Rng::with_seed(2, 5, 7);
+
+    for _ in 0..4096 {
+        let (u, v) = rng.sample_unit_pair();
+        for &x in &[u, v] {
+            assert!(x.all_sign_bits_positive(), "{:?} should be >= 0", x);
+            assert!((x - Mf32::one()).all_sign_bits_negative(), "{:?} should be < 1", x);
+        }
+    }
+}
+
+#[test]
+fn sample_unit_pair_is_uncorrelated() {
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let n = 4096;
+    let (mut su, mut sv, mut suu, mut svv, mut suv) = (0.0, 0.0, 0.0, 0.0, 0.0);
+
+    for _ in 0..n {
+        let (u, v) = rng.sample_unit_pair();
+        for i in 0..8 {
+            let (u, v) = (u.get_coord(i) as f64, v.get_coord(i) as f64);
+            su += u;
+            sv += v;
+            suu += u * u;
+            svv += v * v;
+            suv += u * v;
+        }
+    }
+
+    let m = (n * 8) as f64;
+    let (mean_u, mean_v) = (su / m, sv / m);
+    let cov = suv / m - mean_u * mean_v;
+    let var_u = suu / m - mean_u * mean_u;
+    let var_v = svv / m - mean_v * mean_v;
+    let correlation = cov / (var_u * var_v).sqrt();
+
+    // Both should be uniform, so the mean is 1/2 and the variance 1/12.
+    assert!((mean_u - 0.5).abs() < 0.01 && (mean_v - 0.5).abs() < 0.01);
+    assert!((var_u - 1.0 / 12.0).abs() < 0.01 && (var_v - 1.0 / 12.0).abs() < 0.01);
+    assert!(correlation.abs() < 0.02, "correlation {} should be close to 0", correlation);
+}
+
+#[test]
+fn sample_unit_pair_low_bits_are_not_periodic() {
+    // Bucket the lowest two bits of the 16-bit values of consecutive pairs of
+    // calls. Bits with a short period fill only a few of the 16 buckets, so
+    // the chi-squared statistic with 15 degrees of freedom explodes.
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let n = 1024;
+    let mut buckets = [[0u32; 16]; 16];
+    let low_bits = |x: Mf32, i: usize| (x.get_coord(i) * 65536.0) as usize & 3;
+
+    for _ in 0..n {
+        let (u0, v0) = rng.sample_unit_pair();
+        let (u1, v1) = rng.sample_unit_pair();
+        for i in 0..8 {
+            buckets[i][low_bits(u0, i) * 4 + low_bits(u1, i)] += 1;
+            buckets[8 + i][low_bits(v0, i) * 4 + low_bits(v1, i)] += 1;
+        }
+    }
+
+    let expected = n as f64 / 16.0;
+    for counts in &buckets {
+        let chi_squared: f64 = counts.iter()
+            .map(|&c| (c as f64 - expected) * (c as f64 - expected) / expected)
+            .sum();
+        assert!(chi_squared < 50.0, "low bits are periodic, bucket counts: {:?}", counts);
+    }
+}
+
+#[test]
+fn diagnose_reports_uniform_independent_values() {
+    let d = Rng::with_seed(2, 5, 7).diagnose(4096);
+    assert!((d.mean - 0.5).abs() < 0.01, "mean {} should be close to 1/2", d.mean);
+    assert!((d.variance - 1.0 / 12.0).abs() < 0.01, "variance {} should be close to 1/12", d.variance);
+    assert!(d.serial_correlation.abs() < 0.02, "serial correlation {} should be close to 0", d.serial_correlation);
+    assert!(!d.degenerate);
+}
+
+#[test]
+fn sample_polygon_lies_within_polygon_and_approaches_disk() {
+    use std::f64;
+
+    let mut rng = Rng::with_seed(2, 5, 7);
+    for &blades in &[3, 5, 6, 64] {
+        // A point lies inside the polygon if its projection onto the normal
+        // of every edge is at most the distance from the center to the edge.
+        let n = blades as f32;
+        let apothem = (consts::PI / n).cos();
+        let mut sum_r_sqr = 0.0f64;
+        for _ in 0..1024 {
+            let (xs, ys) = rng.sample_polygon(blades);
+            for i in 0..8 {
+                let (x, y) = (xs.get_coord(i), ys.get_coord(i));
+                for k in 0..blades {
+                    let normal_angle = (2 * k + 1) as f32 * consts::PI / n;
+                    let d = x * normal_angle.cos() + y * normal_angle.sin();
+                    assert!(d <= apothem + 1e-5, "({}, {}) lies outside of the {}-gon", x, y, blades);
+                }
+                sum_r_sqr += (x * x + y * y) as f64;
+            }
+        }
+
+        // For the unit disk, the mean of r^2 is 1/2. For a regular polygon
+        // with circumradius 1 it is (2 + cos(2 pi / n)) / 6.
+        let mean_r_sqr = sum_r_sqr / (1024.0 * 8.0);
+        let expected = (2.0 + (2.0 * f64::consts::PI / blades as f64).cos()) / 6.0;
+        assert!((mean_r_sqr - expected).abs() < 0.01, "{}-gon: mean r^2 {} should be {}", blades, mean_r_sqr, expected);
+        if blades == 64 {
+            assert!((mean_r_sqr - 0.5).abs() < 0.01, "many blades should approximate the disk");
+        }
+    }
+}
+
 #[test]
 fn sample_biunit_is_in_interval() {
     let mut rng = Rng::with_seed(2, 5, 7);
@@ -239,6 +832,94 @@ fn sample_hemisphere_vector_has_unit_norm() {
     }
 }
 
+#[test]
+fn sample_hemisphere_vector_fast_has_unit_norm() {
+    let mut rng = Rng::with_seed(2, 5, 7);
+
+    for _ in 0..4096 {
+        let v = rng.sample_hemisphere_vector_fast();
+        let r = v.norm_squared().sqrt();
+        assert!((r - Mf32::broadcast(0.9999)).all_sign_bits_positive(), "{:?} should be ~1", r);
+        assert!((Mf32::broadcast(1.0001) - r).all_sign_bits_positive(), "{:?} should be ~1", r);
+    }
+}
+
+#[test]
+fn sample_hemisphere_vector_fast_matches_default() {
+    // With the same random numbers, both versions produce nearly the same
+    // vector. Near phi = pi/2 the error of the sine is amplified by the square
+    // root, but the sign of the cosine is still right.
+    let mut rng_a = Rng::with_seed(2, 5, 7);
+    let mut rng_b = Rng::with_seed(2, 5, 7);
+
+    for _ in 0..4096 {
+        let a = rng_a.sample_hemisphere_vector();
+        let b = rng_b.sample_hemisphere_vector_fast();
+        for i in 0..8 {
+            let (ay, by) = (a.y.get_coord(i), b.y.get_coord(i));
+            assert!((a.x.get_coord(i) - b.x.get_coord(i)).abs() < 0.01);
+            assert!((ay - by).abs() < 0.11, "{} vs {}", ay, by);
+            assert!(ay.abs() < 0.11 || ay.signum() == by.signum(), "{} vs {}", ay, by);
+            assert_eq!(a.z.get_coord(i), b.z.get_coord(i));
+        }
+    }
+}
+
+#[test]
+fn sample_cosine_power_has_unit_norm() {
+    let mut rng = Rng::with_seed(2, 5, 7);
+
+    for &n in &[0.0, 1.0, 4.0, 16.0, 100.0] {
+        for _ in 0..4096 {
+            let v = rng.sample_cosine_power(Mf32::broadcast(n));
+            let r = v.norm_squared().sqrt();
+            assert!((r - Mf32::broadcast(0.991)).all_sign_bits_positive(), "{:?} should be ~1", r);
+            assert!((Mf32::broadcast(1.009) - r).all_sign_bits_positive(), "{:?} should be ~1", r);
+            assert!(v.z.all_sign_bits_positive(), "{:?} should be in the upper hemisphere", v.z);
+        }
+    }
+}
+
+#[test]
+fn sample_cosine_power_concentrates_with_n() {
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let mut mean_z = Vec::new();
+
+    for &n in &[1.0, 4.0, 16.0, 64.0] {
+        let mut sum = 0.0;
+        for _ in 0..4096 {
+            let z = rng.sample_cosine_power(Mf32::broadcast(n)).z;
+            sum += z.hsum();
+        }
+        let mean = sum / (4096.0 * 8.0);
+
+        // The expected value of cos(theta) is (n + 1) / (n + 2).
+        let expected = (n + 1.0) / (n + 2.0);
+        assert!((mean - expected).abs() < 0.01, "mean z {} should be ~{} for n = {}", mean, expected, n);
+        mean_z.push(mean);
+    }
+
+    for w in mean_z.windows(2) {
+        assert!(w[0] < w[1], "higher n should concentrate samples near +z");
+    }
+}
+
+#[test]
+fn cosine_power_pdf_integrates_to_one() {
+    // Integrate over the hemisphere with the midpoint rule in theta. The
+    // integrand does not depend on phi, which contributes a factor 2pi.
+    let steps = 4096;
+    for &n in &[1.0, 4.0, 16.0] {
+        let mut integral = 0.0;
+        for i in 0..steps {
+            let theta = (i as f32 + 0.5) / (steps as f32) * consts::FRAC_PI_2;
+            let pdf = cosine_power_pdf(Mf32::broadcast(n), Mf32::broadcast(theta.cos())).0;
+            integral += pdf * theta.sin() * consts::FRAC_PI_2 / (steps as f32) * 2.0 * consts::PI;
+        }
+        assert!((integral - 1.0).abs() < 0.01, "integral is {} for n = {}", integral, n);
+    }
+}
+
 #[test]
 fn sample_u32_does_not_cause_sigsegv() {
     use util::generate_slice8;
@@ -276,6 +957,18 @@ fn bench_sample_unit_1000(b: &mut test::Bencher) {
     });
 }
 
+#[bench]
+fn bench_sample_unit_pair_1000(b: &mut test::Bencher) {
+    let mut rng = Rng::with_seed(2, 5, 7);
+    b.iter(|| {
+        for _ in 0..100 {
+            unroll_10! {{
+                test::black_box(rng.sample_unit_pair());
+            }};
+        }
+    });
+}
+
 #[bench]
 fn bench_sample_hemisphere_vector_1000(b: &mut test::Bencher) {
     let mut rng = Rng::with_seed(2, 5, 7);
@@ -288,6 +981,31 @@ fn bench_sample_hemisphere_vector_1000(b: &mut test::Bencher) {
     });
 }
 
+#[bench]
+fn bench_sample_cosine_power_1000(b: &mut test::Bencher) {
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let n = Mf32::broadcast(16.0);
+    b.iter(|| {
+        for _ in 0..100 {
+            unroll_10! {{
+                test::black_box(rng.sample_cosine_power(n));
+            }};
+        }
+    });
+}
+
+#[bench]
+fn bench_sample_hemisphere_vector_fast_1000(b: &mut test::Bencher) {
+    let mut rng = Rng::with_seed(2, 5, 7);
+    b.iter(|| {
+        for _ in 0..100 {
+            unroll_10! {{
+                test::black_box(rng.sample_hemisphere_vector_fast());
+            }};
+        }
+    });
+}
+
 #[bench]
 fn bench_sample_hemisphere_vector_reject_1000(b: &mut test::Bencher) {
     let mut rng = Rng::with_seed(2, 5, 7);
@@ -299,3 +1017,54 @@ fn bench_sample_hemisphere_vector_reject_1000(b: &mut test::Bencher) {
         }
     });
 }
+
+#[bench]
+fn bench_sample_unit_excl_zero_1000(b: &mut test::Bencher) {
+    let mut rng = Rng::with_seed(2, 5, 7);
+    b.iter(|| {
+        for _ in 0..100 {
+            unroll_10! {{
+                test::black_box(rng.sample_unit_excl_zero());
+            }};
+        }
+    });
+}
+
+#[bench]
+fn bench_sample_biunit_1000(b: &mut test::Bencher) {
+    let mut rng = Rng::with_seed(2, 5, 7);
+    b.iter(|| {
+        for _ in 0..100 {
+            unroll_10! {{
+                test::black_box(rng.sample_biunit());
+            }};
+        }
+    });
+}
+
+#[bench]
+fn bench_sample_angle_1000(b: &mut test::Bencher) {
+    let mut rng = Rng::with_seed(2, 5, 7);
+    b.iter(|| {
+        for _ in 0..100 {
+            unroll_10! {{
+                test::black_box(rng.sample_angle());
+            }};
+        }
+    });
+}
+
+#[bench]
+fn bench_halton_sampler_next_2d_1000(b: &mut test::Bencher) {
+    let mut sampler = HaltonSampler::new();
+    b.iter(|| {
+        for i in 0..100 {
+            // Start a new pixel every iteration, so the dimension stays within
+            // the range that a path would use.
+            sampler.start_pixel(2, 5, i);
+            unroll_10! {{
+                test::black_box(sampler.next_2d());
+            }};
+        }
+    });
+}
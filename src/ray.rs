@@ -8,10 +8,20 @@
 //! This module implements the ray and related structures.
 
 use material::MMaterial;
-use simd::{Mask, Mf32};
+use simd::{Mask, Mf32, Nf32};
 use std::ops::Neg;
 use vector3::{MVector3, SVector3};
 
+/// The spread angle of a ray cone in radians, roughly the angle that one pixel
+/// subtends at the default resolution and field of view.
+const CONE_SPREAD_ANGLE: f32 = 1.0e-3;
+
+/// Offset relative to the magnitude of the coordinates, about four ulps of an
+/// f32, to stay clear of the rounding error in the intersection position. It
+/// must stay small: a hundred units away from a coordinate of 10^6, a larger
+/// offset would already detach shadows.
+const RELATIVE_OFFSET: f32 = 5.0e-7;
+
 #[derive(Clone)]
 pub struct SRay {
     pub origin: SVector3,
@@ -32,6 +42,17 @@ pub struct MRay {
     pub active: Mask,
 }
 
+/// Up to four active rays of an `MRay`, gathered into narrow vectors.
+///
+/// Coordinates are stored per axis, x first.
+pub struct NRay {
+    pub origin: [Nf32; 3],
+    pub direction: [Nf32; 3],
+
+    /// The lane in the source `MRay` of every ray.
+    pub lanes: [usize; 4],
+}
+
 pub struct MIntersection {
     /// The position at which the ray intersected the surface.
     pub position: MVector3,
@@ -47,6 +68,83 @@ pub struct MIntersection {
 
     /// Texture coordinates at the intersection point.
     pub tex_coords: (Mf32, Mf32),
+
+    /// Sign bit 1 if the ray hit the side of the surface that the normal
+    /// points to, so it enters the object, and 0 if it exits the object.
+    pub front_face: Mask,
+
+    /// The light linking bits of the surface, stored as the bit pattern of a
+    /// float, like the material.
+    pub light_links: Mf32,
+
+    /// Barycentric coordinates of the intersection: the weights of the second
+    /// and third vertex of the triangle. The weight of the first vertex is
+    /// one minus their sum.
+    pub barycentric: (Mf32, Mf32),
+
+    /// The vertex colors of the triangle interpolated at the intersection,
+    /// white if the mesh has no vertex colors.
+    pub vertex_color: MVector3,
+
+    /// The index of the triangle in the BVH that the ray hit, stored as the
+    /// bit pattern of a float like the light links, or `NO_TRIANGLE` if the
+    /// ray hit something other than a triangle.
+    pub triangle: Mf32,
+}
+
+/// The triangle index of intersections that did not hit a triangle.
+pub const NO_TRIANGLE: u32 = 0xffff_ffff;
+
+/// Broadcasts a triangle index in the representation of `MIntersection`.
+pub fn triangle_bits(index: u32) -> Mf32 {
+    use std::mem::transmute;
+    Mf32::broadcast(unsafe { transmute(index) })
+}
+
+impl NRay {
+    /// Gathers the rays in the given lanes.
+    pub fn gather(ray: &MRay, lanes: [usize; 4]) -> NRay {
+        let gather = |v: &MVector3| [
+            Nf32::generate(|i| v.x.get_coord(lanes[i])),
+            Nf32::generate(|i| v.y.get_coord(lanes[i])),
+            Nf32::generate(|i| v.z.get_coord(lanes[i])),
+        ];
+        NRay {
+            origin: gather(&ray.origin),
+            direction: gather(&ray.direction),
+            lanes: lanes,
+        }
+    }
+
+    /// Gathers the active rays of the packet if there are at most four of
+    /// them. Unused lanes repeat the first active ray, which does not change
+    /// the outcome of any test. Returns `None` if there are more than four
+    /// active rays, or none.
+    pub fn compact(ray: &MRay) -> Option<NRay> {
+        let mut lanes = [0; 4];
+        let mut n = 0;
+        for k in 0..8 {
+            if !ray.active.get_coord(k).is_sign_negative() {
+                if n == 4 {
+                    return None
+                }
+                lanes[n] = k;
+                n += 1;
+            }
+        }
+        if n == 0 {
+            return None
+        }
+        for i in n..4 {
+            lanes[i] = lanes[0];
+        }
+        Some(NRay::gather(ray, lanes))
+    }
+
+    /// Gathers the values of the lanes of the rays.
+    pub fn gather_mf32(&self, x: Mf32) -> Nf32 {
+        Nf32::generate(|i| x.get_coord(self.lanes[i]))
+    }
 }
 
 impl SRay {
@@ -89,7 +187,48 @@ impl MRay {
     }
 }
 
+/// Panics if the vector does not have unit length in one of the lanes where
+/// the sign bit of `active` is 0. This is slow, it should only be called in
+/// debug builds.
+fn assert_unit(v: MVector3, active: Mask, what: &str) {
+    for i in 0..8 {
+        if active.get_coord(i).is_sign_negative() {
+            continue
+        }
+        let (x, y, z) = (v.x.get_coord(i), v.y.get_coord(i), v.z.get_coord(i));
+        let norm = (x * x + y * y + z * z).sqrt();
+
+        // Vectors are normalized with the approximate reciprocal square root,
+        // so allow for some imprecision. Written such that NaN fails too.
+        if !((norm - 1.0).abs() < 0.01) {
+            panic!("{} in lane {} has norm {}, it should have unit length\n  \
+                    x: {:?}\n  y: {:?}\n  z: {:?}", what, i, norm, v.x, v.y, v.z);
+        }
+    }
+}
+
+impl MRay {
+    /// In debug builds, asserts that the directions of the active rays have
+    /// unit length. Does nothing in release builds.
+    #[inline(always)]
+    pub fn debug_assert_invariants(&self) {
+        if cfg!(debug_assertions) {
+            assert_unit(self.direction, self.active, "ray direction");
+        }
+    }
+}
+
 impl MIntersection {
+    /// In debug builds, asserts that the normals have unit length for the
+    /// lanes where the sign bit of `active` is 0. Does nothing in release
+    /// builds.
+    #[inline(always)]
+    pub fn debug_assert_invariants(&self, active: Mask) {
+        if cfg!(debug_assertions) {
+            assert_unit(self.normal, active, "intersection normal");
+        }
+    }
+
     /// Constructs an empyt intersection with the specified distance and zeroes
     /// in all other fields. The material is set to the sky material.
     pub fn with_max_distance(max_dist: f32) -> MIntersection {
@@ -99,6 +238,11 @@ impl MIntersection {
             distance: Mf32::broadcast(max_dist),
             material: MMaterial::sky(),
             tex_coords: (Mf32::zero(), Mf32::zero()),
+            front_face: Mf32::zero(),
+            light_links: Mask::ones(),
+            barycentric: (Mf32::zero(), Mf32::zero()),
+            vertex_color: MVector3::new(Mf32::one(), Mf32::one(), Mf32::one()),
+            triangle: triangle_bits(NO_TRIANGLE),
         }
     }
 
@@ -111,8 +255,62 @@ impl MIntersection {
             distance: self.distance.pick(other.distance, mask),
             material: self.material.pick(other.material, mask),
             tex_coords: (u, v),
+            front_face: self.front_face.pick(other.front_face, mask),
+            light_links: self.light_links.pick(other.light_links, mask),
+            barycentric: (self.barycentric.0.pick(other.barycentric.0, mask),
+                          self.barycentric.1.pick(other.barycentric.1, mask)),
+            vertex_color: self.vertex_color.pick(other.vertex_color, mask),
+            triangle: self.triangle.pick(other.triangle, mask),
         }
     }
+
+    /// Returns the index of the triangle that the ray in lane `i` hit, if it
+    /// hit a triangle.
+    pub fn triangle_index(&self, i: usize) -> Option<u32> {
+        use std::mem::transmute;
+        let index: u32 = unsafe { transmute(self.triangle.get_coord(i)) };
+        if index == NO_TRIANGLE { None } else { Some(index) }
+    }
+
+    /// Returns a mask with sign bit 1 for the lanes where the light linking
+    /// bits of the surface have no bit in common with `links`.
+    pub fn is_unlinked(&self, links: Mf32) -> Mask {
+        use std::mem::transmute;
+        let common: [u32; 8] = unsafe { transmute(self.light_links & links) };
+        Mf32::generate(|i| if common[i] == 0 { -1.0 } else { 0.0 })
+    }
+
+    /// Returns the normal flipped to the side of the surface that the ray
+    /// came from.
+    pub fn facing_normal(&self) -> MVector3 {
+        (-self.normal).pick(self.normal, self.front_face)
+    }
+
+    /// Returns the ratio of the index of refraction on the side of the ray to
+    /// the index on the other side, for an object with index `ior` in air.
+    /// When entering this is 1 / `ior`, when exiting it is `ior`.
+    pub fn relative_ior(&self, ior: f32) -> Mf32 {
+        Mf32::broadcast(ior).pick(Mf32::broadcast(1.0 / ior), self.front_face)
+    }
+
+    /// Returns the origin for a new ray that leaves the surface in the given
+    /// direction, such that it does not intersect the same surface again.
+    ///
+    /// The origin is moved along the normal, to the side that the direction
+    /// points to, so it works for reflected and transmitted rays alike. The
+    /// size of the offset is the footprint of a ray cone at the intersection,
+    /// and at least the rounding error of the position. A constant offset
+    /// would either be too small for large scenes (causing shadow acne) or too
+    /// large for small scenes (letting light leak underneath occluders), this
+    /// offset scales with the scene.
+    pub fn offset_origin(&self, direction: MVector3) -> MVector3 {
+        let p = self.position;
+        let magnitude = p.x.abs().max(p.y.abs()).max(p.z.abs());
+        let footprint = self.distance * Mf32::broadcast(CONE_SPREAD_ANGLE);
+        let offset = magnitude.mul_add(Mf32::broadcast(RELATIVE_OFFSET), footprint);
+        let normal = self.normal.faceforward(-direction);
+        normal.mul_add(offset, p)
+    }
 }
 
 impl Neg for MRay {
@@ -126,3 +324,76 @@ impl Neg for MRay {
         }
     }
 }
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "ray direction in lane 3 has norm 2")]
+fn debug_assert_invariants_rejects_unnormalized_ray() {
+    let ray = MRay::generate(|i| {
+        let length = if i == 3 { 2.0 } else { 1.0 };
+        SRay::new(SVector3::zero(), SVector3::new(0.0, 0.0, length))
+    });
+    ray.debug_assert_invariants();
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "ray direction in lane 5")]
+fn intersect_nearest_rejects_unnormalized_ray() {
+    use bench;
+    let scene = bench::caustic_scene();
+    let ray = MRay::generate(|i| {
+        let length = if i == 5 { 0.5 } else { 1.0 };
+        SRay::new(SVector3::zero(), SVector3::new(0.0, 0.0, -length))
+    });
+    scene.intersect_nearest(&ray);
+}
+
+#[test]
+fn offset_origin_avoids_acne_and_leaks_at_any_scale() {
+    use bench;
+    use material::SMaterial;
+    use scene::Scene;
+
+    for &s in &[1.0e-3, 1.0e4] {
+        // A floor in the plane z = 0, and a wall in the plane x = 0 that
+        // stands on the floor.
+        let v = |x: f32, y: f32, z: f32| SVector3::new(x * s, y * s, z * s);
+        let quads = [
+            ([v(-1.0, -1.0, 0.0), v(1.0, -1.0, 0.0), v(1.0, 1.0, 0.0), v(-1.0, 1.0, 0.0)], SMaterial::white()),
+            ([v(0.0, -1.0, 0.0), v(0.0, 1.0, 0.0), v(0.0, 1.0, 1.0), v(0.0, -1.0, 1.0)], SMaterial::white()),
+        ];
+        let scene = Scene::from_meshes(&[bench::mesh_from_quads(&quads)]);
+
+        // Shoots rays straight down onto the floor at the given x, and then
+        // shadow rays towards a light far away at the positive x side.
+        let shadow_isect = |x: f32| {
+            let ray = MRay {
+                origin: MVector3::new(Mf32::broadcast(x * s),
+                                      Mf32::generate(|i| (i as f32 * 0.1 + 0.05) * s),
+                                      Mf32::broadcast(2.0 * s)),
+                direction: MVector3::new(Mf32::zero(), Mf32::zero(), -Mf32::one()),
+                active: Mf32::zero(),
+            };
+            let isect = scene.intersect_nearest(&ray);
+            assert!(isect.material.all_sign_bits_positive(), "primary rays must hit the floor");
+
+            let direction = MVector3::new(Mf32::one(), Mf32::zero(), Mf32::one()).normalized();
+            let shadow_ray = MRay {
+                origin: isect.offset_origin(direction),
+                direction: direction,
+                active: Mf32::zero(),
+            };
+            scene.intersect_nearest(&shadow_ray)
+        };
+
+        // Points in the open must see the light, they must not be occluded by
+        // the floor they are on.
+        let lit = shadow_isect(0.5);
+        assert!(lit.material.all_sign_bits_negative(), "shadow acne at scale {}", s);
+
+        // Points just behind the wall must be in its shadow.
+        let shadowed = shadow_isect(-0.002);
+        assert!(shadowed.material.all_sign_bits_positive(), "detached shadow at scale {}", s);
+    }
+}
@@ -5,13 +5,36 @@
 // it under the terms of the GNU General Public License version 3. A copy
 // of the License is available in the root of the repository.
 
-use material::{continue_path, sky_intensity};
-use random::Rng;
-use scene::Scene;
-use simd::{Mf32, Mi32};
+use animation::Animation;
+use bvh::{self, TraversalStats};
+use exr;
+use graph::SceneGraph;
+use imagefmt;
+use material::{MMaterial, continue_path, eval_brdf};
+use num_cpus;
+use photon::PhotonMap;
+use random::{HaltonSampler, Rng, Sampler};
+use ray::{MIntersection, MRay};
+use scene::{Camera, Scene};
+use scoped_threadpool::Pool;
+use simd::{Mask, Mf32, Mi32};
 use std::cell::UnsafeCell;
+use std::cmp;
+use std::f32::consts;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::u32;
+use time;
 use util::{cache_line_aligned_vec, generate_slice8};
-use vector3::{MVector3, SVector3};
+use vector3::{ChannelOrder, MVector3, SVector3};
+use wavefront::Mesh;
+
+#[cfg(test)]
+use {bench, test};
 
 pub struct Renderer {
     scene: Scene,
@@ -24,6 +47,308 @@ pub struct Renderer {
 
     /// The amount that time increases per frame.
     time_delta: f32,
+
+    /// The fraction by which the glossiness of a surface is reduced, if the
+    /// path has already bounced off a diffuse surface. 0.0 disables path
+    /// regularization.
+    path_regularization: f32,
+
+    /// The maximum factor by which one bounce can scale the throughput of a
+    /// path. Infinity disables clamping.
+    max_sample_weight: f32,
+
+    /// The maximum value of any color component of the radiance of paths that
+    /// bounced more than once before reaching a light. Infinity disables
+    /// clamping.
+    indirect_clamp: f32,
+
+    /// The number of samples per pixel after which accumulation stops.
+    max_accumulation: u32,
+
+    /// The maximum number of indirect bounces per path, see
+    /// `set_max_bounces()`. Paths are traced in a bounded loop, so this limits
+    /// the work per pixel regardless of the scene.
+    max_bounces: u32,
+
+    /// The number of bounces along which the albedo guide is accumulated, see
+    /// `set_albedo_bounces()`.
+    albedo_bounces: u32,
+
+    /// The number of paths that were still bouncing when the bounce limit was
+    /// reached, since the last call to `take_bounce_cap_hits()`. This is only
+    /// counted in debug builds.
+    bounce_cap_hits: AtomicUsize,
+
+    /// BVH traversal counters of all worker threads, since the last call to
+    /// `take_traversal_stats()`. Only collected when the `traversal-stats`
+    /// feature is enabled.
+    traversal_stats: Mutex<TraversalStats>,
+
+    /// Caustics to add at the first surface that a camera ray hits, if any.
+    photon_map: Option<PhotonMap>,
+
+    /// The camera of the previous frame, to compute motion vectors. If there
+    /// is none, the current camera is used.
+    previous_camera: Option<Camera>,
+
+    /// The order in which `render_frame_parallel()` schedules patches.
+    tile_order: TileOrder,
+
+    /// The source of sample values for the camera rays.
+    sampler_kind: SamplerKind,
+
+    /// The sub-pixel sample positions of the anti-aliasing pattern, as the
+    /// corner of a cell and the size of the cell in which a random position
+    /// is taken. For fixed positions the size is zero.
+    aa_cells: Vec<(f32, f32)>,
+    aa_cell_size: (f32, f32),
+
+    /// The radius in pixels of the tent reconstruction filter, or 0 for a box
+    /// filter over the pixel. See `set_filter_radius()`.
+    filter_radius: f32,
+
+    /// With adaptive sampling, the relative error at which a pixel stops
+    /// receiving new samples in `accumulate_patch_f32()`.
+    adaptive_threshold: Option<f32>,
+
+    /// A tile of blue noise values in [0, 1) to dither the 8-bit output
+    /// with, if dithering is enabled.
+    dither: Option<Vec<f32>>,
+
+    /// The start and strength of the highlight rolloff, if enabled.
+    highlight_knee: Option<(f32, f32)>,
+
+    /// Whether the color in the 8-bit output is multiplied by the alpha.
+    premultiply_alpha: bool,
+
+    /// The order of the color channels in the bytes of the 8-bit output.
+    channel_order: ChannelOrder,
+
+    /// The reference grid on the ground plane, if enabled.
+    grid: Option<Grid>,
+
+    /// Whether the first row of the buffers is the top row of the image,
+    /// rather than the bottom row.
+    flip_y: bool,
+
+    /// Keyframe animation of the scene, evaluated by `update_scene()`. If
+    /// there is none, the camera orbits the scene.
+    animation: Option<Animation>,
+
+    /// The scene graph that the meshes of the scene were baked from, and the
+    /// meshes in node space, see `set_scene_graph()`.
+    graph: Option<SceneGraph>,
+    graph_meshes: Vec<Mesh>,
+
+    /// Whether the user took control of the scene, see `take_control()`.
+    user_control: bool,
+
+    /// What happens to bounce rays that leave the bounds of the scene.
+    escape_mode: EscapeMode,
+
+    /// The number of bounce rays that escaped the bounds of the scene since
+    /// the last call to `take_light_leaks()`. Only counted with
+    /// `EscapeMode::Leak`.
+    light_leaks: AtomicUsize,
+}
+
+/// The source of the sample values that the renderer uses, see `Sampler`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SamplerKind {
+    /// Independent random numbers from `Rng`.
+    Random,
+
+    /// The scrambled Halton sequence, which covers the pixel area more evenly
+    /// over consecutive frames.
+    Halton,
+}
+
+/// The placement of the samples within a pixel over consecutive frames.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AaPattern {
+    /// A random position in one cell of a grid that covers the pixel.
+    Jittered,
+
+    /// A square grid rotated such that no two samples share a row or column,
+    /// which resolves near-horizontal and near-vertical edges well. The
+    /// number of samples must be a square.
+    RotatedGrid,
+
+    /// Fixed positions that are spread evenly without regular structure (a
+    /// Poisson disk, or blue noise).
+    Poisson,
+}
+
+/// The coordinate system of rendered normals.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NormalSpace {
+    World,
+
+    /// The space of the camera, where the camera looks along the negative
+    /// z-axis, and the y-axis points up.
+    View,
+}
+
+/// The order in which the patches of a frame are rendered.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TileOrder {
+    /// Row by row, starting at the bottom.
+    RowMajor,
+
+    /// Along the Z-order curve, which visits the quadrants of every square
+    /// recursively, so patches that are close in time are close in space.
+    Morton,
+
+    /// Along the Hilbert curve. Like the Z-order curve, but consecutive
+    /// patches are always adjacent (if the grid is a square with a power of
+    /// two size; otherwise the parts of the curve outside the grid are
+    /// skipped).
+    Hilbert,
+}
+
+/// The handling of bounce rays that leave the scene without hitting anything.
+///
+/// Bounce rays start inside the bounds of the scene, so whether they escape
+/// is only known after traversing the BVH. The traversal already skips the
+/// scene for rays that miss the root bounding boxes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EscapeMode {
+    /// Bounce rays are traced like camera rays, and pick up the sky.
+    Trace,
+
+    /// Escaped bounce rays are light leaks: they contribute black, and they
+    /// are counted, see `Renderer::take_light_leaks()`. In a closed interior
+    /// no bounce ray should ever escape, so this finds cracks in the geometry.
+    Leak,
+}
+
+/// An operator that maps radiance to the displayable range [0, 1].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ToneMap {
+    /// Values above 1 are clipped.
+    Clamp,
+
+    /// Every channel x is mapped to x / (1 + x), which compresses highlights
+    /// but never clips.
+    Reinhard,
+
+    /// The fit of the ACES filmic curve by Krzysztof Narkowicz. It has more
+    /// contrast than Reinhard, and a smoother rolloff into white.
+    Aces,
+}
+
+impl ToneMap {
+    /// Maps linear radiance to the range [0, 1].
+    pub fn apply(self, rgb: MVector3) -> MVector3 {
+        match self {
+            ToneMap::Clamp => rgb.clamp_one(),
+            ToneMap::Reinhard => {
+                let reinhard = |c: Mf32| c / (Mf32::one() + c);
+                MVector3::new(reinhard(rgb.x), reinhard(rgb.y), reinhard(rgb.z))
+            }
+            ToneMap::Aces => MVector3::new(aces_filmic(rgb.x), aces_filmic(rgb.y), aces_filmic(rgb.z)),
+        }
+    }
+}
+
+/// An infinite reference grid on the plane y = 0, for orientation.
+///
+/// The grid is not part of the scene, it is not lit and it casts no shadows.
+/// It covers everything that the camera sees below the plane, and geometry in
+/// front of the plane covers the grid. There are lines at integer x and z
+/// coordinates, which fade into the floor with distance to avoid aliasing.
+#[derive(Copy, Clone, Debug)]
+pub struct Grid {
+    pub line_color: SVector3,
+    pub floor_color: SVector3,
+
+    /// The width of the lines in world units.
+    pub line_width: f32,
+
+    /// Up to this distance from the camera, lines are drawn at full strength.
+    pub fade_start: f32,
+
+    /// Beyond this distance from the camera, there are no more lines.
+    pub fade_end: f32,
+}
+
+impl Grid {
+    /// Intersects the rays with the grid plane. Returns the color of the grid,
+    /// and a mask that has the sign bit set for the active rays that hit the
+    /// plane closer than `max_distance`.
+    pub fn shade(&self, ray: &MRay, max_distance: Mf32) -> (MVector3, Mask) {
+        // Rays parallel to the plane get an infinite or NaN distance, the
+        // comparison is true for those, so they miss.
+        let t = -ray.origin.y / ray.direction.y;
+        let miss = (t | t.geq(max_distance)) | ray.active;
+        let p = ray.direction.mul_add(t, ray.origin);
+
+        // Distance to the nearest line along both axes. Far away the rounding
+        // overflows, but there are no lines there.
+        let round = |x: Mf32| x.into_mi32().into_mf32();
+        let dx = (p.x - round(p.x)).abs();
+        let dz = (p.z - round(p.z)).abs();
+        let on_line = dx.min(dz) - Mf32::broadcast(self.line_width * 0.5);
+
+        let fade = (t - Mf32::broadcast(self.fade_start)) * Mf32::broadcast(1.0 / (self.fade_end - self.fade_start));
+        let strength = (Mf32::one() - fade).max(Mf32::zero()).min(Mf32::one());
+        let strength = Mf32::zero().pick(strength, on_line);
+
+        let floor = MVector3::broadcast(self.floor_color);
+        let line = MVector3::broadcast(self.line_color);
+        let color = (line - floor).mul_add(strength, floor);
+
+        (color, miss.neg_xor())
+    }
+}
+
+/// An arbitrary output variable: the part of the light that reached the
+/// camera along a particular class of paths.
+///
+/// A path is classified by the surface that the camera ray hits first. If it
+/// is diffuse, the light is diffuse, otherwise it is specular. Light that
+/// reaches a light source right after that surface is direct, light that
+/// bounces more is indirect. Light sources seen by the camera directly count
+/// as direct diffuse, and caustics as indirect diffuse, so the output
+/// variables add up to the full image.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Aov {
+    DirectDiffuse,
+    IndirectDiffuse,
+    DirectSpecular,
+    IndirectSpecular,
+}
+
+/// All output variables, in the order of their index.
+pub const AOVS: [Aov; 4] = [Aov::DirectDiffuse, Aov::IndirectDiffuse, Aov::DirectSpecular, Aov::IndirectSpecular];
+
+/// A surface that a path hit, recorded by `Renderer::render_single_ray()`.
+#[derive(Clone, Debug)]
+pub struct BounceDebugInfo {
+    pub position: SVector3,
+    pub normal: SVector3,
+    pub distance: f32,
+
+    /// The packed material, see the `material` module for the encoding.
+    pub material: u32,
+
+    /// The color of the material.
+    pub albedo: SVector3,
+
+    /// The fraction of light that the path carries from this surface to the
+    /// camera, before the material of this surface is applied.
+    pub throughput: SVector3,
+}
+
+/// The path of a single ray through the scene.
+#[derive(Clone, Debug)]
+pub struct RayDebugInfo {
+    /// The surfaces that the path hit, in order. The last one is a light
+    /// source or the sky, unless the bounce limit cut the path off.
+    pub bounces: Vec<BounceDebugInfo>,
+
+    /// The radiance that the path carries to the camera.
+    pub color: SVector3,
 }
 
 /// The buffer that an image is rendered into.
@@ -31,11 +356,106 @@ pub struct RenderBuffer {
     buffer: UnsafeCell<Vec<Mi32>>,
 }
 
+/// A floating-point buffer that samples are accumulated into.
+///
+/// The memory layout is as a bitmap of 16x4 blocks, in the order described in
+/// `Renderer::get_pixel_coords_16x4()`. The buffer contains the sum of all
+/// samples, not the mean.
+pub struct HdrBuffer {
+    width: u32,
+    height: u32,
+    buffer: UnsafeCell<Vec<[MVector3; 8]>>,
+
+    /// The sum of the coverage of all samples, in the same layout as the
+    /// color buffer.
+    coverage: UnsafeCell<Vec<[Mf32; 8]>>,
+
+    /// The running mean and variance of the luminance of every pixel, for
+    /// adaptive sampling.
+    stats: UnsafeCell<Vec<[MWelford; 8]>>,
+
+    /// The sum of every output variable, indexed by block and then by
+    /// `Aov`. Empty if the buffer was not created with `with_aovs()`.
+    aovs: UnsafeCell<Vec<[[MVector3; 8]; 4]>>,
+
+    /// The sum of the albedo guide, in the same layout as the color buffer.
+    /// Empty if the buffer was not created with `with_albedo()`.
+    albedo: UnsafeCell<Vec<[MVector3; 8]>>,
+
+    /// The pixels that show a translucent material. The camera and the scene
+    /// do not change while samples are accumulated, so this is computed only
+    /// once, when the buffer is first resolved.
+    subsurface_mask: Mutex<Option<SubsurfaceMask>>,
+
+    /// The number of samples accumulated per pixel.
+    num_samples: u32,
+}
+
+/// The pixels of a frame that show a translucent material, see
+/// `Renderer::subsurface_mask()`.
+struct SubsurfaceMask {
+    /// Per pixel, in the same order as `render_normals_f32()`, the index of
+    /// the diffusion profile of the material in the scene, or `None` if the
+    /// material is not translucent.
+    profiles: Vec<Option<usize>>,
+
+    /// Per pixel, the size of the pixel on the surface in world units.
+    footprints: Vec<f32>,
+}
+
+/// The running mean and variance of 8 values, updated with Welford's
+/// algorithm.
+#[derive(Copy, Clone)]
+struct MWelford {
+    count: Mf32,
+    mean: Mf32,
+
+    /// The sum of squared differences from the mean.
+    m2: Mf32,
+}
+
+/// The number of samples that a pixel receives at least with adaptive
+/// sampling, before its variance estimate is trusted. Rare paths, such as
+/// caustics, do not show up in the first few samples.
+const MIN_ADAPTIVE_SAMPLES: u32 = 16;
+
+/// The lower bound on the variance of the luminance of a pixel that adaptive
+/// sampling assumes. If all samples so far were equal, the estimated variance
+/// is zero, but that does not mean the pixel has converged. With the floor,
+/// dark pixels need more samples, and black pixels never stop early.
+const MIN_ADAPTIVE_VARIANCE: f32 = 1e-6;
+
 struct MPixelData {
     color: MVector3,
+
+    /// 1.0 where the primary ray hit geometry, 0.0 where it hit the sky.
+    coverage: Mf32,
+
     tex_index: Mi32,
     tex_coords: (Mf32, Mf32),
     fresnel: Mf32,
+
+    /// The color split up by path classification, indexed by `Aov`.
+    aovs: [MVector3; 4],
+
+    /// The albedo guide for denoising, see `Renderer::set_albedo_bounces()`.
+    albedo: MVector3,
+}
+
+/// The samples of the 4x2 pixel groups that cover a patch and the margin
+/// around it, to splat them into the pixels of the patch with the
+/// reconstruction filter, see `Renderer::set_filter_radius()`.
+struct SplatSamples {
+    /// The bottom-left pixel of the first group.
+    x: u32,
+    y: u32,
+
+    /// The number of groups per row.
+    columns: u32,
+
+    /// Per group, row by row, the positions of the samples in pixels and the
+    /// rendered values, or `None` if no pixel that needs it can be reached.
+    samples: Vec<Option<(Mf32, Mf32, MPixelData)>>,
 }
 
 impl RenderBuffer {
@@ -65,6 +485,101 @@ impl RenderBuffer {
         }
     }
 
+    /// Compares two buffers of the same size, for finding visual regressions.
+    ///
+    /// Returns a false-color image of the difference, and the mean absolute
+    /// difference of the color channels, in the range [0, 1]. In the image,
+    /// identical pixels are black, and the difference is amplified eightfold:
+    /// pixels whose channels differ by 32 levels in total are bright red, and
+    /// larger differences go through yellow to white. The alpha channel is
+    /// ignored.
+    pub fn diff(&self, other: &RenderBuffer) -> (RenderBuffer, f32) {
+        let (mine, theirs) = (self.as_slice(), other.as_slice());
+        assert_eq!(mine.len(), theirs.len()); // Buffers must have the same size.
+
+        let mut vec = cache_line_aligned_vec(mine.len());
+        unsafe { vec.set_len(mine.len()); }
+        let mut total = 0u64;
+
+        for ((a, b), d) in mine.iter().zip(theirs.iter()).zip(vec.iter_mut()) {
+            let a: [u8; 32] = unsafe { mem::transmute(*a) };
+            let b: [u8; 32] = unsafe { mem::transmute(*b) };
+            let mut out = [0u8; 32];
+            for px in 0..8 {
+                let mut sum = 0u32;
+                for c in 0..3 {
+                    let (x, y) = (a[px * 4 + c] as i32, b[px * 4 + c] as i32);
+                    sum += (x - y).abs() as u32;
+                }
+                total += sum as u64;
+
+                // Heat map: black, red, yellow, white, as the amplified
+                // difference grows.
+                let heat = cmp::min(sum * 8, 255 * 3);
+                out[px * 4 + 0] = cmp::min(heat, 255) as u8;
+                out[px * 4 + 1] = cmp::min(heat.saturating_sub(255), 255) as u8;
+                out[px * 4 + 2] = heat.saturating_sub(510) as u8;
+                out[px * 4 + 3] = 255;
+            }
+            *d = unsafe { mem::transmute(out) };
+        }
+
+        let num_channels = (mine.len() * 8 * 3) as f64;
+        let mae = total as f64 / (num_channels * 255.0);
+        (RenderBuffer { buffer: UnsafeCell::new(vec) }, mae as f32)
+    }
+
+    /// Resolves the mean of an accumulation buffer into a new bitmap.
+    ///
+    /// The radiance is scaled by 2^exposure, mapped to [0, 1] with the tone
+    /// map, and then raised to the power 1 / gamma. The alpha channel contains
+    /// the coverage. Because the accumulation buffer is not modified, the
+    /// image can be resolved again with different settings without rendering
+    /// anything.
+    pub fn resolve_with(hdr_buffer: &HdrBuffer, tone_map: ToneMap, exposure: f32, gamma: f32) -> RenderBuffer {
+        let w = hdr_buffer.width as usize;
+        let render_buffer = RenderBuffer::new(hdr_buffer.width, hdr_buffer.height);
+        let samples = cmp::max(hdr_buffer.num_samples(), 1) as f32;
+        let factor = Mf32::broadcast(exposure.exp2() / samples);
+        let coverage_factor = Mf32::broadcast(1.0 / samples);
+        let inv_gamma = Mf32::broadcast(1.0 / gamma);
+
+        {
+            // This is safe because the buffer was just created, there are no
+            // other borrows. A pixel is 4 bytes, there are 8 in an mi32.
+            let bitmap = unsafe { render_buffer.get_mut_slice() };
+            let pixels = unsafe { slice::from_raw_parts_mut(bitmap.as_mut_ptr() as *mut i32, bitmap.len() * 8) };
+            let blocks = hdr_buffer.as_slice().iter().zip(hdr_buffer.as_coverage_slice());
+
+            for (block_index, (rgbs, coverages)) in blocks.enumerate() {
+                for k in 0..8 {
+                    let rgb = tone_map.apply(rgbs[k] * factor);
+
+                    // Keep the base away from zero, the logarithm in `pow`
+                    // is not defined there. Skip the approximation entirely
+                    // for a gamma of 1, so linear output is exact.
+                    let rgb = if gamma == 1.0 { rgb } else {
+                        let eps = Mf32::broadcast(1.0e-20);
+                        let gamma = |c: Mf32| c.max(eps).pow(inv_gamma);
+                        MVector3::new(gamma(rgb.x), gamma(rgb.y), gamma(rgb.z))
+                    };
+
+                    let r = rgb.x.clamp_scale_to_u8();
+                    let g = rgb.y.clamp_scale_to_u8().map(|x| x << 8);
+                    let b = rgb.z.clamp_scale_to_u8().map(|x| x << 16);
+                    let a = (coverages[k] * coverage_factor).clamp_scale_to_u8().map(|x| x << 24);
+                    let rgba = (r | g) | (b | a);
+
+                    for lane in 0..8 {
+                        pixels[pixel_index(w, block_index, k, lane)] = rgba.get_coord(lane);
+                    }
+                }
+            }
+        }
+
+        render_buffer
+    }
+
     /// Returns a mutable view into the buffer.
     ///
     /// This is unsafe because it allows creating multiple mutable borrows of
@@ -74,6 +589,13 @@ impl RenderBuffer {
         (*self.buffer.get()).as_mut_slice()
     }
 
+    /// Returns a view into the buffer.
+    pub fn as_slice(&self) -> &[Mi32] {
+        // This is safe because the returned borrow prevents mutable borrows
+        // through `&mut self`, and `get_mut_slice()` is unsafe.
+        unsafe { (*self.buffer.get()).as_slice() }
+    }
+
     /// Returns an RGBA bitmap suitable for display.
     #[cfg(not(windows))]
     pub fn into_bitmap(self) -> Vec<u8> {
@@ -117,382 +639,4447 @@ impl RenderBuffer {
 // The render buffer must be shared among threads, but UnsafeCell is not Sync.
 unsafe impl Sync for RenderBuffer {}
 
-impl Renderer {
-    pub fn new(scene: Scene, width: u32, height: u32) -> Renderer {
-        Renderer {
-            scene: scene,
+impl HdrBuffer {
+    /// Allocates a new buffer filled with zeros.
+    ///
+    /// The width must be a multiple of 16 and the height a multiple of 4.
+    pub fn new(width: u32, height: u32) -> HdrBuffer {
+        assert_eq!(width & 15, 0); // Width must be a multiple of 16.
+        assert_eq!(height & 3, 0); // Height must be a multiple of 4.
+
+        let num_elems = (width / 16 * height / 4) as usize;
+        let buffer = (0..num_elems).map(|_| generate_slice8(|_| MVector3::zero())).collect();
+        let coverage = (0..num_elems).map(|_| generate_slice8(|_| Mf32::zero())).collect();
+        let stats = (0..num_elems).map(|_| generate_slice8(|_| MWelford::new())).collect();
+
+        HdrBuffer {
             width: width,
             height: height,
-            enable_debug_view: false,
-            time: 0.0,
-            time_delta: 0.0,
+            buffer: UnsafeCell::new(buffer),
+            coverage: UnsafeCell::new(coverage),
+            stats: UnsafeCell::new(stats),
+            aovs: UnsafeCell::new(Vec::new()),
+            albedo: UnsafeCell::new(Vec::new()),
+            subsurface_mask: Mutex::new(None),
+            num_samples: 0,
         }
     }
 
-    /// Sets the current time and the amount that the time is expected to change
-    /// per frame.
-    pub fn set_time(&mut self, time: f32, delta: f32) {
-        self.time = time;
-        self.time_delta = delta;
+    /// Allocates a new buffer that also accumulates the output variables.
+    pub fn with_aovs(width: u32, height: u32) -> HdrBuffer {
+        let buffer = HdrBuffer::new(width, height);
+        let num_elems = buffer.as_slice().len();
+        let aovs = (0..num_elems).map(|_| [generate_slice8(|_| MVector3::zero()); 4]).collect();
+        HdrBuffer { aovs: UnsafeCell::new(aovs), ..buffer }
     }
 
-    /// For an interactive scene, updates the scene for the new frame.
-    /// TODO: This method does not really belong here.
-    pub fn update_scene(&mut self) {
-        let alpha = self.time * -0.02 + 0.1;
-        let alpha_delta = self.time_delta * -0.02;
-        let cam_position = SVector3::new(-3.8 * alpha.sin(), 1.6, 3.0 * alpha.cos());
-        let cam_pos_delta = SVector3::new(-3.8 * alpha.cos(), 0.0, -3.0 * alpha.sin()) * alpha_delta;
-        self.scene.camera.set_position(cam_position, cam_pos_delta);
-        self.scene.camera.set_rotation(alpha, alpha_delta);
+    /// Returns whether the buffer accumulates the output variables.
+    pub fn has_aovs(&self) -> bool {
+        unsafe { !(*self.aovs.get()).is_empty() }
     }
 
-    pub fn toggle_debug_view(&mut self) {
-        self.enable_debug_view = !self.enable_debug_view;
+    /// See `get_mut_slice()` for why this is unsafe.
+    unsafe fn get_mut_aovs_slice(&self) -> &mut [[[MVector3; 8]; 4]] {
+        (*self.aovs.get()).as_mut_slice()
     }
 
-    /// Returns the screen coordinates of the block of 16x4 pixels where (x, y)
-    /// is the bottom-left coordinate. The order is as follows:
-    ///
-    ///     0c 0d 0e 0f  1c 1d 1e 1f  2c 2d 2e 2f  3c 3d 3e 3f
-    ///     08 09 0a 0b  18 19 1a 1b  28 29 2a 2b  38 39 3a 3b
-    ///     04 05 06 07  14 15 16 17  24 25 26 27  34 35 36 37
-    ///     00 01 02 03  10 11 12 13  20 21 22 23  30 31 32 33
-    ///
-    /// Or, in terms of the mf32s:
-    ///
-    ///     1 1 1 1  3 3 3 3  5 5 5 5  7 7 7 7
-    ///     1 1 1 1  3 3 3 3  5 5 5 5  7 7 7 7
-    ///     0 0 0 0  2 2 2 2  4 4 4 4  6 6 6 6
-    ///     0 0 0 0  2 2 2 2  4 4 4 4  6 6 6 6
-    ///
-    /// Where inside every mf32 the pixels are ordered from left to right,
-    /// bottom to top.
-    fn get_pixel_coords_16x4(&self, x: u32, y: u32, rng: &mut Rng) -> ([Mf32; 8], [Mf32; 8]) {
-        let scale = Mf32::broadcast(2.0 / self.width as f32);
-        let scale_mul = Mf32(2.0, 4.0, 8.0, 12.0, 0.0, 0.0, 0.0, 0.0) * scale;
-
-        let off_x = Mf32(0.0, 1.0, 2.0, 3.0, 0.0, 1.0, 2.0, 3.0);
-        let off_y = Mf32(0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0);
-
-        let base_x = scale * (off_x + Mf32::broadcast(x as f32 - self.width as f32 * 0.5));
-        let base_y = scale * (off_y + Mf32::broadcast(y as f32 - self.height as f32 * 0.5));
-
-        let xs = [
-            base_x,
-            base_x,
-            base_x + Mf32::broadcast(scale_mul.1), // 4.0 * scale
-            base_x + Mf32::broadcast(scale_mul.1), // 4.0 * scale
-            base_x + Mf32::broadcast(scale_mul.2), // 8.0 * scale
-            base_x + Mf32::broadcast(scale_mul.2), // 8.0 * scale
-            base_x + Mf32::broadcast(scale_mul.3), // 12.0 * scale
-            base_x + Mf32::broadcast(scale_mul.3)  // 12.0 * scale
-        ];
-
-        let ys = [
-            base_y, base_y + Mf32::broadcast(scale_mul.0), // 2.0 * scale
-            base_y, base_y + Mf32::broadcast(scale_mul.0), // 2.0 * scale
-            base_y, base_y + Mf32::broadcast(scale_mul.0), // 2.0 * scale
-            base_y, base_y + Mf32::broadcast(scale_mul.0)  // 2.0 * scale
-        ];
+    /// Allocates a new buffer that also accumulates the albedo guide for
+    /// denoising, see `Renderer::set_albedo_bounces()`.
+    pub fn with_albedo(width: u32, height: u32) -> HdrBuffer {
+        let buffer = HdrBuffer::new(width, height);
+        let albedo = (0..buffer.as_slice().len()).map(|_| generate_slice8(|_| MVector3::zero())).collect();
+        HdrBuffer { albedo: UnsafeCell::new(albedo), ..buffer }
+    }
 
-        // Add a random offset of at most one pixel, to sample with anti-alias.
-        // TODO: If I ever do multiple samples per pixel in one frame, I could
-        // do stratified sampling here.
-        let xs_aa = generate_slice8(|i| rng.sample_unit().mul_add(scale, xs[i]));
-        let ys_aa = generate_slice8(|i| rng.sample_unit().mul_add(scale, ys[i]));
+    /// Returns whether the buffer accumulates the albedo guide.
+    pub fn has_albedo(&self) -> bool {
+        unsafe { !(*self.albedo.get()).is_empty() }
+    }
 
-        (xs_aa, ys_aa)
+    /// See `get_mut_slice()` for why this is unsafe.
+    unsafe fn get_mut_albedo_slice(&self) -> &mut [[MVector3; 8]] {
+        (*self.albedo.get()).as_mut_slice()
     }
 
-    /// Shuffles bytes around to store 16x4 rendered pixels in the correct
-    /// location in a bitmap.
-    fn store_mi32_16x4(&self, target: &mut [Mi32], x: u32, y: u32, data: &[Mi32; 8]) {
-        // Helper functions to shuffle around the pixels from the order as
-        // described in `get_pixel_coords_16x4` into four rows of 16 pixels.
-        let mk_line0 = |left: Mi32, right: Mi32|
-            Mi32(left.0, left.1, left.2, left.3, right.0, right.1, right.2, right.3);
-        let mk_line1 = |left: Mi32, right: Mi32|
-            Mi32(left.4, left.5, left.6, left.7, right.4, right.5, right.6, right.7);
+    /// Returns a mutable view into the buffer.
+    ///
+    /// This is unsafe because it allows creating multiple mutable borrows of
+    /// the buffer, which could result in races. Threads should ensure that
+    /// they write to disjoint parts of the buffer.
+    pub unsafe fn get_mut_slice(&self) -> &mut [[MVector3; 8]] {
+        (*self.buffer.get()).as_mut_slice()
+    }
 
-        // Store the pixels in the bitmap. If the bitmap is aligned to the cache
-        // line size, this stores exactly four cache lines, so there is no need
-        // to fetch those lines because all bytes are overwritten. This saves a
-        // trip to memory, which makes this store fast.
-        let idx_line0 = ((y * self.width + 0 * self.width + x) / 8) as usize;
-        let idx_line1 = ((y * self.width + 1 * self.width + x) / 8) as usize;
-        let idx_line2 = ((y * self.width + 2 * self.width + x) / 8) as usize;
-        let idx_line3 = ((y * self.width + 3 * self.width + x) / 8) as usize;
+    /// Returns the accumulated (not averaged) values.
+    pub fn as_slice(&self) -> &[[MVector3; 8]] {
+        // This is safe because the returned borrow prevents mutable borrows
+        // through `&mut self`, and `get_mut_slice()` is unsafe.
+        unsafe { (*self.buffer.get()).as_slice() }
+    }
 
-        target[idx_line0 + 0] = mk_line0(data[0], data[2]);
-        target[idx_line0 + 1] = mk_line0(data[4], data[6]);
-        target[idx_line1 + 0] = mk_line1(data[0], data[2]);
-        target[idx_line1 + 1] = mk_line1(data[4], data[6]);
-        target[idx_line2 + 0] = mk_line0(data[1], data[3]);
-        target[idx_line2 + 1] = mk_line0(data[5], data[7]);
-        target[idx_line3 + 0] = mk_line1(data[1], data[3]);
-        target[idx_line3 + 1] = mk_line1(data[5], data[7]);
+    /// Returns a mutable view into the coverage buffer.
+    ///
+    /// See `get_mut_slice()` for why this is unsafe.
+    pub unsafe fn get_mut_coverage_slice(&self) -> &mut [[Mf32; 8]] {
+        (*self.coverage.get()).as_mut_slice()
     }
 
-    /// Converts floating-point color values to 32-bit RGBA and stores the
-    /// values in the bitmap.
-    fn store_pixels_color_16x4(&self,
-                               bitmap: &mut [Mi32],
-                               x: u32,
-                               y: u32,
-                               data: &[MPixelData; 8]) {
-        // Convert f32 colors to i32 colors in the range 0-255.
-        let range = Mf32::broadcast(255.0);
-        let rgbas = generate_slice8(|i| {
-            // Multiply color by 2.0 to brighten up the scene a bit.
-            let rgb_255 = (data[i].color * Mf32::broadcast(2.0)).clamp_one() * range;
-            let r = rgb_255.x.into_mi32();
-            let g = rgb_255.y.into_mi32().map(|x| x << 8);
-            let b = rgb_255.z.into_mi32().map(|x| x << 16);
-            (r | g) | b
-        });
+    /// Returns the accumulated (not averaged) coverage.
+    pub fn as_coverage_slice(&self) -> &[[Mf32; 8]] {
+        // This is safe for the same reason as in `as_slice()`.
+        unsafe { (*self.coverage.get()).as_slice() }
+    }
 
-        self.store_mi32_16x4(bitmap, x, y, &rgbas);
+    /// See `get_mut_slice()` for why this is unsafe.
+    unsafe fn get_mut_stats_slice(&self) -> &mut [[MWelford; 8]] {
+        (*self.stats.get()).as_mut_slice()
     }
 
-    /// Converts floating-point texture coordinates to integers and stores the
-    /// values in the bitmap.
-    fn store_pixels_gbuffer_16x4(&self,
-                                 gbuffer: &mut [Mi32],
-                                 x: u32,
-                                 y: u32,
-                                 data: &[MPixelData; 8]) {
-        // Generate the pixels for texture coordinates and the Fresnel factor.
-        let range = Mf32::broadcast(255.0);
-        let uvs = generate_slice8(|i| {
-            let tex_index = data[i].tex_index;
-            let tex_x = data[i].tex_coords.0 * range;
-            let tex_y = data[i].tex_coords.1 * range;
-            let fresnel = data[i].fresnel * range;
+    /// Returns the number of samples that were actually rendered for every
+    /// pixel, in the same order as `into_hdr_f32()`. With adaptive sampling,
+    /// this is less than `num_samples()` for pixels that converged early.
+    pub fn pixel_sample_counts(&self) -> Vec<u32> {
+        let w = self.width as usize;
+        let stats = unsafe { (*self.stats.get()).as_slice() };
+        let mut counts = vec![0; w * (self.height as usize)];
+        for (block_index, block) in stats.iter().enumerate() {
+            for (k, welford) in block.iter().enumerate() {
+                for lane in 0..8 {
+                    let i = pixel_index(w, block_index, k, lane);
+                    counts[i] = welford.count.get_coord(lane) as u32;
+                }
+            }
+        }
+        counts
+    }
 
-            // Do not clamp the texture coordinates, make them wrap instead.
-            let wrap = Mi32::broadcast(0xff);
-            let r = tex_x.into_mi32() & wrap;
-            let g = (tex_y.into_mi32() & wrap).map(|x| x << 8);
-            let b = fresnel.into_mi32().map(|x| x << 16);
+    /// Returns the number of samples accumulated per pixel.
+    pub fn num_samples(&self) -> u32 {
+        self.num_samples
+    }
 
-            // Store the texture index in the alpha channel.
-            let a = tex_index.map(|x| x << 24);
+    /// Records that one more sample per pixel has been accumulated.
+    pub fn inc_num_samples(&mut self) {
+        self.num_samples += 1;
+    }
 
-            (r | g) | (b | a)
-        });
+    /// Returns the mean radiance as linear RGB, three floats per pixel.
+    ///
+    /// The pixels are stored row by row, in the same order as the bitmap
+    /// returned by `RenderBuffer::into_bitmap()`: the bottom row comes first.
+    /// No tone mapping or gamma correction is applied.
+    pub fn into_hdr_f32(self) -> Vec<f32> {
+        self.resolve_f32()
+    }
 
-        self.store_mi32_16x4(gbuffer, x, y, &uvs);
+    /// Writes the mean radiance to an OpenEXR file with float channels.
+    pub fn write_exr<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        exr::write_exr(path, self.width, self.height, &self.resolve_f32())
+    }
+
+    /// Returns the mean of an output variable as linear RGB, in the same
+    /// format as `into_hdr_f32()`.
+    ///
+    /// Panics if the buffer was not created with `with_aovs()`.
+    pub fn resolve_aov_f32(&self, aov: Aov) -> Vec<f32> {
+        assert!(self.has_aovs(), "buffer does not accumulate output variables");
+        let aovs = unsafe { (*self.aovs.get()).as_slice() };
+        self.resolve_blocks_f32(aovs.iter().map(|blocks| &blocks[aov as usize]))
+    }
+
+    /// Returns the mean albedo guide as linear RGB, in the same format as
+    /// `into_hdr_f32()`. Pixels of the sky are black.
+    ///
+    /// Panics if the buffer was not created with `with_albedo()`.
+    pub fn resolve_albedo_f32(&self) -> Vec<f32> {
+        assert!(self.has_albedo(), "buffer does not accumulate the albedo");
+        let albedo = unsafe { (*self.albedo.get()).as_slice() };
+        self.resolve_blocks_f32(albedo.iter())
+    }
+
+    /// See `into_hdr_f32()`.
+    fn resolve_f32(&self) -> Vec<f32> {
+        self.resolve_blocks_f32(self.as_slice().iter())
+    }
+
+    /// Averages and reorders blocks that have the layout of the buffer.
+    fn resolve_blocks_f32<'a, I>(&self, blocks: I) -> Vec<f32> where I: Iterator<Item = &'a [MVector3; 8]> {
+        let w = self.width as usize;
+        let factor = 1.0 / (cmp::max(self.num_samples, 1) as f32);
+        let mut rgbs = vec![0.0; w * (self.height as usize) * 3];
+
+        for (block_index, block) in blocks.enumerate() {
+            for (k, mv) in block.iter().enumerate() {
+                for lane in 0..8 {
+                    let i = pixel_index(w, block_index, k, lane) * 3;
+                    rgbs[i + 0] = mv.x.get_coord(lane) * factor;
+                    rgbs[i + 1] = mv.y.get_coord(lane) * factor;
+                    rgbs[i + 2] = mv.z.get_coord(lane) * factor;
+                }
+            }
+        }
+
+        rgbs
+    }
+}
+
+// The buffer must be shared among threads, but UnsafeCell is not Sync.
+unsafe impl Sync for HdrBuffer {}
+
+/// Returns the number of threads to render with: the number of logical cores,
+/// or 1 if that cannot be determined.
+pub fn available_threads() -> u32 {
+    cmp::max(num_cpus::get() as u32, 1)
+}
+
+/// Returns the row-major index of a pixel in a bitmap of width w, given the
+/// index of its 16x4 block, the index into the block, and the lane.
+fn pixel_index(w: usize, block_index: usize, k: usize, lane: usize) -> usize {
+    // See `get_pixel_coords_16x4()` for the order of pixels.
+    let bx = (block_index % (w / 16)) * 16;
+    let by = (block_index / (w / 16)) * 4;
+    let x = bx + (k / 2) * 4 + lane % 4;
+    let y = by + (k % 2) * 2 + lane / 4;
+    y * w + x
+}
+
+/// Returns the integral from minus infinity to t of the tent filter with the
+/// given radius, normalized to an area of 1.
+fn tent_cdf(t: f32, radius: f32) -> f32 {
+    let s = (t / radius).max(-1.0).min(1.0);
+    if s < 0.0 {
+        0.5 * (1.0 + s) * (1.0 + s)
+    } else {
+        1.0 - 0.5 * (1.0 - s) * (1.0 - s)
+    }
+}
+
+/// Compresses the values above `start` smoothly into the range [start, 1).
+///
+/// With d the distance above the knee relative to the remaining headroom,
+/// the curve is d / (1 + d^k)^(1/k) for strength k. It has slope 1 at the
+/// knee, so there is no visible kink, and it approaches 1 asymptotically.
+fn highlight_knee(x: Mf32, start: f32, strength: f32) -> Mf32 {
+    let headroom = 1.0 - start;
+    let s = Mf32::broadcast(start);
+    let k = Mf32::broadcast(strength);
+
+    // Keep d away from zero, the logarithm in `pow` is not defined there.
+    let d = ((x - s) * Mf32::broadcast(1.0 / headroom)).max(Mf32::broadcast(1.0e-20));
+    let denom = (Mf32::one() + d.pow(k)).pow(Mf32::broadcast(1.0 / strength));
+    let compressed = (d / denom).mul_add(Mf32::broadcast(headroom), s);
+
+    // Below the knee the sign of x - start is negative, keep x there.
+    compressed.pick(x, x - s)
+}
+
+/// Approximates the ACES filmic tone curve with the rational fit
+/// x (2.51 x + 0.03) / (x (2.43 x + 0.59) + 0.14), clamped to [0, 1].
+fn aces_filmic(x: Mf32) -> Mf32 {
+    let num = x * x.mul_add(Mf32::broadcast(2.51), Mf32::broadcast(0.03));
+    let den = x.mul_add(x.mul_add(Mf32::broadcast(2.43), Mf32::broadcast(0.59)), Mf32::broadcast(0.14));
+    (num / den).max(Mf32::zero()).min(Mf32::one())
+}
+
+/// Clamps every component of the throughput multiplier of a bounce to at most
+/// `max_weight`.
+fn clamp_sample_weight(weight: MVector3, max_weight: f32) -> MVector3 {
+    let max = Mf32::broadcast(max_weight);
+    MVector3::new(weight.x.min(max), weight.y.min(max), weight.z.min(max))
+}
+
+/// Scales the colors of the lanes that do not have the sign bit of `direct` set
+/// such that no component exceeds `max`.
+fn clamp_indirect(color: MVector3, direct: Mask, max: f32) -> MVector3 {
+    let largest = color.x.max(color.y).max(color.z);
+    let scale = (Mf32::broadcast(max) / largest).min(Mf32::one());
+    (color * scale).pick(color, direct)
+}
+
+fn luminance(color: MVector3) -> Mf32 {
+    color.x.mul_add(Mf32::broadcast(0.2126),
+                    color.y.mul_add(Mf32::broadcast(0.7152), color.z * Mf32::broadcast(0.0722)))
+}
+
+impl MWelford {
+    fn new() -> MWelford {
+        MWelford {
+            count: Mf32::zero(),
+            mean: Mf32::zero(),
+            m2: Mf32::zero(),
+        }
+    }
+
+    /// Adds a sample for the lanes where the sign bit of `skip` is 0.
+    fn insert(&mut self, x: Mf32, skip: Mask) {
+        let count = self.count + Mf32::one();
+        let delta = x - self.mean;
+        let mean = delta.mul_add(count.recip_precise(), self.mean);
+        let m2 = delta.mul_add(x - mean, self.m2);
+        self.count = count.pick(self.count, skip);
+        self.mean = mean.pick(self.mean, skip);
+        self.m2 = m2.pick(self.m2, skip);
+    }
+
+    /// Sets the sign bit to 1 for the lanes where the 95% confidence interval
+    /// of the mean lies within the fraction `rel` of the mean.
+    fn is_converged(&self, rel: f32) -> Mask {
+        // The variance of the mean is the sample variance divided by n, where
+        // the sample variance is m2 / (n - 1), but at least the floor. The
+        // confidence interval is 1.96 standard errors wide on either side.
+        // Compare squares to avoid a square root. A pixel with zero mean has
+        // a zero bound, so it never converges.
+        let n = self.count;
+        let variance = (self.m2 * (n - Mf32::one()).max(Mf32::one()).recip_precise())
+            .max(Mf32::broadcast(MIN_ADAPTIVE_VARIANCE));
+        let var_mean = variance * n.max(Mf32::one()).recip_precise();
+        let bound = self.mean * Mf32::broadcast(rel);
+        let margin = var_mean.mul_sub(Mf32::broadcast(1.96 * 1.96), bound * bound);
+
+        // The sign bit is 1 if there are at least the minimum number of
+        // samples.
+        let enough = Mf32::broadcast(MIN_ADAPTIVE_SAMPLES as f32 - 0.5) - n;
+
+        margin & enough
+    }
+}
+
+impl Renderer {
+    pub fn new(scene: Scene, width: u32, height: u32) -> Renderer {
+        Renderer {
+            scene: scene,
+            width: width,
+            height: height,
+            enable_debug_view: false,
+            time: 0.0,
+            time_delta: 0.0,
+            path_regularization: 0.0,
+            max_accumulation: u32::MAX,
+            max_bounces: 3,
+            albedo_bounces: 0,
+            bounce_cap_hits: AtomicUsize::new(0),
+            traversal_stats: Mutex::new(TraversalStats::default()),
+            photon_map: None,
+            previous_camera: None,
+            tile_order: TileOrder::RowMajor,
+            sampler_kind: SamplerKind::Random,
+            aa_cells: vec![(0.0, 0.0)],
+            aa_cell_size: (1.0, 1.0),
+            filter_radius: 0.0,
+            adaptive_threshold: None,
+            dither: None,
+            flip_y: false,
+            highlight_knee: None,
+            premultiply_alpha: false,
+            channel_order: ChannelOrder::Rgba,
+            grid: None,
+            max_sample_weight: ::std::f32::INFINITY,
+            indirect_clamp: ::std::f32::INFINITY,
+            animation: None,
+            graph: None,
+            graph_meshes: Vec::new(),
+            user_control: false,
+            escape_mode: EscapeMode::Trace,
+            light_leaks: AtomicUsize::new(0),
+        }
+    }
+
+    /// Enables or disables dithering of the 8-bit output.
+    ///
+    /// The dither is added after the conversion to display values, right
+    /// before quantization, with an amplitude of one 8-bit step. The pattern
+    /// is blue noise that shifts every frame, so it is hard to see, and it
+    /// averages out when frames are blended over time.
+    pub fn set_dither(&mut self, enabled: bool) {
+        self.dither = if enabled { Some(blue_noise_tile()) } else { None };
+    }
+
+    /// Sets the vertical orientation of the rendered images.
+    ///
+    /// By default the first row of the buffers is the bottom row of the
+    /// image, which is what OpenGL expects for textures. Image files usually
+    /// store the top row first; when `flip_y` is true, the renderer produces
+    /// rows in that order instead. Note that `HdrBuffer::write_exr()` expects
+    /// the bottom row first.
+    pub fn set_flip_y(&mut self, flip_y: bool) {
+        self.flip_y = flip_y;
+    }
+
+    /// Negates the vertical screen coordinates if the rows are flipped.
+    fn orient_ys(&self, ys: [Mf32; 8]) -> [Mf32; 8] {
+        if self.flip_y { generate_slice8(|i| -ys[i]) } else { ys }
+    }
+
+    /// Enables a smooth rolloff of highlights in the 8-bit output, or
+    /// disables it if `knee` is `None`.
+    ///
+    /// Without rolloff, values above 1 are clipped, which causes harsh edges
+    /// around bright areas. With a knee at (start, strength), values below
+    /// `start` pass through unchanged, and above it they are compressed
+    /// smoothly towards 1, per channel. A higher strength makes the shoulder
+    /// sharper; as it goes to infinity, the curve approaches clipping.
+    pub fn set_highlight_knee(&mut self, knee: Option<(f32, f32)>) {
+        if let Some((start, strength)) = knee {
+            assert!(0.0 <= start && start < 1.0, "knee must start in [0, 1)");
+            assert!(strength > 0.0, "knee strength must be positive");
+        }
+        self.highlight_knee = knee;
+    }
+
+    /// Sets whether the 8-bit output has premultiplied alpha.
+    ///
+    /// The alpha channel contains the coverage, which is fractional at the
+    /// edges of geometry. By default the color is straight, not scaled by the
+    /// alpha. Compositing tools often expect premultiplied color instead;
+    /// then the color is clipped to [0, 1] and multiplied by the alpha before
+    /// quantization.
+    pub fn set_premultiply_alpha(&mut self, premultiply: bool) {
+        self.premultiply_alpha = premultiply;
+    }
+
+    /// Enables the reference grid on the ground plane, or disables it if
+    /// `grid` is `None`.
+    pub fn set_grid(&mut self, grid: Option<Grid>) {
+        if let Some(ref grid) = grid {
+            assert!(grid.line_width > 0.0, "grid lines must have a positive width");
+            assert!(grid.fade_start < grid.fade_end, "grid lines must fade out after they start to fade");
+        }
+        self.grid = grid;
+    }
+
+    /// Sets the order of the channels in the 8-bit color output.
+    ///
+    /// The default is RGBA. Uploading BGRA data is faster on some GPUs, and
+    /// producing it here saves a swizzle before display. This does not affect
+    /// the gbuffer.
+    pub fn set_channel_order(&mut self, order: ChannelOrder) {
+        self.channel_order = order;
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Changes the size of the rendered images. Both dimensions must be
+    /// multiples of 16.
+    ///
+    /// Returns whether the size changed. If it did, the buffers of the old
+    /// size can no longer be used: the caller must allocate new render
+    /// buffers, and start accumulating in a new buffer from
+    /// `new_buffer_f32()`. The pixel coordinates of the previous frame no
+    /// longer match either, so motion vectors start over too.
+    pub fn resize(&mut self, width: u32, height: u32) -> bool {
+        assert_eq!(width & 15, 0);  // Width must be a multiple of 16.
+        assert_eq!(height & 15, 0); // Height must be a multiple of 16.
+
+        if (width, height) == (self.width, self.height) {
+            return false
+        }
+
+        self.width = width;
+        self.height = height;
+        self.previous_camera = None;
+        true
+    }
+
+    pub fn scene(&self) -> &Scene {
+        &self.scene
+    }
+
+    pub fn scene_mut(&mut self) -> &mut Scene {
+        &mut self.scene
+    }
+
+    /// Sets the current time and the amount that the time is expected to change
+    /// per frame.
+    pub fn set_time(&mut self, time: f32, delta: f32) {
+        self.time = time;
+        self.time_delta = delta;
+    }
+
+    /// Sets the amount of path regularization, in the range [0, 1].
+    ///
+    /// Paths that bounce off a diffuse surface and then off a glossy surface
+    /// (caustics) are hard to sample, and they converge slowly. Regularization
+    /// makes glossy surfaces rougher after a diffuse bounce. This introduces
+    /// bias, but it reduces the variance of such paths a lot. A factor of 0.0
+    /// disables regularization, a factor of 1.0 treats every surface after
+    /// the first diffuse bounce as diffuse.
+    pub fn set_path_regularization(&mut self, factor: f32) {
+        assert!(0.0 <= factor && factor <= 1.0, "regularization factor must be in [0, 1]");
+        self.path_regularization = factor;
+    }
+
+    /// Sets the maximum factor by which one bounce can scale a path.
+    ///
+    /// The throughput multiplier of a bounce is the BRDF divided by the
+    /// probability density of the sampled direction. When the density is
+    /// close to zero, for instance at grazing angles, a single sample can
+    /// become extremely bright and show up as a firefly. Clamping the weight
+    /// removes such outliers at the cost of some bias (the image becomes
+    /// slightly darker). Pass infinity to disable clamping, the default.
+    pub fn set_max_sample_weight(&mut self, max_weight: f32) {
+        assert!(max_weight > 0.0, "maximum sample weight must be positive");
+        self.max_sample_weight = max_weight;
+    }
+
+    /// Sets the maximum radiance of indirect light.
+    ///
+    /// Paths that reach a light source after more than one bounce are scaled
+    /// down such that no color component exceeds `max`, without changing the
+    /// hue. Direct light, seen by the camera or reflected once, is never
+    /// clamped, so highlights stay accurate. Indirect light is noisier than
+    /// direct light, and clamping it suppresses fireflies at the cost of some
+    /// bias. Pass infinity to disable clamping, the default.
+    pub fn set_indirect_clamp(&mut self, max: f32) {
+        assert!(max > 0.0, "indirect clamp must be positive");
+        self.indirect_clamp = max;
+    }
+
+    /// Sets the keyframe animation that `update_scene()` evaluates, replacing
+    /// the built-in camera orbit.
+    pub fn set_animation(&mut self, animation: Option<Animation>) {
+        self.animation = animation;
+    }
+
+    /// Sets the scene graph whose node tracks the animation evaluates. The
+    /// meshes of the scene must be `graph.bake_meshes(&meshes)`. Every frame,
+    /// `update_scene()` moves the triangles of the scene to the animated
+    /// transforms, and sets the motion of the meshes for motion vectors.
+    pub fn set_scene_graph(&mut self, graph: SceneGraph, meshes: Vec<Mesh>) {
+        self.graph = Some(graph);
+        self.graph_meshes = meshes;
+    }
+
+    /// Stops animating the scene, so the user can arrange it. The scene stays
+    /// as it is at the current time, without motion blur, and from then on
+    /// `update_scene()` leaves it alone.
+    pub fn take_control(&mut self) {
+        if !self.user_control {
+            self.time_delta = 0.0;
+            self.update_scene();
+            self.user_control = true;
+        }
+    }
+
+    /// For an interactive scene, updates the scene for the new frame.
+    /// TODO: This method does not really belong here.
+    pub fn update_scene(&mut self) {
+        // The camera of the previous frame is needed for motion vectors.
+        self.previous_camera = Some(self.scene.camera().clone());
+
+        if self.user_control {
+            return
+        }
+
+        if let Some(ref animation) = self.animation {
+            animation.apply(&mut self.scene, self.time, self.time_delta);
+            if let Some(ref mut graph) = self.graph {
+                graph.retain_transforms();
+                animation.apply_to_graph(graph, self.time);
+                self.scene.update_mesh_vertices(&graph.bake_meshes(&self.graph_meshes));
+                self.scene.set_mesh_motion(graph.motion_transforms());
+            }
+            return
+        }
+
+        let alpha = self.time * -0.02 + 0.1;
+        let alpha_delta = self.time_delta * -0.02;
+        let cam_position = SVector3::new(-3.8 * alpha.sin(), 1.6, 3.0 * alpha.cos());
+        let cam_pos_delta = SVector3::new(-3.8 * alpha.cos(), 0.0, -3.0 * alpha.sin()) * alpha_delta;
+        self.scene.camera_mut().set_position(cam_position, cam_pos_delta);
+        self.scene.camera_mut().set_rotation(alpha, alpha_delta);
+    }
+
+    /// Returns whether `update_scene()` moved or turned the camera, compared
+    /// to the camera of the previous frame.
+    pub fn camera_moved(&self) -> bool {
+        match self.previous_camera {
+            Some(ref previous) => !self.scene.camera().has_same_view(previous),
+            None => false,
+        }
+    }
+
+    /// Enables or disables sub-pixel jitter for temporal anti-aliasing. If
+    /// enabled, the offset is taken from the given frame number. See
+    /// `Camera::set_jitter_frame()`. This applies to all cameras, so the
+    /// jitter persists when switching cameras.
+    pub fn set_jitter_frame(&mut self, frame_number: Option<u32>) {
+        for camera in self.scene.cameras_mut() {
+            match frame_number {
+                Some(n) => camera.set_jitter_frame(n),
+                None => camera.disable_jitter(),
+            }
+        }
+    }
+
+    /// Selects the camera of the scene that subsequent frames are rendered
+    /// with.
+    pub fn set_active_camera(&mut self, index: usize) {
+        self.scene.set_active_camera(index);
+    }
+
+    /// Returns the sub-pixel jitter offset of the current frame, in pixels
+    /// relative to the pixel center.
+    pub fn jitter(&self) -> Option<(f32, f32)> {
+        self.scene.camera().jitter()
+    }
+
+    pub fn toggle_debug_view(&mut self) {
+        self.enable_debug_view = !self.enable_debug_view;
+    }
+
+    /// Returns the screen coordinates of the block of 16x4 pixels where (x, y)
+    /// is the bottom-left coordinate. The order is as follows:
+    ///
+    ///     0c 0d 0e 0f  1c 1d 1e 1f  2c 2d 2e 2f  3c 3d 3e 3f
+    ///     08 09 0a 0b  18 19 1a 1b  28 29 2a 2b  38 39 3a 3b
+    ///     04 05 06 07  14 15 16 17  24 25 26 27  34 35 36 37
+    ///     00 01 02 03  10 11 12 13  20 21 22 23  30 31 32 33
+    ///
+    /// Or, in terms of the mf32s:
+    ///
+    ///     1 1 1 1  3 3 3 3  5 5 5 5  7 7 7 7
+    ///     1 1 1 1  3 3 3 3  5 5 5 5  7 7 7 7
+    ///     0 0 0 0  2 2 2 2  4 4 4 4  6 6 6 6
+    ///     0 0 0 0  2 2 2 2  4 4 4 4  6 6 6 6
+    ///
+    /// Where inside every mf32 the pixels are ordered from left to right,
+    /// bottom to top. With `set_flip_y(true)` the vertical coordinate is
+    /// negated, so (x, y) is the top-left coordinate, and "bottom" above
+    /// reads "top".
+    ///
+    /// Every mf32 is a sample of its own, see `get_pixel_coords_4x2()`.
+    fn get_pixel_coords_16x4<S: Sampler>(&self, x: u32, y: u32, frame_number: u32, sampler: &mut S) -> ([Mf32; 8], [Mf32; 8]) {
+        let coords = generate_slice8(|k| self.get_pixel_coords_4x2(x, y, k, frame_number, sampler));
+        (generate_slice8(|k| coords[k].0), generate_slice8(|k| coords[k].1))
+    }
+
+    /// Starts a new sample for mf32 `k` of the block of 16x4 pixels at (x, y),
+    /// and returns the screen coordinates of its pixels, in the order of
+    /// `get_pixel_coords_16x4()`. The offset in the pixel takes the first two
+    /// dimensions of the sample, also with temporal jitter, so the dimensions
+    /// after it are always the same ones.
+    fn get_pixel_coords_4x2<S: Sampler>(&self, x: u32, y: u32, k: usize, frame_number: u32, sampler: &mut S) -> (Mf32, Mf32) {
+        let (px, py) = self.get_sample_positions_4x2(x, y, k, frame_number, sampler);
+        self.pixel_to_screen(px, py)
+    }
+
+    /// Converts positions in pixels, where pixel (x, y) covers [x, x + 1) by
+    /// [y, y + 1), to screen coordinates.
+    fn pixel_to_screen(&self, px: Mf32, py: Mf32) -> (Mf32, Mf32) {
+        let scale = Mf32::broadcast(2.0 / self.width as f32);
+        let xs = scale * (px - Mf32::broadcast(self.width as f32 * 0.5));
+        let ys = scale * (py - Mf32::broadcast(self.height as f32 * 0.5));
+        (xs, if self.flip_y { -ys } else { ys })
+    }
+
+    /// Starts a new sample as `get_pixel_coords_4x2()` does, but returns the
+    /// positions of the samples in pixels rather than in screen coordinates.
+    fn get_sample_positions_4x2<S: Sampler>(&self, x: u32, y: u32, k: usize, frame_number: u32, sampler: &mut S) -> (Mf32, Mf32) {
+        let (gx, gy) = block_pixel(x, y, k, 0);
+        sampler.start_pixel(gx, gy, frame_number);
+        let (u, v) = sampler.next_2d();
+
+        let off_x = Mf32(0.0, 1.0, 2.0, 3.0, 0.0, 1.0, 2.0, 3.0);
+        let off_y = Mf32(0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0);
+        let px = off_x + Mf32::broadcast(gx as f32);
+        let py = off_y + Mf32::broadcast(gy as f32);
+
+        // With temporal anti-aliasing jitter, every pixel in the frame is
+        // sampled at the same offset from the pixel center. Otherwise add an
+        // offset of at most one pixel from the anti-aliasing pattern.
+        if let Some((jx, jy)) = self.scene.camera().jitter() {
+            return (px + Mf32::broadcast(jx + 0.5), py + Mf32::broadcast(jy + 0.5))
+        }
+
+        let (cell_w, cell_h) = self.aa_cell_size;
+        let cells = generate_slice8(|lane| {
+            let (px, py) = block_pixel(x, y, k, lane);
+            let hash = px.wrapping_mul(73856093) ^ py.wrapping_mul(19349663);
+            let n = self.aa_cells.len() as u32;
+            self.aa_cells[(frame_number.wrapping_add(hash) % n) as usize]
+        });
+        let cell_x = Mf32::generate(|lane| cells[lane].0);
+        let cell_y = Mf32::generate(|lane| cells[lane].1);
+        let u = u.mul_add(Mf32::broadcast(cell_w), cell_x);
+        let v = v.mul_add(Mf32::broadcast(cell_h), cell_y);
+
+        (px + u, py + v)
+    }
+
+    /// Shuffles bytes around to store 16x4 rendered pixels in the correct
+    /// location in a bitmap.
+    fn store_mi32_16x4(&self, target: &mut [Mi32], x: u32, y: u32, data: &[Mi32; 8]) {
+        // Helper functions to shuffle around the pixels from the order as
+        // described in `get_pixel_coords_16x4` into four rows of 16 pixels.
+        let mk_line0 = |left: Mi32, right: Mi32|
+            Mi32(left.0, left.1, left.2, left.3, right.0, right.1, right.2, right.3);
+        let mk_line1 = |left: Mi32, right: Mi32|
+            Mi32(left.4, left.5, left.6, left.7, right.4, right.5, right.6, right.7);
+
+        // Store the pixels in the bitmap. If the bitmap is aligned to the cache
+        // line size, this stores exactly four cache lines, so there is no need
+        // to fetch those lines because all bytes are overwritten. This saves a
+        // trip to memory, which makes this store fast.
+        let idx_line0 = ((y * self.width + 0 * self.width + x) / 8) as usize;
+        let idx_line1 = ((y * self.width + 1 * self.width + x) / 8) as usize;
+        let idx_line2 = ((y * self.width + 2 * self.width + x) / 8) as usize;
+        let idx_line3 = ((y * self.width + 3 * self.width + x) / 8) as usize;
+
+        target[idx_line0 + 0] = mk_line0(data[0], data[2]);
+        target[idx_line0 + 1] = mk_line0(data[4], data[6]);
+        target[idx_line1 + 0] = mk_line1(data[0], data[2]);
+        target[idx_line1 + 1] = mk_line1(data[4], data[6]);
+        target[idx_line2 + 0] = mk_line0(data[1], data[3]);
+        target[idx_line2 + 1] = mk_line0(data[5], data[7]);
+        target[idx_line3 + 0] = mk_line1(data[1], data[3]);
+        target[idx_line3 + 1] = mk_line1(data[5], data[7]);
+    }
+
+    /// Converts floating-point color values to 32-bit RGBA and stores the
+    /// values in the bitmap. See `pack_pixels_color_16x4()` for the alpha
+    /// channel.
+    fn store_pixels_color_16x4(&self,
+                               bitmap: &mut [Mi32],
+                               x: u32,
+                               y: u32,
+                               frame_number: u32,
+                               data: &[MPixelData; 8]) {
+        let rgbas = self.pack_pixels_color_16x4(x, y, frame_number, data);
+        self.store_mi32_16x4(bitmap, x, y, &rgbas);
+    }
+
+    /// Converts floating-point color values to 32-bit RGBA, in the order of
+    /// `get_pixel_coords_16x4()`.
+    ///
+    /// The alpha channel contains the coverage: the fraction of the samples
+    /// of the pixel whose primary ray hit geometry. So the sky is transparent,
+    /// and pixels at the silhouette of geometry are partially transparent. The
+    /// color is premultiplied by it only with `set_premultiply_alpha()`.
+    fn pack_pixels_color_16x4(&self, x: u32, y: u32, frame_number: u32, data: &[MPixelData; 8]) -> [Mi32; 8] {
+        // Convert f32 colors to i32 colors in the range 0-255.
+        generate_slice8(|i| {
+            // Multiply color by 2.0 to brighten up the scene a bit.
+            let rgb = data[i].color * Mf32::broadcast(2.0);
+
+            let rgb = match self.highlight_knee {
+                Some((start, strength)) => {
+                    let knee = |c: Mf32| highlight_knee(c, start, strength);
+                    MVector3::new(knee(rgb.x), knee(rgb.y), knee(rgb.z))
+                }
+                None => rgb,
+            };
+
+            let rgb = if self.premultiply_alpha {
+                let alpha = data[i].coverage.max(Mf32::zero()).min(Mf32::one());
+                rgb.clamp_one() * alpha
+            } else {
+                rgb
+            };
+
+            // Dither in display space, where a step of the output is 1/255.
+            // The value must be clamped first, so the extremes stay exact.
+            let rgb = match self.dither {
+                Some(ref noise) => {
+                    let offset = Mf32::generate(|lane| {
+                        let (px, py) = block_pixel(x, y, i, lane);
+                        dither_offset(noise, px, py, frame_number)
+                    });
+                    let (zero, one) = (Mf32::zero(), Mf32::one());
+                    let clamp = |c: Mf32| one.min(c).max(zero) + offset;
+                    MVector3::new(clamp(rgb.x), clamp(rgb.y), clamp(rgb.z))
+                }
+                None => rgb,
+            };
+
+            rgb.pack_rgba8_with(data[i].coverage, self.channel_order)
+        })
+    }
+
+    /// Converts floating-point texture coordinates to integers and stores the
+    /// values in the bitmap.
+    fn store_pixels_gbuffer_16x4(&self,
+                                 gbuffer: &mut [Mi32],
+                                 x: u32,
+                                 y: u32,
+                                 data: &[MPixelData; 8]) {
+        let uvs = self.pack_pixels_gbuffer_16x4(data);
+        self.store_mi32_16x4(gbuffer, x, y, &uvs);
+    }
+
+    /// Converts floating-point texture coordinates and the Fresnel factor to
+    /// 32-bit gbuffer pixels, in the order of `get_pixel_coords_16x4()`.
+    fn pack_pixels_gbuffer_16x4(&self, data: &[MPixelData; 8]) -> [Mi32; 8] {
+        // Generate the pixels for texture coordinates and the Fresnel factor.
+        let range = Mf32::broadcast(255.0);
+        generate_slice8(|i| {
+            let tex_index = data[i].tex_index;
+            let tex_x = data[i].tex_coords.0 * range;
+            let tex_y = data[i].tex_coords.1 * range;
+            let fresnel = data[i].fresnel * range;
+
+            // Do not clamp the texture coordinates, make them wrap instead.
+            let wrap = Mi32::broadcast(0xff);
+            let r = tex_x.into_mi32() & wrap;
+            let g = (tex_y.into_mi32() & wrap).map(|x| x << 8);
+            let b = fresnel.into_mi32().map(|x| x << 16);
+
+            // Store the texture index in the alpha channel.
+            let a = tex_index.map(|x| x << 24);
+
+            (r | g) | (b | a)
+        })
+    }
+
+    /// Returns the screen coordinates of the centers of a block of 16x4 pixels
+    /// at (x, y) in a frame downscaled by `scale`, in the order of
+    /// `get_pixel_coords_16x4()`. There is no anti-aliasing jitter.
+    fn get_scaled_pixel_coords_16x4(&self, x: u32, y: u32, scale: u32) -> ([Mf32; 8], [Mf32; 8]) {
+        let s = scale as f32;
+        let unit = 2.0 / self.width as f32;
+        let (half_w, half_h) = (self.width as f32 * 0.5, self.height as f32 * 0.5);
+        let xs = generate_slice8(|k| Mf32::generate(|lane| {
+            let (px, _) = block_pixel(x, y, k, lane);
+            ((px as f32 + 0.5) * s - half_w) * unit
+        }));
+        let ys = generate_slice8(|k| Mf32::generate(|lane| {
+            let (_, py) = block_pixel(x, y, k, lane);
+            ((py as f32 + 0.5) * s - half_h) * unit
+        }));
+        (xs, self.orient_ys(ys))
     }
 
     /// Renders a block of 16x4 pixels, where (x, y) is the coordinate of the
     /// bottom-left pixel. Bitmap must be an array of 8 pixels at once, and it
     /// must be aligned to 64 bytes (a cache line). Also returns texture indices
     /// for every pixel.
-    fn render_block_16x4(&self, x: u32, y: u32, rng: &mut Rng) -> [MPixelData; 8] {
-        let (xs, ys) = self.get_pixel_coords_16x4(x, y, rng);
+    fn render_block_16x4<S: Sampler>(&self, x: u32, y: u32, frame_number: u32, sampler: &mut S) -> [MPixelData; 8] {
+        if self.enable_debug_view {
+            let (xs, ys) = self.get_pixel_coords_16x4(x, y, frame_number, sampler);
+            generate_slice8(|i| self.render_pixels_debug(xs[i], ys[i]))
+        } else {
+            generate_slice8(|i| {
+                let (xs, ys) = self.get_pixel_coords_4x2(x, y, i, frame_number, sampler);
+                self.render_pixels(xs, ys, sampler)
+            })
+        }
+    }
+
+    /// Returns the number of pixels next to a pixel whose samples can reach it
+    /// with the reconstruction filter.
+    fn splat_margin(&self) -> u32 {
+        // A sample reaches the center of a pixel if it is closer than the
+        // filter radius, and the samples of a pixel are at least 0.5 away
+        // from the center of its neighbor.
+        (self.filter_radius - 0.5).max(0.0).ceil() as u32
+    }
+
+    /// Renders one sample for every pixel of the square patch at (x, y), and
+    /// for the pixels in the margin around it, for `gather_splats_16x4()`.
+    ///
+    /// The samples of the margin belong to the neighboring patches, but the
+    /// samples of a pixel depend only on its coordinates and the frame number,
+    /// so every patch renders the same samples there. This costs the work of
+    /// the margin twice, but patches write only to their own pixels, so they
+    /// can still be rendered in parallel.
+    ///
+    /// Only the 16x4 blocks of the patch for which `active` is true, row by
+    /// row, are gathered later. Groups whose samples cannot reach any of
+    /// those blocks are not rendered, so a converged patch traces no paths.
+    fn render_splat_samples<S: Sampler>(&self,
+                                        x: u32,
+                                        y: u32,
+                                        patch_width: u32,
+                                        frame_number: u32,
+                                        active: &[bool],
+                                        sampler: &mut S)
+                                        -> SplatSamples {
+        let margin = self.splat_margin();
+        let x0 = x.saturating_sub(margin) & !3;
+        let y0 = y.saturating_sub(margin) & !1;
+        let x1 = cmp::min(x + patch_width + margin, self.width);
+        let y1 = cmp::min(y + patch_width + margin, self.height);
+        let columns = (x1 - x0 + 3) / 4;
+        let rows = (y1 - y0 + 1) / 2;
+
+        // A group of 4x2 pixels reaches a block of 16x4 pixels if they are
+        // less than the margin apart, like in `gather_splats_16x4()`.
+        let blocks_per_row = patch_width / 16;
+        let reaches_active = |gx: u32, gy: u32| {
+            active.iter().enumerate().any(|(b, &is_active)| {
+                let bx = x + (b as u32 % blocks_per_row) * 16;
+                let by = y + (b as u32 / blocks_per_row) * 4;
+                is_active &&
+                    gx <= bx + 15 + margin && bx <= gx + 3 + margin &&
+                    gy <= by + 3 + margin && by <= gy + 1 + margin
+            })
+        };
+
+        let mut samples = Vec::with_capacity((columns * rows) as usize);
+        for j in 0..rows {
+            for i in 0..columns {
+                let (gx, gy) = (x0 + i * 4, y0 + j * 2);
+                if !reaches_active(gx, gy) {
+                    samples.push(None);
+                    continue
+                }
+
+                // Find the block and the mf32 in it that the group belongs to.
+                let (bx, by) = (gx & !15, gy & !3);
+                let k = ((gx - bx) / 4 * 2 + (gy - by) / 2) as usize;
+                let (px, py) = self.get_sample_positions_4x2(bx, by, k, frame_number, sampler);
+                let (xs, ys) = self.pixel_to_screen(px, py);
+                let data = if self.enable_debug_view {
+                    self.render_pixels_debug(xs, ys)
+                } else {
+                    self.render_pixels(xs, ys, sampler)
+                };
+                samples.push(Some((px, py, data)));
+            }
+        }
+
+        SplatSamples {
+            x: x0,
+            y: y0,
+            columns: columns,
+            samples: samples,
+        }
+    }
+
+    /// Splats the samples into the block of 16x4 pixels at (x, y), in the
+    /// order of `get_pixel_coords_16x4()`.
+    ///
+    /// Every sample contributes to every pixel whose center is within the
+    /// filter radius, weighted by the tent filter normalized to an area of 1.
+    /// There is one sample per pixel, so the expected sum of the weights is 1,
+    /// and the sum is not divided by the actual weights: the result is an
+    /// unbiased estimate of the filtered image, so it can be accumulated like
+    /// a box filtered sample. Near the border of the image, part of the filter
+    /// has no samples, so the weights are scaled up there. The gbuffer values
+    /// are those of the own sample of the pixel.
+    fn gather_splats_16x4(&self, splats: &SplatSamples, x: u32, y: u32) -> [MPixelData; 8] {
+        let radius = self.filter_radius;
+        let margin = self.splat_margin();
+        let inv_radius = Mf32::broadcast(1.0 / radius);
+        let zero = Mf32::zero();
+        let rows = splats.samples.len() as u32 / splats.columns;
+        let group = |i: u32, j: u32| {
+            splats.samples[(j * splats.columns + i) as usize].as_ref().expect("splat sample was not rendered")
+        };
+
+        generate_slice8(|k| {
+            let (tx, ty) = block_pixel(x, y, k, 0);
+            let cx = Mf32::generate(|lane| (tx + lane as u32 % 4) as f32 + 0.5);
+            let cy = Mf32::generate(|lane| (ty + lane as u32 / 4) as f32 + 0.5);
+            let border = Mf32::generate(|lane| {
+                let (px, py) = (cx.get_coord(lane), cy.get_coord(lane));
+                let fx = tent_cdf(self.width as f32 - px, radius) - tent_cdf(-px, radius);
+                let fy = tent_cdf(self.height as f32 - py, radius) - tent_cdf(-py, radius);
+                1.0 / (radius * radius * fx * fy)
+            });
+
+            let own = &group((tx - splats.x) / 4, (ty - splats.y) / 2).2;
+            let mut data = MPixelData {
+                color: MVector3::zero(),
+                coverage: zero,
+                tex_index: own.tex_index,
+                tex_coords: own.tex_coords,
+                fresnel: own.fresnel,
+                aovs: [MVector3::zero(); 4],
+                albedo: MVector3::zero(),
+            };
+
+            let i0 = (cmp::max(tx.saturating_sub(margin), splats.x) - splats.x) / 4;
+            let i1 = cmp::min((tx + 3 + margin - splats.x) / 4, splats.columns - 1);
+            let j0 = (cmp::max(ty.saturating_sub(margin), splats.y) - splats.y) / 2;
+            let j1 = cmp::min((ty + 1 + margin - splats.y) / 2, rows - 1);
+            for j in j0..j1 + 1 {
+                for i in i0..i1 + 1 {
+                    let &(ref px, ref py, ref sample) = group(i, j);
+                    for lane in 0..8 {
+                        let dx = (cx - Mf32::broadcast(px.get_coord(lane))).abs();
+                        let dy = (cy - Mf32::broadcast(py.get_coord(lane))).abs();
+                        let wx = (Mf32::one() - dx * inv_radius).max(zero);
+                        let wy = (Mf32::one() - dy * inv_radius).max(zero);
+                        let w = wx * wy * border;
+                        data.color = sample.color.broadcast_lane(lane).mul_add(w, data.color);
+                        data.coverage = Mf32::broadcast(sample.coverage.get_coord(lane)).mul_add(w, data.coverage);
+                        for a in 0..4 {
+                            data.aovs[a] = sample.aovs[a].broadcast_lane(lane).mul_add(w, data.aovs[a]);
+                        }
+                        data.albedo = sample.albedo.broadcast_lane(lane).mul_add(w, data.albedo);
+                    }
+                }
+            }
+
+            data
+        })
+    }
+
+    /// Intersects only the primary rays for a block of 16x4 pixels, to fill
+    /// the gbuffer without path tracing. The color is left black.
+    fn render_block_primary_16x4<S: Sampler>(&self, x: u32, y: u32, frame_number: u32, sampler: &mut S) -> [MPixelData; 8] {
+        generate_slice8(|i| {
+            let (xs, ys) = self.get_pixel_coords_4x2(x, y, i, frame_number, sampler);
+            let t = sampler.next_1d();
+            let ray = self.scene.camera().get_ray_lens(xs, ys, t, sampler.rng());
+            let (isect, _) = self.scene.intersect_nearest_opaque(&ray);
+
+            // Without a sampled bounce there is no half-way vector, so take
+            // the Fresnel factor of the mirror direction, for which the
+            // half-way vector is the normal.
+            let ct = Mf32::one() - isect.normal.dot(ray.direction).abs();
+            let ct2 = ct * ct;
+            let missed = isect.distance.geq(Mf32::broadcast(1.0e5));
+
+            MPixelData {
+                color: MVector3::zero(),
+                coverage: Mf32::one().pick(Mf32::zero(), missed),
+                tex_index: isect.material.get_texture(),
+                tex_coords: isect.tex_coords,
+                fresnel: (ct2 * ct2 * ct).abs(),
+                aovs: [MVector3::zero(); 4],
+                albedo: MVector3::zero(),
+            }
+        })
+    }
+
+    /// Renders a square part of a frame.
+    ///
+    /// The (x, y) coordinate is the coordinate of the bottom-left pixel of the
+    /// patch. The patch width must be a multiple of 16.
+    pub fn render_patch_u8(&self,
+                           bitmap: &mut [Mi32],
+                           gbuffer: &mut [Mi32],
+                           patch_width: u32,
+                           x: u32,
+                           y: u32,
+                           frame_number: u32) {
+        match self.sampler_kind {
+            SamplerKind::Random => {
+                let mut sampler = Rng::with_seed(0, 0, 0);
+                self.render_patch_u8_with(&mut sampler, bitmap, gbuffer, patch_width, x, y, frame_number)
+            }
+            SamplerKind::Halton => {
+                let mut sampler = HaltonSampler::new();
+                self.render_patch_u8_with(&mut sampler, bitmap, gbuffer, patch_width, x, y, frame_number)
+            }
+        }
+    }
+
+    /// Implements `render_patch_u8()` for a particular kind of sampler.
+    fn render_patch_u8_with<S: Sampler>(&self,
+                                        sampler: &mut S,
+                                        bitmap: &mut [Mi32],
+                                        gbuffer: &mut [Mi32],
+                                        patch_width: u32,
+                                        x: u32,
+                                        y: u32,
+                                        frame_number: u32) {
+        assert_eq!(patch_width & 15, 0); // Patch width must be a multiple of 16.
+        let w = patch_width / 16;
+        let h = patch_width / 4;
+
+        for i in 0..w {
+            for j in 0..h {
+                let xb = x + i * 16;
+                let yb = y + j * 4;
+                let data = self.render_block_16x4(xb, yb, frame_number, sampler);
+                self.store_pixels_color_16x4(bitmap, xb, yb, frame_number, &data);
+                self.store_pixels_gbuffer_16x4(gbuffer, xb, yb, &data);
+            }
+        }
+
+        self.collect_traversal_stats();
+    }
+
+    /// Renders a square part of a frame at a reduced resolution, and upscales
+    /// it to fill the full patch.
+    ///
+    /// Every block of `scale` by `scale` pixels gets the value of a single
+    /// sample at its center, so a scale of 2 traces a quarter of the rays.
+    /// This gives quick feedback while the camera or scene is changing; render
+    /// at scale 1 afterwards to refine the frame. A scale of 1 is the same as
+    /// `render_patch_u8()`. The patch width divided by the scale must be a
+    /// multiple of 16, and (x, y) must be a multiple of the scale.
+    pub fn render_patch_u8_scaled(&self,
+                                  bitmap: &mut [Mi32],
+                                  gbuffer: &mut [Mi32],
+                                  patch_width: u32,
+                                  x: u32,
+                                  y: u32,
+                                  scale: u32,
+                                  frame_number: u32) {
+        if scale == 1 {
+            return self.render_patch_u8(bitmap, gbuffer, patch_width, x, y, frame_number);
+        }
+
+        match self.sampler_kind {
+            SamplerKind::Random => {
+                let mut sampler = Rng::with_seed(0, 0, 0);
+                self.render_patch_u8_scaled_with(&mut sampler, bitmap, gbuffer, patch_width, x, y, scale, frame_number)
+            }
+            SamplerKind::Halton => {
+                let mut sampler = HaltonSampler::new();
+                self.render_patch_u8_scaled_with(&mut sampler, bitmap, gbuffer, patch_width, x, y, scale, frame_number)
+            }
+        }
+    }
+
+    /// Implements `render_patch_u8_scaled()` for a particular kind of sampler.
+    fn render_patch_u8_scaled_with<S: Sampler>(&self,
+                                               sampler: &mut S,
+                                               bitmap: &mut [Mi32],
+                                               gbuffer: &mut [Mi32],
+                                               patch_width: u32,
+                                               x: u32,
+                                               y: u32,
+                                               scale: u32,
+                                               frame_number: u32) {
+        assert!(scale > 0 && patch_width % (scale * 16) == 0,
+                "patch width must be a multiple of 16 times the scale");
+        assert!(x % scale == 0 && y % scale == 0);
+
+        // Write the upscaled pixels one by one, rather than as 16x4 blocks.
+        let w = self.width as usize;
+        let pixels = unsafe { slice::from_raw_parts_mut(bitmap.as_mut_ptr() as *mut i32, bitmap.len() * 8) };
+        let gpixels = unsafe { slice::from_raw_parts_mut(gbuffer.as_mut_ptr() as *mut i32, gbuffer.len() * 8) };
+
+        let low_width = patch_width / scale;
+
+        for i in 0..low_width / 16 {
+            for j in 0..low_width / 4 {
+                let xb = x / scale + i * 16;
+                let yb = y / scale + j * 4;
+                let (xs, ys) = self.get_scaled_pixel_coords_16x4(xb, yb, scale);
+                let data = if self.enable_debug_view {
+                    generate_slice8(|k| self.render_pixels_debug(xs[k], ys[k]))
+                } else {
+                    generate_slice8(|k| {
+                        // The samples are at the centers of the pixels, but
+                        // the offset still takes the first dimensions, so the
+                        // later ones are the same as at full resolution.
+                        let (gx, gy) = block_pixel(xb, yb, k, 0);
+                        sampler.start_pixel(gx, gy, frame_number);
+                        sampler.next_2d();
+                        self.render_pixels(xs[k], ys[k], sampler)
+                    })
+                };
+                let rgbas = self.pack_pixels_color_16x4(xb, yb, frame_number, &data);
+                let uvs = self.pack_pixels_gbuffer_16x4(&data);
+
+                for k in 0..8 {
+                    for lane in 0..8 {
+                        let (px, py) = block_pixel(xb, yb, k, lane);
+                        for dy in 0..scale {
+                            let row = (py * scale + dy) as usize * w;
+                            for dx in 0..scale {
+                                let index = row + (px * scale + dx) as usize;
+                                pixels[index] = rgbas[k].get_coord(lane);
+                                gpixels[index] = uvs[k].get_coord(lane);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        self.collect_traversal_stats();
+    }
+
+    /// Renders a full frame in parallel on the thread pool.
+    ///
+    /// The frame is split into square patches of `patch_width` pixels, and
+    /// the width and height must be multiples of the patch width. Workers
+    /// check the cancel flag before every patch, and skip the remaining
+    /// patches once it is set. A patch is either rendered completely or not at
+    /// all, so a cancelled frame leaves the previous contents in the patches
+    /// that were skipped. Returns the number of patches rendered.
+    ///
+    /// The buffers are borrowed mutably for the whole frame, so nothing else
+    /// can read them while the workers write their patches.
+    pub fn render_frame_parallel(&self,
+                                 pool: &mut Pool,
+                                 bitmap: &mut RenderBuffer,
+                                 gbuffer: &mut RenderBuffer,
+                                 patch_width: u32,
+                                 frame_number: u32,
+                                 cancel: &AtomicBool)
+                                 -> u32 {
+        assert_eq!(self.width % patch_width, 0);
+        assert_eq!(self.height % patch_width, 0);
+
+        let num_rendered = AtomicUsize::new(0);
+        let tiles = tile_sequence(self.tile_order, self.width / patch_width, self.height / patch_width);
+        {
+            let num_rendered_ref = &num_rendered;
+            let bitmap = &*bitmap;
+            let gbuffer = &*gbuffer;
+            pool.scoped(|scope| {
+                for &(i, j) in &tiles {
+                    scope.execute(move || {
+                        if cancel.load(Ordering::Relaxed) {
+                            return
+                        }
+
+                        // The patches are disjoint, and nothing else can
+                        // access the buffers while they are borrowed mutably
+                        // for the whole frame, so this is safe.
+                        let bitmap = unsafe { bitmap.get_mut_slice() };
+                        let gbuffer = unsafe { gbuffer.get_mut_slice() };
+                        let (x, y) = (i * patch_width, j * patch_width);
+                        self.render_patch_u8(bitmap, gbuffer, patch_width, x, y, frame_number);
+                        num_rendered_ref.fetch_add(1, Ordering::Relaxed);
+                    });
+                }
+            });
+        }
+
+        num_rendered.load(Ordering::Relaxed) as u32
+    }
+
+    /// Renders a full frame on as many threads as the machine has cores.
+    ///
+    /// This creates a thread pool for the frame, so for interactive rendering
+    /// prefer `render_frame_parallel()` with a long-lived pool. The patch width
+    /// is 32 if it divides the size of the frame, and 16 otherwise. Returns the
+    /// number of threads used.
+    pub fn render_frame_auto(&self, bitmap: &mut RenderBuffer, gbuffer: &mut RenderBuffer, frame_number: u32) -> u32 {
+        let num_threads = available_threads();
+        let patch_width = if self.width % 32 == 0 && self.height % 32 == 0 { 32 } else { 16 };
+        let mut pool = Pool::new(num_threads);
+        let cancel = AtomicBool::new(false);
+        self.render_frame_parallel(&mut pool, bitmap, gbuffer, patch_width, frame_number, &cancel);
+        num_threads
+    }
+
+    /// Renders a square part of a frame, adds the contribution to the buffer.
+    ///
+    /// The (x, y) coordinate is the coordinate of the bottom-left pixel of the
+    /// patch. The patch width must be a multiple of 16. The memory layout of
+    /// the HDR buffer is as a bitmap of 16x4 blocks.
+    ///
+    /// This also fills the gbuffer. This is not done accumulatively, it is
+    /// filled for the current frame. (Though the gbuffer should be fairly
+    /// constant anyway, and there is no way to blend it, apart from averaging
+    /// texture coordinates.)
+    ///
+    /// Once the buffer has converged (see `set_max_accumulation()`), this does
+    /// nothing at all.
+    ///
+    /// Multiple threads may accumulate into the same buffer at the same time,
+    /// as long as the patches are disjoint. This is unsafe because the buffer
+    /// is shared: the caller must ensure that no other patch that overlaps
+    /// this one is accumulated into the same buffer concurrently, and that
+    /// the buffer is not read during the call.
+    pub unsafe fn accumulate_patch_f32(&self,
+                                       hdr_buffer: &HdrBuffer,
+                                       gbuffer: &mut [Mi32],
+                                       patch_width: u32,
+                                       x: u32,
+                                       y: u32,
+                                       frame_number: u32) {
+        match self.sampler_kind {
+            SamplerKind::Random => {
+                let mut sampler = Rng::with_seed(0, 0, 0);
+                self.accumulate_patch_f32_with(&mut sampler, hdr_buffer, gbuffer, patch_width, x, y, frame_number)
+            }
+            SamplerKind::Halton => {
+                let mut sampler = HaltonSampler::new();
+                self.accumulate_patch_f32_with(&mut sampler, hdr_buffer, gbuffer, patch_width, x, y, frame_number)
+            }
+        }
+    }
+
+    /// Implements `accumulate_patch_f32()` for a particular kind of sampler.
+    /// See there for the contract that makes this unsafe.
+    unsafe fn accumulate_patch_f32_with<S: Sampler>(&self,
+                                                    sampler: &mut S,
+                                                    hdr_buffer: &HdrBuffer,
+                                                    gbuffer: &mut [Mi32],
+                                                    patch_width: u32,
+                                                    x: u32,
+                                                    y: u32,
+                                                    frame_number: u32) {
+        assert_eq!(patch_width & 15, 0); // Patch width must be a multiple of 16.
+        assert!(hdr_buffer.width == self.width && hdr_buffer.height == self.height,
+                "buffer size does not match the renderer, allocate a new buffer after resizing");
+
+        if self.is_converged(hdr_buffer) {
+            return
+        }
+
+        // This is safe as long as the patches are disjoint, because then every
+        // thread touches different parts of the buffer.
+        let coverage_buffer = hdr_buffer.get_mut_coverage_slice();
+        let stats_buffer = hdr_buffer.get_mut_stats_slice();
+        let aovs_buffer = hdr_buffer.get_mut_aovs_slice();
+        let albedo_buffer = hdr_buffer.get_mut_albedo_slice();
+        let num_samples = hdr_buffer.num_samples();
+        let hdr_buffer = hdr_buffer.get_mut_slice();
+
+        // Pixels that have converged receive their current mean as a new
+        // sample, so their mean does not change.
+        let freeze = Mf32::broadcast(1.0 / cmp::max(num_samples, 1) as f32);
+
+        let w = patch_width / 16;
+        let h = patch_width / 4;
+        let block_index = |i: u32, j: u32| ((y / 4 + j) * (self.width / 16) + (x / 16 + i)) as usize;
+
+        // Find the converged pixels of every block, row by row, up front.
+        let converged: Vec<[Mask; 8]> = (0..w * h).map(|b| {
+            let stats = stats_buffer[block_index(b % w, b / w)];
+            match self.adaptive_threshold {
+                Some(rel) => generate_slice8(|k| stats[k].is_converged(rel)),
+                None => generate_slice8(|_| Mf32::zero()),
+            }
+        }).collect();
+        let all_converged = |mask: &[Mask; 8]| mask.iter().all(|m| m.all_sign_bits_negative());
+
+        // With a reconstruction filter, the samples of the patch and of the
+        // margin around it are rendered first, and then splatted. Only the
+        // samples that can reach a block that is not converged are rendered.
+        let splats = if self.filter_radius > 0.0 {
+            let active: Vec<bool> = converged.iter().map(|mask| !all_converged(mask)).collect();
+            Some(self.render_splat_samples(x, y, patch_width, frame_number, &active, sampler))
+        } else {
+            None
+        };
+
+        for i in 0..w {
+            for j in 0..h {
+                let xb = x + i * 16;
+                let yb = y + j * 4;
+                let index = block_index(i, j);
+                let current = hdr_buffer[index];
+                let current_coverage = coverage_buffer[index];
+                let mut stats = stats_buffer[index];
+                let converged = converged[(j * w + i) as usize];
+                let frozen = generate_slice8(|k| current[k].mul_add(freeze, current[k]));
+                let frozen_coverage = generate_slice8(|k| current_coverage[k].mul_add(freeze, current_coverage[k]));
+
+                // The gbuffer must be filled every frame, but that needs only
+                // the primary rays.
+                if all_converged(&converged) {
+                    hdr_buffer[index] = frozen;
+                    coverage_buffer[index] = frozen_coverage;
+                    if let Some(aovs) = aovs_buffer.get_mut(index) {
+                        for aov in aovs.iter_mut() {
+                            *aov = generate_slice8(|k| aov[k].mul_add(freeze, aov[k]));
+                        }
+                    }
+                    if let Some(albedo) = albedo_buffer.get_mut(index) {
+                        *albedo = generate_slice8(|k| albedo[k].mul_add(freeze, albedo[k]));
+                    }
+                    let data = self.render_block_primary_16x4(xb, yb, frame_number, sampler);
+                    self.store_pixels_gbuffer_16x4(gbuffer, xb, yb, &data);
+                    continue
+                }
+
+                let data = match splats {
+                    Some(ref splats) => self.gather_splats_16x4(splats, xb, yb),
+                    None => self.render_block_16x4(xb, yb, frame_number, sampler),
+                };
+                hdr_buffer[index] = generate_slice8(|k| (current[k] + data[k].color).pick(frozen[k], converged[k]));
+                coverage_buffer[index] = generate_slice8(|k| {
+                    (current_coverage[k] + data[k].coverage).pick(frozen_coverage[k], converged[k])
+                });
+                for k in 0..8 {
+                    stats[k].insert(luminance(data[k].color), converged[k]);
+                }
+                stats_buffer[index] = stats;
+                if let Some(aovs) = aovs_buffer.get_mut(index) {
+                    for (a, aov) in aovs.iter_mut().enumerate() {
+                        *aov = generate_slice8(|k| {
+                            (aov[k] + data[k].aovs[a]).pick(aov[k].mul_add(freeze, aov[k]), converged[k])
+                        });
+                    }
+                }
+                if let Some(albedo) = albedo_buffer.get_mut(index) {
+                    *albedo = generate_slice8(|k| {
+                        (albedo[k] + data[k].albedo).pick(albedo[k].mul_add(freeze, albedo[k]), converged[k])
+                    });
+                }
+                self.store_pixels_gbuffer_16x4(gbuffer, xb, yb, &data);
+            }
+        }
+
+        self.collect_traversal_stats();
+    }
+
+    /// Accumulates one sample per pixel of a full frame into the buffer, in
+    /// parallel on the thread pool, and counts the sample.
+    ///
+    /// The result does not depend on the number of threads, nor on the order
+    /// in which the patches are scheduled: every pixel is owned by exactly one
+    /// patch, so its sum is always accumulated in the same order, and the
+    /// random numbers are seeded by the pixel coordinates and the frame number
+    /// only. The output is therefore identical bit for bit on any pool.
+    pub fn accumulate_frame_parallel(&self,
+                                     pool: &mut Pool,
+                                     hdr_buffer: &mut HdrBuffer,
+                                     gbuffer: &mut RenderBuffer,
+                                     patch_width: u32,
+                                     frame_number: u32) {
+        assert_eq!(self.width % patch_width, 0);
+        assert_eq!(self.height % patch_width, 0);
+
+        if self.is_converged(hdr_buffer) {
+            return
+        }
+
+        let tiles = tile_sequence(self.tile_order, self.width / patch_width, self.height / patch_width);
+        {
+            let hdr_buffer = &*hdr_buffer;
+            let gbuffer = &*gbuffer;
+            pool.scoped(|scope| {
+                for &(i, j) in &tiles {
+                    scope.execute(move || {
+                        // The patches are disjoint, and nothing else can
+                        // access the buffers while they are borrowed mutably
+                        // for the whole frame, so this is safe.
+                        let (x, y) = (i * patch_width, j * patch_width);
+                        unsafe {
+                            let gbuffer = gbuffer.get_mut_slice();
+                            self.accumulate_patch_f32(hdr_buffer, gbuffer, patch_width, x, y, frame_number);
+                        }
+                    });
+                }
+            });
+        }
+
+        hdr_buffer.inc_num_samples();
+    }
+
+    /// Sets the number of samples per pixel after which accumulation stops.
+    ///
+    /// When the accumulation buffer has this many samples, it is considered
+    /// converged, and further calls to `accumulate_patch_f32()` are no-ops.
+    /// A new buffer (as created when the camera starts moving again) starts
+    /// accumulating from zero.
+    pub fn set_max_accumulation(&mut self, max_samples: u32) {
+        self.max_accumulation = max_samples;
+    }
+
+    /// Enables or disables adaptive sampling for `accumulate_patch_f32()`.
+    ///
+    /// With adaptive sampling, a pixel stops receiving new samples once the
+    /// 95% confidence interval of its mean luminance lies within the given
+    /// fraction of the mean, so samples are spent where noise is still
+    /// visible. Blocks of pixels that have all converged are not path traced
+    /// at all.
+    pub fn set_adaptive_sampling(&mut self, threshold: Option<f32>) {
+        self.adaptive_threshold = threshold;
+    }
+
+    /// Sets the photon map to estimate caustics with, or disables the caustic
+    /// pass if it is `None`.
+    ///
+    /// With a photon map, light that reaches the surface that the camera sees
+    /// through glass comes from the photon map only; paths that find it are
+    /// dropped. The photon map must be traced again when the scene changes.
+    pub fn set_photon_map(&mut self, photon_map: Option<PhotonMap>) {
+        self.photon_map = photon_map;
+    }
+
+    /// Sets the order in which `render_frame_parallel()` renders patches.
+    pub fn set_tile_order(&mut self, order: TileOrder) {
+        self.tile_order = order;
+    }
+
+    /// Sets the anti-aliasing pattern with the given number of samples per
+    /// pixel. Every pixel cycles through the samples over consecutive frames,
+    /// starting at a different sample for neighboring pixels.
+    ///
+    /// By default, the pattern is `Jittered` with a single cell, so every
+    /// sample is at a random position in the pixel.
+    pub fn set_aa_pattern(&mut self, pattern: AaPattern, num_samples: u32) {
+        let (cells, cell_size) = aa_cells(pattern, num_samples);
+        self.aa_cells = cells;
+        self.aa_cell_size = cell_size;
+    }
+
+    /// Sets the radius in pixels of the reconstruction filter for
+    /// `accumulate_patch_f32()`.
+    ///
+    /// With a radius of 0 (the default), every sample counts fully for its own
+    /// pixel only, a box filter. A positive radius selects a tent filter: a
+    /// sample is splatted into every pixel whose center is closer than the
+    /// radius along both axes, weighted by the distance, see
+    /// `gather_splats_16x4()`. Radii up to 0.5 keep the samples in their own
+    /// pixel, but they weigh the samples near the center more than the box
+    /// does, so the image is sharper and aliases more. A radius of 1 gives a
+    /// smooth image, larger radii blur it. Splatting renders a margin of
+    /// samples around every patch twice, so wide filters are slower.
+    pub fn set_filter_radius(&mut self, radius: f32) {
+        assert!(radius >= 0.0, "filter radius must not be negative");
+        self.filter_radius = radius;
+    }
+
+    /// Sets the source of sample values for subsequent frames.
+    pub fn set_sampler(&mut self, kind: SamplerKind) {
+        self.sampler_kind = kind;
+    }
+
+    /// Sets the maximum number of indirect bounces per path.
+    ///
+    /// With 0 bounces, the renderer computes direct lighting only: a path ends
+    /// at the light source that it reaches from the surface that the camera
+    /// sees, or it is black. This is a fast preview without noise from
+    /// indirect light. Every additional bounce lets light reach the camera via
+    /// one more surface. Paths that have not found a light source after this
+    /// many bounces are terminated, and contribute black. The default is 3.
+    pub fn set_max_bounces(&mut self, max_bounces: u32) {
+        self.max_bounces = max_bounces;
+    }
+
+    /// Sets the number of bounces that contribute to the albedo guide, which
+    /// is accumulated into buffers created with `HdrBuffer::with_albedo()`.
+    ///
+    /// The albedo of a mirror-like surface is not a useful guide for
+    /// denoising, it is flat while the reflection is detailed. With bounces,
+    /// every surface along the path contributes its albedo weighted by how
+    /// diffuse it is, and the rest of the weight goes to the surfaces further
+    /// along the path. So a diffuse primary surface dominates the guide, and a
+    /// mirror contributes the albedo of the surface it reflects. Glass is seen
+    /// through. A path that ends at a light or the sky gives the remaining
+    /// weight to the surface it left from. The default is 0, the albedo of the
+    /// surface that the camera sees.
+    pub fn set_albedo_bounces(&mut self, bounces: u32) {
+        self.albedo_bounces = bounces;
+    }
+
+    /// Sets the handling of bounce rays that leave the scene without hitting
+    /// anything. The default is `EscapeMode::Trace`. Camera rays always see
+    /// the sky.
+    pub fn set_escape_mode(&mut self, mode: EscapeMode) {
+        self.escape_mode = mode;
+    }
+
+    /// Returns the number of bounce rays that escaped the bounds of the scene
+    /// since the last call, and resets the count. Always returns 0 unless the
+    /// escape mode is `EscapeMode::Leak`.
+    pub fn take_light_leaks(&self) -> usize {
+        self.light_leaks.swap(0, Ordering::Relaxed)
+    }
+
+    /// Returns the number of paths that were terminated by the bounce limit
+    /// since the last call, and resets the count. Always returns 0 in release
+    /// builds.
+    pub fn take_bounce_cap_hits(&self) -> usize {
+        self.bounce_cap_hits.swap(0, Ordering::Relaxed)
+    }
+
+    /// Moves the traversal counters of the current thread into the totals of
+    /// the renderer. Called by workers after every patch.
+    fn collect_traversal_stats(&self) {
+        if cfg!(feature = "traversal-stats") {
+            let stats = bvh::take_thread_traversal_stats();
+            self.traversal_stats.lock().unwrap().add(&stats);
+        }
+    }
+
+    /// Returns the BVH traversal counters of all patches rendered since the
+    /// last call, and resets them. Always returns zeros unless the
+    /// `traversal-stats` feature is enabled.
+    pub fn take_traversal_stats(&self) -> TraversalStats {
+        let mut stats = self.traversal_stats.lock().unwrap();
+        mem::replace(&mut *stats, TraversalStats::default())
+    }
+
+    /// Prints the BVH traversal counters of the frame, if they are collected.
+    pub fn print_traversal_stats(&self) {
+        if cfg!(feature = "traversal-stats") {
+            self.take_traversal_stats().print();
+        }
+    }
+
+    /// Prints a warning if any paths reached the bounce limit since the last
+    /// call. Such paths are black, which biases the image, so if there are
+    /// many of them, the limit should be raised. This is meant to be printed
+    /// on request along with the other statistics, not every frame.
+    pub fn warn_if_bounce_cap_hit(&self) {
+        let hits = self.take_bounce_cap_hits();
+        if hits > 0 {
+            println!("warning: {} paths were terminated after {} bounces since the last report",
+                     hits, self.max_bounces);
+        }
+    }
+
+    /// Returns whether the buffer has accumulated the maximum number of
+    /// samples.
+    pub fn is_converged(&self, hdr_buffer: &HdrBuffer) -> bool {
+        hdr_buffer.num_samples() >= self.max_accumulation
+    }
+
+    /// Creates a new float buffer, the size of the viewport, that can be
+    /// rendered to with `accumulate_patch_f32()`.
+    pub fn new_buffer_f32(&self) -> HdrBuffer {
+        HdrBuffer::new(self.width, self.height)
+    }
+
+    /// Converts a buffer of floating point values used for accumulative
+    /// rendering into a 32 bit per pixel RGBA bitmap.
+    pub fn buffer_f32_into_render_buffer(&self,
+                                         hdr_buffer: &HdrBuffer,
+                                         render_buffer: &mut RenderBuffer) {
+        self.resolve_into_render_buffer(hdr_buffer, render_buffer, false);
+    }
+
+    /// See `buffer_f32_into_render_buffer()`. If `opaque` is true, every pixel
+    /// is resolved as if it had full coverage, so the alpha channel is opaque
+    /// and the color is straight, also with premultiplied alpha.
+    fn resolve_into_render_buffer(&self,
+                                  hdr_buffer: &HdrBuffer,
+                                  render_buffer: &mut RenderBuffer,
+                                  opaque: bool) {
+        let w = self.width / 16;
+        let h = self.height / 4;
+        assert_eq!(w * 16, self.width);
+        assert_eq!(h * 4, self.height);
+        let factor = Mf32::broadcast(1.0 / (cmp::max(hdr_buffer.num_samples(), 1) as f32));
+        let blurred = self.blur_subsurface(hdr_buffer);
+        let hdr_slice = match blurred {
+            Some(ref blocks) => &blocks[..],
+            None => hdr_buffer.as_slice(),
+        };
+        let coverage_slice = hdr_buffer.as_coverage_slice();
+        let coverage_factor = if opaque { Mf32::zero() } else { factor };
+        let coverage_offset = if opaque { Mf32::one() } else { Mf32::zero() };
+
+        {
+            // This is safe here because there is only one mutable borrow.
+            let bitmap = unsafe { render_buffer.get_mut_slice() };
+
+            for j in 0..h {
+                for i in 0..w {
+                    let rgbs = hdr_slice[(j * w + i) as usize];
+                    let rgbs = generate_slice8(|k| rgbs[k] * factor);
+                    let coverages = coverage_slice[(j * w + i) as usize];
+                    let data = generate_slice8(|k| {
+                        MPixelData {
+                            color: rgbs[k],
+                            // The mean coverage is fractional at edges.
+                            coverage: coverages[k].mul_add(coverage_factor, coverage_offset),
+                            // These values are unused, only the color and
+                            // coverage are stored in this function.
+                            tex_index: Mi32::zero(),
+                            tex_coords: (Mf32::zero(), Mf32::zero()),
+                            fresnel: Mf32::zero(),
+                            aovs: [MVector3::zero(); 4],
+                            albedo: MVector3::zero(),
+                        }
+                    });
+                    self.store_pixels_color_16x4(bitmap, i * 16, j * 4, hdr_buffer.num_samples(), &data);
+                }
+            }
+        }
+    }
+
+    /// Returns the blocks of the buffer with the diffusion profiles of the
+    /// translucent materials applied to the pixels that show them, or `None`
+    /// if no pixel shows a translucent material.
+    fn blur_subsurface(&self, hdr_buffer: &HdrBuffer) -> Option<Vec<[MVector3; 8]>> {
+        let mut cached_mask = hdr_buffer.subsurface_mask.lock().unwrap();
+        if cached_mask.is_none() {
+            *cached_mask = self.subsurface_mask();
+        }
+        let mask = match *cached_mask {
+            Some(ref mask) => mask,
+            None => return None,
+        };
+
+        let (w, h) = (self.width as usize, self.height as usize);
+        let blocks = hdr_buffer.as_slice();
+        let mut radiance = vec![SVector3::zero(); w * h];
+        for (block_index, block) in blocks.iter().enumerate() {
+            for (k, mv) in block.iter().enumerate() {
+                for lane in 0..8 {
+                    radiance[pixel_index(w, block_index, k, lane)] =
+                        SVector3::new(mv.x.get_coord(lane), mv.y.get_coord(lane), mv.z.get_coord(lane));
+                }
+            }
+        }
+
+        // The blur only changes the pixels in the mask, and the masks of the
+        // profiles are disjoint, so the profiles can be applied one by one.
+        for index in 0..self.scene.num_subsurface_profiles() {
+            let profile_mask: Vec<bool> = mask.profiles.iter().map(|&p| p == Some(index)).collect();
+            if profile_mask.iter().any(|&m| m) {
+                let profile = self.scene.subsurface_profile(index);
+                radiance = profile.blur(&radiance, &profile_mask, &mask.footprints, w, h);
+            }
+        }
+
+        let blocks = (0..blocks.len()).map(|block_index| {
+            generate_slice8(|k| MVector3::generate(|lane| radiance[pixel_index(w, block_index, k, lane)]))
+        }).collect();
+        Some(blocks)
+    }
+
+    /// Returns for every pixel the diffusion profile of the material at its
+    /// center, and the size of the pixel on that surface. Returns `None` if
+    /// no pixel shows a translucent material.
+    fn subsurface_mask(&self) -> Option<SubsurfaceMask> {
+        if self.scene.num_subsurface_profiles() == 0 {
+            return None
+        }
+
+        let (w, h) = (self.width as usize, self.height as usize);
+        let scale = 2.0 / self.width as f32;
+        let camera = self.scene.camera();
+        let mut profiles = vec![None; w * h];
+        let mut footprints = vec![0.0; w * h];
+
+        for j in 0..self.height / 4 {
+            for i in 0..self.width / 16 {
+                for k in 0..8 {
+                    let pixels = generate_slice8(|lane| block_pixel(i * 16, j * 4, k, lane));
+                    let xs = Mf32::generate(|lane| (pixels[lane].0 as f32 + 0.5 - w as f32 * 0.5) * scale);
+                    let ys = Mf32::generate(|lane| (pixels[lane].1 as f32 + 0.5 - h as f32 * 0.5) * scale);
+                    let ys = if self.flip_y { -ys } else { ys };
+                    let ray = camera.get_ray(xs, ys, Mf32::zero());
+                    let (isect, _) = self.scene.intersect_nearest_opaque(&ray);
+
+                    // The size of a pixel is the distance between the rays
+                    // through neighboring pixels, at the distance of the hit.
+                    let next = camera.get_ray(xs + Mf32::broadcast(scale), ys, Mf32::zero());
+                    let spread = next.direction.distance(ray.direction);
+                    let size = spread * isect.distance;
+
+                    let missed = isect.distance.geq(Mf32::broadcast(1.0e5));
+                    for lane in 0..8 {
+                        if missed.get_coord(lane).is_sign_negative() {
+                            continue
+                        }
+                        let (px, py) = pixels[lane];
+                        let index = py as usize * w + px as usize;
+                        profiles[index] = self.scene.subsurface_index(isect.material.get_material(lane));
+                        footprints[index] = size.get_coord(lane);
+                    }
+                }
+            }
+        }
+
+        if profiles.iter().all(|p| p.is_none()) {
+            None
+        } else {
+            Some(SubsurfaceMask {
+                profiles: profiles,
+                footprints: footprints,
+            })
+        }
+    }
+
+    /// Draws a handle for a dragged object at the given point: a white ring
+    /// around it, on top of the rendered image. The ring is cleared in the
+    /// G-buffer, so it is not textured.
+    pub fn draw_handle(&self, bitmap: &mut RenderBuffer, gbuffer: &mut RenderBuffer, point: SVector3) {
+        let (xs, ys, behind) = self.scene.camera().project(MVector3::broadcast(point));
+        if !behind.all_sign_bits_positive() {
+            return
+        }
+
+        // Invert the mapping from pixels to screen coordinates of
+        // `get_pixel_coords_16x4()`.
+        let (w, h) = (self.width as i32, self.height as i32);
+        let y = if self.flip_y { -ys.0 } else { ys.0 };
+        let cx = xs.0 * (w / 2) as f32 + (w / 2) as f32;
+        let cy = y * (w / 2) as f32 + (h / 2) as f32;
+        let radius = 6.0;
+
+        // This is safe because both buffers are borrowed mutably. A pixel is
+        // 4 bytes, there are 8 in an mi32.
+        let (bitmap, gbuffer) = unsafe { (bitmap.get_mut_slice(), gbuffer.get_mut_slice()) };
+        let pixels = unsafe { slice::from_raw_parts_mut(bitmap.as_mut_ptr() as *mut i32, bitmap.len() * 8) };
+        let gpixels = unsafe { slice::from_raw_parts_mut(gbuffer.as_mut_ptr() as *mut i32, gbuffer.len() * 8) };
+
+        for py in (cy - radius) as i32 - 1..(cy + radius) as i32 + 2 {
+            for px in (cx - radius) as i32 - 1..(cx + radius) as i32 + 2 {
+                let (dx, dy) = (px as f32 + 0.5 - cx, py as f32 + 0.5 - cy);
+                let d = (dx * dx + dy * dy).sqrt();
+                if (d - radius).abs() < 1.0 && px >= 0 && px < w && py >= 0 && py < h {
+                    let index = (py * w + px) as usize;
+                    pixels[index] = -1; // Opaque white.
+                    gpixels[index] = 0;
+                }
+            }
+        }
+    }
+
+    /// Saves the image accumulated so far as a PNG file, and returns its path.
+    ///
+    /// The buffer is resolved like for display by
+    /// `buffer_f32_into_render_buffer()`, so this works at any point during
+    /// convergence, and it does not disturb accumulation. Without a path, the
+    /// file is named after the current time, in the working directory. If
+    /// `exr` is true, the linear radiance is written to an OpenEXR file next
+    /// to the PNG as well.
+    ///
+    /// The image is opaque and the color is straight, also with
+    /// `set_premultiply_alpha()`, so the sky is saved as it is displayed.
+    /// Both files store the top row first, whether the rows are flipped with
+    /// `set_flip_y()` or not.
+    pub fn save_screenshot(&self, hdr_buffer: &HdrBuffer, path: Option<&Path>, exr: bool) -> io::Result<PathBuf> {
+        let path = match path {
+            Some(path) => path.with_extension("png"),
+            None => {
+                let stamp = time::strftime("%Y-%m-%d-%H%M%S", &time::now()).unwrap();
+                PathBuf::from(format!("screenshot-{}.png", stamp))
+            }
+        };
+
+        let mut render_buffer = RenderBuffer::new(self.width, self.height);
+        self.resolve_into_render_buffer(hdr_buffer, &mut render_buffer, true);
+        let bitmap = render_buffer.into_bitmap();
+
+        // Image files store the top row first, and PNG has red in the first
+        // byte.
+        let row_len = self.width as usize * 4;
+        let mut rgba = Vec::with_capacity(bitmap.len());
+        let mut push_row = |row: &[u8]| {
+            for pixel in row.chunks(4) {
+                match self.channel_order {
+                    ChannelOrder::Rgba => rgba.extend_from_slice(&[pixel[0], pixel[1], pixel[2], pixel[3]]),
+                    ChannelOrder::Bgra => rgba.extend_from_slice(&[pixel[2], pixel[1], pixel[0], pixel[3]]),
+                }
+            }
+        };
+        if self.flip_y {
+            for row in bitmap.chunks(row_len) {
+                push_row(row);
+            }
+        } else {
+            for row in bitmap.chunks(row_len).rev() {
+                push_row(row);
+            }
+        }
+
+        try!(imagefmt::write(&path, self.width as usize, self.height as usize, imagefmt::ColFmt::RGBA, &rgba,
+                             imagefmt::ColType::ColorAlpha)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, format!("failed to write png: {:?}", err))));
+
+        if exr {
+            // The EXR writer expects the bottom row first.
+            let rgbs = hdr_buffer.resolve_f32();
+            let rgbs: Vec<f32> = if self.flip_y {
+                rgbs.chunks(self.width as usize * 3).rev().flat_map(|row| row.iter().cloned()).collect()
+            } else {
+                rgbs
+            };
+            try!(exr::write_exr(path.with_extension("exr"), self.width, self.height, &rgbs));
+        }
+
+        Ok(path)
+    }
+
+    /// Returns the normal of the surface that the camera sees at every pixel
+    /// center, in the same format as `HdrBuffer::into_hdr_f32()`. Pixels
+    /// that show the sky get a zero vector.
+    pub fn render_normals_f32(&self, space: NormalSpace) -> Vec<f32> {
+        let (w, h) = (self.width as usize, self.height as usize);
+        let scale = 2.0 / self.width as f32;
+        let camera = self.scene.camera();
+        let mut normals = vec![0.0; w * h * 3];
+
+        for j in 0..self.height / 4 {
+            for i in 0..self.width / 16 {
+                for k in 0..8 {
+                    let pixels = generate_slice8(|lane| block_pixel(i * 16, j * 4, k, lane));
+                    let xs = Mf32::generate(|lane| (pixels[lane].0 as f32 + 0.5 - w as f32 * 0.5) * scale);
+                    let ys = Mf32::generate(|lane| (pixels[lane].1 as f32 + 0.5 - h as f32 * 0.5) * scale);
+                    let ys = if self.flip_y { -ys } else { ys };
+                    let ray = camera.get_ray(xs, ys, Mf32::zero());
+                    let (isect, _) = self.scene.intersect_nearest_opaque(&ray);
+                    let missed = isect.distance.geq(Mf32::broadcast(1.0e5));
+                    let normal = match space {
+                        NormalSpace::World => isect.normal,
+                        NormalSpace::View => camera.world_to_view(isect.normal),
+                    };
+                    let normal = normal.pick(MVector3::zero(), missed);
+                    for lane in 0..8 {
+                        let (px, py) = pixels[lane];
+                        let index = (py as usize * w + px as usize) * 3;
+                        normals[index + 0] = normal.x.get_coord(lane);
+                        normals[index + 1] = normal.y.get_coord(lane);
+                        normals[index + 2] = normal.z.get_coord(lane);
+                    }
+                }
+            }
+        }
+
+        normals
+    }
+
+    /// Returns for every pixel center the offset in pixels to where the
+    /// surface that the camera sees there was in the previous frame, as two
+    /// floats (x, y) per pixel, in the same order as `render_normals_f32()`.
+    ///
+    /// Surfaces move with the camera of the previous frame, which
+    /// `update_scene()` retains, and with the motion of their mesh, see
+    /// `Scene::set_mesh_motion()`. Pixels that show the sky only move with the
+    /// camera. Surfaces that were behind the previous camera get a zero
+    /// vector.
+    pub fn render_motion_vectors_f32(&self) -> Vec<f32> {
+        let (w, h) = (self.width as usize, self.height as usize);
+        let scale = 2.0 / self.width as f32;
+        let camera = self.scene.camera();
+        let previous = self.previous_camera.as_ref().unwrap_or(camera);
+        let mut vectors = vec![0.0; w * h * 2];
+
+        for j in 0..self.height / 4 {
+            for i in 0..self.width / 16 {
+                for k in 0..8 {
+                    let pixels = generate_slice8(|lane| block_pixel(i * 16, j * 4, k, lane));
+                    let xs = Mf32::generate(|lane| (pixels[lane].0 as f32 + 0.5 - w as f32 * 0.5) * scale);
+                    let ys = Mf32::generate(|lane| (pixels[lane].1 as f32 + 0.5 - h as f32 * 0.5) * scale);
+                    let ys = if self.flip_y { -ys } else { ys };
+                    let ray = camera.get_ray(xs, ys, Mf32::zero());
+                    let isect = self.scene.intersect_nearest(&ray);
+                    let positions = self.scene.previous_positions(&isect);
+                    let (prev_xs, prev_ys, behind) = previous.project(positions);
+
+                    // Screen coordinates to pixels, rows go the other way
+                    // when the image is flipped.
+                    let inv_scale = Mf32::broadcast(1.0 / scale);
+                    let dx = ((prev_xs - xs) * inv_scale).pick(Mf32::zero(), behind);
+                    let dy = ((prev_ys - ys) * inv_scale).pick(Mf32::zero(), behind);
+                    let dy = if self.flip_y { -dy } else { dy };
+                    for lane in 0..8 {
+                        let (px, py) = pixels[lane];
+                        let index = (py as usize * w + px as usize) * 2;
+                        vectors[index + 0] = dx.get_coord(lane);
+                        vectors[index + 1] = dy.get_coord(lane);
+                    }
+                }
+            }
+        }
+
+        vectors
+    }
+
+    /// Traces one ray through the full pipeline, and records every surface
+    /// that the path hit along the way. This is intended for debugging the
+    /// shading of a single pixel. The direction must be normalized.
+    pub fn render_single_ray(&self, origin: SVector3, direction: SVector3) -> RayDebugInfo {
+        let ray = MRay {
+            origin: MVector3::broadcast(origin),
+            direction: MVector3::broadcast(direction),
+            active: Mf32::zero(),
+        };
+        let mut sampler = Rng::with_seed(0, 0, 1);
+        let mut bounces = Vec::new();
+        let data = self.trace_path(ray, &mut sampler, Some(&mut bounces));
+        RayDebugInfo {
+            bounces: bounces,
+            color: SVector3::new(data.color.x.0, data.color.y.0, data.color.z.0),
+        }
+    }
+
+    /// Returns colors for the pixels, as well as the texture indices.
+    fn render_pixels<S: Sampler>(&self, x: Mf32, y: Mf32, sampler: &mut S) -> MPixelData {
+        let t = sampler.next_1d();
+        let ray = self.scene.camera().get_ray_lens(x, y, t, sampler.rng());
+        self.trace_path(ray, sampler, None)
+    }
+
+    /// Returns the light that reaches the surface directly from a random point
+    /// on the area lights, and leaves it towards the origin of the ray. The
+    /// radiance is not yet multiplied by the throughput of the path.
+    ///
+    /// Only the lanes with the sign bit of `shade` set are shaded, the others
+    /// are zero.
+    fn sample_area_lights(&self,
+                          material: MMaterial,
+                          ray: &MRay,
+                          isect: &MIntersection,
+                          shade: Mask,
+                          rng: &mut Rng,
+                          ignore_fresnel: bool)
+                          -> MVector3 {
+        let sample = self.scene.sample_light(rng);
+        let dist = isect.position.distance(sample.position);
+        let dist_sqr = dist * dist;
+        let direction = (sample.position - isect.position) * dist.recip_precise();
+        let cos_light = sample.normal.dot(direction).neg_sub().max(Mf32::zero());
+
+        // A light with wrap lighting also reaches surfaces that face slightly
+        // away from it, and a light with a photometric profile emits more in
+        // some directions than in others.
+        let cos_receiver = self.scene.light_receiver_factor(&sample, isect.normal.dot(direction));
+        let cos_light = cos_light * self.scene.light_radiance_factor(&sample, -direction);
+
+        // Area lights do not occlude each other, and glass only attenuates.
+        // But if there is a photon map, it has the light that glass refracts
+        // already, and then glass casts a full shadow.
+        let shadow_ray = MRay {
+            origin: isect.offset_origin(direction),
+            direction: direction,
+            active: shade.neg_xor(),
+        };
+        let (occluder, transmittance) = if self.photon_map.is_some() {
+            let white = MVector3::new(Mf32::one(), Mf32::one(), Mf32::one());
+            (self.scene.intersect_nearest(&shadow_ray), white)
+        } else {
+            self.scene.intersect_nearest_opaque(&shadow_ray)
+        };
+        let visible = occluder.distance.geq(dist * Mf32::broadcast(0.999));
+
+        let brdf = eval_brdf(material, self.scene.ggx_albedo(), ray, isect, direction, ignore_fresnel);
+        let weight = (cos_receiver * cos_light) / (dist_sqr * sample.pdf);
+        let radiance = brdf.mul_coords(sample.radiance).mul_coords(transmittance) * weight;
+        MVector3::zero().pick(radiance, visible & shade)
+    }
+
+    /// Traces paths starting at the ray. If `bounces` is not `None`, the
+    /// surfaces that the path in lane 0 hits are recorded.
+    fn trace_path<S: Sampler>(&self,
+                              mut ray: MRay,
+                              sampler: &mut S,
+                              mut bounces: Option<&mut Vec<BounceDebugInfo>>)
+                              -> MPixelData {
+        let mut color = MVector3::new(Mf32::one(), Mf32::one(), Mf32::one());
+        let mut coverage = Mf32::zero();
+        let mut caustic = MVector3::zero();
+        let mut hit_emissive = Mf32::zero();
+        let mut texture_index = Mi32::zero();
+        let mut texture_coords = (Mf32::zero(), Mf32::zero());
+        let mut fresnel = Mf32::zero();
+
+        // Sign bit is 1 for the paths that bounced off a diffuse surface.
+        let mut diffuse_seen = Mf32::zero();
+
+        // Path classification for the output variables. Sign bit is 1 for the
+        // paths whose first surface is diffuse, and for the paths that reached
+        // a light source after at most one bounce.
+        let mut first_diffuse = Mf32::zero();
+        let mut direct = Mf32::zero();
+
+        // The amount of distance fog at the surface that the camera sees.
+        let mut fog_amount = Mf32::zero();
+
+        // The albedo guide, the weight that the surfaces further along the
+        // path still have in it, and the albedo of the last surface.
+        let mut albedo = MVector3::zero();
+        let mut albedo_weight = Mf32::one();
+        let mut last_albedo = MVector3::zero();
+
+        // The color of the reference grid, and the mask of the pixels that
+        // show the grid instead of the scene.
+        let mut grid_color = MVector3::zero();
+        let mut on_grid = Mf32::zero();
+
+        // The light linking bits of the surface that the ray left from. The
+        // camera sees every light.
+        let mut receiver_links = Mask::ones();
+
+        // Light from the area lights, split by whether it reached the camera
+        // after at most one bounce. The area lights are sampled explicitly at
+        // every surface, so a path counts a light that it hits only if it did
+        // not sample lights before, when the ray came from the camera or
+        // through glass. The sign bit of `specular_chain` is 1 for those.
+        let has_area_lights = self.scene.num_lights() > 0;
+        let mut area_direct = MVector3::zero();
+        let mut area_indirect = MVector3::zero();
+        let mut specular_chain = Mask::ones();
+
+        // With a photon map, the light that reaches the first surface through
+        // glass is in the photon map, so the paths that find it must not count
+        // it again. The sign bit of `through_glass` is 1 while a path went
+        // only through glass after the first surface, and the sign bit of
+        // `passed_glass` is 1 once it passed any glass at all.
+        let mut through_glass = Mf32::zero();
+        let mut passed_glass = Mf32::zero();
+
+        // One intersection for the surface that the camera sees, one for the
+        // light source, and one per indirect bounce in between.
+        for i in 0..self.max_bounces + 2 {
+            let isect = self.scene.intersect_nearest_stochastic(&ray, sampler.rng());
+
+            // An area light in front of the surface ends the path as an
+            // emissive surface would. The light that it emits counts only
+            // along specular chains, other paths sampled it explicitly.
+            let isect = if has_area_lights {
+                let (radiance, hit) = self.scene.intersect_lights(&ray, isect.distance);
+                let hit = hit & ray.active.neg_xor();
+                let seen = MVector3::zero().pick(color.mul_coords(radiance), hit & specular_chain);
+                if i <= 1 {
+                    area_direct = area_direct + seen;
+                } else {
+                    area_indirect = area_indirect + seen;
+                }
+                color = color.pick(MVector3::zero(), hit);
+                MIntersection {
+                    material: isect.material.pick(MMaterial::sky(), hit),
+                    .. isect
+                }
+            } else {
+                isect
+            };
+            let isect = self.scene.apply_height_maps(isect);
+            hit_emissive = isect.material;
+
+            if i > 0 && self.photon_map.is_some() {
+                let photon_light = through_glass & passed_glass & isect.material;
+                color = color.pick(MVector3::zero(), photon_light);
+                through_glass = through_glass & isect.material.is_glass();
+                passed_glass = passed_glass | isect.material.is_glass();
+            }
+
+            // A ray that hits the back face of glass traveled inside it, so the
+            // glass absorbed part of the light along the way (the Beer-Lambert
+            // law).
+            let inside_glass = isect.material.is_glass() & isect.front_face.neg_xor();
+            let absorbed = color.mul_coords(isect.material.get_transmittance(isect.distance));
+            color = color.pick(absorbed, inside_glass);
+
+            // Bounce rays that hit nothing left the scene through a crack.
+            if i > 0 && self.escape_mode == EscapeMode::Leak {
+                let escaped = isect.distance.geq(Mf32::broadcast(1.0e5)) & ray.active.neg_xor();
+                let num_escaped = (0..8).filter(|&k| escaped.get_coord(k).is_sign_negative()).count();
+                self.light_leaks.fetch_add(num_escaped, Ordering::Relaxed);
+                color = color.pick(MVector3::zero(), escaped);
+            }
+
+            // A light only illuminates the surfaces it is linked to, so drop
+            // the paths that reach a light directly from an unlinked surface.
+            // Other surfaces can still reflect the light onto them.
+            if self.scene.has_light_links() {
+                let unlinked = isect.material & isect.is_unlinked(receiver_links) & ray.active.neg_xor();
+                color = color.pick(MVector3::zero(), unlinked);
+                receiver_links = isect.light_links.pick(receiver_links, ray.active);
+            }
+
+            if let Some(ref mut bounces) = bounces {
+                if !ray.active.0.is_sign_negative() {
+                    use std::mem::transmute;
+                    let lane0 = |v: MVector3| SVector3::new(v.x.0, v.y.0, v.z.0);
+                    bounces.push(BounceDebugInfo {
+                        position: lane0(isect.position),
+                        normal: lane0(isect.normal),
+                        distance: isect.distance.0,
+                        material: unsafe { transmute(isect.material.0) },
+                        albedo: lane0(isect.material.get_color().mul_coords(isect.vertex_color)),
+                        throughput: lane0(color),
+                    });
+                }
+            }
+
+            // Do not allow NaNs to creep in.
+            debug_assert!(ray.direction.all_finite(), "infinite ray direction at iteration {}", i);
+            debug_assert!(isect.position.all_finite(), "infinite intersection at iteration {}", i);
+            debug_assert!(isect.distance.all_finite(), "infinite distance at iteration {}", i);
+
+            // Rays that miss all geometry end at the sky, far away.
+            if i == 0 {
+                let missed = isect.distance.geq(Mf32::broadcast(1.0e5));
+                coverage = Mf32::one().pick(Mf32::zero(), missed);
+                first_diffuse = isect.material.is_diffuse() | isect.material;
+                if let Some(fog) = self.scene.fog() {
+                    fog_amount = fog.amount(isect.distance).pick(Mf32::zero(), missed);
+                }
+                if let Some(ref grid) = self.grid {
+                    let (color, mask) = grid.shade(&ray, isect.distance);
+                    grid_color = color;
+                    on_grid = mask;
+                }
+            }
+            if i <= 1 {
+                direct = direct | isect.material;
+            }
+
+            // Accumulate the albedo guide. A path that ends at a light or the
+            // sky gives the remaining weight to the surface it left from.
+            // Glossiness index 1 is still diffuse, and 5 is the most
+            // mirror-like. The last surface takes all of the remaining weight.
+            if i <= self.albedo_bounces {
+                let ends_here = isect.material & ray.active.neg_xor();
+                albedo = albedo + MVector3::zero().pick(last_albedo * albedo_weight, ends_here);
+
+                let done = ray.active | isect.material;
+                let surface_albedo = isect.material.get_color().mul_coords(isect.vertex_color);
+                let specular = if i == self.albedo_bounces {
+                    Mf32::zero()
+                } else {
+                    let gloss = isect.material.get_glossiness().into_mf32();
+                    let specular = ((gloss - Mf32::one()) * Mf32::broadcast(0.25)).max(Mf32::zero()).min(Mf32::one());
+                    specular.pick(Mf32::one(), isect.material.is_glass())
+                };
+                let contribution = (albedo_weight * (Mf32::one() - specular)).pick(Mf32::zero(), done);
+                albedo = surface_albedo.mul_add(contribution, albedo);
+                albedo_weight = (albedo_weight * specular).pick(Mf32::zero(), done);
+                last_albedo = surface_albedo;
+            }
+
+            // Stop when every ray hit a light source.
+            if isect.material.all_sign_bits_negative() {
+                break;
+            }
+
+            // The path tracer rarely finds light focused by glass, so add the
+            // caustic estimate at the first surface, assuming it is diffuse.
+            // As with the color modulation, the texture is applied on the GPU.
+            if i == 0 {
+                if let Some(ref photon_map) = self.photon_map {
+                    let white = MVector3::new(Mf32::one(), Mf32::one(), Mf32::one());
+                    let albedo = isect.material.get_color().pick(white, isect.material.has_texture());
+                    let albedo = albedo.mul_coords(isect.vertex_color);
+                    let irradiance = photon_map.irradiance_8(isect.position);
+                    let radiance = irradiance.mul_coords(albedo) * Mf32::broadcast(1.0 / consts::PI);
+                    let receiver = (isect.material | isect.material.is_glass()).neg_xor();
+                    caustic = MVector3::zero().pick(color.mul_coords(radiance), receiver);
+                    through_glass = receiver;
+                }
+            }
+
+            // Get a new ray and the color modulation. For the first bounce, the
+            // Fresnel term should not contribute to the color modulation
+            // because that is handled on the GPU.
+            let material = if self.path_regularization > 0.0 {
+                isect.material.regularize(self.path_regularization, diffuse_seen)
+            } else {
+                isect.material
+            };
+            diffuse_seen = diffuse_seen | isect.material.is_diffuse();
+
+            // Sample the area lights at surfaces that scatter light, glass
+            // only reflects and refracts it.
+            if has_area_lights {
+                let skip = ray.active | isect.material | isect.material.is_glass();
+                let direct_light = self.sample_area_lights(material, &ray, &isect, skip.neg_xor(),
+                                                           sampler.rng(), i == 0);
+                let direct_light = color.mul_coords(direct_light.mul_coords(isect.vertex_color));
+                if i == 0 {
+                    area_direct = area_direct + direct_light;
+                } else {
+                    area_indirect = area_indirect + direct_light;
+                }
+                specular_chain = specular_chain & isect.material.is_glass();
+            }
+
+            let (new_ray, color_mod, fr) =
+                continue_path(material, &self.scene, &ray, &isect, sampler.rng(), i == 0);
+            ray = new_ray;
+            let color_mod = clamp_sample_weight(color_mod, self.max_sample_weight);
+            color = color.mul_coords(color_mod.mul_coords(isect.vertex_color));
+
+            if i == 0 {
+                texture_index = isect.material.get_texture();
+                texture_coords = isect.tex_coords;
+                fresnel = fr;
+            }
+        }
+
+        // Paths that did not end at a light source were cut off by the bounce
+        // limit.
+        if cfg!(debug_assertions) {
+            let num_cut = (0..8).filter(|&i| !hit_emissive.get_coord(i).is_sign_negative()).count();
+            self.bounce_cap_hits.fetch_add(num_cut, Ordering::Relaxed);
+        }
+
+        // Compute light contribution.
+        let emission = self.scene.sky_intensity_jittered(ray.direction, sampler.rng());
+        color = color.mul_coords(emission);
+
+        // If the last thing that a ray hit was an emissive material, it has
+        // found a light source and the computed color is correct. If the ray
+        // did not find a light source but the loop was terminated, the computed
+        // color is invalid; it should be black.
+        let zero = MVector3::zero();
+        let path_color = zero.pick(color, hit_emissive);
+        let (path_color, caustic, area_indirect) = if self.indirect_clamp < ::std::f32::INFINITY {
+            let all_indirect = Mf32::zero();
+            (clamp_indirect(path_color, direct, self.indirect_clamp),
+             clamp_indirect(caustic, all_indirect, self.indirect_clamp),
+             clamp_indirect(area_indirect, all_indirect, self.indirect_clamp))
+        } else {
+            (path_color, caustic, area_indirect)
+        };
+        let color = path_color + caustic + area_direct + area_indirect;
+
+        // Every path contributes to exactly one output variable, except for the
+        // caustic, which is always indirect diffuse, and the light sampled from
+        // the area lights, which is split already.
+        let diffuse = zero.pick(path_color, first_diffuse);
+        let specular = path_color.pick(zero, first_diffuse);
+        let aovs = [
+            zero.pick(diffuse, direct) + zero.pick(area_direct, first_diffuse),
+            diffuse.pick(zero, direct) + caustic + zero.pick(area_indirect, first_diffuse),
+            zero.pick(specular, direct) + area_direct.pick(zero, first_diffuse),
+            specular.pick(zero, direct) + area_indirect.pick(zero, first_diffuse),
+        ];
+
+        // Distance fog blends the color towards the fog color. The light of
+        // the fog itself counts as direct diffuse.
+        let (color, aovs) = match self.scene.fog() {
+            Some(fog) => {
+                let fogged = fog.apply(color, fog_amount);
+                let keep = Mf32::one() - fog_amount;
+                let fog_light = fogged - color * keep;
+                let aovs = [aovs[0] * keep + fog_light, aovs[1] * keep, aovs[2] * keep, aovs[3] * keep];
+                (fogged, aovs)
+            }
+            None => (color, aovs),
+        };
+
+        // The grid covers the scene, it is like a light seen directly.
+        let (color, aovs, coverage) = if self.grid.is_some() {
+            let aovs = [
+                aovs[0].pick(grid_color, on_grid),
+                aovs[1].pick(zero, on_grid),
+                aovs[2].pick(zero, on_grid),
+                aovs[3].pick(zero, on_grid),
+            ];
+            let tex_index: Mf32 = unsafe { mem::transmute(texture_index) };
+            texture_index = unsafe { mem::transmute(tex_index.pick(Mf32::zero(), on_grid)) };
+            fresnel = fresnel.pick(Mf32::zero(), on_grid);
+            (color.pick(grid_color, on_grid), aovs, coverage.pick(Mf32::one(), on_grid))
+        } else {
+            (color, aovs, coverage)
+        };
+
+        MPixelData {
+            color: color,
+            coverage: coverage,
+            tex_index: texture_index,
+            tex_coords: texture_coords,
+            fresnel: fresnel,
+            aovs: aovs,
+            albedo: albedo,
+        }
+    }
+
+    fn render_pixels_debug(&self, x: Mf32, y: Mf32) -> MPixelData {
+        let t = Mf32::zero();
+        let ray = self.scene.camera().get_ray(x, y, t);
+        let (numi_aabb, numi_tri) = self.scene.intersect_debug(&ray);
+
+        let g = Mf32::broadcast((numi_aabb as f32).log2() * 0.1);
+        let b = Mf32::broadcast((numi_tri as f32).log2() * 0.1);
+
+        let color = MVector3::new(Mf32::zero(), g, b);
+
+        MPixelData {
+            color: color,
+            coverage: Mf32::one(),
+            tex_index: Mi32::zero(),
+            tex_coords: (Mf32::zero(), Mf32::zero()),
+            fresnel: Mf32::zero(),
+            aovs: [MVector3::zero(); 4],
+            albedo: MVector3::zero(),
+        }
+    }
+}
+
+/// Returns the coordinates of the point at distance `d` along the Hilbert
+/// curve that fills a square of size n (a power of two).
+fn hilbert_point(n: u32, d: u32) -> (u32, u32) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = d;
+    let mut s = 1;
+    while s < n {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+
+        // Rotate the quadrant, so the sub-curves connect.
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            mem::swap(&mut x, &mut y);
+        }
+
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x, y)
+}
+
+/// Returns the coordinates of the point at index `d` on the Z-order curve,
+/// by deinterleaving the bits of `d`.
+fn morton_point(d: u32) -> (u32, u32) {
+    let mut x = 0;
+    let mut y = 0;
+    for bit in 0..16 {
+        x |= ((d >> (2 * bit)) & 1) << bit;
+        y |= ((d >> (2 * bit + 1)) & 1) << bit;
+    }
+    (x, y)
+}
+
+/// Returns the (column, row) index of every patch in a grid of w by h
+/// patches, in the given order.
+pub fn tile_sequence(order: TileOrder, w: u32, h: u32) -> Vec<(u32, u32)> {
+    let mut tiles = Vec::with_capacity((w * h) as usize);
+    let n = cmp::max(w, h).next_power_of_two();
+    match order {
+        TileOrder::RowMajor => {
+            for j in 0..h {
+                for i in 0..w {
+                    tiles.push((i, j));
+                }
+            }
+        }
+        TileOrder::Morton => {
+            tiles.extend((0..n * n).map(morton_point).filter(|&(i, j)| i < w && j < h));
+        }
+        TileOrder::Hilbert => {
+            tiles.extend((0..n * n).map(|d| hilbert_point(n, d)).filter(|&(i, j)| i < w && j < h));
+        }
+    }
+    tiles
+}
+
+/// Returns the coordinates of the pixel in lane `lane` of element `k` of a
+/// block of 16x4 pixels at (x, y), see `Renderer::get_pixel_coords_16x4()`.
+fn block_pixel(x: u32, y: u32, k: usize, lane: usize) -> (u32, u32) {
+    let px = x + (k as u32 / 2) * 4 + lane as u32 % 4;
+    let py = y + (k as u32 % 2) * 2 + lane as u32 / 4;
+    (px, py)
+}
+
+/// The width and height of the blue noise tile used for dithering.
+const BLUE_NOISE_SIZE: u32 = 16;
+
+/// Generates a tile of blue noise: every value occurs once, and similar values
+/// are far apart, also when the tile is repeated.
+///
+/// The pixels are ranked by repeatedly picking the pixel that is farthest
+/// from the pixels picked so far, as measured by a Gaussian energy, which is
+/// the last phase of the void-and-cluster method by Ulichney (1993).
+fn blue_noise_tile() -> Vec<f32> {
+    let n = BLUE_NOISE_SIZE as i32;
+    let len = (n * n) as usize;
+    let mut energy = vec![0.0f32; len];
+    let mut rank = vec![None; len];
+    let sigma2 = 2.0 * 1.5 * 1.5;
+
+    for r in 0..len {
+        // The lowest energy is the largest void. Ties resolve to the first
+        // pixel, so the tile is deterministic.
+        let mut best = 0;
+        let mut best_energy = ::std::f32::INFINITY;
+        for i in 0..len {
+            if rank[i].is_none() && energy[i] < best_energy {
+                best = i;
+                best_energy = energy[i];
+            }
+        }
+        rank[best] = Some(r);
+
+        let (bx, by) = (best as i32 % n, best as i32 / n);
+        for i in 0..len {
+            // Distance on the torus, so the tile repeats seamlessly.
+            let dx = ((i as i32 % n) - bx).abs();
+            let dy = ((i as i32 / n) - by).abs();
+            let (dx, dy) = (cmp::min(dx, n - dx) as f32, cmp::min(dy, n - dy) as f32);
+            energy[i] += (-(dx * dx + dy * dy) / sigma2).exp();
+        }
+    }
+
+    // Map the ranks to the centers of equal intervals in [0, 1).
+    rank.iter().map(|r| (r.unwrap() as f32 + 0.5) / len as f32).collect()
+}
+
+/// Returns the dither offset for a pixel in a frame, in display units, in
+/// the range of half an 8-bit step around zero.
+///
+/// Every frame, the noise is shifted by the golden ratio modulo 1. The values
+/// of a pixel over successive frames are then spread evenly, so their mean
+/// approaches zero quickly.
+fn dither_offset(noise: &[f32], x: u32, y: u32, frame_number: u32) -> f32 {
+    let i = (y % BLUE_NOISE_SIZE) * BLUE_NOISE_SIZE + x % BLUE_NOISE_SIZE;
+    let golden = 0.61803398875_f64;
+    let shift = (frame_number as f64 * golden).fract() as f32;
+    let v = (noise[i as usize] + shift).fract();
+    (v - 0.5) * (1.0 / 255.0)
+}
+
+/// Returns the cells of an anti-aliasing pattern: the corners, and the size
+/// of every cell.
+fn aa_cells(pattern: AaPattern, n: u32) -> (Vec<(f32, f32)>, (f32, f32)) {
+    assert!(n > 0, "an anti-aliasing pattern needs at least one sample");
+    let nf = n as f32;
+    match pattern {
+        AaPattern::Jittered => {
+            // Use a grid of roughly square cells. If n is not a product of
+            // the grid size, some cells of the last row are never used.
+            let cols = (nf.sqrt().ceil()) as u32;
+            let rows = (n + cols - 1) / cols;
+            let size = (1.0 / cols as f32, 1.0 / rows as f32);
+            let cells = (0..n).map(|i| ((i % cols) as f32 * size.0, (i / cols) as f32 * size.1)).collect();
+            (cells, size)
+        }
+        AaPattern::RotatedGrid => {
+            // A k by k grid with basis vectors (k, 1) / n and (1, -k) / n,
+            // which are orthogonal and of equal length. Sample a * k + b is
+            // in column a * k + b and row (k - 1 - b) * k + a, so every row
+            // and column has exactly one sample.
+            let k = (nf.sqrt().round()) as u32;
+            assert_eq!(k * k, n, "a rotated grid needs a square number of samples");
+            let cells = (0..n).map(|i| {
+                let (a, b) = (i / k, i % k);
+                let col = i;
+                let row = (k - 1 - b) * k + a;
+                ((col as f32 + 0.5) / nf, (row as f32 + 0.5) / nf)
+            }).collect();
+            (cells, (0.0, 0.0))
+        }
+        AaPattern::Poisson => {
+            // Mitchell's best candidate algorithm: of a number of random
+            // candidates, keep the one farthest from the samples so far. Wrap
+            // around the pixel, so the pattern also tiles well.
+            let mut rng = Rng::from_u64(n as u64);
+            let mut cells: Vec<(f32, f32)> = Vec::with_capacity(n as usize);
+            let wrapped = |d: f32| d.abs().min(1.0 - d.abs());
+            for i in 0..n {
+                let mut best = (0.0, 0.0);
+                let mut best_dist = -1.0;
+                for _ in 0..(i + 1) {
+                    let (u, v) = rng.sample_unit_pair();
+                    for lane in 0..8 {
+                        let c = (u.get_coord(lane), v.get_coord(lane));
+                        let dist = cells.iter().fold(::std::f32::INFINITY, |acc, &(x, y)| {
+                            let (dx, dy) = (wrapped(c.0 - x), wrapped(c.1 - y));
+                            acc.min(dx * dx + dy * dy)
+                        });
+                        if dist > best_dist {
+                            best = c;
+                            best_dist = dist;
+                        }
+                    }
+                }
+                cells.push(best);
+            }
+            (cells, (0.0, 0.0))
+        }
+    }
+}
+
+/// Returns `n` sub-pixel sample positions in [0, 1) for the pattern. For the
+/// jittered pattern, the positions within the cells are drawn from the rng.
+pub fn aa_sample_positions(pattern: AaPattern, n: u32, rng: &mut Rng) -> Vec<(f32, f32)> {
+    let (cells, (w, h)) = aa_cells(pattern, n);
+    cells.iter().map(|&(x, y)| {
+        let (u, v) = rng.sample_unit_pair();
+        (x + u.get_coord(0) * w, y + v.get_coord(0) * h)
+    }).collect()
+}
+
+#[test]
+fn blue_noise_tile_is_a_permutation() {
+    let tile = blue_noise_tile();
+    let mut ranks: Vec<u32> = tile.iter().map(|&v| (v * 256.0) as u32).collect();
+    ranks.sort();
+    assert_eq!(ranks, (0..256).collect::<Vec<u32>>());
+
+    // Neighboring pixels never have almost the same value.
+    let n = BLUE_NOISE_SIZE as usize;
+    for y in 0..n {
+        for x in 0..n {
+            let right = tile[y * n + (x + 1) % n];
+            let below = tile[((y + 1) % n) * n + x];
+            let v = tile[y * n + x];
+            assert!((v - right).abs() > 1.5 / 256.0 && (v - below).abs() > 1.5 / 256.0);
+        }
+    }
+}
+
+#[test]
+fn temporal_dither_averages_to_undithered_value() {
+    let mut renderer = Renderer::new(bench::caustic_scene(), 16, 16);
+
+    // A display value that lies between two 8-bit steps.
+    let value = 0.1234;
+    let data = generate_slice8(|_| MPixelData {
+        color: MVector3::new(Mf32::broadcast(value * 0.5), Mf32::zero(), Mf32::broadcast(0.5)),
+        coverage: Mf32::one(),
+        tex_index: Mi32::zero(),
+        tex_coords: (Mf32::zero(), Mf32::zero()),
+        fresnel: Mf32::zero(),
+        aovs: [MVector3::zero(); 4],
+        albedo: MVector3::zero(),
+    });
+    let mean_error = |renderer: &Renderer| {
+        let num_frames = 256;
+        let mut sums = vec![0.0f32; 64];
+        let mut bitmap = vec![Mi32::zero(); 32];
+        for frame in 0..num_frames {
+            renderer.store_pixels_color_16x4(&mut bitmap, 0, 0, frame, &data);
+            for (i, pixels) in bitmap[..8].iter().enumerate() {
+                for lane in 0..8 {
+                    let rgba = pixels.get_coord(lane);
+                    sums[i * 8 + lane] += (rgba & 0xff) as f32;
+                    // Black and white are unaffected by dither.
+                    assert_eq!((rgba >> 8) & 0xff, 0);
+                    assert_eq!((rgba >> 16) & 0xff, 255);
+                }
+            }
+        }
+        sums.iter().map(|s| (s / num_frames as f32 - value * 255.0).abs()).fold(0.0, f32::max)
+    };
+
+    // Without dither, every frame rounds the same way.
+    assert!(mean_error(&renderer) > 0.4);
+
+    renderer.set_dither(true);
+    assert!(mean_error(&renderer) < 0.02);
+}
+
+#[test]
+fn aa_patterns_stay_within_pixel() {
+    let mut rng = Rng::with_seed(2, 5, 7);
+    for &pattern in &[AaPattern::Jittered, AaPattern::RotatedGrid, AaPattern::Poisson] {
+        for &n in &[1, 4, 9, 16] {
+            let positions = aa_sample_positions(pattern, n, &mut rng);
+            assert_eq!(positions.len(), n as usize);
+            for &(x, y) in &positions {
+                assert!(x >= 0.0 && x < 1.0 && y >= 0.0 && y < 1.0,
+                        "{:?} sample ({}, {}) outside the pixel", pattern, x, y);
+            }
+        }
+    }
+}
+
+#[test]
+fn aa_rotated_grid_lies_on_lattice() {
+    let mut rng = Rng::with_seed(2, 5, 7);
+
+    // With 4 samples this is the classic rotated grid supersampling pattern.
+    let rgss = aa_sample_positions(AaPattern::RotatedGrid, 4, &mut rng);
+    assert_eq!(rgss, vec![(0.125, 0.625), (0.375, 0.125), (0.625, 0.875), (0.875, 0.375)]);
+
+    // Every difference between samples is an integer combination of the
+    // basis vectors (3, 1) / 9 and (1, -3) / 9, and no row or column is used
+    // twice.
+    let positions = aa_sample_positions(AaPattern::RotatedGrid, 9, &mut rng);
+    let mut rows: Vec<u32> = positions.iter().map(|&(_, y)| (y * 9.0) as u32).collect();
+    let mut cols: Vec<u32> = positions.iter().map(|&(x, _)| (x * 9.0) as u32).collect();
+    rows.sort();
+    cols.sort();
+    assert_eq!(rows, (0..9).collect::<Vec<u32>>());
+    assert_eq!(cols, (0..9).collect::<Vec<u32>>());
+    for &(x, y) in &positions {
+        let (dx, dy) = ((x - positions[0].0) * 9.0, (y - positions[0].1) * 9.0);
+        // Solve (dx, dy) = a (3, 1) + b (1, -3).
+        let a = (3.0 * dx + dy) / 10.0;
+        let b = (dx - 3.0 * dy) / 10.0;
+        assert!((a - a.round()).abs() < 1e-4 && (b - b.round()).abs() < 1e-4,
+                "({}, {}) is not on the lattice", x, y);
+    }
+}
+
+#[test]
+fn aa_poisson_samples_are_spread_out() {
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let positions = aa_sample_positions(AaPattern::Poisson, 16, &mut rng);
+    for (i, &(x0, y0)) in positions.iter().enumerate() {
+        for &(x1, y1) in &positions[i + 1..] {
+            let d2 = (x1 - x0) * (x1 - x0) + (y1 - y0) * (y1 - y0);
+            assert!(d2 > 0.1 * 0.1, "samples ({}, {}) and ({}, {}) are too close", x0, y0, x1, y1);
+        }
+    }
+}
+
+#[test]
+fn render_buffer_into_bitmap() {
+    let render_buffer = RenderBuffer::new(1280, 736);
+    let bitmap = render_buffer.into_bitmap();
+    drop(bitmap);
+    let render_buffer = RenderBuffer::new(1280, 736);
+    let _bitmap = render_buffer.into_bitmap();
+    // The render buffer was transmuted or copied into a vector of pixels, and
+    // dropping the vector at this point should not result in a crash.
+}
+
+#[test]
+fn hdr_buffer_into_hdr_f32_preserves_radiance() {
+    // A value that cannot be represented with 8 bits per channel.
+    let radiance = MVector3::new(Mf32::broadcast(3.14159), Mf32::broadcast(0.001234), Mf32::broadcast(17.5));
+    let mut hdr = HdrBuffer::new(32, 8);
+    for block in unsafe { hdr.get_mut_slice() } {
+        *block = generate_slice8(|_| radiance * Mf32::broadcast(2.0));
+    }
+    hdr.inc_num_samples();
+    hdr.inc_num_samples();
+
+    let rgbs = hdr.into_hdr_f32();
+    assert_eq!(rgbs.len(), 32 * 8 * 3);
+    for rgb in rgbs.chunks(3) {
+        assert_eq!(rgb, &[3.14159, 0.001234, 17.5]);
+    }
+}
+
+#[test]
+fn hdr_buffer_into_hdr_f32_is_row_major() {
+    // Store the pixel coordinates of every sample as its color, using the
+    // same pixel coordinates that are used for rendering, and then check that
+    // every pixel ends up in the right place.
+    let (width, height) = (32, 8);
+    let renderer = Renderer::new(bench::caustic_scene(), width, height);
+    let hdr = renderer.new_buffer_f32();
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let scale = Mf32::broadcast(width as f32 * 0.5);
+
+    for j in 0..(height / 4) {
+        for i in 0..(width / 16) {
+            let (xs, ys) = renderer.get_pixel_coords_16x4(i * 16, j * 4, 0, &mut rng);
+            let block = generate_slice8(|k| {
+                let px = xs[k].mul_add(scale, Mf32::broadcast(width as f32 * 0.5));
+                let py = ys[k].mul_add(scale, Mf32::broadcast(height as f32 * 0.5));
+                MVector3::new(px.map(|x| x.floor()), py.map(|y| y.floor()), Mf32::zero())
+            });
+            unsafe { hdr.get_mut_slice()[(j * (width / 16) + i) as usize] = block; }
+        }
+    }
+
+    let rgbs = hdr.into_hdr_f32();
+    for y in 0..height {
+        for x in 0..width {
+            let i = ((y * width + x) * 3) as usize;
+            assert_eq!((rgbs[i], rgbs[i + 1]), (x as f32, y as f32));
+        }
+    }
+}
+
+#[test]
+fn camera_moved_detects_orbit_but_not_fixed_time() {
+    let mut renderer = Renderer::new(bench::caustic_scene(), 32, 32);
+    renderer.set_time(1.0, 0.0);
+    renderer.update_scene();
+    renderer.update_scene();
+    assert!(!renderer.camera_moved());
+
+    // The built-in orbit moves the camera as time passes.
+    renderer.set_time(2.0, 0.0);
+    renderer.update_scene();
+    assert!(renderer.camera_moved());
+}
+
+#[test]
+fn accumulation_halts_at_max() {
+    let (width, height) = (32, 32);
+    let mut renderer = Renderer::new(bench::caustic_scene(), width, height);
+    renderer.set_max_accumulation(3);
+    let mut hdr = renderer.new_buffer_f32();
+    let gbuffer = RenderBuffer::new(width, height);
+    let gbuffer_slice = unsafe { gbuffer.get_mut_slice() };
+
+    let mut snapshot = Vec::new();
+    for frame in 1..7 {
+        let converged = renderer.is_converged(&hdr);
+        unsafe { renderer.accumulate_patch_f32(&hdr, gbuffer_slice, 32, 0, 0, frame) };
+        if !converged {
+            hdr.inc_num_samples();
+        }
+        if frame == 3 {
+            snapshot = hdr.as_slice().to_vec();
+        }
+    }
+
+    assert!(renderer.is_converged(&hdr));
+    assert_eq!(hdr.num_samples(), 3);
+    assert!(hdr.as_slice() == &snapshot[..], "the image should not change after convergence");
+}
+
+#[test]
+fn adaptive_sampling_stops_flat_region_early() {
+    // Look away from the wall, so the top half of the screen shows only the
+    // smooth sky, and the bottom half shows the path traced floor.
+    let (width, height) = (32, 32);
+    let mut renderer = Renderer::new(bench::caustic_scene(), width, height);
+    renderer.scene.camera_mut().set_rotation(consts::PI, 0.0);
+    renderer.set_adaptive_sampling(Some(0.02));
+    let mut hdr = renderer.new_buffer_f32();
+    let gbuffer = RenderBuffer::new(width, height);
+    let gbuffer_slice = unsafe { gbuffer.get_mut_slice() };
+
+    let num_frames = 48;
+    for frame in 0..num_frames {
+        unsafe { renderer.accumulate_patch_f32(&hdr, gbuffer_slice, 32, 0, 0, frame) };
+        hdr.inc_num_samples();
+    }
+
+    let counts = hdr.pixel_sample_counts();
+    let sky_row = &counts[((height - 1) * width) as usize..];
+    let floor_row = &counts[..width as usize];
+    assert!(sky_row.iter().all(|&n| n == MIN_ADAPTIVE_SAMPLES), "sky samples: {:?}", sky_row);
+    assert!(floor_row.iter().all(|&n| n == num_frames), "floor samples: {:?}", floor_row);
+
+    // The sky did not change after it converged, so its mean is still right.
+    let rgbs = hdr.into_hdr_f32();
+    let i = (((height - 1) * width) * 3) as usize;
+    assert!(rgbs[i] > 0.0 && rgbs[i].is_finite());
+}
+
+#[test]
+fn adaptive_sampling_never_stops_black_pixel_early() {
+    // A pixel where no path found light yet has zero mean and zero variance,
+    // but it may still be a caustic that is rarely sampled.
+    let mut stats = MWelford::new();
+    for _ in 0..1000 {
+        stats.insert(Mf32::zero(), Mf32::zero());
+    }
+    assert!(stats.is_converged(0.02).all_sign_bits_positive());
+
+    // Equal nonzero samples do converge, once there are enough of them.
+    let mut stats = MWelford::new();
+    for i in 0..MIN_ADAPTIVE_SAMPLES {
+        assert!(stats.is_converged(0.02).all_sign_bits_positive(), "converged after {} samples", i);
+        stats.insert(Mf32::one(), Mf32::zero());
+    }
+    assert!(stats.is_converged(0.02).all_sign_bits_negative());
+}
+
+#[test]
+fn aovs_add_up_to_full_image() {
+    let (width, height) = (32, 32);
+    let renderer = Renderer::new(bench::caustic_scene(), width, height);
+    let mut hdr = HdrBuffer::with_aovs(width, height);
+    let gbuffer = RenderBuffer::new(width, height);
+    let gbuffer_slice = unsafe { gbuffer.get_mut_slice() };
+
+    for frame in 0..4 {
+        unsafe { renderer.accumulate_patch_f32(&hdr, gbuffer_slice, 32, 0, 0, frame) };
+        hdr.inc_num_samples();
+    }
+
+    let aovs: Vec<Vec<f32>> = AOVS.iter().map(|&aov| hdr.resolve_aov_f32(aov)).collect();
+    let rgbs = hdr.into_hdr_f32();
+    for (i, &beauty) in rgbs.iter().enumerate() {
+        let sum: f32 = aovs.iter().map(|aov| aov[i]).sum();
+        assert!((sum - beauty).abs() <= 1e-5 * (1.0 + beauty), "aovs sum to {}, but image is {}", sum, beauty);
+    }
+
+    // The scene has both direct and indirect light.
+    let total = |aov: Aov| aovs[aov as usize].iter().sum::<f32>();
+    assert!(total(Aov::DirectDiffuse) > 0.0);
+    assert!(total(Aov::IndirectDiffuse) > 0.0);
+}
+
+#[test]
+fn render_frame_auto_matches_explicit_pool() {
+    let (width, height) = (64, 48);
+    let renderer = Renderer::new(bench::caustic_scene(), width, height);
+
+    let mut bitmap_auto = RenderBuffer::new(width, height);
+    let mut gbuffer_auto = RenderBuffer::new(width, height);
+    let num_threads = renderer.render_frame_auto(&mut bitmap_auto, &mut gbuffer_auto, 3);
+    assert!(num_threads >= 1);
+
+    // The height is not a multiple of 32, so the patches are 16 wide.
+    let mut bitmap = RenderBuffer::new(width, height);
+    let mut gbuffer = RenderBuffer::new(width, height);
+    let cancel = AtomicBool::new(false);
+    renderer.render_frame_parallel(&mut Pool::new(1), &mut bitmap, &mut gbuffer, 16, 3, &cancel);
+
+    assert_eq!(bitmap_auto.into_bitmap(), bitmap.into_bitmap());
+    assert_eq!(gbuffer_auto.into_bitmap(), gbuffer.into_bitmap());
+}
+
+#[test]
+fn render_frame_parallel_stops_when_cancelled() {
+    let (width, height, patch_width) = (64, 32, 16);
+    let num_patches = (width / patch_width) * (height / patch_width);
+    let renderer = Renderer::new(bench::caustic_scene(), width, height);
+    let mut bitmap = RenderBuffer::new(width, height);
+    let mut gbuffer = RenderBuffer::new(width, height);
+    let mut pool = Pool::new(4);
+
+    let cancel = AtomicBool::new(false);
+    let n = renderer.render_frame_parallel(&mut pool, &mut bitmap, &mut gbuffer, patch_width, 1, &cancel);
+    assert_eq!(n, num_patches);
+    let pixels = |bitmap: &RenderBuffer| -> Vec<i32> {
+        bitmap.as_slice().iter().flat_map(|x| (0..8).map(move |k| x.get_coord(k))).collect()
+    };
+    let previous = pixels(&bitmap);
+
+    // When the flag is set, nothing is rendered at all, and the skipped
+    // patches keep the previous frame.
+    cancel.store(true, Ordering::SeqCst);
+    let n = renderer.render_frame_parallel(&mut pool, &mut bitmap, &mut gbuffer, patch_width, 2, &cancel);
+    assert_eq!(n, 0);
+    assert!(previous == pixels(&bitmap), "a cancelled frame must not change the bitmap");
+}
+
+#[test]
+fn alpha_channel_contains_coverage() {
+    // Look away from the wall, so the top half of the screen shows only sky,
+    // and the bottom row shows the floor.
+    let (width, height) = (32, 32);
+    let mut renderer = Renderer::new(bench::caustic_scene(), width, height);
+    renderer.scene.camera_mut().set_rotation(consts::PI, 0.0);
+    let bitmap = RenderBuffer::new(width, height);
+    let gbuffer = RenderBuffer::new(width, height);
+    unsafe {
+        renderer.render_patch_u8(bitmap.get_mut_slice(), gbuffer.get_mut_slice(), 32, 0, 0, 1);
+    }
+
+    let rgbas = bitmap.into_bitmap();
+    let alpha = |x: u32, y: u32| rgbas[((y * width + x) * 4 + 3) as usize];
+    assert_eq!(alpha(16, height - 1), 0);
+    assert_eq!(alpha(16, 0), 255);
+}
+
+#[test]
+fn facing_mirrors_respect_bounce_cap() {
+    use scene::Scene;
+    use material::SMaterial;
+
+    // A closed box of glossy walls around the camera, where every pair of
+    // opposite walls faces each other like mirrors. No path can bounce out. The
+    // lights outside of the box are there only because direct sampling needs
+    // exactly 8 triangles.
+    let v = SVector3::new;
+    let mirror = SMaterial::white().with_glossiness(5);
+    let mut quads = bench::box_quads(v(-1.0, -1.0, -1.0), v(1.0, 1.0, 1.0), mirror);
+    for quad in &mut quads {
+        // Flip the winding so the normals point into the box.
+        quad.0.reverse();
+    }
+    for i in 0..4 {
+        let x0 = -2.0 + i as f32;
+        let x1 = x0 + 1.0;
+        quads.push(([v(x0, 5.0, -0.5), v(x0, 5.0, 0.5), v(x1, 5.0, 0.5), v(x1, 5.0, -0.5)], SMaterial::sky()));
+    }
+    let scene = Scene::from_meshes(&[bench::mesh_from_quads(&quads)]);
+
+    let (width, height) = (32, 32);
+    let mut renderer = Renderer::new(scene, width, height);
+    let bitmap = RenderBuffer::new(width, height);
+    let gbuffer = RenderBuffer::new(width, height);
+
+    for &max_bounces in &[0, 1, 198] {
+        renderer.set_max_bounces(max_bounces);
+        unsafe {
+            renderer.render_patch_u8(bitmap.get_mut_slice(), gbuffer.get_mut_slice(), 32, 0, 0, 1);
+        }
+
+        // Every path stays inside the box, so nearly all of them are cut off.
+        // Some paths sample the light through a wall, which ends them with
+        // zero contribution.
+        if cfg!(debug_assertions) {
+            let hits = renderer.take_bounce_cap_hits();
+            let num_pixels = (width * height) as usize;
+            assert!(hits > 0);
+            assert!(hits <= num_pixels);
+        }
+
+        // Either way, no light reaches the camera.
+        for pixels in unsafe { bitmap.get_mut_slice() } {
+            for i in 0..8 {
+                assert_eq!(pixels.get_coord(i) & 0xffffff, 0);
+            }
+        }
+    }
+}
+
+#[test]
+fn light_linking_excludes_unlinked_object() {
+    use scene::Scene;
+    use material::SMaterial;
+
+    // The camera looks at a wall that consists of two objects side by side,
+    // lit by a light behind the camera. The light is linked only to the left
+    // object. A black box around everything keeps out the sky, and the two
+    // objects are coplanar, so they cannot reflect light onto each other.
+    let v = SVector3::new;
+    let render = |right_links: u32| {
+        let mut enclosure = bench::box_quads(v(-3.0, -3.0, -3.0), v(3.0, 3.0, 3.0), SMaterial::diffuse(0.0, 0.0, 0.0));
+        for quad in &mut enclosure {
+            quad.0.reverse();
+        }
+        let mut lights = Vec::new();
+        for i in 0..4 {
+            let x0 = -2.0 + i as f32;
+            let x1 = x0 + 1.0;
+            lights.push(([v(x0, -0.5, 2.5), v(x0, 0.5, 2.5), v(x1, 0.5, 2.5), v(x1, -0.5, 2.5)], SMaterial::sky()));
+        }
+        let wall = |x0: f32, x1: f32| vec![([v(x0, -2.0, -2.0), v(x1, -2.0, -2.0), v(x1, 2.0, -2.0), v(x0, 2.0, -2.0)],
+                                             SMaterial::white())];
+        let mut light_mesh = bench::mesh_from_quads(&lights);
+        let mut left = bench::mesh_from_quads(&wall(-2.0, 0.0));
+        let mut right = bench::mesh_from_quads(&wall(0.0, 2.0));
+        light_mesh.light_links = 1;
+        left.light_links = 1;
+        right.light_links = right_links;
+        let meshes = [bench::mesh_from_quads(&enclosure), light_mesh, left, right];
+        let scene = Scene::from_meshes(&meshes);
+
+        let (width, height) = (32, 32);
+        let renderer = Renderer::new(scene, width, height);
+        let gbuffer = RenderBuffer::new(width, height);
+        let mut brightness = [0u32; 2];
+        // Start at frame 1: the random state for frame 0 at pixel (0, 0) is
+        // all zeros.
+        for frame in 1..5 {
+            let bitmap = RenderBuffer::new(width, height);
+            unsafe {
+                renderer.render_patch_u8(bitmap.get_mut_slice(), gbuffer.get_mut_slice(), 32, 0, 0, frame);
+            }
+            let rgbas = bitmap.into_bitmap();
+            for y in 0..height {
+                // Skip the columns near the seam between the objects.
+                for x in (0..12).chain(20..32) {
+                    let i = ((y * width + x) * 4) as usize;
+                    let sum = rgbas[i] as u32 + rgbas[i + 1] as u32 + rgbas[i + 2] as u32;
+                    brightness[(x / 16) as usize] += sum;
+                }
+            }
+        }
+        brightness
+    };
+
+    let unlinked = render(2);
+    assert!(unlinked[0] > 0, "the linked object should be lit");
+    assert_eq!(unlinked[1], 0, "the unlinked object should receive no light");
+
+    // Linking the right object too lights it up.
+    let linked = render(1 | 2);
+    assert!(linked[1] > 0);
+}
+
+#[test]
+fn render_normals_in_world_and_view_space() {
+    use scene::Scene;
+    use material::SMaterial;
+
+    // Two walls: one in front of the camera facing it, and one to the side.
+    let v = SVector3::new;
+    let quads = vec![
+        ([v(-9.0, -9.0, -2.0), v(9.0, -9.0, -2.0), v(9.0, 9.0, -2.0), v(-9.0, 9.0, -2.0)], SMaterial::white()),
+        ([v(2.0, -9.0, -9.0), v(2.0, -9.0, 9.0), v(2.0, 9.0, 9.0), v(2.0, 9.0, -9.0)], SMaterial::white()),
+    ];
+    let scene = Scene::from_meshes(&[bench::mesh_from_quads(&quads)]);
+    let mut renderer = Renderer::new(scene, 16, 16);
+    // Look near the center, but not on the diagonal, where the triangles of
+    // the walls meet.
+    let center = |normals: &[f32]| {
+        let i = (5 * 16 + 10) * 3;
+        v(normals[i], normals[i + 1], normals[i + 2])
+    };
+    let near = |a: SVector3, b: SVector3| (a - b).norm_squared() < 1e-6;
+
+    // With the default camera, world and view space coincide.
+    let world = center(&renderer.render_normals_f32(NormalSpace::World));
+    let view = center(&renderer.render_normals_f32(NormalSpace::View));
+    assert!(near(world, v(0.0, 0.0, 1.0)), "unexpected world normal {:?}", world);
+    assert!(near(view, v(0.0, 0.0, 1.0)), "unexpected view normal {:?}", view);
+
+    // Turned towards the side wall, the world normal changes, but the wall
+    // still faces the camera in view space.
+    renderer.scene.camera_mut().set_rotation(consts::PI * 0.5, 0.0);
+    let world = center(&renderer.render_normals_f32(NormalSpace::World));
+    let view = center(&renderer.render_normals_f32(NormalSpace::View));
+    assert!(near(world, v(-1.0, 0.0, 0.0)), "unexpected world normal {:?}", world);
+    assert!(near(view, v(0.0, 0.0, 1.0)), "unexpected view normal {:?}", view);
+}
+
+#[test]
+fn render_motion_vectors_follow_moving_mesh() {
+    use graph::{NodeContent, SceneGraph};
+    use scene::Scene;
+    use material::SMaterial;
+    use transform::SAffine;
+
+    // A static wall, and a small quad in front of it in the center.
+    let v = SVector3::new;
+    let wall = vec![([v(-9.0, -9.0, -5.0), v(9.0, -9.0, -5.0), v(9.0, 9.0, -5.0), v(-9.0, 9.0, -5.0)],
+                     SMaterial::white())];
+    let quad = vec![([v(-0.5, -0.5, -2.0), v(0.5, -0.5, -2.0), v(0.5, 0.5, -2.0), v(-0.5, 0.5, -2.0)],
+                     SMaterial::white())];
+    let render = |motion: SVector3| {
+        // The quad hangs in a scene graph, and moved by `motion` since the
+        // previous frame.
+        let meshes = [bench::mesh_from_quads(&wall), bench::mesh_from_quads(&quad)];
+        let mut graph = SceneGraph::new();
+        graph.add_root(NodeContent::Mesh(0), SAffine::identity());
+        let node = graph.add_root(NodeContent::Mesh(1), SAffine::translation(-motion));
+        graph.retain_transforms();
+        graph.set_transform(node, SAffine::identity());
+
+        let mut scene = Scene::from_meshes(&graph.bake_meshes(&meshes));
+        scene.set_mesh_motion(graph.motion_transforms());
+        let renderer = Renderer::new(scene, 32, 32);
+        renderer.render_motion_vectors_f32()
+    };
+
+    // Without motion, nothing moves.
+    let vectors = render(SVector3::zero());
+    assert!(vectors.iter().all(|d| d.abs() < 1e-3));
+
+    // When the quad moved to the right, its pixels came from the left. The
+    // quad covers the pixels within 6.8 pixels from the center.
+    let vectors = render(v(0.5, 0.0, 0.0));
+    for y in 0..32 {
+        for x in 0..32 {
+            let (dx, dy) = (vectors[(y * 32 + x) * 2], vectors[(y * 32 + x) * 2 + 1]);
+            assert!(dy.abs() < 1e-3);
+            if x < 9 || x > 22 || y < 9 || y > 22 {
+                assert!(dx.abs() < 1e-3, "pixel ({}, {}) off the quad moved by {}", x, y, dx);
+            }
+        }
+    }
+    // Sample below the diagonal of the quad, where the triangles meet.
+    let dx = vectors[(13 * 32 + 19) * 2];
+    assert!((dx + 6.8).abs() < 0.1, "expected the quad to move 6.8 pixels, not {}", -dx);
+}
+
+#[test]
+fn update_scene_animates_scene_graph_nodes() {
+    use animation::{Animation, Interpolation, Target, Track};
+    use graph::NodeContent;
+    use material::SMaterial;
+    use scene::Scene;
+    use transform::SAffine;
+
+    // A static wall, and a small quad in front of it that moves to the right.
+    let v = SVector3::new;
+    let wall = vec![([v(-9.0, -9.0, -5.0), v(9.0, -9.0, -5.0), v(9.0, 9.0, -5.0), v(-9.0, 9.0, -5.0)],
+                     SMaterial::white())];
+    let quad = vec![([v(-0.5, -0.5, -2.0), v(0.5, -0.5, -2.0), v(0.5, 0.5, -2.0), v(-0.5, 0.5, -2.0)],
+                     SMaterial::white())];
+    let meshes = vec![bench::mesh_from_quads(&wall), bench::mesh_from_quads(&quad)];
+    let mut graph = SceneGraph::new();
+    graph.add_root(NodeContent::Mesh(0), SAffine::identity());
+    let node = graph.add_root(NodeContent::Mesh(1), SAffine::identity());
+    let scene = Scene::from_meshes(&graph.bake_meshes(&meshes));
+
+    let mut track = Track::new(Interpolation::Linear);
+    track.add_keyframe(0.0, SVector3::zero());
+    track.add_keyframe(1.0, v(1.0, 0.0, 0.0));
+    let mut animation = Animation::new();
+    animation.add_position_track(Target::Node(node), track);
+
+    let mut renderer = Renderer::new(scene, 32, 32);
+    renderer.set_animation(Some(animation));
+    renderer.set_scene_graph(graph, meshes);
+    renderer.update_scene();
+    renderer.set_time(0.5, 0.0);
+    renderer.update_scene();
+
+    // The triangles of the quad moved half a unit to the right.
+    let distance = |x: f32| {
+        let ray = MRay::new(MVector3::broadcast(v(x, 0.0, 0.0)), MVector3::broadcast(v(0.0, 0.0, -1.0)));
+        renderer.scene().intersect_nearest(&ray).distance.0
+    };
+    assert!((distance(0.8) - 2.0).abs() < 1e-3, "distance is {}", distance(0.8));
+    assert!((distance(-0.3) - 5.0).abs() < 1e-3, "distance is {}", distance(-0.3));
+
+    // And the motion vectors point back to where it was in the previous
+    // frame, 6.8 pixels to the left.
+    let vectors = renderer.render_motion_vectors_f32();
+    let dx = vectors[(13 * 32 + 19) * 2];
+    assert!((dx + 6.8).abs() < 0.1, "expected the quad to move 6.8 pixels, not {}", -dx);
+    assert!(vectors[(13 * 32 + 2) * 2].abs() < 1e-3);
+}
+
+#[test]
+fn glass_preset_refracts_camera_rays() {
+    use scene::Scene;
+    use material::{Material, SMaterial};
+
+    // A slab of the glass preset between the camera and a light.
+    let v = SVector3::new;
+    let glass = Material::preset("glass").unwrap().to_smaterial();
+    let mut quads = bench::box_quads(v(-2.0, -2.0, -3.0), v(2.0, 2.0, -2.0), glass);
+    quads.push(([v(-9.0, -9.0, -6.0), v(9.0, -9.0, -6.0), v(9.0, 9.0, -6.0), v(-9.0, 9.0, -6.0)], SMaterial::sky()));
+    let scene = Scene::from_meshes(&[bench::mesh_from_quads(&quads)]);
+    let renderer = Renderer::new(scene, 16, 16);
+
+    // The camera sees the glass itself, not the light behind it.
+    let info = renderer.render_single_ray(v(0.3, 0.2, 0.0), v(0.0, 0.0, -1.0));
+    assert!(info.bounces.len() >= 2);
+    let hit = &info.bounces[0];
+    assert_eq!((hit.material >> 29) & 1, 1, "the first surface should be glass");
+    assert!((hit.distance - 2.0).abs() < 1e-4);
+
+    // Whether the glass reflects the sky or transmits the light, it is not
+    // black.
+    let c = info.color;
+    assert!(c.x > 0.1 && c.y > 0.1 && c.z > 0.1, "glass rendered as {}", c);
+}
+
+#[test]
+fn area_lights_are_sampled_and_visible() {
+    use light::{AreaLight, EmissionMap};
+    use material::SMaterial;
+    use scene::Scene;
+
+    // The camera looks at a white wall inside a black box. The only emissive
+    // triangles are sealed off behind the wall, so without area lights the
+    // wall is black.
+    let v = SVector3::new;
+    let mut enclosure = bench::box_quads(v(-3.0, -3.0, -3.0), v(3.0, 3.0, 3.0), SMaterial::diffuse(0.0, 0.0, 0.0));
+    for quad in &mut enclosure {
+        quad.0.reverse();
+    }
+    let wall = [([v(-3.0, -3.0, -2.0), v(3.0, -3.0, -2.0), v(3.0, 3.0, -2.0), v(-3.0, 3.0, -2.0)],
+                 SMaterial::white())];
+    let mut hidden = Vec::new();
+    for i in 0..4 {
+        let x0 = -2.0 + i as f32;
+        let x1 = x0 + 1.0;
+        hidden.push(([v(x0, -0.5, -2.5), v(x0, 0.5, -2.5), v(x1, 0.5, -2.5), v(x1, -0.5, -2.5)], SMaterial::sky()));
+    }
+    let meshes = [bench::mesh_from_quads(&enclosure),
+                  bench::mesh_from_quads(&wall),
+                  bench::mesh_from_quads(&hidden)];
+
+    let render = |scene: Scene| {
+        let (width, height) = (32, 32);
+        let renderer = Renderer::new(scene, width, height);
+        let gbuffer = RenderBuffer::new(width, height);
+        let mut brightness = 0;
+        for frame in 1..3 {
+            let bitmap = RenderBuffer::new(width, height);
+            unsafe {
+                renderer.render_patch_u8(bitmap.get_mut_slice(), gbuffer.get_mut_slice(), 32, 0, 0, frame);
+            }
+            let rgbas = bitmap.into_bitmap();
+            for (i, &c) in rgbas.iter().enumerate() {
+                if i % 4 != 3 {
+                    brightness += c as u32;
+                }
+            }
+        }
+        (renderer, brightness)
+    };
+
+    let (_, dark) = render(Scene::from_meshes(&meshes));
+    assert_eq!(dark, 0, "the wall should receive no light without area lights");
+
+    // A light behind the camera, facing the wall, lights it up. A second,
+    // small light in front of the wall faces the camera.
+    let mut scene = Scene::from_meshes(&meshes);
+    scene.add_area_light(AreaLight::new(v(-0.5, 0.5, 2.5), v(1.0, 0.0, 0.0), v(0.0, -1.0, 0.0),
+                                        EmissionMap::constant(v(4.0, 4.0, 4.0))));
+    let radiance = v(0.8, 0.4, 0.2);
+    scene.add_area_light(AreaLight::new(v(1.0, 1.0, -1.5), v(0.2, 0.0, 0.0), v(0.0, 0.2, 0.0),
+                                        EmissionMap::constant(radiance)));
+    let (renderer, lit) = render(scene);
+    assert!(lit > 0, "the area light should light the wall");
+
+    // The camera sees the emission of the small light directly.
+    let info = renderer.render_single_ray(v(1.1, 1.1, 0.0), v(0.0, 0.0, -1.0));
+    assert!((info.color - radiance).norm_squared() < 1e-10, "color is {}", info.color);
+}
+
+#[test]
+fn area_light_profile_shapes_rendered_radiance() {
+    use ies;
+    use light::{AreaLight, EmissionMap};
+    use material::SMaterial;
+    use scene::Scene;
+
+    // A large light in front of the camera, facing it, above a floor that
+    // the ray does not hit. The profile is bright along the axis of the
+    // light and dim to the side.
+    let v = SVector3::new;
+    let floor = [([v(-9.0, -9.0, 9.0), v(9.0, -9.0, 9.0), v(9.0, -9.0, -9.0), v(-9.0, -9.0, -9.0)],
+                  SMaterial::white())];
+    let mut scene = Scene::from_meshes(&[bench::mesh_from_quads(&floor)]);
+    let mut light = AreaLight::new(v(-5.0, -5.0, -2.0), v(10.0, 0.0, 0.0), v(0.0, 10.0, 0.0),
+                                   EmissionMap::constant(v(1.0, 1.0, 1.0)));
+    light.set_profile(Some(ies::spot_profile()));
+    scene.add_area_light(light);
+    let renderer = Renderer::new(scene, 16, 16);
+
+    // Along the axis the light has the radiance of its emission map. At 60
+    // degrees the profile has 0.367 of its peak intensity, spread over half
+    // the projected area.
+    let on_axis = renderer.render_single_ray(SVector3::zero(), v(0.0, 0.0, -1.0));
+    assert!((on_axis.color.x - 1.0).abs() < 1e-5, "color is {}", on_axis.color);
+    let oblique = renderer.render_single_ray(SVector3::zero(), v(0.75f32.sqrt(), 0.0, -0.5));
+    assert!((oblique.color.x - 0.3667 / 0.5).abs() < 1e-3, "color is {}", oblique.color);
+}
+
+#[test]
+fn subsurface_blur_softens_only_subsurface_pixels() {
+    use material::SMaterial;
+    use scene::Scene;
+
+    // The camera looks at a wall that is translucent in the left half of the
+    // view, and diffuse in the right half. The translucent material is
+    // textured, which does not affect its profile.
+    let v = SVector3::new;
+    let translucent = SMaterial::white().with_texture(1);
+    let walls = [([v(-9.0, -9.0, -2.0), v(0.0, -9.0, -2.0), v(0.0, 9.0, -2.0), v(-9.0, 9.0, -2.0)],
+                  translucent),
+                 ([v(0.0, -9.0, -2.0), v(9.0, -9.0, -2.0), v(9.0, 9.0, -2.0), v(0.0, 9.0, -2.0)],
+                  SMaterial::white())];
+    let (width, height) = (32, 8);
+    let mut renderer = Renderer::new(Scene::from_meshes(&[bench::mesh_from_quads(&walls)]), width, height);
+
+    // A sharp light boundary in both halves.
+    let w = width as usize;
+    let fill = |hdr: &HdrBuffer| {
+        for block_index in 0..(w / 16) * (height as usize / 4) {
+            let block = generate_slice8(|k| MVector3::generate(|lane| {
+                let x = pixel_index(w, block_index, k, lane) % w;
+                if x < 8 || (x >= 16 && x < 24) { SVector3::one() } else { SVector3::zero() }
+            }));
+            unsafe { hdr.get_mut_slice()[block_index] = block; }
+        }
+    };
+    let hdr = renderer.new_buffer_f32();
+    fill(&hdr);
+    assert!(renderer.blur_subsurface(&hdr).is_none());
+
+    renderer.scene_mut().set_subsurface(translucent, v(0.5, 0.5, 0.5));
+    let mask = renderer.subsurface_mask().unwrap();
+    assert_eq!(mask.profiles[4 * w + 2], Some(0));
+    assert_eq!(mask.profiles[4 * w + 29], None);
+    let footprint = mask.footprints[4 * w + 2];
+    assert!(footprint > 0.03 && footprint < 0.2, "footprint is {}", footprint);
+
+    // The mask is computed on the first resolve, and kept with the buffer.
+    let hdr = renderer.new_buffer_f32();
+    fill(&hdr);
+    let blurred = renderer.blur_subsurface(&hdr).unwrap();
+    let at = |x: usize, y: usize| {
+        let block = &blurred[(y / 4) * (w / 16) + x / 16];
+        block[((x % 16) / 4) * 2 + (y % 4) / 2].x.get_coord((y % 2) * 4 + x % 4)
+    };
+
+    // Light spreads across the boundary of the translucent half, but not
+    // across the boundary of the diffuse half.
+    assert!(at(8, 4) > 0.1, "shadow side of translucent boundary is {}", at(8, 4));
+    assert!(at(7, 4) < 0.9, "lit side of translucent boundary is {}", at(7, 4));
+    assert_eq!(at(23, 4), 1.0);
+    assert_eq!(at(24, 4), 0.0);
+    assert!(hdr.subsurface_mask.lock().unwrap().is_some());
+}
+
+#[test]
+fn render_single_ray_records_first_hit() {
+    use scene::Scene;
+    use material::SMaterial;
+
+    // An orange floor, with lights high above it.
+    let v = SVector3::new;
+    let mut quads = vec![([v(-4.0, 0.0, 4.0), v(4.0, 0.0, 4.0), v(4.0, 0.0, -4.0), v(-4.0, 0.0, -4.0)],
+                          SMaterial::diffuse(0.8, 0.4, 0.2))];
+    for i in 0..4 {
+        let x0 = -2.0 + i as f32;
+        let x1 = x0 + 1.0;
+        quads.push(([v(x0, 5.0, -0.5), v(x0, 5.0, 0.5), v(x1, 5.0, 0.5), v(x1, 5.0, -0.5)], SMaterial::sky()));
+    }
+    let scene = Scene::from_meshes(&[bench::mesh_from_quads(&quads)]);
+    let renderer = Renderer::new(scene, 16, 16);
+
+    let info = renderer.render_single_ray(v(0.5, 1.0, 0.3), v(0.0, -1.0, 0.0));
+    assert!(!info.bounces.is_empty());
+    let hit = &info.bounces[0];
+    assert!((hit.position - v(0.5, 0.0, 0.3)).norm_squared() < 1e-8);
+    assert!((hit.normal - v(0.0, 1.0, 0.0)).norm_squared() < 1e-6);
+    assert!((hit.distance - 1.0).abs() < 1e-4);
+    assert!((hit.albedo - v(0.8, 0.4, 0.2)).norm_squared() < 1e-4);
+    assert_eq!(hit.throughput, v(1.0, 1.0, 1.0));
+    assert_eq!(hit.material >> 31, 0, "the floor is not emissive");
+
+    let c = info.color;
+    assert!(c.x.is_finite() && c.y.is_finite() && c.z.is_finite());
+    assert!(c.x >= 0.0 && c.y >= 0.0 && c.z >= 0.0);
+}
+
+#[test]
+fn tile_sequence_visits_every_tile_once() {
+    for &order in &[TileOrder::RowMajor, TileOrder::Morton, TileOrder::Hilbert] {
+        for &(w, h) in &[(8, 8), (5, 3), (40, 23)] {
+            let mut tiles = tile_sequence(order, w, h);
+            assert_eq!(tiles.len(), (w * h) as usize);
+            tiles.sort();
+            tiles.dedup();
+            assert_eq!(tiles.len(), (w * h) as usize, "{:?} visits a tile twice", order);
+            assert!(tiles.iter().all(|&(i, j)| i < w && j < h));
+        }
+    }
+}
+
+#[test]
+fn tile_sequence_hilbert_is_contiguous() {
+    let tiles = tile_sequence(TileOrder::Hilbert, 16, 16);
+    assert_eq!(tiles[0], (0, 0));
+    for pair in tiles.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        let dist = (a.0 as i32 - b.0 as i32).abs() + (a.1 as i32 - b.1 as i32).abs();
+        assert_eq!(dist, 1, "tiles {:?} and {:?} are not adjacent", a, b);
+    }
+}
+
+#[test]
+fn resize_updates_pixel_coords() {
+    let mut renderer = Renderer::new(bench::caustic_scene(), 32, 16);
+    assert!(!renderer.resize(32, 16));
+    assert!(renderer.resize(64, 32));
+    assert_eq!((renderer.width(), renderer.height()), (64, 32));
+
+    // Every block of pixels maps back to the pixels it covers in an image of
+    // the new size.
+    let (width, height) = (64, 32);
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let scale = Mf32::broadcast(width as f32 * 0.5);
+    for j in 0..(height / 4) {
+        for i in 0..(width / 16) {
+            let (xs, ys) = renderer.get_pixel_coords_16x4(i * 16, j * 4, 0, &mut rng);
+            for k in 0..8 {
+                let px = xs[k].mul_add(scale, Mf32::broadcast(width as f32 * 0.5));
+                let py = ys[k].mul_add(scale, Mf32::broadcast(height as f32 * 0.5));
+                for lane in 0..8 {
+                    let (x, y) = block_pixel(i * 16, j * 4, k, lane);
+                    assert_eq!(px.get_coord(lane).floor(), x as f32);
+                    assert_eq!(py.get_coord(lane).floor(), y as f32);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+#[should_panic(expected = "buffer size does not match the renderer")]
+fn resize_rejects_old_buffer() {
+    let mut renderer = Renderer::new(bench::caustic_scene(), 32, 16);
+    let hdr = renderer.new_buffer_f32();
+    renderer.resize(48, 16);
+    let gbuffer = RenderBuffer::new(48, 16);
+    unsafe { renderer.accumulate_patch_f32(&hdr, gbuffer.get_mut_slice(), 16, 0, 0, 1) };
+}
+
+#[test]
+fn jitter_offsets_all_pixels_equally() {
+    let mut renderer = Renderer::new(bench::caustic_scene(), 64, 64);
+    renderer.set_jitter_frame(Some(3));
+    let (jx, jy) = renderer.jitter().unwrap();
+
+    // Different patches and random states must give the same offset.
+    let mut rng_a = Rng::with_seed(1, 2, 3);
+    let mut rng_b = Rng::with_seed(4, 5, 6);
+    let (xs_a, ys_a) = renderer.get_pixel_coords_16x4(0, 0, 0, &mut rng_a);
+    let (xs_b, ys_b) = renderer.get_pixel_coords_16x4(16, 4, 0, &mut rng_b);
+    let scale = 2.0 / 64.0;
+    for i in 0..8 {
+        let dx_a = xs_a[i].get_coord(0) / scale + 32.0;
+        let dx_b = xs_b[i].get_coord(0) / scale + 32.0 - 16.0;
+        let dy_b = ys_b[i].get_coord(0) / scale + 32.0 - 4.0;
+        let dy_a = ys_a[i].get_coord(0) / scale + 32.0;
+        assert!((dx_a - dx_b).abs() < 1e-3 && (dy_a - dy_b).abs() < 1e-3);
+        assert!((dx_a.fract() - (jx + 0.5)).abs() < 1e-3);
+        assert!((dy_a.fract() - (jy + 0.5)).abs() < 1e-3);
+    }
+}
+
+#[test]
+fn path_regularization_reduces_variance() {
+    // Measures the mean per-pixel variance of the luminance over many frames,
+    // for pixels that look at the floor of the caustic scene.
+    let measure = |factor: f32| {
+        let mut renderer = Renderer::new(bench::caustic_scene(), 64, 64);
+        renderer.set_path_regularization(factor);
+        let (xs, ys) = (Mf32::generate(|i| (i as f32) * 0.1 - 0.4), Mf32::broadcast(-0.4));
+        let n = 2048;
+        let mut sum = Mf32::zero();
+        let mut sum_sqr = Mf32::zero();
+        for frame in 0..n {
+            let mut rng = Rng::with_seed(7, 11, frame);
+            let c = renderer.render_pixels(xs, ys, &mut rng).color;
+            let lum = c.x + c.y + c.z;
+            sum = sum + lum;
+            sum_sqr = lum.mul_add(lum, sum_sqr);
+        }
+        let mean = sum * Mf32::broadcast(1.0 / n as f32);
+        let variance = sum_sqr * Mf32::broadcast(1.0 / n as f32) - mean * mean;
+        variance.hsum() / 8.0
+    };
+
+    let var_plain = measure(0.0);
+    let var_regularized = measure(1.0);
+    assert!(var_regularized < var_plain,
+            "variance with regularization ({}) should be lower than without ({})",
+            var_regularized, var_plain);
+}
+
+/// Renders full frames of the indoor scene with the given number of threads.
+///
+/// The resolution is a quarter of that of the interactive application, to
+/// keep the single-threaded benchmark reasonably fast. The bencher reports
+/// throughput in bytes; here one byte is one primary ray, so the reported
+/// MB/s is the number of million rays per second.
+#[cfg(test)]
+fn bench_render_frame(bencher: &mut test::Bencher, num_threads: u32) {
+    let (width, height, patch_width) = (640, 368, 16);
+    let mut renderer = Renderer::new(bench::indoor_scene(), width, height);
+    renderer.set_time(0.0, 0.0);
+    renderer.update_scene();
+    let renderer = renderer;
+
+    let mut bitmap = RenderBuffer::new(width, height);
+    let mut gbuffer = RenderBuffer::new(width, height);
+    let mut pool = Pool::new(num_threads);
+    let cancel = AtomicBool::new(false);
+    let mut frame_number = 1;
+
+    bencher.bytes = (width * height) as u64;
+    bencher.iter(|| {
+        renderer.render_frame_parallel(&mut pool, &mut bitmap, &mut gbuffer, patch_width, frame_number, &cancel);
+        frame_number += 1;
+    });
+}
+
+#[bench]
+fn bench_render_frame_single_threaded(bencher: &mut test::Bencher) {
+    bench_render_frame(bencher, 1);
+}
+
+#[bench]
+fn bench_render_frame_multi_threaded(bencher: &mut test::Bencher) {
+    bench_render_frame(bencher, available_threads());
+}
+
+#[test]
+fn flip_y_puts_top_row_first() {
+    use scene::Scene;
+    use material::SMaterial;
+
+    // A black box with emissive quads in the top half of the wall in front of
+    // the camera, so only the top half of the image is bright.
+    let v = SVector3::new;
+    let mut enclosure = bench::box_quads(v(-3.0, -3.0, -3.0), v(3.0, 3.0, 3.0), SMaterial::diffuse(0.0, 0.0, 0.0));
+    for quad in &mut enclosure {
+        quad.0.reverse();
+    }
+    let mut lights = Vec::new();
+    for i in 0..4 {
+        let x0 = -3.0 + 1.5 * i as f32;
+        let x1 = x0 + 1.5;
+        lights.push(([v(x0, 0.0, -2.5), v(x1, 0.0, -2.5), v(x1, 3.0, -2.5), v(x0, 3.0, -2.5)], SMaterial::sky()));
+    }
+
+    // Returns the brightness of the first and of the last rows in the bitmap.
+    let render = |flip_y: bool| {
+        let meshes = [bench::mesh_from_quads(&enclosure), bench::mesh_from_quads(&lights)];
+        let (width, height) = (32, 32);
+        let mut renderer = Renderer::new(Scene::from_meshes(&meshes), width, height);
+        renderer.set_flip_y(flip_y);
+        let bitmap = RenderBuffer::new(width, height);
+        let gbuffer = RenderBuffer::new(width, height);
+        unsafe {
+            renderer.render_patch_u8(bitmap.get_mut_slice(), gbuffer.get_mut_slice(), 32, 0, 0, 1);
+        }
+        let rgbas = bitmap.into_bitmap();
+        let mut brightness = [0u32; 2];
+        for y in (0..12).chain(20..32) {
+            for x in 0..width {
+                let i = ((y * width + x) * 4) as usize;
+                brightness[(y / 16) as usize] += rgbas[i] as u32 + rgbas[i + 1] as u32 + rgbas[i + 2] as u32;
+            }
+        }
+        brightness
+    };
+
+    let bottom_first = render(false);
+    assert!(bottom_first[0] < bottom_first[1], "by default the top row should be last");
+    let top_first = render(true);
+    assert!(top_first[0] > top_first[1], "with flip_y the top row should be first");
+}
+
+#[test]
+fn vertex_colors_interpolate_at_centroid() {
+    use scene::Scene;
+    use material::SMaterial;
+
+    // A white floor with red, green, and blue vertices, with lights high
+    // above it.
+    let v = SVector3::new;
+    let mut quads = vec![([v(-4.0, 0.0, 4.0), v(4.0, 0.0, 4.0), v(4.0, 0.0, -4.0), v(-4.0, 0.0, -4.0)],
+                          SMaterial::white())];
+    for i in 0..4 {
+        let x0 = -2.0 + i as f32;
+        let x1 = x0 + 1.0;
+        quads.push(([v(x0, 5.0, -0.5), v(x0, 5.0, 0.5), v(x1, 5.0, 0.5), v(x1, 5.0, -0.5)], SMaterial::sky()));
+    }
+    let mut mesh = bench::mesh_from_quads(&quads);
+    mesh.colors = vec![SVector3::one(); mesh.vertices.len()];
+    mesh.colors[0] = v(1.0, 0.0, 0.0);
+    mesh.colors[1] = v(0.0, 1.0, 0.0);
+    mesh.colors[2] = v(0.0, 0.0, 1.0);
+    let renderer = Renderer::new(Scene::from_meshes(&[mesh]), 16, 16);
+
+    // Aim at the centroid of the first triangle of the floor.
+    let centroid = v(4.0 / 3.0, 0.0, 4.0 / 3.0);
+    let info = renderer.render_single_ray(centroid + v(0.0, 1.0, 0.0), v(0.0, -1.0, 0.0));
+    let hit = &info.bounces[0];
+    assert!((hit.position - centroid).norm_squared() < 1e-8);
+    let third = 1.0 / 3.0;
+    assert!((hit.albedo - v(third, third, third)).norm_squared() < 1e-8, "albedo is {}", hit.albedo);
+}
+
+#[test]
+fn clamp_sample_weight_limits_near_zero_pdf_samples() {
+    // A diffuse bounce with a cosine of 1e-6 has a weight of about
+    // brdf * cos / pdf. With a tiny probability density it explodes.
+    let brdf = Mf32::broadcast(1.0 / consts::PI);
+    let cos = Mf32::broadcast(1.0e-6);
+    let pdf = Mf32::generate(|i| if i < 4 { 1.0e-12 } else { 1.0e-6 / consts::PI });
+    let w = brdf * cos / pdf;
+    let weight = MVector3::new(w, w, w);
+
+    let clamped = clamp_sample_weight(weight, 10.0);
+    for i in 0..4 {
+        assert_eq!(clamped.x.get_coord(i), 10.0);
+        assert_eq!(clamped.z.get_coord(i), 10.0);
+    }
+    // Ordinary samples are not affected.
+    for i in 4..8 {
+        assert_eq!(clamped.y.get_coord(i), w.get_coord(i));
+    }
+
+    // Clamping is disabled by default.
+    let unclamped = clamp_sample_weight(weight, ::std::f32::INFINITY);
+    assert_eq!(unclamped.x.get_coord(0), w.get_coord(0));
+}
+
+#[test]
+fn indirect_clamp_darkens_only_indirect_light() {
+    use scene::Scene;
+    use material::SMaterial;
+
+    // A white box with emissive quads in the top half of the wall in front of
+    // the camera. The camera sees the light directly in the top half of the
+    // image, and mostly indirect light in the bottom half.
+    let v = SVector3::new;
+    let mut enclosure = bench::box_quads(v(-3.0, -3.0, -3.0), v(3.0, 3.0, 3.0), SMaterial::white());
+    for quad in &mut enclosure {
+        quad.0.reverse();
+    }
+    let mut lights = Vec::new();
+    for i in 0..4 {
+        let x0 = -3.0 + 1.5 * i as f32;
+        let x1 = x0 + 1.5;
+        lights.push(([v(x0, 0.0, -2.5), v(x1, 0.0, -2.5), v(x1, 3.0, -2.5), v(x0, 3.0, -2.5)], SMaterial::sky()));
+    }
+
+    let render = |clamp: Option<f32>| {
+        let meshes = [bench::mesh_from_quads(&enclosure), bench::mesh_from_quads(&lights)];
+        let (width, height) = (32, 32);
+        let mut renderer = Renderer::new(Scene::from_meshes(&meshes), width, height);
+        if let Some(max) = clamp {
+            renderer.set_indirect_clamp(max);
+        }
+        let bitmap = RenderBuffer::new(width, height);
+        let gbuffer = RenderBuffer::new(width, height);
+        unsafe {
+            renderer.render_patch_u8(bitmap.get_mut_slice(), gbuffer.get_mut_slice(), 32, 0, 0, 1);
+        }
+        bitmap.into_bitmap()
+    };
+
+    let unclamped = render(None);
+    let clamped = render(Some(0.01));
+    let sum = |rgbas: &[u8], rows: ::std::ops::Range<usize>| {
+        rgbas[rows.start * 32 * 4..rows.end * 32 * 4].iter().map(|&c| c as u32).sum::<u32>()
+    };
+    assert!(sum(&unclamped, 20..30) > 0);
+    assert_eq!(sum(&unclamped, 20..30), sum(&clamped, 20..30), "direct light must not be clamped");
+    assert!(sum(&clamped, 0..12) < sum(&unclamped, 0..12), "indirect light must be clamped");
+}
+
+#[test]
+fn render_buffer_diff_finds_changed_pixel() {
+    let fill = |buffer: &mut RenderBuffer| {
+        for (i, pixels) in unsafe { buffer.get_mut_slice() }.iter_mut().enumerate() {
+            *pixels = Mi32::broadcast((i as i32 * 0x010305) | (0xff << 24));
+        }
+    };
+    let (mut a, mut b) = (RenderBuffer::new(16, 16), RenderBuffer::new(16, 16));
+    fill(&mut a);
+    fill(&mut b);
+
+    let (image, mae) = a.diff(&b);
+    assert_eq!(mae, 0.0);
+    let rgbas = image.into_bitmap();
+    assert!(rgbas.chunks(4).all(|px| px[0..3] == [0, 0, 0]));
+
+    // A buffer can be compared with itself.
+    let (_, mae) = a.diff(&a);
+    assert_eq!(mae, 0.0);
+
+    // Change the red channel of one pixel by 51 levels.
+    let pixels = unsafe { b.get_mut_slice() };
+    let mut bytes: [u8; 32] = unsafe { mem::transmute(pixels[5]) };
+    bytes[2 * 4] = bytes[2 * 4].wrapping_add(51);
+    pixels[5] = unsafe { mem::transmute(bytes) };
+
+    let (image, mae) = a.diff(&b);
+    assert!((mae - 51.0 / (16.0 * 16.0 * 3.0 * 255.0)).abs() < 1e-9);
+    let rgbas = image.into_bitmap();
+    let changed = 5 * 8 + 2;
+    for (i, px) in rgbas.chunks(4).enumerate() {
+        if i == changed {
+            assert_eq!(px, &[255, 153, 0, 255]);
+        } else {
+            assert_eq!(&px[0..3], &[0, 0, 0]);
+        }
+    }
+}
+
+#[test]
+fn clamp_indirect_keeps_direct_lanes() {
+    let bright = MVector3::new(Mf32::broadcast(40.0), Mf32::broadcast(20.0), Mf32::broadcast(10.0));
+    let direct = Mf32::generate(|i| if i < 4 { -0.0 } else { 0.0 });
+    let clamped = clamp_indirect(bright, direct, 4.0);
+    for i in 0..4 {
+        assert_eq!(clamped.x.get_coord(i), 40.0);
+    }
+    // Indirect lanes are scaled down, but keep their hue.
+    for i in 4..8 {
+        assert_eq!(clamped.x.get_coord(i), 4.0);
+        assert_eq!(clamped.y.get_coord(i), 2.0);
+        assert_eq!(clamped.z.get_coord(i), 1.0);
+    }
 
-        if self.enable_debug_view {
-            generate_slice8(|i| self.render_pixels_debug(xs[i], ys[i]))
-        } else {
-            generate_slice8(|i| self.render_pixels(xs[i], ys[i], rng))
+    // Black stays black, without dividing by zero.
+    let black = clamp_indirect(MVector3::zero(), Mf32::zero(), 4.0);
+    assert_eq!(black.x.get_coord(0), 0.0);
+}
+
+#[test]
+fn highlight_knee_passes_shadows_and_compresses_highlights() {
+    let (start, strength) = (0.6, 2.0);
+
+    // Below the knee values are unchanged.
+    let low = Mf32(0.0, 0.1, 0.2, 0.3, 0.4, 0.5, 0.55, 0.6);
+    let out = highlight_knee(low, start, strength);
+    for i in 0..8 {
+        assert_eq!(out.get_coord(i), low.get_coord(i));
+    }
+
+    // Above the knee values increase monotonically but stay below 1.
+    let mut prev = start;
+    for j in 0..64 {
+        let high = Mf32::generate(|i| start + 0.01 * (j * 8 + i + 1) as f32);
+        let out = highlight_knee(high, start, strength);
+        for i in 0..8 {
+            let y = out.get_coord(i);
+            assert!(y > prev, "{} should exceed {} at {}", y, prev, high.get_coord(i));
+            assert!(y < high.get_coord(i) && y < 1.0);
+            prev = y;
         }
     }
+}
 
-    /// Renders a square part of a frame.
-    ///
-    /// The (x, y) coordinate is the coordinate of the bottom-left pixel of the
-    /// patch. The patch width must be a multiple of 16.
-    pub fn render_patch_u8(&self,
-                           bitmap: &mut [Mi32],
-                           gbuffer: &mut [Mi32],
-                           patch_width: u32,
-                           x: u32,
-                           y: u32,
-                           frame_number: u32) {
-        assert_eq!(patch_width & 15, 0); // Patch width must be a multiple of 16.
-        let w = patch_width / 16;
-        let h = patch_width / 4;
-        let mut rng = Rng::with_seed(x, y, frame_number);
+#[test]
+fn render_far_from_origin_matches_render_near_origin() {
+    use scene::Scene;
+    use material::SMaterial;
 
-        for i in 0..w {
-            for j in 0..h {
-                let xb = x + i * 16;
-                let yb = y + j * 4;
-                let data = self.render_block_16x4(xb, yb, &mut rng);
-                self.store_pixels_color_16x4(bitmap, xb, yb, &data);
-                self.store_pixels_gbuffer_16x4(gbuffer, xb, yb, &data);
+    // A white box with emissive quads in the top half of the wall in front of
+    // the camera, about a hundred units in size, and a smaller box inside.
+    let render = |offset: SVector3| {
+        let v = |x: f32, y: f32, z: f32| SVector3::new(x, y, z) * 100.0 + offset;
+        let mut enclosure = bench::box_quads(v(-3.0, -3.0, -3.0), v(3.0, 3.0, 3.0), SMaterial::white());
+        for quad in &mut enclosure {
+            quad.0.reverse();
+        }
+        enclosure.extend(bench::box_quads(v(-1.5, -3.0, -2.0), v(0.5, -1.0, -1.0), SMaterial::white()));
+        let mut lights = Vec::new();
+        for i in 0..4 {
+            let x0 = -3.0 + 1.5 * i as f32;
+            let x1 = x0 + 1.5;
+            lights.push(([v(x0, 0.0, -2.5), v(x1, 0.0, -2.5), v(x1, 3.0, -2.5), v(x0, 3.0, -2.5)], SMaterial::sky()));
+        }
+
+        let meshes = [bench::mesh_from_quads(&enclosure), bench::mesh_from_quads(&lights)];
+        let mut scene = Scene::from_meshes(&meshes);
+        scene.camera_mut().set_position(offset, SVector3::zero());
+        let (width, height) = (64, 64);
+        let renderer = Renderer::new(scene, width, height);
+        let hdr = renderer.new_buffer_f32();
+        let gbuffer = RenderBuffer::new(width, height);
+        for frame in 1..9 {
+            unsafe { renderer.accumulate_patch_f32(&hdr, gbuffer.get_mut_slice(), 32, 0, 0, frame) };
+            unsafe { renderer.accumulate_patch_f32(&hdr, gbuffer.get_mut_slice(), 32, 32, 0, frame) };
+            unsafe { renderer.accumulate_patch_f32(&hdr, gbuffer.get_mut_slice(), 32, 0, 32, frame) };
+            unsafe { renderer.accumulate_patch_f32(&hdr, gbuffer.get_mut_slice(), 32, 32, 32, frame) };
+        }
+        let mut bitmap = RenderBuffer::new(width, height);
+        renderer.buffer_f32_into_render_buffer(&hdr, &mut bitmap);
+        bitmap
+    };
+
+    // Far from the origin the coordinates have only a few bits left for the
+    // fraction, but the images should still be nearly identical. Paths can
+    // diverge slightly due to rounding, so allow for some noise.
+    let near = render(SVector3::zero());
+    let far = render(SVector3::new(1.0e6, 1.0e6, 1.0e6));
+    let (_, mae) = near.diff(&far);
+    assert!(mae < 0.02, "mean absolute error is {}", mae);
+}
+
+#[test]
+fn escape_mode_leak_finds_cracks_in_closed_box() {
+    use scene::Scene;
+    use material::SMaterial;
+
+    // A white box around the camera with emissive quads inside, below the
+    // ceiling. Optionally the wall behind the camera is missing.
+    let v = SVector3::new;
+    let render = |crack: bool| {
+        let mut enclosure = bench::box_quads(v(-3.0, -3.0, -3.0), v(3.0, 3.0, 3.0), SMaterial::white());
+        for quad in &mut enclosure {
+            quad.0.reverse();
+        }
+        if crack {
+            // The face at z = 3.
+            enclosure.remove(2);
+        }
+        let mut lights = Vec::new();
+        for i in 0..4 {
+            let x0 = -2.0 + i as f32;
+            let x1 = x0 + 1.0;
+            lights.push(([v(x0, 2.5, -0.5), v(x0, 2.5, 0.5), v(x1, 2.5, 0.5), v(x1, 2.5, -0.5)], SMaterial::sky()));
+        }
+        let meshes = [bench::mesh_from_quads(&enclosure), bench::mesh_from_quads(&lights)];
+        let (width, height) = (32, 32);
+        let mut renderer = Renderer::new(Scene::from_meshes(&meshes), width, height);
+        renderer.set_escape_mode(EscapeMode::Leak);
+        let bitmap = RenderBuffer::new(width, height);
+        let gbuffer = RenderBuffer::new(width, height);
+        for frame in 1..5 {
+            unsafe {
+                renderer.render_patch_u8(bitmap.get_mut_slice(), gbuffer.get_mut_slice(), 32, 0, 0, frame);
             }
         }
+        let leaks = renderer.take_light_leaks();
+        let rgbas = bitmap.into_bitmap();
+        (leaks, rgbas.iter().map(|&c| c as u32).sum::<u32>())
+    };
+
+    // In the closed box, no bounce ray reaches the sky, but there is light.
+    let (leaks, brightness) = render(false);
+    assert_eq!(leaks, 0, "{} rays escaped from a closed box", leaks);
+    assert!(brightness > 0);
+
+    // Through the missing wall, bounce rays escape.
+    let (leaks, _) = render(true);
+    assert!(leaks > 0, "no rays escaped through the missing wall");
+}
+
+#[test]
+fn accumulate_frame_parallel_is_independent_of_scheduling() {
+    let (width, height, patch_width) = (64, 64, 16);
+    let render = |num_threads: u32, order: TileOrder| {
+        let mut renderer = Renderer::new(bench::caustic_scene(), width, height);
+        renderer.set_tile_order(order);
+        let mut hdr = renderer.new_buffer_f32();
+        let mut gbuffer = RenderBuffer::new(width, height);
+        let mut pool = Pool::new(num_threads);
+        for frame in 1..5 {
+            renderer.accumulate_frame_parallel(&mut pool, &mut hdr, &mut gbuffer, patch_width, frame);
+        }
+        assert_eq!(hdr.num_samples(), 4);
+        let mut bitmap = RenderBuffer::new(width, height);
+        renderer.buffer_f32_into_render_buffer(&hdr, &mut bitmap);
+        let bits: Vec<u32> = hdr.into_hdr_f32().iter().map(|&x| unsafe { mem::transmute(x) }).collect();
+        (bits, bitmap.into_bitmap())
+    };
+
+    let (bits_1, bitmap_1) = render(1, TileOrder::RowMajor);
+    let (bits_8, bitmap_8) = render(8, TileOrder::Hilbert);
+    assert!(bits_1 == bits_8, "accumulated radiance differs between 1 and 8 threads");
+    assert_eq!(bitmap_1, bitmap_8);
+}
+
+#[test]
+fn resolve_with_applies_tone_map_to_retained_buffer() {
+    let mut hdr = HdrBuffer::new(16, 16);
+    let rgb = MVector3::new(Mf32::broadcast(6.0), Mf32::broadcast(0.5), Mf32::zero());
+    for (block, coverage) in unsafe { hdr.get_mut_slice().iter_mut().zip(hdr.get_mut_coverage_slice()) } {
+        *block = generate_slice8(|_| rgb);
+        *coverage = generate_slice8(|_| Mf32::broadcast(2.0));
     }
+    hdr.inc_num_samples();
+    hdr.inc_num_samples();
 
-    /// Renders a square part of a frame, adds the contribution to the buffer.
-    ///
-    /// The (x, y) coordinate is the coordinate of the bottom-left pixel of the
-    /// patch. The patch width must be a multiple of 16. The memory layout of
-    /// the HDR buffer is as a bitmap of 16x4 blocks.
-    ///
-    /// This also fills the gbuffer. This is not done accumulatively, it is
-    /// filled for the current frame. (Though the gbuffer should be fairly
-    /// constant anyway, and there is no way to blend it, apart from averaging
-    /// texture coordinates.)
-    pub fn accumulate_patch_f32(&self,
-                                hdr_buffer: &mut [[MVector3; 8]],
-                                gbuffer: &mut [Mi32],
-                                patch_width: u32,
-                                x: u32,
-                                y: u32,
-                                frame_number: u32) {
-        assert_eq!(patch_width & 15, 0); // Patch width must be a multiple of 16.
-        let w = patch_width / 16;
-        let h = patch_width / 4;
-        let mut rng = Rng::with_seed(x, y, frame_number);
+    // The mean is (3.0, 0.25, 0.0). Clamping clips red, Reinhard maps it to
+    // 3 / 4, and green to 0.25 / 1.25 = 0.2.
+    let clamp = RenderBuffer::resolve_with(&hdr, ToneMap::Clamp, 0.0, 1.0).into_bitmap();
+    let reinhard = RenderBuffer::resolve_with(&hdr, ToneMap::Reinhard, 0.0, 1.0).into_bitmap();
+    assert_eq!(clamp.len(), 16 * 16 * 4);
+    for (c, r) in clamp.chunks(4).zip(reinhard.chunks(4)) {
+        assert_eq!(c, &[255, 64, 0, 255]);
+        assert_eq!(r, &[191, 51, 0, 255]);
+    }
 
-        for i in 0..w {
-            for j in 0..h {
-                let xb = x + i * 16;
-                let yb = y + j * 4;
-                let data = self.render_block_16x4(xb, yb, &mut rng);
-                let index = ((y / 4 + j) * (self.width / 16) + (x / 16 + i)) as usize;
-                let current = hdr_buffer[index];
-                hdr_buffer[index] = generate_slice8(|k| current[k] + data[k].color);
-                self.store_pixels_gbuffer_16x4(gbuffer, xb, yb, &data);
+    // Resolving does not consume the buffer, the first operator gives the
+    // same result again.
+    let again = RenderBuffer::resolve_with(&hdr, ToneMap::Clamp, 0.0, 1.0).into_bitmap();
+    assert_eq!(clamp, again);
+}
+
+#[test]
+fn aces_filmic_matches_known_response() {
+    let map = |x: f32| ToneMap::Aces.apply(MVector3::new(Mf32::broadcast(x), Mf32::zero(), Mf32::one())).x.get_coord(0);
+    let expected = [(0.0, 0.0), (0.18, 0.2669), (0.5, 0.6163), (1.0, 0.8038), (1.0e4, 1.0)];
+    for &(x, y) in &expected {
+        assert!((map(x) - y).abs() < 1e-3, "ACES({}) = {}, expected {}", x, map(x), y);
+    }
+
+    // Channels are mapped independently, and the curve is monotonic.
+    let mid = ToneMap::Aces.apply(MVector3::new(Mf32::broadcast(0.5), Mf32::zero(), Mf32::one()));
+    assert_eq!(mid.y.get_coord(0), 0.0);
+    assert!((mid.z.get_coord(0) - 0.8038).abs() < 1e-3);
+    assert!(map(2.0) > map(1.0) && map(4.0) > map(2.0));
+
+    // Compared to Reinhard, ACES has more contrast in the mid tones.
+    let reinhard = ToneMap::Reinhard.apply(MVector3::new(Mf32::broadcast(1.0), Mf32::zero(), Mf32::zero()));
+    assert!(map(1.0) > reinhard.x.get_coord(0));
+}
+
+#[cfg(test)]
+fn bench_diffuse_box(bencher: &mut test::Bencher, narrow_packets: bool) {
+    let (width, height, patch_width) = (128, 64, 16);
+    let mut renderer = Renderer::new(bench::diffuse_box_scene(), width, height);
+    renderer.scene_mut().set_narrow_packets(narrow_packets);
+    let renderer = renderer;
+
+    let mut bitmap = RenderBuffer::new(width, height);
+    let mut gbuffer = RenderBuffer::new(width, height);
+    let mut pool = Pool::new(1);
+    let cancel = AtomicBool::new(false);
+    let mut frame_number = 1;
+
+    bencher.bytes = (width * height) as u64;
+    bencher.iter(|| {
+        renderer.render_frame_parallel(&mut pool, &mut bitmap, &mut gbuffer, patch_width, frame_number, &cancel);
+        frame_number += 1;
+    });
+}
+
+#[bench]
+fn bench_diffuse_box_wide_packets(bencher: &mut test::Bencher) {
+    bench_diffuse_box(bencher, false);
+}
+
+#[bench]
+fn bench_diffuse_box_narrow_packets(bencher: &mut test::Bencher) {
+    bench_diffuse_box(bencher, true);
+}
+
+#[test]
+fn narrow_packets_render_the_same_image() {
+    let (width, height) = (64, 32);
+    let render = |narrow_packets: bool| {
+        let mut renderer = Renderer::new(bench::diffuse_box_scene(), width, height);
+        renderer.scene_mut().set_narrow_packets(narrow_packets);
+        let bitmap = RenderBuffer::new(width, height);
+        let gbuffer = RenderBuffer::new(width, height);
+        for frame in 1..3 {
+            for x in 0..2 {
+                unsafe {
+                    renderer.render_patch_u8(bitmap.get_mut_slice(), gbuffer.get_mut_slice(), 32, x * 32, 0, frame);
+                }
             }
         }
+        bitmap.into_bitmap()
+    };
+
+    let wide = render(false);
+    let narrow = render(true);
+    assert!(wide.iter().any(|&c| c > 0), "the image is black");
+    assert!(wide == narrow, "narrow packets changed the image");
+}
+
+#[test]
+fn premultiply_alpha_scales_color_by_coverage() {
+    let (width, height) = (16, 16);
+    let mut renderer = Renderer::new(bench::caustic_scene(), width, height);
+    let mut hdr = renderer.new_buffer_f32();
+
+    // Pure red at half coverage. The output brightens the color by a factor
+    // 2, so this red is fully saturated.
+    let red = MVector3::new(Mf32::broadcast(0.5), Mf32::zero(), Mf32::zero());
+    for (block, coverage) in unsafe { hdr.get_mut_slice().iter_mut().zip(hdr.get_mut_coverage_slice()) } {
+        *block = generate_slice8(|_| red);
+        *coverage = generate_slice8(|_| Mf32::broadcast(0.5));
     }
+    hdr.inc_num_samples();
 
-    /// Creates a new float buffer, the size of the viewport, that can be
-    /// rendered to with `accumulate_patch_f32()`.
-    pub fn new_buffer_f32(&self) -> Vec<[MVector3; 8]> {
-        let w = self.width / 16;
-        let h = self.height / 4;
-        let mut buffer = Vec::with_capacity((w * h) as usize);
-        for _ in 0..(w * h) {
-            buffer.push(generate_slice8(|_| MVector3::zero()));
+    let mut resolve = |premultiply: bool| {
+        renderer.set_premultiply_alpha(premultiply);
+        let mut bitmap = RenderBuffer::new(width, height);
+        renderer.buffer_f32_into_render_buffer(&hdr, &mut bitmap);
+        bitmap.into_bitmap()
+    };
+
+    for rgba in resolve(false).chunks(4) {
+        assert_eq!(rgba, &[255, 0, 0, 128]);
+    }
+    for rgba in resolve(true).chunks(4) {
+        assert_eq!(rgba, &[128, 0, 0, 128]);
+    }
+}
+
+#[test]
+fn render_patch_u8_scaled_upscales_to_full_size() {
+    let (width, height) = (64, 64);
+    let renderer = Renderer::new(bench::diffuse_box_scene(), width, height);
+    let bitmap = RenderBuffer::new(width, height);
+    let gbuffer = RenderBuffer::new(width, height);
+    for x in 0..2 {
+        for y in 0..2 {
+            unsafe {
+                renderer.render_patch_u8_scaled(bitmap.get_mut_slice(), gbuffer.get_mut_slice(), 32, x * 32, y * 32, 2, 1);
+            }
         }
-        buffer
     }
 
-    /// Converts a buffer of floating point values used for accumulative
-    /// rendering into a 32 bit per pixel RGBA bitmap.
-    pub fn buffer_f32_into_render_buffer(&self,
-                                         hdr_buffer: &[[MVector3; 8]],
-                                         render_buffer: &mut RenderBuffer,
-                                         num_samples: u32) {
-        let w = self.width / 16;
-        let h = self.height / 4;
-        assert_eq!(w * 16, self.width);
-        assert_eq!(h * 4, self.height);
-        let factor = Mf32::broadcast(1.0 / (num_samples as f32));
+    // At a quarter of the resolution, every 2x2 block of pixels is uniform.
+    let pixels = bitmap.into_bitmap();
+    let gpixels = gbuffer.into_bitmap();
+    let w = width as usize;
+    for buffer in &[&pixels, &gpixels] {
+        for y in 0..height as usize {
+            for x in 0..w {
+                let corner = ((y & !1) * w + (x & !1)) * 4;
+                let pixel = (y * w + x) * 4;
+                assert_eq!(&buffer[pixel..pixel + 4], &buffer[corner..corner + 4],
+                           "pixel ({}, {}) differs from its block", x, y);
+            }
+        }
+    }
 
-        {
-            // This is safe here because there is only one mutable borrow.
-            let bitmap = unsafe { render_buffer.get_mut_slice() };
+    // The blocks themselves are not all the same.
+    assert!(pixels.chunks(4).any(|rgba| rgba != &pixels[0..4]), "the image is uniform");
+}
 
-            for j in 0..h {
-                for i in 0..w {
-                    let rgbs = hdr_buffer[(j * w + i) as usize];
-                    let rgbs = generate_slice8(|k| rgbs[k] * factor);
-                    let data = generate_slice8(|k| {
-                        MPixelData {
-                            color: rgbs[k],
-                            // These values are unused, only the color is stored
-                            // in this function.
-                            tex_index: Mi32::zero(),
-                            tex_coords: (Mf32::zero(), Mf32::zero()),
-                            fresnel: Mf32::zero(),
-                        }
-                    });
-                    self.store_pixels_color_16x4(bitmap, i * 16, j * 4, &data);
-                }
+#[test]
+fn bgra_channel_order_swaps_red_and_blue() {
+    let (width, height) = (16, 16);
+    let mut renderer = Renderer::new(bench::caustic_scene(), width, height);
+    let mut hdr = renderer.new_buffer_f32();
+
+    // The output is brightened by a factor 2, so this is (255, 128, 64).
+    let color = MVector3::new(Mf32::broadcast(0.5), Mf32::broadcast(0.25), Mf32::broadcast(0.125));
+    for (block, coverage) in unsafe { hdr.get_mut_slice().iter_mut().zip(hdr.get_mut_coverage_slice()) } {
+        *block = generate_slice8(|_| color);
+        *coverage = generate_slice8(|_| Mf32::one());
+    }
+    hdr.inc_num_samples();
+
+    let mut resolve = |order: ChannelOrder| {
+        renderer.set_channel_order(order);
+        let mut bitmap = RenderBuffer::new(width, height);
+        renderer.buffer_f32_into_render_buffer(&hdr, &mut bitmap);
+        bitmap.into_bitmap()
+    };
+
+    let rgba = resolve(ChannelOrder::Rgba);
+    let bgra = resolve(ChannelOrder::Bgra);
+    assert_eq!(&rgba[0..4], &[255, 128, 64, 255]);
+    for (p, q) in rgba.chunks(4).zip(bgra.chunks(4)) {
+        assert_eq!(q, &[p[2], p[1], p[0], p[3]]);
+    }
+}
+
+#[test]
+fn albedo_guide_sees_through_mirror() {
+    use scene::Scene;
+    use material::SMaterial;
+
+    // A mirror in front of the camera, a red wall behind it, and a light
+    // above, out of view.
+    let v = SVector3::new;
+    let mirror = SMaterial::white().with_glossiness(5);
+    let quads = vec![
+        ([v(-9.0, -9.0, -2.0), v(9.0, -9.0, -2.0), v(9.0, 9.0, -2.0), v(-9.0, 9.0, -2.0)], mirror),
+        ([v(9.0, -9.0, 2.0), v(-9.0, -9.0, 2.0), v(-9.0, 9.0, 2.0), v(9.0, 9.0, 2.0)], SMaterial::diffuse(0.8, 0.1, 0.1)),
+        ([v(-1.0, 8.9, -1.0), v(1.0, 8.9, -1.0), v(1.0, 8.9, 1.0), v(-1.0, 8.9, 1.0)], SMaterial::sky()),
+    ];
+    let mut renderer = Renderer::new(Scene::from_meshes(&[bench::mesh_from_quads(&quads)]), 16, 16);
+    let mut render = |bounces: u32| {
+        renderer.set_albedo_bounces(bounces);
+        let mut hdr = HdrBuffer::with_albedo(16, 16);
+        let gbuffer = RenderBuffer::new(16, 16);
+        let gbuffer_slice = unsafe { gbuffer.get_mut_slice() };
+        for frame in 0..16 {
+            unsafe { renderer.accumulate_patch_f32(&hdr, gbuffer_slice, 16, 0, 0, frame) };
+            hdr.inc_num_samples();
+        }
+        let albedo = hdr.resolve_albedo_f32();
+        let i = (5 * 16 + 10) * 3;
+        v(albedo[i], albedo[i + 1], albedo[i + 2])
+    };
+
+    // Without bounces the guide is the flat white of the mirror.
+    let primary = render(0);
+    assert!((primary - v(1.0, 1.0, 1.0)).norm_squared() < 1e-4, "unexpected albedo {:?}", primary);
+
+    // With a bounce, it is mostly the wall seen in the mirror. Paths that
+    // sample the light from the mirror keep its white.
+    let reflected = render(1);
+    assert!(reflected.y < 0.5, "unexpected albedo {:?}", reflected);
+    assert!(reflected.x > reflected.y + 0.4, "unexpected albedo {:?}", reflected);
+}
+
+#[test]
+fn fog_tints_distant_surfaces() {
+    use scene::{Fog, FogFalloff, Scene};
+    use material::SMaterial;
+
+    // Two emissive walls, so the color is known after the first hit. A near
+    // one on the left at distance 1, and a far one at distance 100.
+    let v = SVector3::new;
+    let quads = vec![
+        ([v(-9.0, -9.0, -1.0), v(-0.1, -9.0, -1.0), v(-0.1, 9.0, -1.0), v(-9.0, 9.0, -1.0)], SMaterial::sky()),
+        ([v(-1e3, -1e3, -100.0), v(1e3, -1e3, -100.0), v(1e3, 1e3, -100.0), v(-1e3, 1e3, -100.0)], SMaterial::sky()),
+    ];
+    let mut renderer = Renderer::new(Scene::from_meshes(&[bench::mesh_from_quads(&quads)]), 16, 16);
+
+    // Lanes 0-3 look at the near wall, lanes 4-7 at the far one.
+    let xs = Mf32(-0.5, -0.5, -0.5, -0.5, 0.5, 0.5, 0.5, 0.5);
+    let ys = Mf32(-0.1, 0.0, 0.1, 0.2, -0.1, 0.0, 0.1, 0.2);
+    let render = |renderer: &Renderer| renderer.render_pixels(xs, ys, &mut Rng::with_seed(1, 2, 3)).color;
+    let clear = render(&renderer);
+
+    let fog_color = v(0.2, 0.4, 0.6);
+    renderer.scene_mut().set_fog(Some(Fog {
+        color: fog_color,
+        density: 0.05,
+        falloff: FogFalloff::Exponential,
+    }));
+    let fogged = render(&renderer);
+
+    for i in 0..8 {
+        let c = v(clear.x.get_coord(i), clear.y.get_coord(i), clear.z.get_coord(i));
+        let f = v(fogged.x.get_coord(i), fogged.y.get_coord(i), fogged.z.get_coord(i));
+        let difference = (c - fog_color).norm_squared().sqrt();
+        assert!(difference > 0.1, "the wall should not have the fog color already");
+        if i < 4 {
+            // At distance 1, about 5% of the color is fog.
+            assert!((f - c).norm_squared().sqrt() < 0.07 * difference, "near wall lane {} is {}, not {}", i, f, c);
+        } else {
+            // At distance 100, less than 1% of the wall is left.
+            assert!((f - fog_color).norm_squared().sqrt() < 0.01 * difference, "far wall lane {} is {}", i, f);
+        }
+    }
+}
+
+#[test]
+fn grid_shows_lines_at_integer_coordinates() {
+    let v = SVector3::new;
+    let grid = Grid {
+        line_color: v(1.0, 1.0, 1.0),
+        floor_color: v(0.2, 0.2, 0.2),
+        line_width: 0.05,
+        fade_start: 10.0,
+        fade_end: 20.0,
+    };
+
+    // From a height of 1, look down at lines, between lines, at a line beyond
+    // the fade distance, up into the sky, and at a line behind a wall.
+    let targets = [v(0.0, 0.0, -1.0), v(0.5, 0.0, -1.5), v(3.0, 0.0, -2.7), v(-0.3, 0.0, 0.5),
+                   v(40.0, 0.0, -30.0), v(0.5, 2.0, -3.0), v(1.0, 0.0, 1.0), v(2.0, 0.0, -3.0)];
+    let origin = v(0.0, 1.0, 0.0);
+    let ray = MRay {
+        origin: MVector3::broadcast(origin),
+        direction: MVector3::generate(|i| (targets[i] - origin).normalized()),
+        active: Mf32::zero(),
+    };
+    let max_distance = Mf32(1e5, 1e5, 1e5, 1e5, 1e5, 1e5, 1e5, 1.0);
+    let (color, on_grid) = grid.shade(&ray, max_distance);
+
+    let expected = [Some(1.0), Some(0.2), Some(1.0), Some(0.2), Some(0.2), None, Some(1.0), None];
+    for i in 0..8 {
+        match expected[i] {
+            Some(c) => {
+                assert!(on_grid.get_coord(i).is_sign_negative(), "lane {} should hit the grid", i);
+                assert!((color.x.get_coord(i) - c).abs() < 1e-5, "lane {} has color {}", i, color.x.get_coord(i));
             }
+            None => assert!(!on_grid.get_coord(i).is_sign_negative(), "lane {} should not hit the grid", i),
         }
     }
+}
 
-    /// Returns colors for the pixels, as well as the texture indices.
-    fn render_pixels(&self, x: Mf32, y: Mf32, rng: &mut Rng) -> MPixelData {
-        let t = rng.sample_unit();
-        let mut ray = self.scene.camera.get_ray(x, y, t);
-        let mut color = MVector3::new(Mf32::one(), Mf32::one(), Mf32::one());
-        let mut hit_emissive = Mf32::zero();
-        let mut texture_index = Mi32::zero();
-        let mut texture_coords = (Mf32::zero(), Mf32::zero());
-        let mut fresnel = Mf32::zero();
+#[test]
+fn photon_map_replaces_paths_through_glass() {
+    use material::SMaterial;
+    use photon::PhotonMap;
+    use scene::Scene;
 
-        let max_bounces = 5;
-        for i in 0..max_bounces {
-            let isect = self.scene.intersect_nearest(&ray);
-            hit_emissive = isect.material;
+    // The caustic scene, with a glass ball between the light and the floor.
+    let caustic_scene = || {
+        let mut scene = bench::caustic_scene();
+        let glass = SMaterial::colored_glass(1.0, 1.0, 1.0);
+        let ball = bench::sphere_mesh(SVector3::new(0.0, 1.0, -2.5), 0.8, glass, 12, 24);
+        scene.merge(Scene::from_meshes(&[ball]), false);
+        scene
+    };
+    let (width, height) = (64, 32);
+    let render = |photon_map: Option<PhotonMap>| {
+        let mut renderer = Renderer::new(caustic_scene(), width, height);
+        renderer.set_photon_map(photon_map);
+        let mut hdr = HdrBuffer::new(width, height);
+        let gbuffer = RenderBuffer::new(width, height);
+        let gbuffer_slice = unsafe { gbuffer.get_mut_slice() };
+        for frame in 1..5 {
+            unsafe { renderer.accumulate_patch_f32(&hdr, gbuffer_slice, 32, 0, 0, frame) };
+            unsafe { renderer.accumulate_patch_f32(&hdr, gbuffer_slice, 32, 32, 0, frame) };
+            hdr.inc_num_samples();
+        }
+        hdr.into_hdr_f32()
+    };
+    let sum = |xs: &[f32]| xs.iter().sum::<f32>();
 
-            // Do not allow NaNs to creep in.
-            debug_assert!(ray.direction.all_finite(), "infinite ray direction at iteration {}", i);
-            debug_assert!(isect.position.all_finite(), "infinite intersection at iteration {}", i);
-            debug_assert!(isect.distance.all_finite(), "infinite distance at iteration {}", i);
+    // With an empty photon map, the paths that reach the light through the
+    // ball after the first surface are dropped, and nothing replaces them.
+    // The samples are the same otherwise.
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let without = render(None);
+    let empty = render(Some(PhotonMap::trace(&caustic_scene(), 0, 0.1, &mut rng)));
+    assert!(without.iter().zip(empty.iter()).all(|(a, b)| b <= a));
+    assert!(sum(&empty) < sum(&without), "paths through glass should be dropped");
 
-            // Stop when every ray hit a light source.
-            if isect.material.all_sign_bits_negative() {
-                break;
-            }
+    // The photons bring the light back, from the emissive triangles of the
+    // scene, which has no area lights.
+    let photon_map = PhotonMap::trace(&caustic_scene(), 4096, 0.1, &mut rng);
+    assert!(photon_map.len() > 0);
+    let with = render(Some(photon_map));
+    assert!(sum(&with) > sum(&empty));
+}
 
-            // Get a new ray and the color modulation. For the first bounce, the
-            // Fresnel term should not contribute to the color modulation
-            // because that is handled on the GPU.
-            let (new_ray, color_mod, fr) =
-                continue_path(isect.material, &self.scene, &ray, &isect, rng, i == 0);
-            ray = new_ray;
-            color = color.mul_coords(color_mod);
+#[test]
+fn zero_bounces_renders_direct_light_only() {
+    let (width, height) = (32, 32);
+    let mut renderer = Renderer::new(bench::caustic_scene(), width, height);
+    renderer.set_max_bounces(0);
+    let mut hdr = HdrBuffer::with_aovs(width, height);
+    let gbuffer = RenderBuffer::new(width, height);
+    let gbuffer_slice = unsafe { gbuffer.get_mut_slice() };
 
-            if i == 0 {
-                texture_index = isect.material.get_texture();
-                texture_coords = isect.tex_coords;
-                fresnel = fr;
+    for frame in 0..4 {
+        unsafe { renderer.accumulate_patch_f32(&hdr, gbuffer_slice, 32, 0, 0, frame) };
+        hdr.inc_num_samples();
+    }
+
+    // Without a photon map, all indirect light comes from bounces.
+    let total = |aov: Aov| hdr.resolve_aov_f32(aov).iter().sum::<f32>();
+    assert!(total(Aov::DirectDiffuse) > 0.0);
+    assert_eq!(total(Aov::IndirectDiffuse), 0.0);
+    assert_eq!(total(Aov::IndirectSpecular), 0.0);
+}
+
+#[test]
+fn filter_radius_softens_rendered_edge() {
+    use scene::Scene;
+    use material::SMaterial;
+
+    // An emissive quad covers the right half of the image, with its edge at
+    // the border between column 7 and 8.
+    let v = SVector3::new;
+    let quads = vec![
+        ([v(0.0, -9.0, -2.0), v(9.0, -9.0, -2.0), v(9.0, 9.0, -2.0), v(0.0, 9.0, -2.0)], SMaterial::sky()),
+    ];
+    let scene = Scene::from_meshes(&[bench::mesh_from_quads(&quads)]);
+    let mut renderer = Renderer::new(scene, 16, 16);
+
+    // Returns the mean coverage of columns 7 and 8.
+    let mut measure = |radius: f32| {
+        renderer.set_filter_radius(radius);
+        let mut hdr = HdrBuffer::new(16, 16);
+        let gbuffer = RenderBuffer::new(16, 16);
+        let gbuffer_slice = unsafe { gbuffer.get_mut_slice() };
+        for frame in 0..32 {
+            unsafe { renderer.accumulate_patch_f32(&hdr, gbuffer_slice, 16, 0, 0, frame) };
+            hdr.inc_num_samples();
+        }
+        let mut columns = [0.0; 16];
+        for (block_index, block) in hdr.as_coverage_slice().iter().enumerate() {
+            for (k, coverage) in block.iter().enumerate() {
+                for lane in 0..8 {
+                    columns[pixel_index(16, block_index, k, lane) % 16] += coverage.get_coord(lane);
+                }
             }
         }
+        (columns[7] / (16.0 * 32.0), columns[8] / (16.0 * 32.0))
+    };
 
-        // Compute light contribution.
-        let emission = sky_intensity(ray.direction);
-        color = color.mul_coords(emission);
+    // A box filter keeps every sample in its pixel, so the edge is sharp.
+    assert_eq!(measure(0.0), (0.0, 1.0));
 
-        // If the last thing that a ray hit was an emissive material, it has
-        // found a light source and the computed color is correct. If the ray
-        // did not find a light source but the loop was terminated, the computed
-        // color is invalid; it should be black.
-        let color = MVector3::zero().pick(color, hit_emissive);
+    // A tent of radius 1.5 gives (1 - 0.5 / 1.5)^2 / 2 = 22% of the weight of
+    // a pixel to the samples beyond the edge.
+    let (left, right) = measure(1.5);
+    assert!((left - 0.222).abs() < 0.08, "coverage left of the edge is {}", left);
+    assert!((right - 0.778).abs() < 0.08, "coverage right of the edge is {}", right);
+}
 
-        MPixelData {
-            color: color,
-            tex_index: texture_index,
-            tex_coords: texture_coords,
-            fresnel: fresnel,
+#[test]
+fn filter_radius_skips_converged_patch() {
+    use scene::Scene;
+    use material::SMaterial;
+
+    // A closed box around the camera, with the lights outside of it, so every
+    // path is cut off by the bounce limit, and the number of cut paths shows
+    // whether paths were traced at all.
+    let v = SVector3::new;
+    let mut quads = bench::box_quads(v(-1.0, -1.0, -1.0), v(1.0, 1.0, 1.0), SMaterial::white());
+    for quad in &mut quads {
+        quad.0.reverse();
+    }
+    for i in 0..4 {
+        let x0 = -2.0 + i as f32;
+        let x1 = x0 + 1.0;
+        quads.push(([v(x0, 5.0, -0.5), v(x0, 5.0, 0.5), v(x1, 5.0, 0.5), v(x1, 5.0, -0.5)], SMaterial::sky()));
+    }
+    let scene = Scene::from_meshes(&[bench::mesh_from_quads(&quads)]);
+
+    let (width, height) = (64, 32);
+    let mut renderer = Renderer::new(scene, width, height);
+    renderer.set_max_bounces(0);
+    renderer.set_filter_radius(1.5);
+    renderer.set_adaptive_sampling(Some(0.02));
+    let hdr = HdrBuffer::new(width, height);
+    let gbuffer = RenderBuffer::new(width, height);
+    let gbuffer_slice = unsafe { gbuffer.get_mut_slice() };
+
+    // Converge the left patch, as if all of its pixels had the same nonzero
+    // luminance in every sample.
+    {
+        let stats = unsafe { hdr.get_mut_stats_slice() };
+        for j in 0..height / 4 {
+            for i in 0..2 {
+                for welford in stats[(j * (width / 16) + i) as usize].iter_mut() {
+                    for _ in 0..MIN_ADAPTIVE_SAMPLES {
+                        welford.insert(Mf32::one(), Mf32::zero());
+                    }
+                }
+            }
         }
     }
 
-    fn render_pixels_debug(&self, x: Mf32, y: Mf32) -> MPixelData {
-        let t = Mf32::zero();
-        let ray = self.scene.camera.get_ray(x, y, t);
-        let (numi_aabb, numi_tri) = self.scene.intersect_debug(&ray);
+    // The samples of the right patch reach into the left patch, but the left
+    // patch renders nothing but primary rays.
+    unsafe { renderer.accumulate_patch_f32(&hdr, gbuffer_slice, 32, 0, 0, 1) };
+    if cfg!(debug_assertions) {
+        assert_eq!(renderer.take_bounce_cap_hits(), 0, "the converged patch traced paths");
+    }
+    unsafe { renderer.accumulate_patch_f32(&hdr, gbuffer_slice, 32, 32, 0, 1) };
+    if cfg!(debug_assertions) {
+        assert!(renderer.take_bounce_cap_hits() > 0);
+    }
+}
 
-        let g = Mf32::broadcast((numi_aabb as f32).log2() * 0.1);
-        let b = Mf32::broadcast((numi_tri as f32).log2() * 0.1);
+#[test]
+fn save_screenshot_writes_resolved_image() {
+    use std::env;
+    use std::fs;
 
-        let color = MVector3::new(Mf32::zero(), g, b);
+    // A reddish image at half coverage, which gets brighter towards the end
+    // of the buffer, so flipped rows are detected.
+    let (width, height) = (32, 32);
+    let mut hdr = HdrBuffer::new(width, height);
+    for (i, (block, coverage)) in unsafe { hdr.get_mut_slice().iter_mut().zip(hdr.get_mut_coverage_slice()) }.enumerate() {
+        let color = MVector3::new(Mf32::broadcast(i as f32 / 32.0), Mf32::broadcast(0.25), Mf32::zero());
+        *block = generate_slice8(|_| color);
+        *coverage = generate_slice8(|_| Mf32::broadcast(0.5));
+    }
+    hdr.inc_num_samples();
 
-        MPixelData {
-            color: color,
-            tex_index: Mi32::zero(),
-            tex_coords: (Mf32::zero(), Mf32::zero()),
-            fresnel: Mf32::zero(),
-        }
+    // Returns the png and the exr, and the resolved render buffer and the
+    // mean radiance that they should contain, all with the top row first.
+    let path = env::temp_dir().join("convector_save_screenshot.png");
+    let save = |renderer: &Renderer| {
+        let written = renderer.save_screenshot(&hdr, Some(&path), true).unwrap();
+        assert_eq!(written, path);
+        let image = imagefmt::read(&path, imagefmt::ColFmt::RGBA).unwrap();
+        let (exr_width, exr_height, exr_rgbs) = exr::read_exr(path.with_extension("exr"));
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.with_extension("exr")).unwrap();
+        assert_eq!((image.w, image.h), (width as usize, height as usize));
+        assert_eq!((exr_width, exr_height), (width, height));
+
+        // The exr reader returns the bottom row first, and so do the buffers,
+        // unless the rows are flipped.
+        let mut render_buffer = RenderBuffer::new(width, height);
+        renderer.buffer_f32_into_render_buffer(&hdr, &mut render_buffer);
+        let reverse_rows = |pixels: Vec<u8>, row_len: usize| -> Vec<u8> {
+            pixels.chunks(row_len).rev().flat_map(|row| row.iter().cloned()).collect()
+        };
+        let reverse_rows_f32 = |pixels: Vec<f32>, row_len: usize| -> Vec<f32> {
+            pixels.chunks(row_len).rev().flat_map(|row| row.iter().cloned()).collect()
+        };
+        let (bitmap, rgbs) = (render_buffer.into_bitmap(), hdr.resolve_f32());
+        let (bitmap, rgbs) = if renderer.flip_y {
+            (bitmap, rgbs)
+        } else {
+            (reverse_rows(bitmap, width as usize * 4), reverse_rows_f32(rgbs, width as usize * 3))
+        };
+        (image.buf, bitmap, reverse_rows_f32(exr_rgbs, width as usize * 3), rgbs)
+    };
+
+    // The png is opaque, although the render buffer has the coverage.
+    let mut renderer = Renderer::new(bench::caustic_scene(), width, height);
+    let (image, expected, exr_rgbs, expected_rgbs) = save(&renderer);
+    for (p, q) in image.chunks(4).zip(expected.chunks(4)) {
+        assert_eq!(&p[0..3], &q[0..3], "the png differs from the resolved image");
+        assert_eq!((p[3], q[3]), (255, 128), "the png is not opaque");
+    }
+    assert!(exr_rgbs == expected_rgbs, "the exr differs from the accumulated image");
+
+    // With premultiplied alpha, the color in the png is still straight.
+    renderer.set_premultiply_alpha(true);
+    let (premultiplied_image, _, _, _) = save(&renderer);
+    assert!(premultiplied_image == image, "the png has premultiplied color");
+    renderer.set_premultiply_alpha(false);
+
+    // With flipped rows, both files still have the top row first.
+    renderer.set_flip_y(true);
+    let (flipped_image, expected, exr_rgbs, expected_rgbs) = save(&renderer);
+    for (p, q) in flipped_image.chunks(4).zip(expected.chunks(4)) {
+        assert_eq!(&p[0..3], &q[0..3], "the png differs from the resolved image");
     }
+    assert!(exr_rgbs == expected_rgbs, "the exr is upside down");
+    assert!(flipped_image != image, "the image does not depend on the row order");
 }
 
 #[test]
-fn render_buffer_into_bitmap() {
-    let render_buffer = RenderBuffer::new(1280, 736);
-    let bitmap = render_buffer.into_bitmap();
-    drop(bitmap);
-    let render_buffer = RenderBuffer::new(1280, 736);
-    let _bitmap = render_buffer.into_bitmap();
-    // The render buffer was transmuted or copied into a vector of pixels, and
-    // dropping the vector at this point should not result in a crash.
+fn take_control_stops_the_camera_orbit() {
+    let mut renderer = Renderer::new(bench::caustic_scene(), 64, 32);
+    let pick = |renderer: &Renderer| renderer.scene().camera().get_pick_ray(0.0, 0.0).origin;
+
+    renderer.set_time(10.0, 0.1);
+    renderer.update_scene();
+    renderer.take_control();
+    let held = pick(&renderer);
+
+    renderer.set_time(20.0, 0.1);
+    renderer.update_scene();
+    assert!((pick(&renderer) - held).norm_squared() < 1e-10);
+}
+
+#[test]
+fn draw_handle_rings_the_projected_point() {
+    let (width, height) = (64, 32);
+    let renderer = Renderer::new(bench::caustic_scene(), width, height);
+    let mut bitmap = RenderBuffer::new(width, height);
+    let mut gbuffer = RenderBuffer::new(width, height);
+    bitmap.fill_black();
+    gbuffer.fill_black();
+
+    // The camera looks along the negative z-axis, so this point projects onto
+    // the center of the image.
+    renderer.draw_handle(&mut bitmap, &mut gbuffer, SVector3::new(0.0, 0.0, -3.0));
+    let pixels = bitmap.into_bitmap();
+    let white = |x: usize, y: usize| pixels[(y * width as usize + x) * 4] == 255;
+    assert!(white(32 + 6, 16) && white(32 - 7, 16) && white(32, 16 + 6));
+    assert!(!white(32, 16));
 }
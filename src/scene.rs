@@ -5,18 +5,33 @@
 // it under the terms of the GNU General Public License version 3. A copy
 // of the License is available in the root of the repository.
 
+use aabb::Aabb;
 use bvh::Bvh;
-use material::{MDirectSample, MMaterial};
+use emitter::EmitterBvh;
+use hdr;
+use light::{AreaLight, EnvironmentMap, MLightSample};
+use material::{GgxAlbedoTable, HeightMap, Material, MDirectSample, MMaterial, SMaterial, sky_intensity};
 use quaternion::{MQuaternion, SQuaternion, rotate};
-use random::Rng;
-use ray::{MIntersection, MRay};
-use simd::Mf32;
+use random::{Rng, halton};
+use ray::{MIntersection, MRay, NO_TRIANGLE, SRay, triangle_bits};
+use sky::PhysicalSky;
+use splat::{Splat, SplatBvh};
+use simd::{Mask, Mf32};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::f32;
 use std::f32::consts::PI;
+use std::io;
+use std::path::Path;
+use std::u32;
+use subsurface::Subsurface;
+use transform::SAffine;
 use triangle::Triangle;
 use util::generate_slice8;
 use vector3::{MVector3, SVector3};
 use wavefront::Mesh;
 
+#[derive(Clone)]
 pub struct Camera {
     position: SVector3,
     position_delta: SVector3,
@@ -27,6 +42,33 @@ pub struct Camera {
     /// Distance such that a vector at `(1, 0, screen_distance)` makes an angle
     /// of the desired field of view with `(-1, 0, screen_distance)`.
     screen_distance: f32,
+
+    /// Sub-pixel offset of the sampling grid for the current frame, if
+    /// temporal anti-aliasing jitter is enabled.
+    jitter: Option<(f32, f32)>,
+
+    /// Radius of the lens, zero for a pinhole camera without depth of field.
+    aperture_radius: f32,
+
+    /// Distance along the view direction of the plane that is in focus.
+    focus_distance: f32,
+
+    aperture: Aperture,
+
+    /// Distance along the view direction of the near clip plane. Surfaces
+    /// in front of it are not visible.
+    near_clip: f32,
+}
+
+/// The shape of the lens aperture, which determines the shape of out of focus
+/// highlights (bokeh).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Aperture {
+    /// A circular aperture.
+    Disk,
+
+    /// A regular polygon with the given number of blades (at least three).
+    Polygon(u32),
 }
 
 impl Camera {
@@ -38,9 +80,24 @@ impl Camera {
             orientation: SQuaternion::new(1.0, 0.0, 0.0, 0.0),
             orientation_delta: SQuaternion::new(0.0, 0.0, 0.0, 0.0),
             screen_distance: 1.0 / (PI / 5.0).sin(),
+            jitter: None,
+            aperture_radius: 0.0,
+            focus_distance: 1.0,
+            aperture: Aperture::Disk,
+            near_clip: 0.0,
         }
     }
 
+    /// Returns whether the other camera has the same position, orientation,
+    /// and field of view. The jitter and the motion during the frame are not
+    /// compared.
+    pub fn has_same_view(&self, other: &Camera) -> bool {
+        let (q, r) = (self.orientation, other.orientation);
+        self.position == other.position &&
+            (q.a, q.b, q.c, q.d) == (r.a, r.b, r.c, r.d) &&
+            self.screen_distance == other.screen_distance
+    }
+
     /// Sets the position of the camera at the beginning of the frame, and the
     /// offset such that position + delta is the position at the end of the
     /// frame.
@@ -73,6 +130,68 @@ impl Camera {
         self.orientation_delta = SQuaternion::new(x_delta, 0.0, -y_delta, 0.0);
     }
 
+    /// Enables depth of field. Points at `focus_distance` along the view
+    /// direction are sharp, and the blur elsewhere grows with the radius of
+    /// the lens. A radius of zero disables depth of field.
+    pub fn set_depth_of_field(&mut self, radius: f32, focus_distance: f32, aperture: Aperture) {
+        assert!(radius >= 0.0 && focus_distance > 0.0);
+        if let Aperture::Polygon(blades) = aperture {
+            assert!(blades >= 3, "a polygonal aperture needs at least three blades");
+        }
+        self.aperture_radius = radius;
+        self.focus_distance = focus_distance;
+        self.aperture = aperture;
+    }
+
+    /// Sets the distance along the view direction of the near clip plane.
+    ///
+    /// Camera rays start at the plane, so surfaces closer than that are not
+    /// hit, and the camera looks past them. This makes cutaway views into
+    /// closed geometry. Intersection distances of camera rays are measured
+    /// from the plane. A distance of zero disables clipping.
+    pub fn set_near_clip(&mut self, distance: f32) {
+        assert!(distance >= 0.0, "near clip distance must not be negative");
+        self.near_clip = distance;
+    }
+
+    /// Moves the origin of a camera ray forward to the near clip plane. The
+    /// direction is in camera space, and it must point away from the camera.
+    fn clip_origin(&self, origin: MVector3, dir_src: MVector3, dir: MVector3) -> MVector3 {
+        if self.near_clip == 0.0 {
+            return origin
+        }
+
+        // The camera looks along the negative z-axis in camera space.
+        let t = Mf32::broadcast(-self.near_clip) / dir_src.z;
+        dir.mul_add(t, origin)
+    }
+
+    /// Enables jitter for temporal anti-aliasing, and sets the offset for the
+    /// given frame.
+    ///
+    /// Instead of sampling a random point in every pixel, all pixels of the
+    /// frame are sampled at the same sub-pixel offset. The offsets of
+    /// successive frames follow the Halton sequence in bases 2 and 3, so over
+    /// time they cover the pixel evenly.
+    pub fn set_jitter_frame(&mut self, frame_number: u32) {
+        // Skip element 0 of the sequence, which would be (0, 0) for every
+        // base, and wrap around eventually to keep float precision.
+        let index = frame_number % 1024 + 1;
+        self.jitter = Some((halton(index, 2) - 0.5, halton(index, 3) - 0.5));
+    }
+
+    /// Disables jitter, pixels are sampled randomly again.
+    pub fn disable_jitter(&mut self) {
+        self.jitter = None;
+    }
+
+    /// Returns the sub-pixel offset of the current frame relative to the
+    /// pixel center, in pixels, in the range [-0.5, 0.5). Returns `None` if
+    /// jitter is disabled.
+    pub fn jitter(&self) -> Option<(f32, f32)> {
+        self.jitter
+    }
+
     /// Returns a camera ray for the given screen coordinates.
     ///
     /// Values for x are in the range (-1, 1), the scale is uniform in both
@@ -92,40 +211,501 @@ impl Camera {
         let dir = rotate(&dir_src, &orientation);
 
         MRay {
-            origin: origin,
+            origin: self.clip_origin(origin, dir_src, dir),
+            direction: dir,
+            active: Mf32::zero(),
+        }
+    }
+
+    /// Returns a camera ray for the given screen coordinates that passes
+    /// through a random point on the lens, see `set_depth_of_field()`.
+    ///
+    /// Without depth of field, this is the same as `get_ray()`.
+    pub fn get_ray_lens(&self, x: Mf32, y: Mf32, t: Mf32, rng: &mut Rng) -> MRay {
+        if self.aperture_radius == 0.0 {
+            return self.get_ray(x, y, t)
+        }
+
+        let origin = MVector3::broadcast(self.position);
+        let origin_delta = MVector3::broadcast(self.position_delta);
+        let origin = origin_delta.mul_add(t, origin);
+
+        let orientation = MQuaternion::broadcast(self.orientation);
+        let orientation_delta = MQuaternion::broadcast(self.orientation_delta);
+        let orientation = orientation.interpolate(&orientation_delta, t);
+
+        let (lens_x, lens_y) = match self.aperture {
+            Aperture::Disk => rng.sample_disk(),
+            Aperture::Polygon(blades) => rng.sample_polygon(blades),
+        };
+        let radius = Mf32::broadcast(self.aperture_radius);
+        let lens = MVector3::new(lens_x * radius, lens_y * radius, Mf32::zero());
+
+        // In camera space, the point on the screen scaled to the focal plane
+        // is in focus. Rays from all points on the lens converge there.
+        let scale = Mf32::broadcast(self.focus_distance / self.screen_distance);
+        let focus = MVector3::new(x, y, Mf32::broadcast(-self.screen_distance)) * scale;
+        let dir_src = (focus - lens).normalized();
+        let dir = rotate(&dir_src, &orientation);
+
+        MRay {
+            origin: self.clip_origin(origin + rotate(&lens, &orientation), dir_src, dir),
             direction: dir,
             active: Mf32::zero(),
         }
     }
+
+    /// Returns the ray through screen coordinates (x, y) at the beginning of
+    /// the frame, for picking objects with the mouse.
+    pub fn get_pick_ray(&self, x: f32, y: f32) -> SRay {
+        let ray = self.get_ray(Mf32::broadcast(x), Mf32::broadcast(y), Mf32::zero());
+        let origin = SVector3::new(ray.origin.x.0, ray.origin.y.0, ray.origin.z.0);
+        let direction = SVector3::new(ray.direction.x.0, ray.direction.y.0, ray.direction.z.0);
+        SRay::new(origin, direction.normalized())
+    }
+
+    /// Projects points onto the screen, at the beginning of the frame. This
+    /// is the inverse of `get_ray()`: it returns the screen coordinates in the
+    /// same range. The mask has the sign bit set for points that are not in
+    /// front of the camera, their coordinates are meaningless.
+    pub fn project(&self, points: MVector3) -> (Mf32, Mf32, Mask) {
+        let relative = points - MVector3::broadcast(self.position);
+        let view = self.world_to_view(relative);
+        let behind = view.z.geq(Mf32::zero());
+        let scale = Mf32::broadcast(-self.screen_distance) / view.z;
+        (view.x * scale, view.y * scale, behind)
+    }
+
+    /// Transforms directions from world space into view space, at the
+    /// beginning of the frame. In view space the camera looks along the
+    /// negative z-axis, and the y-axis points up.
+    pub fn world_to_view(&self, direction: MVector3) -> MVector3 {
+        rotate(&direction, &MQuaternion::broadcast(self.orientation.conjugate()))
+    }
+}
+
+/// The object that a `LightDrag` moves.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DragTarget {
+    /// An area light, by index.
+    Light(usize),
+
+    /// An emitter made of triangles that share vertices, by index into the
+    /// BVH triangle list.
+    Emitter(Vec<u32>),
+}
+
+/// A light that is being dragged with the mouse.
+///
+/// The light moves in the plane through the picked point perpendicular to the
+/// ray that picked it, such that the point stays under the cursor. Both area
+/// lights and emissive triangles can be dragged.
+pub struct LightDrag {
+    target: DragTarget,
+    plane_normal: SVector3,
+    plane_distance: f32,
+    last_point: SVector3,
+}
+
+impl LightDrag {
+    /// Starts dragging the light under the screen coordinates (x, y), in the
+    /// same range as for `Camera::get_ray()`. Returns `None` if there is no
+    /// light there. If an area light and an emitter are both under the
+    /// cursor, the nearest one is picked.
+    pub fn begin(scene: &Scene, x: f32, y: f32) -> Option<LightDrag> {
+        let ray = scene.camera().get_pick_ray(x, y);
+        let light = scene.pick_light(&ray).map(|index| {
+            let center = scene.light_center(index);
+            (DragTarget::Light(index), center, (center - ray.origin).dot(ray.direction))
+        });
+        let emitter = scene.pick_emitter(&ray).map(|(triangles, distance)| {
+            (DragTarget::Emitter(triangles), ray.origin + ray.direction * distance, distance)
+        });
+        let picked = match (light, emitter) {
+            (Some(l), Some(e)) => if l.2 <= e.2 { Some(l) } else { Some(e) },
+            (l, e) => l.or(e),
+        };
+        picked.map(|(target, point, _)| {
+            LightDrag {
+                target: target,
+                plane_normal: ray.direction,
+                plane_distance: ray.direction.dot(point),
+                last_point: point,
+            }
+        })
+    }
+
+    /// Returns the object being dragged.
+    pub fn target(&self) -> &DragTarget {
+        &self.target
+    }
+
+    /// Returns the point that stays under the cursor: the center of an area
+    /// light, or the point where the emitter was picked, moved along.
+    pub fn position(&self) -> SVector3 {
+        self.last_point
+    }
+
+    /// Moves the light such that it follows the cursor at screen coordinates
+    /// (x, y). Returns the offset that the light moved by.
+    pub fn update(&mut self, scene: &mut Scene, x: f32, y: f32) -> SVector3 {
+        let ray = scene.camera().get_pick_ray(x, y);
+        let denom = ray.direction.dot(self.plane_normal);
+
+        // If the cursor points away from the plane, leave the light in place.
+        if denom <= 1e-6 {
+            return SVector3::zero()
+        }
+
+        let t = (self.plane_distance - ray.origin.dot(self.plane_normal)) / denom;
+        let point = ray.origin + ray.direction * t;
+        let offset = point - self.last_point;
+        match self.target {
+            DragTarget::Light(index) => scene.move_light(index, offset),
+            DragTarget::Emitter(ref triangles) => scene.move_emitter(triangles, offset),
+        }
+        self.last_point = point;
+        offset
+    }
+}
+
+/// A problem with the scene geometry found by `Scene::validate_winding()`.
+///
+/// Triangles are identified by their index in the BVH triangle list.
+#[derive(Debug, PartialEq)]
+pub enum Warning {
+    /// The triangle has zero area, so it has no normal at all.
+    DegenerateTriangle { triangle: usize },
+
+    /// The triangles share an edge, but they traverse it in the same
+    /// direction, so their normals point to opposite sides of the surface.
+    InconsistentWinding { triangle: usize, neighbor: usize },
+}
+
+/// The maximum number of glass surfaces that a ray can pass through.
+const MAX_GLASS_HITS: u32 = 8;
+
+/// The maximum number of cutout texels that a ray can pass through.
+const MAX_CUTOUT_HITS: u32 = 8;
+
+/// The opacity of a texture, used to cut holes into surfaces such as leaves
+/// or fences.
+pub struct AlphaMask {
+    width: u32,
+    height: u32,
+
+    /// Opacity per texel, row by row.
+    alpha: Vec<u8>,
+
+    /// Texels with an alpha below the threshold are transparent.
+    threshold: u8,
+
+    /// Whether the alpha is a probability of being opaque, rather than
+    /// compared against the threshold.
+    stochastic: bool,
+}
+
+impl AlphaMask {
+    /// Creates a mask from a width times height grid of alpha values.
+    pub fn new(width: u32, height: u32, alpha: Vec<u8>, threshold: u8) -> AlphaMask {
+        assert_eq!(alpha.len(), (width * height) as usize);
+        AlphaMask {
+            width: width,
+            height: height,
+            alpha: alpha,
+            threshold: threshold,
+            stochastic: false,
+        }
+    }
+
+    /// Creates a mask for stochastic transparency, where a ray hits a texel
+    /// with a probability equal to its alpha, and passes through otherwise.
+    ///
+    /// Overlapping partially transparent surfaces then blend correctly when
+    /// samples are accumulated, without sorting the hits. Intersections that
+    /// are not given a random number treat alpha below one half as
+    /// transparent.
+    pub fn stochastic(width: u32, height: u32, alpha: Vec<u8>) -> AlphaMask {
+        AlphaMask { stochastic: true, ..AlphaMask::new(width, height, alpha, 128) }
+    }
+
+    /// Returns whether the texel at texture coordinates (u, v) is transparent.
+    /// The coordinates wrap, like they do for the textures on the GPU.
+    ///
+    /// For a stochastic mask, `xi` is a random number in [0, 1), and the texel
+    /// is transparent if its alpha does not exceed it. Otherwise `xi` is
+    /// ignored.
+    pub fn is_transparent(&self, u: f32, v: f32, xi: f32) -> bool {
+        let s = u - u.floor();
+        let t = v - v.floor();
+        let i = cmp::min((s * self.width as f32) as u32, self.width - 1);
+        let j = cmp::min((t * self.height as f32) as u32, self.height - 1);
+        let alpha = self.alpha[(j * self.width + i) as usize];
+        if self.stochastic {
+            alpha as f32 * (1.0 / 255.0) <= xi
+        } else {
+            alpha < self.threshold
+        }
+    }
+}
+
+/// How the amount of distance fog grows with distance.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FogFalloff {
+    /// The fraction of light that gets through decays exponentially with
+    /// distance, as in a uniform medium.
+    Exponential,
+
+    /// The amount of fog grows linearly, and it is complete at a distance of
+    /// one over the density.
+    Linear,
+}
+
+/// Distance fog for depth cueing (aerial perspective).
+///
+/// This is not volumetric scattering, the fog is not lit by the lights of the
+/// scene. Geometry seen by the camera is blended towards the fog color based
+/// on its distance. The sky is not affected.
+#[derive(Copy, Clone, Debug)]
+pub struct Fog {
+    pub color: SVector3,
+    pub density: f32,
+    pub falloff: FogFalloff,
+}
+
+impl Fog {
+    /// Returns the fraction of the fog color in [0, 1] at the given distances.
+    pub fn amount(&self, distance: Mf32) -> Mf32 {
+        let d = distance * Mf32::broadcast(self.density);
+        match self.falloff {
+            FogFalloff::Exponential => {
+                // e^-d = 2^(-d log2(e)).
+                let transmitted = (d * Mf32::broadcast(-f32::consts::LOG2_E)).exp2();
+                Mf32::one() - transmitted
+            }
+            FogFalloff::Linear => d.max(Mf32::zero()).min(Mf32::one()),
+        }
+    }
+
+    /// Blends the color towards the fog color by the given amount.
+    pub fn apply(&self, color: MVector3, amount: Mf32) -> MVector3 {
+        let fog = MVector3::broadcast(self.color);
+        (fog - color).mul_add(amount, color)
+    }
 }
 
 pub struct Scene {
-    pub camera: Camera,
+    /// All viewpoints of the scene. There is at least one.
+    cameras: Vec<Camera>,
+
+    /// Index of the camera that primary rays are generated with.
+    active_camera: usize,
 
     /// Bounding volume hierarchy of all triangles in the scene.
     bvh: Bvh,
 
+    /// Point cloud geometry, if any.
+    splats: SplatBvh,
+
     /// Indices into the BVH's triangle list, of triangles that have a material
     /// eligible for direct sampling.
     direct_sample: Vec<u32>,
+
+    /// Hierarchy over the direct sampling triangles, for picking one by
+    /// importance.
+    emitters: EmitterBvh,
+
+    /// Area lights with an emission map.
+    lights: Vec<AreaLight>,
+
+    /// Alpha masks per texture index. Surfaces with a masked texture are
+    /// absent where the mask is transparent.
+    alpha_masks: [Option<AlphaMask>; 4],
+
+    /// The directional albedo of the GGX lobe, for energy compensation.
+    ggx_albedo: GgxAlbedoTable,
+
+    /// Height maps per texture index. They tilt the shading normal of the
+    /// surfaces with that texture.
+    height_maps: [Option<HeightMap>; 4],
+
+    /// Radiance of the sky. If there is none, the built-in gradient is used.
+    environment: Option<EnvironmentMap>,
+
+    /// Analytic sky, used when there is no environment map.
+    physical_sky: Option<PhysicalSky>,
+
+    /// Distance fog applied to the surfaces that the camera sees, if any.
+    fog: Option<Fog>,
+
+    /// The diffusion profiles of translucent materials, see
+    /// `set_subsurface()`.
+    subsurface: Vec<(SMaterial, Subsurface)>,
+
+    /// For every triangle in the BVH, the index of the mesh it came from, and
+    /// the index of the triangle in that mesh.
+    triangle_sources: Vec<(u32, u32)>,
+
+    /// Per mesh, the transform from its current position to its position in
+    /// the previous frame, see `set_mesh_motion()`.
+    mesh_motion: Vec<SAffine>,
+
+    /// Whether any triangle has a vertex color other than white. If not, the
+    /// colors need not be interpolated.
+    vertex_colors: bool,
+
+    /// Whether any mesh restricts its light linking bits. If not, the check
+    /// can be skipped.
+    light_linking: bool,
+
+    /// Whether to traverse the BVH with narrow packets when at most four rays
+    /// of a packet are active, see `Bvh::intersect_nearest_narrow()`.
+    narrow_packets: bool,
 }
 
 impl Scene {
     pub fn from_meshes(meshes: &[Mesh]) -> Scene {
         let bvh = Bvh::from_meshes(meshes);
-
-        let mut direct_sample = Vec::new();
-        for i in 0..bvh.triangles.len() {
-            if bvh.triangles[i].material.is_direct_sample() {
-                direct_sample.push(i as u32);
-            }
-        }
+        let direct_sample = direct_sample_indices(&bvh);
+        let vertex_colors = has_vertex_colors(&bvh);
+        let sources: Vec<(u32, u32)> = meshes.iter()
+            .enumerate()
+            .flat_map(|(i, mesh)| (0..mesh.triangles.len() as u32).map(move |k| (i as u32, k)))
+            .collect();
+        let triangle_sources = bvh.sources.iter().map(|&i| sources[i as usize]).collect();
 
         Scene {
-            camera: Camera::new(),
-            bvh: bvh,
+            cameras: vec![Camera::new()],
+            active_camera: 0,
+            emitters: EmitterBvh::build(&bvh.triangles, &direct_sample),
             direct_sample: direct_sample,
+            bvh: bvh,
+            splats: SplatBvh::empty(),
+            lights: Vec::new(),
+            alpha_masks: [None, None, None, None],
+            height_maps: [None, None, None, None],
+            ggx_albedo: GgxAlbedoTable::new(),
+            environment: None,
+            physical_sky: None,
+            fog: None,
+            subsurface: Vec::new(),
+            triangle_sources: triangle_sources,
+            mesh_motion: vec![SAffine::identity(); meshes.len()],
+            vertex_colors: vertex_colors,
+            light_linking: meshes.iter().any(|m| m.light_links != u32::MAX),
+            narrow_packets: false,
+        }
+    }
+
+    /// Enables or disables narrow traversal for sparse packets.
+    ///
+    /// Bounce rays diverge, and after a few bounces many of them have found a
+    /// light source, so most lanes of their packets are inactive. With narrow
+    /// packets, only the active rays are tested against the bounding boxes.
+    /// The rendered image does not change.
+    pub fn set_narrow_packets(&mut self, enable: bool) {
+        self.narrow_packets = enable;
+    }
+
+    /// Returns whether light linking excludes any light from any surface.
+    pub fn has_light_links(&self) -> bool {
+        self.light_linking
+    }
+
+    /// Adds the triangles, splats, and lights of the other scene to this scene.
+    ///
+    /// Materials are stored with the triangles, so they carry over as they
+    /// are. The BVH and the list of triangles to sample directly are rebuilt.
+    /// If `keep_cameras` is true, the cameras of the other scene are added
+    /// after the cameras of this scene, otherwise they are dropped. Alpha
+    /// masks, height maps, the environment, and the fog of the other scene are
+    /// only used
+    /// where this scene has none, and so are the diffusion profiles of its
+    /// translucent materials.
+    pub fn merge(&mut self, other: Scene, keep_cameras: bool) {
+        let mut triangles = self.bvh.triangles.clone();
+        triangles.extend(other.bvh.triangles.iter().cloned());
+        let mut sources = self.triangle_sources.clone();
+        let num_meshes = self.mesh_motion.len() as u32;
+        sources.extend(other.triangle_sources.iter().map(|&(m, k)| (m + num_meshes, k)));
+        self.mesh_motion.extend(other.mesh_motion.iter().cloned());
+        self.bvh = Bvh::build(&triangles);
+        self.triangle_sources = self.bvh.sources.iter().map(|&i| sources[i as usize]).collect();
+        self.direct_sample = direct_sample_indices(&self.bvh);
+        self.emitters = EmitterBvh::build(&self.bvh.triangles, &self.direct_sample);
+        self.vertex_colors = has_vertex_colors(&self.bvh);
+        self.light_linking = self.light_linking || other.light_linking;
+        if !other.splats.is_empty() {
+            let mut splats = self.splats.splats().to_vec();
+            splats.extend(other.splats.splats().iter().cloned());
+            self.splats = SplatBvh::build(splats);
+        }
+
+        let Scene { cameras, lights, mut alpha_masks, mut height_maps, environment, physical_sky, fog, subsurface, .. } = other;
+        if keep_cameras {
+            self.cameras.extend(cameras);
         }
+        self.lights.extend(lights);
+        for (mine, theirs) in self.alpha_masks.iter_mut().zip(alpha_masks.iter_mut()) {
+            if mine.is_none() {
+                *mine = theirs.take();
+            }
+        }
+        for (mine, theirs) in self.height_maps.iter_mut().zip(height_maps.iter_mut()) {
+            if mine.is_none() {
+                *mine = theirs.take();
+            }
+        }
+        if self.environment.is_none() {
+            self.environment = environment;
+        }
+        if self.physical_sky.is_none() {
+            self.physical_sky = physical_sky;
+        }
+        if self.fog.is_none() {
+            self.fog = fog;
+        }
+        for (material, profile) in subsurface {
+            if self.subsurface_index(material).is_none() {
+                self.subsurface.push((material, profile));
+            }
+        }
+    }
+
+    /// Returns the active camera.
+    pub fn camera(&self) -> &Camera {
+        &self.cameras[self.active_camera]
+    }
+
+    /// Returns the active camera.
+    pub fn camera_mut(&mut self) -> &mut Camera {
+        &mut self.cameras[self.active_camera]
+    }
+
+    /// Returns all cameras, in the order in which they were added.
+    pub fn cameras_mut(&mut self) -> &mut [Camera] {
+        &mut self.cameras[..]
+    }
+
+    /// Adds a camera and returns its index. The scene starts out with one
+    /// camera at index 0.
+    pub fn add_camera(&mut self, camera: Camera) -> usize {
+        self.cameras.push(camera);
+        self.cameras.len() - 1
+    }
+
+    /// Makes the camera with the given index the active one.
+    pub fn set_active_camera(&mut self, index: usize) {
+        assert!(index < self.cameras.len(), "camera index {} out of range", index);
+        self.active_camera = index;
+    }
+
+    pub fn active_camera(&self) -> usize {
+        self.active_camera
+    }
+
+    pub fn num_cameras(&self) -> usize {
+        self.cameras.len()
     }
 
     pub fn print_stats(&self) {
@@ -138,6 +718,45 @@ impl Scene {
                  100.0 * self.direct_sample.len() as f32 / self.bvh.triangles.len() as f32);
     }
 
+    /// Checks all triangles for degeneracy and inconsistent winding.
+    ///
+    /// The renderer derives the normal of a triangle from its winding, the
+    /// meshes do not provide normals of their own. So a triangle without area
+    /// or a triangle wound the other way around than its neighbors has a
+    /// broken normal, which shows up as black artifacts in the render. This
+    /// is a debugging aid to track down the cause, it is slow.
+    pub fn validate_winding(&self) -> Vec<Warning> {
+        let mut warnings = Vec::new();
+
+        // Maps a directed edge to the triangle that has it. In a consistently
+        // wound mesh, a neighboring triangle traverses a shared edge in the
+        // opposite direction.
+        let mut edges = HashMap::new();
+
+        for (i, tri) in self.bvh.triangles.iter().enumerate() {
+            let e1 = tri.v0 - tri.v2;
+            let e2 = tri.v1 - tri.v0;
+            let normal_denorm = e1.cross(e2);
+
+            if normal_denorm.norm_squared() == 0.0 {
+                warnings.push(Warning::DegenerateTriangle { triangle: i });
+                continue;
+            }
+
+            let vertices = [vertex_key(tri.v0), vertex_key(tri.v1), vertex_key(tri.v2)];
+            for k in 0..3 {
+                let edge = (vertices[k], vertices[(k + 1) % 3]);
+                if let Some(&j) = edges.get(&edge) {
+                    warnings.push(Warning::InconsistentWinding { triangle: i, neighbor: j });
+                } else {
+                    edges.insert(edge, i);
+                }
+            }
+        }
+
+        warnings
+    }
+
     /// Returns 8 random points on 8 random triangles eligible for direct
     /// sampling.
     pub fn get_direct_sample(&self, rng: &mut Rng) -> MDirectSample {
@@ -158,6 +777,35 @@ impl Scene {
         // TODO: Are the bounds checks a bottleneck here?
         let indices = generate_slice8(|i| (random_bits[i] >> 29) as u32);
         let tri_indices = generate_slice8(|i| self.direct_sample[indices[i] as usize]);
+        self.sample_triangles(tri_indices, rng)
+    }
+
+    /// Returns 8 random points on direct sampling triangles picked for the 8
+    /// shading points with a probability proportional to their importance,
+    /// see `EmitterBvh`. `emitter_probability()` returns the probability of
+    /// picking a triangle.
+    pub fn get_emitter_sample(&self, points: &MVector3, rng: &mut Rng) -> MDirectSample {
+        let u = rng.sample_unit();
+        let tri_indices = generate_slice8(|i| {
+            let point = SVector3::new(points.x.get_coord(i), points.y.get_coord(i), points.z.get_coord(i));
+            let (triangle, _pdf) = self.emitters.sample(point, u.get_coord(i))
+                .expect("emitter sampling requires direct sampling triangles");
+            triangle
+        });
+        self.sample_triangles(tri_indices, rng)
+    }
+
+    /// Returns the probability that `get_emitter_sample()` picks the direct
+    /// sampling triangle with the given index, for each of the shading points.
+    pub fn emitter_probability(&self, points: &MVector3, triangle: u32) -> Mf32 {
+        Mf32::generate(|i| {
+            let point = SVector3::new(points.x.get_coord(i), points.y.get_coord(i), points.z.get_coord(i));
+            self.emitters.pdf(point, triangle)
+        })
+    }
+
+    /// Returns a uniformly distributed point on each of the 8 triangles.
+    fn sample_triangles(&self, tri_indices: [u32; 8], rng: &mut Rng) -> MDirectSample {
         let tris = generate_slice8(|i| &self.bvh.triangles[tri_indices[i] as usize]);
 
         // Gather the vertices of the triangles into SIMD vectors, so from now
@@ -201,45 +849,1503 @@ impl Scene {
         ds
     }
 
+    /// Cuts out the transparent parts of all surfaces with the given texture
+    /// index. Texture index 0 means "no texture" and cannot be masked.
+    pub fn set_alpha_mask(&mut self, texture_index: u32, mask: AlphaMask) {
+        assert!(texture_index >= 1 && texture_index <= 3);
+        self.alpha_masks[texture_index as usize] = Some(mask);
+    }
+
+    /// Returns the table that compensates the GGX lobe for the energy lost
+    /// to multiple scattering.
+    pub fn ggx_albedo(&self) -> &GgxAlbedoTable {
+        &self.ggx_albedo
+    }
+
+    /// Perturbs the shading normal of all surfaces with the given texture
+    /// index by a height map. Texture index 0 means "no texture".
+    pub fn set_height_map(&mut self, texture_index: u32, map: HeightMap) {
+        assert!(texture_index >= 1 && texture_index <= 3);
+        self.height_maps[texture_index as usize] = Some(map);
+    }
+
+    /// Replaces the sky with an environment map.
+    pub fn set_environment(&mut self, environment: Option<EnvironmentMap>) {
+        self.environment = environment;
+    }
+
+    /// Loads an equirectangular environment map from a Radiance `.hdr` file,
+    /// and uses it as the sky.
+    pub fn load_environment_hdr<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let image = try!(hdr::read_hdr(path));
+        self.environment = Some(EnvironmentMap::from_image(image));
+        Ok(())
+    }
+
+    /// Replaces the sky with a physical daylight sky, lit by a sun in the
+    /// given direction. See `PhysicalSky::new()` for the turbidity. An
+    /// environment map, if set, takes precedence.
+    pub fn set_sky(&mut self, sun_direction: SVector3, turbidity: f32) {
+        self.physical_sky = Some(PhysicalSky::new(sun_direction, turbidity));
+    }
+
+    /// Enables distance fog, or disables it if `fog` is `None`.
+    pub fn set_fog(&mut self, fog: Option<Fog>) {
+        if let Some(ref fog) = fog {
+            assert!(fog.density >= 0.0, "fog density must not be negative");
+        }
+        self.fog = fog;
+    }
+
+    pub fn fog(&self) -> Option<&Fog> {
+        self.fog.as_ref()
+    }
+
+    /// Makes a material translucent, with the given mean free path of light
+    /// below the surface per channel, in world units. The color of the
+    /// material is the albedo of the diffusion profile.
+    ///
+    /// Materials are stored with the triangles in their packed form, so this
+    /// applies to every surface that has exactly this material. The renderer
+    /// spreads the light of those surfaces when it resolves the accumulated
+    /// image, see the `subsurface` module.
+    pub fn set_subsurface(&mut self, material: SMaterial, mean_free_path: SVector3) {
+        let albedo = MMaterial::broadcast_material(material).get_color();
+        let albedo = SVector3::new(albedo.x.get_coord(0), albedo.y.get_coord(0), albedo.z.get_coord(0));
+        let profile = Subsurface::new(mean_free_path, albedo);
+        match self.subsurface_index(material) {
+            Some(i) => self.subsurface[i].1 = profile,
+            None => self.subsurface.push((material, profile)),
+        }
+    }
+
+    /// Converts the material to the packed encoding that the meshes store,
+    /// and makes it translucent if it has a mean free path.
+    pub fn add_material(&mut self, material: &Material) -> SMaterial {
+        let packed = material.to_smaterial();
+        if let Some(mean_free_path) = material.subsurface {
+            self.set_subsurface(packed, mean_free_path);
+        }
+        packed
+    }
+
+    /// Returns the index of the diffusion profile of the material, or `None`
+    /// if the material is not translucent.
+    pub fn subsurface_index(&self, material: SMaterial) -> Option<usize> {
+        self.subsurface.iter().position(|&(m, _)| m == material)
+    }
+
+    /// Returns the diffusion profile with the given index.
+    pub fn subsurface_profile(&self, index: usize) -> &Subsurface {
+        &self.subsurface[index].1
+    }
+
+    /// Returns the number of translucent materials.
+    pub fn num_subsurface_profiles(&self) -> usize {
+        self.subsurface.len()
+    }
+
+    /// Returns the radiance of the sky in the given directions.
+    pub fn sky_intensity(&self, ray_direction: MVector3) -> MVector3 {
+        match (&self.environment, &self.physical_sky) {
+            (&Some(ref environment), _) => environment.lookup_8(ray_direction),
+            (&None, &Some(ref sky)) => sky.radiance_8(ray_direction),
+            (&None, &None) => sky_intensity(ray_direction),
+        }
+    }
+
+    /// Returns the radiance of the sky in the given directions, with the
+    /// lookup into an environment map jittered within a texel, see
+    /// `EnvironmentMap::lookup_jittered()`. Accumulated over many samples,
+    /// this is the bilinearly filtered environment.
+    pub fn sky_intensity_jittered(&self, ray_direction: MVector3, rng: &mut Rng) -> MVector3 {
+        match self.environment {
+            Some(ref environment) => environment.lookup_8_jittered(ray_direction, rng),
+            None => self.sky_intensity(ray_direction),
+        }
+    }
+
+    pub fn add_area_light(&mut self, light: AreaLight) {
+        self.lights.push(light);
+    }
+
+    /// Returns the index of the nearest area light whose bounding sphere the
+    /// ray intersects, if any.
+    pub fn pick_light(&self, ray: &SRay) -> Option<usize> {
+        let mut nearest = None;
+        let mut nearest_t = f32::INFINITY;
+
+        for (i, light) in self.lights.iter().enumerate() {
+            // Solve |origin + t * direction - center|^2 = radius^2 for t.
+            let to_origin = ray.origin - light.center();
+            let r = light.bounding_radius();
+            let b = to_origin.dot(ray.direction);
+            let c = to_origin.norm_squared() - r * r;
+            let discriminant = b * b - c;
+            if discriminant < 0.0 {
+                continue
+            }
+
+            // Take the far intersection if the origin is inside the sphere.
+            let sqrt_d = discriminant.sqrt();
+            let t = if -b - sqrt_d >= 0.0 { -b - sqrt_d } else { -b + sqrt_d };
+            if t >= 0.0 && t < nearest_t {
+                nearest = Some(i);
+                nearest_t = t;
+            }
+        }
+
+        nearest
+    }
+
+    /// Moves area light `index` by the given offset.
+    pub fn move_light(&mut self, index: usize, offset: SVector3) {
+        self.lights[index].translate(offset);
+    }
+
+    /// Returns the emitter that the ray hits first, if nothing else is in
+    /// front of it, and the distance along the ray. The emitter is the group
+    /// of direct sampling triangles connected to the hit triangle by shared
+    /// vertices, as indices into the BVH triangle list.
+    pub fn pick_emitter(&self, ray: &SRay) -> Option<(Vec<u32>, f32)> {
+        let mray = MRay::broadcast(ray);
+        let distance = self.intersect_nearest(&mray).distance.0;
+        let max_distance = distance * 1.0001 + 1e-5;
+
+        let hit = self.direct_sample.iter().cloned().find(|&i| {
+            let triangle = &self.bvh.triangles[i as usize];
+            let isect = triangle.intersect(&mray, MIntersection::with_max_distance(max_distance));
+            isect.distance.0 < max_distance
+        });
+
+        hit.map(|first| {
+            let mut group = vec![first];
+            let mut vertices = HashSet::new();
+            let mut added = true;
+            while added {
+                added = false;
+                for &i in &group {
+                    let tri = &self.bvh.triangles[i as usize];
+                    vertices.extend(&[vertex_key(tri.v0), vertex_key(tri.v1), vertex_key(tri.v2)]);
+                }
+                for &i in &self.direct_sample {
+                    let tri = &self.bvh.triangles[i as usize];
+                    let keys = [vertex_key(tri.v0), vertex_key(tri.v1), vertex_key(tri.v2)];
+                    if !group.contains(&i) && keys.iter().any(|k| vertices.contains(k)) {
+                        group.push(i);
+                        added = true;
+                    }
+                }
+            }
+            group.sort();
+            (group, distance)
+        })
+    }
+
+    /// Moves the given triangles by the offset. The BVH is refitted rather
+    /// than rebuilt, so triangle indices remain valid.
+    pub fn move_emitter(&mut self, triangles: &[u32], offset: SVector3) {
+        for &i in triangles {
+            let triangle = &mut self.bvh.triangles[i as usize];
+            triangle.v0 = triangle.v0 + offset;
+            triangle.v1 = triangle.v1 + offset;
+            triangle.v2 = triangle.v2 + offset;
+        }
+        self.bvh.refit();
+        self.emitters = EmitterBvh::build(&self.bvh.triangles, &self.direct_sample);
+    }
+
+    /// Returns the number of area lights.
+    pub fn num_lights(&self) -> usize {
+        self.lights.len()
+    }
+
+    /// Returns the bounding box of all triangles and splats in the scene.
+    pub fn bounds(&self) -> Aabb {
+        if self.splats.is_empty() {
+            self.bvh.bounds()
+        } else {
+            Aabb::enclose_aabbs(&[self.bvh.bounds(), self.splats.bounds()])
+        }
+    }
+
+    /// Returns the point on the surface of the scene that is closest to the
+    /// given point, the normal of the surface there, and the distance to the
+    /// point. Only triangles are considered, splats are not.
+    pub fn closest_point(&self, point: SVector3) -> (SVector3, SVector3, f32) {
+        let (closest, index, distance) = self.bvh.closest_point(point);
+        (closest, self.bvh.triangles[index as usize].normal(), distance)
+    }
+
+    /// Replaces the point cloud of the scene. Every point is rendered as a
+    /// diffuse disk, see the `splat` module.
+    pub fn set_splats(&mut self, splats: Vec<Splat>) {
+        self.splats = SplatBvh::build(splats);
+    }
+
+    /// Removes the area lights that do not reach the scene: the lights for
+    /// which the irradiance is less than `cutoff` everywhere within the
+    /// bounds of the scene. Returns the number of lights removed.
+    /// The remaining lights keep their order, but their indices shift.
+    pub fn prune_ineffective_lights(&mut self, cutoff: f32) -> usize {
+        let bounds = self.bounds();
+        let before = self.lights.len();
+        self.lights.retain(|light| bounds.distance_to(light.center()) <= light.effective_radius(cutoff));
+        before - self.lights.len()
+    }
+
+    /// Returns the center of area light `index`.
+    pub fn light_center(&self, index: usize) -> SVector3 {
+        self.lights[index].center()
+    }
+
+    /// Returns 8 random points on the area lights, with the radiance emitted
+    /// there.
+    ///
+    /// Every lane picks a light uniformly, and then a point on the light
+    /// proportional to the luminance of its emission map. The returned
+    /// probability density accounts for both. There must be at least one
+    /// light.
+    pub fn sample_light(&self, rng: &mut Rng) -> MLightSample {
+        assert!(!self.lights.is_empty(), "scene has no area lights to sample");
+
+        if self.lights.len() == 1 {
+            return self.lights[0].sample(rng)
+        }
+
+        // Sample every light, and then pick one per lane. Like in
+        // `get_direct_sample()`, use the high order bits of the random number.
+        // This samples more points than needed, but there are few lights.
+        let n = self.lights.len();
+        let random_bits = rng.sample_u32();
+        let indices = generate_slice8(|i| ((random_bits[i] as u64 * n as u64) >> 32) as usize);
+        let samples: Vec<MLightSample> = self.lights.iter().map(|l| l.sample(rng)).collect();
+        let pick = |i: usize| &samples[indices[i]];
+
+        MLightSample {
+            position: MVector3::new(Mf32::generate(|i| pick(i).position.x.get_coord(i)),
+                                    Mf32::generate(|i| pick(i).position.y.get_coord(i)),
+                                    Mf32::generate(|i| pick(i).position.z.get_coord(i))),
+            normal: MVector3::new(Mf32::generate(|i| pick(i).normal.x.get_coord(i)),
+                                  Mf32::generate(|i| pick(i).normal.y.get_coord(i)),
+                                  Mf32::generate(|i| pick(i).normal.z.get_coord(i))),
+            radiance: MVector3::new(Mf32::generate(|i| pick(i).radiance.x.get_coord(i)),
+                                    Mf32::generate(|i| pick(i).radiance.y.get_coord(i)),
+                                    Mf32::generate(|i| pick(i).radiance.z.get_coord(i))),
+            pdf: Mf32::generate(|i| pick(i).pdf.get_coord(i)) * Mf32::broadcast(1.0 / n as f32),
+            lights: indices,
+        }
+    }
+
+    /// Returns the radiance that the sampled lights emit towards the given
+    /// directions, relative to the radiance of the samples. See
+    /// `AreaLight::radiance_factor()`.
+    pub fn light_radiance_factor(&self, sample: &MLightSample, direction: MVector3) -> Mf32 {
+        if !self.lights.iter().any(|l| l.has_profile()) {
+            return Mf32::one()
+        }
+        Mf32::generate(|i| {
+            let d = SVector3::new(direction.x.get_coord(i), direction.y.get_coord(i), direction.z.get_coord(i));
+            self.lights[sample.lights[i]].radiance_factor(d)
+        })
+    }
+
+    /// Returns the cosine term at the receivers for the sampled lights, given
+    /// the cosine between the surface normal and the direction to the light.
+    /// This is the clamped cosine, unless a light has wrap lighting, see
+    /// `AreaLight::set_wrap()`.
+    pub fn light_receiver_factor(&self, sample: &MLightSample, cos_theta: Mf32) -> Mf32 {
+        if !self.lights.iter().any(|l| l.has_wrap()) {
+            return cos_theta.max(Mf32::zero())
+        }
+        Mf32::generate(|i| self.lights[sample.lights[i]].receiver_factor(cos_theta.get_coord(i)))
+    }
+
+    /// Picks points on the area lights to shade the given surface points,
+    /// with resampled importance sampling (RIS).
+    ///
+    /// Every lane draws `num_candidates` points, picking a light uniformly and
+    /// a point on it as `sample_light()` does, and keeps one of them with
+    /// probability proportional to its unshadowed contribution divided by its
+    /// probability density. Only the chosen point needs a shadow ray. Returns
+    /// the chosen points (with the density of drawing them as a candidate)
+    /// and the RIS weights. The contribution of a point, including
+    /// visibility, times its weight is an unbiased estimate of the direct
+    /// light, with much less variance than a single uniform sample when there
+    /// are many lights. Drawing a candidate does not depend on the number of
+    /// lights, so this scales to thousands of lights.
+    pub fn sample_light_ris(&self,
+                            position: MVector3,
+                            normal: MVector3,
+                            rng: &mut Rng,
+                            num_candidates: u32)
+                            -> (MLightSample, Mf32) {
+        assert!(!self.lights.is_empty(), "scene has no area lights to sample");
+        assert!(num_candidates > 0, "RIS needs at least one candidate");
+
+        let n = self.lights.len();
+        let mut chosen = generate_slice8(|_| (SVector3::zero(), SVector3::zero(), SVector3::zero(), 1.0, 0.0, 0));
+        let mut weight_sum = [0.0f32; 8];
+
+        for _ in 0..num_candidates {
+            let random_bits = rng.sample_u32();
+            let xi = rng.sample_unit();
+            let (a, b) = rng.sample_unit_pair();
+            let select = rng.sample_unit();
+            for k in 0..8 {
+                let index = ((random_bits[k] as u64 * n as u64) >> 32) as usize;
+                let light = &self.lights[index];
+                let (point, radiance, pdf_area) = light.sample_point(xi.get_coord(k), a.get_coord(k), b.get_coord(k));
+                let pdf = pdf_area / n as f32;
+
+                // The target density is the unshadowed contribution, with the
+                // color reduced to its luminance.
+                let p = SVector3::new(position.x.get_coord(k), position.y.get_coord(k), position.z.get_coord(k));
+                let nr = SVector3::new(normal.x.get_coord(k), normal.y.get_coord(k), normal.z.get_coord(k));
+                let to_light = point - p;
+                let dist_sqr = to_light.norm_squared();
+                let dir = to_light * (1.0 / dist_sqr.sqrt());
+                let cos_receiver = nr.dot(dir).max(0.0);
+                let emitted = light.directional_factor(-dir);
+                let lum = 0.2126 * radiance.x + 0.7152 * radiance.y + 0.0722 * radiance.z;
+                let target = lum * cos_receiver * emitted / dist_sqr;
+
+                // Keep this candidate with probability proportional to its
+                // weight, as in weighted reservoir sampling.
+                let w = if pdf > 0.0 { target / pdf } else { 0.0 };
+                weight_sum[k] += w;
+                if w > 0.0 && select.get_coord(k) * weight_sum[k] < w {
+                    chosen[k] = (point, light.normal(), radiance, pdf, target, index);
+                }
+            }
+        }
+
+        let sample = MLightSample {
+            position: MVector3::generate(|k| chosen[k].0),
+            normal: MVector3::generate(|k| chosen[k].1),
+            radiance: MVector3::generate(|k| chosen[k].2),
+            pdf: Mf32::generate(|k| chosen[k].3),
+            lights: generate_slice8(|k| chosen[k].5),
+        };
+        let weight = Mf32::generate(|k| {
+            let target = chosen[k].4;
+            if target > 0.0 { weight_sum[k] / (num_candidates as f32 * target) } else { 0.0 }
+        });
+        (sample, weight)
+    }
+
+    /// Intersects the rays with the area lights. Returns the radiance of the
+    /// nearest light that a ray hits before `max_distance`, and a mask that
+    /// has the sign bit set for the rays that hit a light.
+    ///
+    /// Area lights are not part of the BVH, so this has to be tested in
+    /// addition to the nearest intersection. Lights emit to one side only, and
+    /// from the back they are invisible.
+    pub fn intersect_lights(&self, ray: &MRay, max_distance: Mf32) -> (MVector3, Mask) {
+        let mut radiance = [SVector3::zero(); 8];
+        let mut hit = [0.0f32; 8];
+        for k in 0..8 {
+            let origin = SVector3::new(ray.origin.x.get_coord(k), ray.origin.y.get_coord(k), ray.origin.z.get_coord(k));
+            let direction = SVector3::new(ray.direction.x.get_coord(k),
+                                          ray.direction.y.get_coord(k),
+                                          ray.direction.z.get_coord(k));
+            let mut nearest_t = max_distance.get_coord(k);
+            for light in &self.lights {
+                if let Some((t, r)) = light.intersect(origin, direction) {
+                    if t < nearest_t {
+                        nearest_t = t;
+                        radiance[k] = r;
+                        hit[k] = -1.0;
+                    }
+                }
+            }
+        }
+        (MVector3::generate(|k| radiance[k]), Mf32::generate(|k| hit[k]))
+    }
+
     /// Returns the number of triangles eligible for direct sampling.
     pub fn direct_sample_num(&self) -> usize {
         self.direct_sample.len()
     }
 
-    pub fn foreach_direct_sample<F: FnMut(&Triangle)>(&self, mut f: F) {
-        for i in &self.direct_sample {
+    /// Calls `f` with the index and the triangle of every triangle eligible
+    /// for direct sampling.
+    pub fn foreach_direct_sample<F: FnMut(u32, &Triangle)>(&self, mut f: F) {
+        for &i in &self.direct_sample {
             // TODO: Remove the bounds check?
-            let triangle = &self.bvh.triangles[*i as usize];
-            f(triangle);
+            let triangle = &self.bvh.triangles[i as usize];
+            f(i, triangle);
         }
     }
 
     /// Returns the interections with the shortest distance along the ray.
     ///
     /// Intersects the sky if no other geometry was intersected.
+    ///
+    /// Rays pass through the transparent parts of surfaces that have an alpha
+    /// mask. After `MAX_CUTOUT_HITS` transparent texels, a ray stops at the
+    /// surface regardless.
     pub fn intersect_nearest(&self, ray: &MRay) -> MIntersection {
-        let huge_distance = Mf32::broadcast(1.0e5);
-        let far_away = MIntersection {
-            position: ray.direction.mul_add(huge_distance, ray.origin),
-            normal: ray.direction,
-            distance: huge_distance,
-            material: MMaterial::sky(),
-            tex_coords: (Mf32::zero(), Mf32::zero()),
+        self.intersect_nearest_alpha(ray, None)
+    }
+
+    /// Like `intersect_nearest()`, but draws the random numbers for
+    /// stochastic alpha masks from the rng, see `AlphaMask::stochastic()`.
+    pub fn intersect_nearest_stochastic(&self, ray: &MRay, rng: &mut Rng) -> MIntersection {
+        self.intersect_nearest_alpha(ray, Some(rng))
+    }
+
+    /// See `intersect_nearest()`. Without an rng, stochastic alpha masks
+    /// compare the alpha to one half.
+    fn intersect_nearest_alpha(&self, ray: &MRay, mut rng: Option<&mut Rng>) -> MIntersection {
+        ray.debug_assert_invariants();
+        let mut isect = self.intersect_nearest_unmasked(ray);
+
+        if self.alpha_masks.iter().all(|m| m.is_none()) {
+            return isect
+        }
+
+        let mut segment = ray.clone();
+        let mut traveled = Mf32::zero();
+
+        for _ in 0..MAX_CUTOUT_HITS {
+            // Continue only the active rays that hit a transparent texel. Sign
+            // bit 1 means that the ray is done.
+            let xi = match rng {
+                Some(ref mut rng) => rng.sample_unit(),
+                None => Mf32::broadcast(0.5),
+            };
+            let done = segment.active | self.is_cutout(&isect, xi).neg_xor();
+            if done.all_sign_bits_negative() {
+                break
+            }
+
+            traveled = (traveled + isect.distance).pick(traveled, done);
+            segment = MRay {
+                origin: isect.offset_origin(segment.direction),
+                direction: segment.direction,
+                active: done,
+            };
+            let next = self.intersect_nearest_unmasked(&segment);
+            isect = next.pick(&isect, done);
+        }
+
+        isect.distance = isect.distance + traveled;
+        isect
+    }
+
+    /// Returns the nearest intersections, ignoring alpha masks.
+    fn intersect_nearest_unmasked(&self, ray: &MRay) -> MIntersection {
+        let isect = if self.narrow_packets {
+            self.bvh.intersect_nearest_narrow(ray, sky_intersection(ray))
+        } else {
+            self.bvh.intersect_nearest(ray, sky_intersection(ray))
         };
-        self.bvh.intersect_nearest(ray, far_away)
+        let isect = self.interpolate_vertex_colors(isect);
+        let isect = if self.splats.is_empty() { isect } else { self.splats.intersect_nearest(ray, isect) };
+        isect.debug_assert_invariants(ray.active);
+        isect
+    }
+
+    /// Fills in the vertex colors of the triangles that the rays hit.
+    ///
+    /// Triangle intersection leaves the vertex color white, so that the
+    /// colors are interpolated from the barycentric coordinates once, after
+    /// traversal, rather than for every triangle that is tested.
+    fn interpolate_vertex_colors(&self, isect: MIntersection) -> MIntersection {
+        if !self.vertex_colors {
+            return isect
+        }
+        let (b1, b2) = isect.barycentric;
+        let color = MVector3::generate(|i| match isect.triangle_index(i) {
+            Some(t) => self.bvh.triangles[t as usize].color_at(b1.get_coord(i), b2.get_coord(i)),
+            None => SVector3::one(),
+        });
+        MIntersection { vertex_color: color, .. isect }
+    }
+
+    /// Replaces the normal of intersections with a surface that has a height
+    /// map by the normal of the displaced surface. Intersections with other
+    /// surfaces, and with triangles whose texture coordinates are degenerate,
+    /// are returned unchanged.
+    pub fn apply_height_maps(&self, isect: MIntersection) -> MIntersection {
+        if self.height_maps.iter().all(|m| m.is_none()) {
+            return isect
+        }
+
+        let texture = isect.material.get_texture();
+        let mut derivatives = [None; 8];
+        for i in 0..8 {
+            if self.height_maps[texture.get_coord(i) as usize].is_some() {
+                derivatives[i] = isect.triangle_index(i)
+                    .and_then(|t| self.bvh.triangles[t as usize].position_derivatives());
+            }
+        }
+
+        // Lanes without derivatives get an arbitrary frame, their result is
+        // not used.
+        let dp_du = MVector3::generate(|i| derivatives[i].map_or(SVector3::new(1.0, 0.0, 0.0), |d| d.0));
+        let dp_dv = MVector3::generate(|i| derivatives[i].map_or(SVector3::new(0.0, 1.0, 0.0), |d| d.1));
+
+        let mut normal = isect.normal;
+        for k in 1..4 {
+            if let Some(ref map) = self.height_maps[k] {
+                let mask = Mf32::generate(|i| {
+                    let mapped = derivatives[i].is_some() && texture.get_coord(i) as usize == k;
+                    if mapped { -1.0 } else { 0.0 }
+                });
+                if mask.all_sign_bits_positive() {
+                    continue
+                }
+                let bumped = map.height_to_normal(isect.normal, dp_du, dp_dv, isect.tex_coords);
+                normal = normal.pick(bumped, mask);
+            }
+        }
+
+        MIntersection { normal: normal, .. isect }
+    }
+
+    /// Returns a mask with sign bit 1 for the intersections that lie on a
+    /// transparent texel of an alpha mask, with `xi` the random numbers for
+    /// stochastic masks.
+    fn is_cutout(&self, isect: &MIntersection, xi: Mf32) -> Mf32 {
+        let texture = isect.material.get_texture();
+        let (u, v) = isect.tex_coords;
+        Mf32::generate(|i| {
+            match self.alpha_masks[texture.get_coord(i) as usize] {
+                Some(ref mask) if mask.is_transparent(u.get_coord(i), v.get_coord(i), xi.get_coord(i)) => -1.0,
+                _ => 0.0,
+            }
+        })
+    }
+
+    /// Returns the nearest intersection with a surface that is not glass, and
+    /// the fraction of light per channel that is transmitted along the way.
+    /// This is for visibility queries such as shadow rays, the path tracer
+    /// itself reflects and refracts at glass.
+    ///
+    /// Rays pass straight through glass surfaces. When a ray leaves glass, the
+    /// light is attenuated according to the distance traveled inside (the
+    /// Beer-Lambert law), so passing through several panes of glass
+    /// accumulates the absorption of all of them. After `MAX_GLASS_HITS`
+    /// glass surfaces, a ray stops at the glass.
+    pub fn intersect_nearest_opaque(&self, ray: &MRay) -> (MIntersection, MVector3) {
+        self.intersect_nearest_opaque_alpha(ray, None)
+    }
+
+    /// Like `intersect_nearest_opaque()`, but draws the random numbers for
+    /// stochastic alpha masks from the rng, see `AlphaMask::stochastic()`.
+    pub fn intersect_nearest_opaque_stochastic(&self, ray: &MRay, rng: &mut Rng) -> (MIntersection, MVector3) {
+        self.intersect_nearest_opaque_alpha(ray, Some(rng))
+    }
+
+    /// See `intersect_nearest_opaque()` and `intersect_nearest_alpha()`.
+    fn intersect_nearest_opaque_alpha(&self, ray: &MRay, mut rng: Option<&mut Rng>) -> (MIntersection, MVector3) {
+        let one = Mf32::one();
+        let mut transmittance = MVector3::new(one, one, one);
+        let mut segment = ray.clone();
+        let mut isect = self.intersect_nearest_alpha(&segment, rng.as_mut().map(|r| &mut **r));
+
+        // The distance from the ray origin to the origin of the segment.
+        let mut traveled = Mf32::zero();
+
+        for _ in 0..MAX_GLASS_HITS {
+            // Continue only the active rays that hit glass. Sign bit 1 means
+            // that the ray is done.
+            let done = segment.active | isect.material.is_glass().neg_xor();
+            if done.all_sign_bits_negative() {
+                break
+            }
+
+            // A ray leaves the glass if it hits the back face. Then the
+            // segment up to here was inside.
+            let absorbed = transmittance.mul_coords(isect.material.get_transmittance(isect.distance));
+            transmittance = absorbed.pick(transmittance, isect.front_face | done);
+
+            traveled = (traveled + isect.distance).pick(traveled, done);
+            segment = MRay {
+                origin: isect.offset_origin(segment.direction),
+                direction: segment.direction,
+                active: done,
+            };
+            let next = self.intersect_nearest_alpha(&segment, rng.as_mut().map(|r| &mut **r));
+            isect = next.pick(&isect, done);
+        }
+
+        isect.distance = isect.distance + traveled;
+        (isect, transmittance)
     }
 
     /// Returns the number of AABBs and triangles intersected to find the
     /// nearest intersection.
     pub fn intersect_debug(&self, ray: &MRay) -> (u32, u32) {
-        let huge_distance = Mf32::broadcast(1.0e5);
-        let far_away = MIntersection {
-            position: ray.direction.mul_add(huge_distance, ray.origin),
-            normal: ray.direction,
-            distance: huge_distance,
-            material: MMaterial::sky(),
-            tex_coords: (Mf32::zero(), Mf32::zero()),
+        self.bvh.intersect_debug(ray, sky_intersection(ray))
+    }
+
+    /// Moves the triangles to the vertices of the given meshes, which must be
+    /// the meshes that the scene was built from, in order, with the same
+    /// triangles. The BVH is refitted rather than rebuilt, so this is cheap
+    /// enough to animate the scene, but the BVH becomes less efficient when
+    /// triangles move far.
+    pub fn update_mesh_vertices(&mut self, meshes: &[Mesh]) {
+        assert_eq!(meshes.len(), self.mesh_motion.len(), "there must be one mesh per mesh of the scene");
+        for (triangle, &(m, k)) in self.bvh.triangles.iter_mut().zip(&self.triangle_sources) {
+            let mesh = &meshes[m as usize];
+            let (i0, i1, i2) = mesh.triangles[k as usize].vertices;
+            triangle.v0 = mesh.vertices[i0 as usize];
+            triangle.v1 = mesh.vertices[i1 as usize];
+            triangle.v2 = mesh.vertices[i2 as usize];
+        }
+        self.bvh.refit();
+        self.emitters = EmitterBvh::build(&self.bvh.triangles, &self.direct_sample);
+    }
+
+    /// Sets for every mesh that the scene was built from, in order, the
+    /// transform from its current position to its position in the previous
+    /// frame, see `SceneGraph::motion_transforms()`. This is only used to
+    /// compute motion vectors.
+    pub fn set_mesh_motion(&mut self, motion: Vec<SAffine>) {
+        assert_eq!(motion.len(), self.mesh_motion.len(), "there must be one transform per mesh");
+        self.mesh_motion = motion;
+    }
+
+    /// Returns the positions of the intersections in the previous frame.
+    /// Surfaces other than triangles do not move.
+    pub fn previous_positions(&self, isect: &MIntersection) -> MVector3 {
+        MVector3::generate(|i| {
+            let p = SVector3::new(isect.position.x.get_coord(i),
+                                  isect.position.y.get_coord(i),
+                                  isect.position.z.get_coord(i));
+            match isect.triangle_index(i) {
+                Some(t) => self.mesh_motion[self.triangle_sources[t as usize].0 as usize].apply_point(p),
+                None => p,
+            }
+        })
+    }
+}
+
+/// Returns an intersection with the sky far away along the ray, for rays that
+/// hit no geometry.
+fn sky_intersection(ray: &MRay) -> MIntersection {
+    let huge_distance = Mf32::broadcast(1.0e5);
+    MIntersection {
+        position: ray.direction.mul_add(huge_distance, ray.origin),
+        normal: ray.direction,
+        distance: huge_distance,
+        material: MMaterial::sky(),
+        tex_coords: (Mf32::zero(), Mf32::zero()),
+        front_face: Mf32::zero(),
+        light_links: Mask::ones(),
+        barycentric: (Mf32::zero(), Mf32::zero()),
+        vertex_color: MVector3::new(Mf32::one(), Mf32::one(), Mf32::one()),
+        triangle: triangle_bits(NO_TRIANGLE),
+    }
+}
+
+/// Returns the indices of the triangles in the BVH that have a material
+/// eligible for direct sampling.
+fn direct_sample_indices(bvh: &Bvh) -> Vec<u32> {
+    let mut direct_sample = Vec::new();
+    for i in 0..bvh.triangles.len() {
+        if bvh.triangles[i].material.is_direct_sample() {
+            direct_sample.push(i as u32);
+        }
+    }
+    direct_sample
+}
+
+/// Returns whether any triangle in the BVH has a vertex color other than
+/// white.
+fn has_vertex_colors(bvh: &Bvh) -> bool {
+    let white = SVector3::one();
+    bvh.triangles.iter().any(|t| t.color0 != white || t.color1 != white || t.color2 != white)
+}
+
+/// Returns the bit patterns of the coordinates, for exact comparison of
+/// vertices in a hash map.
+fn vertex_key(v: SVector3) -> (u32, u32, u32) {
+    use std::mem::transmute;
+    unsafe { (transmute(v.x), transmute(v.y), transmute(v.z)) }
+}
+
+#[test]
+fn validate_winding_reports_degenerate_triangle() {
+    use material::SMaterial;
+    use wavefront::{self, Mesh};
+
+    let vertices = vec![
+        SVector3::new(0.0, 0.0, 0.0),
+        SVector3::new(1.0, 0.0, 0.0),
+        SVector3::new(1.0, 1.0, 0.0),
+        SVector3::new(0.0, 1.0, 0.0),
+        SVector3::new(2.0, 0.0, 0.0),
+        SVector3::new(10.0, 0.0, 0.0),
+        SVector3::new(11.0, 0.0, 0.0),
+        SVector3::new(11.0, 1.0, 0.0),
+        SVector3::new(10.0, 1.0, 0.0),
+    ];
+    let tri = |i0, i1, i2| wavefront::Triangle {
+        vertices: (i0, i1, i2),
+        tex_coords: None,
+        material: SMaterial::white(),
+    };
+    let mesh = Mesh {
+        vertices: vertices,
+        tex_coords: Vec::new(),
+        colors: Vec::new(),
+        // A consistently wound quad, and a triangle with collinear vertices.
+        // The second quad is far away, so the BVH has something to split.
+        triangles: vec![tri(0, 1, 2), tri(0, 2, 3), tri(0, 1, 4), tri(5, 6, 7), tri(5, 7, 8)],
+        light_links: u32::MAX,
+    };
+
+    let scene = Scene::from_meshes(&[mesh]);
+    let warnings = scene.validate_winding();
+
+    assert_eq!(1, warnings.len(), "expected one warning, got {:?}", warnings);
+    match warnings[0] {
+        Warning::DegenerateTriangle { triangle } => {
+            let t = &scene.bvh.triangles[triangle];
+            assert_eq!(t.v2, SVector3::new(2.0, 0.0, 0.0));
+        }
+        ref w => panic!("expected a degenerate triangle, got {:?}", w),
+    }
+}
+
+#[test]
+fn validate_winding_reports_inconsistent_winding() {
+    use material::SMaterial;
+    use wavefront::{self, Mesh};
+
+    let vertices = vec![
+        SVector3::new(0.0, 0.0, 0.0),
+        SVector3::new(1.0, 0.0, 0.0),
+        SVector3::new(1.0, 1.0, 0.0),
+        SVector3::new(0.0, 1.0, 0.0),
+        SVector3::new(10.0, 0.0, 0.0),
+        SVector3::new(11.0, 0.0, 0.0),
+        SVector3::new(11.0, 1.0, 0.0),
+        SVector3::new(10.0, 1.0, 0.0),
+    ];
+    let tri = |i0, i1, i2| wavefront::Triangle {
+        vertices: (i0, i1, i2),
+        tex_coords: None,
+        material: SMaterial::white(),
+    };
+    let mesh = Mesh {
+        vertices: vertices,
+        tex_coords: Vec::new(),
+        colors: Vec::new(),
+        // The second triangle is wound the other way around. The quad far
+        // away is consistent, it is there so the BVH has something to split.
+        triangles: vec![tri(0, 1, 2), tri(0, 3, 2), tri(4, 5, 6), tri(4, 6, 7)],
+        light_links: u32::MAX,
+    };
+
+    let scene = Scene::from_meshes(&[mesh]);
+    let warnings = scene.validate_winding();
+
+    assert_eq!(1, warnings.len(), "expected one warning, got {:?}", warnings);
+    match warnings[0] {
+        Warning::InconsistentWinding { .. } => {}
+        ref w => panic!("expected inconsistent winding, got {:?}", w),
+    }
+}
+
+#[test]
+fn world_to_view_rotates_with_camera() {
+    let normal = MVector3::broadcast(SVector3::new(0.0, 0.0, 1.0));
+    let mut camera = Camera::new();
+    let same = camera.world_to_view(normal);
+    assert!(((same - normal).norm_squared() - Mf32::broadcast(1e-10)).all_sign_bits_negative());
+
+    // Turned by a quarter, the camera looks along the positive x-axis, so
+    // the positive z-axis points to its right.
+    camera.set_rotation(PI * 0.5, 0.0);
+    let view = camera.world_to_view(normal);
+    let expected = MVector3::broadcast(SVector3::new(1.0, 0.0, 0.0));
+    assert!(((view - expected).norm_squared() - Mf32::broadcast(1e-10)).all_sign_bits_negative());
+}
+
+#[test]
+fn depth_of_field_rays_converge_at_focus_distance() {
+    let mut camera = Camera::new();
+    camera.set_depth_of_field(0.2, 3.0, Aperture::Polygon(6));
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let (x, y) = (Mf32::broadcast(0.3), Mf32::broadcast(-0.2));
+    let pinhole = camera.get_ray(x, y, Mf32::zero());
+
+    for _ in 0..16 {
+        let ray = camera.get_ray_lens(x, y, Mf32::zero(), &mut rng);
+        for i in 0..8 {
+            let o = SVector3::new(ray.origin.x.get_coord(i), ray.origin.y.get_coord(i), ray.origin.z.get_coord(i));
+            let d = SVector3::new(ray.direction.x.get_coord(i), ray.direction.y.get_coord(i), ray.direction.z.get_coord(i));
+            let p = SVector3::new(pinhole.direction.x.0, pinhole.direction.y.0, pinhole.direction.z.0);
+
+            // The origin lies on the lens, and every ray passes through the
+            // point where the pinhole ray meets the focal plane.
+            assert!(o.z == 0.0 && o.norm_squared() <= 0.2 * 0.2 + 1e-6);
+            let focus = p * (-3.0 / p.z);
+            let at_focus = o + d * ((-3.0 - o.z) / d.z);
+            assert!((at_focus - focus).norm_squared() < 1e-8, "{} should be {}", at_focus, focus);
+        }
+    }
+}
+
+#[test]
+fn near_clip_culls_surfaces_in_front_of_plane() {
+    use bench;
+    use material::SMaterial;
+
+    // Two walls in front of the camera, a white one at distance 1, and a red
+    // one at distance 3.
+    let v = SVector3::new;
+    let quads = [
+        ([v(-9.0, -9.0, -1.0), v(9.0, -9.0, -1.0), v(9.0, 9.0, -1.0), v(-9.0, 9.0, -1.0)], SMaterial::white()),
+        ([v(-9.0, -9.0, -3.0), v(9.0, -9.0, -3.0), v(9.0, 9.0, -3.0), v(-9.0, 9.0, -3.0)], SMaterial::diffuse(1.0, 0.0, 0.0)),
+    ];
+    let mut scene = Scene::from_meshes(&[bench::mesh_from_quads(&quads)]);
+    let (x, y) = (Mf32::broadcast(0.3), Mf32::broadcast(-0.2));
+
+    let isect = scene.intersect_nearest(&scene.camera().get_ray(x, y, Mf32::zero()));
+    assert_eq!(isect.material.get_color().y.0, 1.0, "the white wall should be visible");
+
+    // With the clip plane between the walls, the camera sees the red wall.
+    scene.camera_mut().set_near_clip(2.0);
+    let ray = scene.camera().get_ray(x, y, Mf32::zero());
+    assert!((ray.origin.z.0 + 2.0).abs() < 1e-5, "ray should start at the clip plane");
+    let isect = scene.intersect_nearest(&ray);
+    assert_eq!(isect.material.get_color().y.0, 0.0, "the white wall should be clipped");
+    assert_eq!(isect.material.get_color().x.0, 1.0);
+    assert!((isect.position.z.0 + 3.0).abs() < 1e-5);
+}
+
+#[test]
+fn merge_keeps_triangles_with_their_materials() {
+    use bench;
+    use material::SMaterial;
+    use std::mem::transmute;
+
+    // A square in the plane z = 0, shifted along the x-axis, and a copy of it
+    // far above. (The BVH does not split a single square, and it needs two
+    // roots.)
+    let scene_with_square = |x: f32, material: SMaterial| {
+        let square = |y: f32| [SVector3::new(x - 1.0, y - 1.0, 0.0), SVector3::new(x + 1.0, y - 1.0, 0.0),
+                               SVector3::new(x + 1.0, y + 1.0, 0.0), SVector3::new(x - 1.0, y + 1.0, 0.0)];
+        let quads = [(square(0.0), material.clone()), (square(10.0), material)];
+        Scene::from_meshes(&[bench::mesh_from_quads(&quads)])
+    };
+    let red = SMaterial::diffuse(1.0, 0.0, 0.0);
+    let green = SMaterial::diffuse(0.0, 1.0, 0.0);
+    let mut scene = scene_with_square(-2.0, red.clone());
+    let mut other = scene_with_square(2.0, green.clone());
+    other.add_camera(Camera::new());
+    scene.merge(other, true);
+    assert_eq!(scene.num_cameras(), 3);
+
+    // The even lanes aim at the red triangle, the odd lanes at the green one.
+    let ray = MRay {
+        origin: MVector3::new(Mf32::generate(|i| if i % 2 == 0 { -2.0 } else { 2.0 }),
+                              Mf32::zero(),
+                              Mf32::broadcast(-1.0)),
+        direction: MVector3::new(Mf32::zero(), Mf32::zero(), Mf32::one()),
+        active: Mf32::zero(),
+    };
+    let isect = scene.intersect_nearest(&ray);
+    let bits = |m: MMaterial, i: usize| -> u32 { unsafe { transmute(m.get_coord(i)) } };
+    let red_bits = bits(MMaterial::broadcast_material(red), 0);
+    let green_bits = bits(MMaterial::broadcast_material(green), 0);
+    for i in 0..8 {
+        assert!((isect.distance.get_coord(i) - 1.0).abs() < 1e-4);
+        let expected = if i % 2 == 0 { red_bits } else { green_bits };
+        assert_eq!(bits(isect.material, i), expected);
+    }
+
+    // Without keeping the cameras, only the triangles are added.
+    let mut scene = scene_with_square(-2.0, SMaterial::white());
+    scene.merge(scene_with_square(2.0, SMaterial::white()), false);
+    assert_eq!(scene.num_cameras(), 1);
+}
+
+#[test]
+fn thick_glass_casts_darker_more_saturated_shadow() {
+    use bench;
+    use material::SMaterial;
+
+    // Shoot rays up from a floor through a slab of colored glass, and measure
+    // the transmittance towards the sky.
+    let transmittance = |thickness: f32| {
+        let mut quads = vec![
+            ([SVector3::new(-4.0, 0.0, 4.0), SVector3::new(4.0, 0.0, 4.0),
+              SVector3::new(4.0, 0.0, -4.0), SVector3::new(-4.0, 0.0, -4.0)], SMaterial::white()),
+        ];
+        let glass = SMaterial::colored_glass(0.9, 0.6, 0.3);
+        let min = SVector3::new(-1.0, 1.0, -1.0);
+        let max = SVector3::new(1.0, 1.0 + thickness, 1.0);
+        quads.extend(bench::box_quads(min, max, glass));
+        let scene = Scene::from_meshes(&[bench::mesh_from_quads(&quads)]);
+
+        let ray = MRay {
+            origin: MVector3::new(Mf32::generate(|i| i as f32 * 0.1 - 0.35), Mf32::broadcast(0.01), Mf32::zero()),
+            direction: MVector3::new(Mf32::zero(), Mf32::one(), Mf32::zero()),
+            active: Mf32::zero(),
         };
-        self.bvh.intersect_debug(ray, far_away)
+        let (isect, t) = scene.intersect_nearest_opaque(&ray);
+
+        // The rays should pass through the glass and hit the sky.
+        assert!(isect.material.all_sign_bits_negative());
+        (t.x.0, t.y.0, t.z.0)
+    };
+
+    let (r_thin, g_thin, b_thin) = transmittance(0.1);
+    let (r_thick, g_thick, b_thick) = transmittance(1.0);
+
+    // Transmittance per unit of distance is the glass color.
+    assert!((r_thick - 0.9).abs() < 0.01 && (g_thick - 0.6).abs() < 0.01 && (b_thick - 0.3).abs() < 0.01);
+
+    let saturation = |r: f32, b: f32| (r - b) / r;
+    assert!(r_thick + g_thick + b_thick < r_thin + g_thin + b_thin, "thick glass should be darker");
+    assert!(saturation(r_thick, b_thick) > saturation(r_thin, b_thin), "thick glass should be more saturated");
+}
+
+#[test]
+fn intersect_nearest_opaque_stops_at_opaque_surface() {
+    use bench;
+    use material::SMaterial;
+
+    // A glass slab floating above an opaque block: the rays pass the glass
+    // and hit the block, and they are attenuated by the glass.
+    let mut quads = bench::box_quads(SVector3::new(-1.0, 1.0, -1.0), SVector3::new(1.0, 1.5, 1.0),
+                                     SMaterial::colored_glass(0.5, 0.5, 0.5));
+    quads.extend(bench::box_quads(SVector3::new(-1.0, 3.0, -1.0), SVector3::new(1.0, 4.0, 1.0),
+                                  SMaterial::white()));
+    let scene = Scene::from_meshes(&[bench::mesh_from_quads(&quads)]);
+    let ray = MRay {
+        origin: MVector3::broadcast(SVector3::new(0.3, 0.0, 0.2)),
+        direction: MVector3::new(Mf32::zero(), Mf32::one(), Mf32::zero()),
+        active: Mf32::zero(),
+    };
+    let (isect, t) = scene.intersect_nearest_opaque(&ray);
+    assert!((isect.distance.0 - 3.0).abs() < 0.01);
+    assert!((t.x.0 - 0.5f32.sqrt()).abs() < 0.01);
+}
+
+#[test]
+fn intersection_front_face_inverts_ior_when_exiting() {
+    use bench;
+    use material::SMaterial;
+
+    // Rays go up through a glass slab. The even lanes start below the slab
+    // and enter it, the odd lanes start inside and exit it.
+    let quads = bench::box_quads(SVector3::new(-1.0, 1.0, -1.0), SVector3::new(1.0, 2.0, 1.0),
+                                 SMaterial::glass());
+    let scene = Scene::from_meshes(&[bench::mesh_from_quads(&quads)]);
+    let ray = MRay {
+        origin: MVector3::new(Mf32::generate(|i| i as f32 * 0.1 - 0.35),
+                              Mf32::generate(|i| if i % 2 == 0 { 0.0 } else { 1.5 }),
+                              Mf32::zero()),
+        direction: MVector3::new(Mf32::zero(), Mf32::one(), Mf32::zero()),
+        active: Mf32::zero(),
+    };
+    let isect = scene.intersect_nearest(&ray);
+    let eta = isect.relative_ior(1.5);
+    let facing = isect.facing_normal();
+
+    for i in 0..8 {
+        let entering = i % 2 == 0;
+        assert_eq!(isect.front_face.get_coord(i).is_sign_negative(), entering);
+        assert!((isect.position.y.get_coord(i) - if entering { 1.0 } else { 2.0 }).abs() < 1e-4);
+
+        // The facing normal always points back towards the ray origin.
+        assert!(facing.y.get_coord(i) < -0.99);
+    }
+
+    // Entering and exiting lanes use reciprocal ratios.
+    assert!((eta.get_coord(0) - 1.0 / 1.5).abs() < 1e-6);
+    assert!((eta.get_coord(1) - 1.5).abs() < 1e-6);
+    assert!((eta.get_coord(0) * eta.get_coord(1) - 1.0).abs() < 1e-6);
+}
+
+#[test]
+fn sample_light_accounts_for_light_choice() {
+    use bench;
+    use light::EmissionMap;
+
+    // Two lights with uniform emission, of area 1 and 4. Every lane picks
+    // either one with probability 1/2.
+    let mut scene = bench::caustic_scene();
+    let white = SVector3::new(1.0, 1.0, 1.0);
+    let x = SVector3::new(1.0, 0.0, 0.0);
+    let z = SVector3::new(0.0, 0.0, 1.0);
+    scene.add_area_light(AreaLight::new(SVector3::zero(), x, z, EmissionMap::constant(white)));
+    scene.add_area_light(AreaLight::new(SVector3::new(5.0, 0.0, 0.0), x * 2.0, z * 2.0, EmissionMap::constant(white)));
+
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let mut picked_large = 0;
+    for _ in 0..256 {
+        let sample = scene.sample_light(&mut rng);
+        for i in 0..8 {
+            let large = sample.position.x.get_coord(i) >= 5.0;
+            let expected_pdf = if large { 0.5 / 4.0 } else { 0.5 / 1.0 };
+            assert!((sample.pdf.get_coord(i) - expected_pdf).abs() < 1e-6);
+            picked_large += large as u32;
+        }
+    }
+    assert!(picked_large > 900 && picked_large < 1150);
+}
+
+#[test]
+fn sample_light_ris_reduces_variance_with_many_lights() {
+    use bench;
+    use light::EmissionMap;
+
+    // A point at the origin facing up, under a grid of 256 lights facing
+    // down. Most lights are dim and far away, a few are bright and close.
+    let mut scene = bench::caustic_scene();
+    let x = SVector3::new(0.2, 0.0, 0.0);
+    let z = SVector3::new(0.0, 0.0, 0.2);
+    for i in 0..16 {
+        for j in 0..16 {
+            let near = i % 5 == 0 && j % 5 == 0;
+            let height = if near { 1.0 } else { 8.0 };
+            let radiance = if near { 20.0 } else { 1.0 };
+            let origin = SVector3::new(i as f32 - 8.0, height, j as f32 - 8.0);
+            let emission = EmissionMap::constant(SVector3::new(radiance, radiance, radiance));
+            scene.add_area_light(AreaLight::new(origin, x, z, emission));
+        }
+    }
+
+    // The irradiance estimate of a point on a light, without occlusion.
+    let position = MVector3::zero();
+    let normal = MVector3::new(Mf32::zero(), Mf32::one(), Mf32::zero());
+    let contribution = |sample: &MLightSample, k: usize| {
+        let p = SVector3::new(sample.position.x.get_coord(k),
+                              sample.position.y.get_coord(k),
+                              sample.position.z.get_coord(k));
+        let dist_sqr = p.norm_squared();
+        let cos = p.y / dist_sqr.sqrt();
+        sample.radiance.x.get_coord(k) * cos * cos / dist_sqr
+    };
+
+    // Returns the mean and variance of the estimates.
+    let stats = |estimates: &[f32]| {
+        let n = estimates.len() as f32;
+        let mean = estimates.iter().sum::<f32>() / n;
+        let var = estimates.iter().map(|e| (e - mean) * (e - mean)).sum::<f32>() / n;
+        (mean, var)
+    };
+
+    let mut rng = Rng::with_seed(3, 1, 4);
+    let mut uniform = Vec::new();
+    let mut ris = Vec::new();
+    for _ in 0..512 {
+        let sample = scene.sample_light(&mut rng);
+        for k in 0..8 {
+            uniform.push(contribution(&sample, k) / sample.pdf.get_coord(k));
+        }
+        let (sample, weight) = scene.sample_light_ris(position, normal, &mut rng, 16);
+        for k in 0..8 {
+            ris.push(contribution(&sample, k) * weight.get_coord(k));
+        }
     }
+
+    let (mean_uniform, var_uniform) = stats(&uniform);
+    let (mean_ris, var_ris) = stats(&ris);
+
+    // Both are estimates of the same irradiance, with one shadow ray each.
+    assert!((mean_ris - mean_uniform).abs() < 0.1 * mean_uniform,
+            "uniform: {}, ris: {}", mean_uniform, mean_ris);
+    assert!(var_ris * 10.0 < var_uniform, "uniform: {}, ris: {}", var_uniform, var_ris);
+}
+
+#[test]
+fn prune_ineffective_lights_drops_distant_light() {
+    use bench;
+    use light::EmissionMap;
+
+    let mut scene = bench::caustic_scene();
+    let bounds = scene.bounds();
+    let x = SVector3::new(1.0, 0.0, 0.0);
+    let z = SVector3::new(0.0, 0.0, 1.0);
+    let white = EmissionMap::constant(SVector3::new(1.0, 1.0, 1.0));
+    let far = bounds.far + SVector3::new(1000.0, 1000.0, 1000.0);
+    scene.add_area_light(AreaLight::new(far, x, z, white));
+    let white = EmissionMap::constant(SVector3::new(1.0, 1.0, 1.0));
+    let near = bounds.far + SVector3::new(1.0, 1.0, 1.0);
+    scene.add_area_light(AreaLight::new(near, x, z, white));
+
+    // With a unit light, the irradiance at a distance of 1000 is about 1e-6.
+    assert_eq!(scene.prune_ineffective_lights(1e-4), 1);
+    assert_eq!(scene.num_lights(), 1);
+    assert!((scene.light_center(0) - (near + (x + z) * 0.5)).norm_squared() < 1e-6);
+
+    // A tiny cutoff keeps the remaining light.
+    assert_eq!(scene.prune_ineffective_lights(1e-9), 0);
+}
+
+#[test]
+fn camera_jitter_follows_halton_sequence() {
+    let mut camera = Camera::new();
+    assert_eq!(camera.jitter(), None);
+
+    let mut offsets: Vec<(f32, f32)> = Vec::new();
+    for frame in 0..16 {
+        camera.set_jitter_frame(frame);
+        let (jx, jy) = camera.jitter().unwrap();
+        assert!(-0.5 <= jx && jx < 0.5 && -0.5 <= jy && jy < 0.5);
+        assert_eq!((jx, jy), (halton(frame + 1, 2) - 0.5, halton(frame + 1, 3) - 0.5));
+        assert!(!offsets.contains(&(jx, jy)), "frame {} repeats an earlier offset", frame);
+        offsets.push((jx, jy));
+    }
+
+    // The first few elements of the Halton sequence in base 2.
+    camera.set_jitter_frame(0);
+    assert_eq!(camera.jitter().unwrap().0, 0.0);
+    camera.set_jitter_frame(1);
+    assert_eq!(camera.jitter().unwrap().0, -0.25);
+    camera.set_jitter_frame(2);
+    assert_eq!(camera.jitter().unwrap().0, 0.25);
+
+    camera.disable_jitter();
+    assert_eq!(camera.jitter(), None);
+}
+
+#[test]
+fn pick_light_selects_nearest_light() {
+    use bench;
+    use light::EmissionMap;
+
+    let mut scene = bench::caustic_scene();
+    let square = |z: f32| {
+        AreaLight::new(SVector3::new(-0.5, -0.5, z),
+                       SVector3::new(1.0, 0.0, 0.0),
+                       SVector3::new(0.0, 1.0, 0.0),
+                       EmissionMap::constant(SVector3::one()))
+    };
+    scene.add_area_light(square(-8.0));
+    scene.add_area_light(square(-5.0));
+
+    // The camera looks along the negative z-axis.
+    assert_eq!(scene.pick_light(&scene.camera().get_pick_ray(0.0, 0.0)), Some(1));
+    assert_eq!(scene.pick_light(&scene.camera().get_pick_ray(0.9, 0.0)), None);
+}
+
+#[test]
+fn light_receiver_factor_applies_wrap_of_sampled_light() {
+    use bench;
+    use light::EmissionMap;
+
+    let mut scene = bench::caustic_scene();
+    let square = |z: f32| {
+        AreaLight::new(SVector3::new(-0.5, -0.5, z),
+                       SVector3::new(1.0, 0.0, 0.0),
+                       SVector3::new(0.0, 1.0, 0.0),
+                       EmissionMap::constant(SVector3::one()))
+    };
+    scene.add_area_light(square(-8.0));
+    let mut wrapped = square(-5.0);
+    wrapped.set_wrap(1.0, 1.0);
+    scene.add_area_light(wrapped);
+
+    // A receiver that faces slightly away only receives light from the
+    // wrapped light.
+    let mut rng = Rng::with_seed(2, 3, 5);
+    let cos_theta = Mf32::broadcast(-0.2);
+    for _ in 0..16 {
+        let sample = scene.sample_light(&mut rng);
+        let factor = scene.light_receiver_factor(&sample, cos_theta);
+        for i in 0..8 {
+            let expected = if sample.lights[i] == 1 { 0.4 } else { 0.0 };
+            assert!((factor.get_coord(i) - expected).abs() < 1e-6);
+        }
+    }
+}
+
+#[test]
+fn light_drag_moves_light_with_cursor() {
+    use bench;
+    use light::EmissionMap;
+
+    let mut scene = bench::caustic_scene();
+    scene.add_area_light(AreaLight::new(SVector3::new(-0.5, -0.5, -5.0),
+                                        SVector3::new(1.0, 0.0, 0.0),
+                                        SVector3::new(0.0, 1.0, 0.0),
+                                        EmissionMap::constant(SVector3::one())));
+
+    assert!(LightDrag::begin(&scene, 0.9, 0.9).is_none());
+    let mut drag = LightDrag::begin(&scene, 0.0, 0.0).unwrap();
+    assert_eq!(drag.target(), &DragTarget::Light(0));
+
+    // The light is dragged in the plane z = -5. The screen is at distance
+    // `screen_distance` from the camera, so a cursor offset on the screen
+    // scales by 5 / screen_distance.
+    let scale = 5.0 / scene.camera().screen_distance;
+    let offset = drag.update(&mut scene, 0.1, 0.0);
+    drag.update(&mut scene, 0.1, -0.2);
+    let center = scene.light_center(0);
+    assert!((offset.x - 0.1 * scale).abs() < 1e-3 && offset.y.abs() < 1e-3 && offset.z.abs() < 1e-3);
+    assert!((center.x - 0.1 * scale).abs() < 1e-3);
+    assert!((center.y + 0.2 * scale).abs() < 1e-3);
+    assert!((center.z + 5.0).abs() < 1e-3);
+}
+
+#[test]
+fn light_drag_moves_emissive_triangles() {
+    use bench;
+
+    // The light of the caustic scene is made of four quads at y = 3, that
+    // span x from -2 to 2. The camera is at the origin.
+    let mut scene = bench::caustic_scene();
+    let project = |scene: &Scene, p: SVector3| {
+        let (x, y, _) = scene.camera().project(MVector3::broadcast(p));
+        (x.0, y.0)
+    };
+    let barycenters = |scene: &Scene| {
+        let mut points = Vec::new();
+        scene.foreach_direct_sample(|_, tri| points.push(tri.barycenter()));
+        points
+    };
+
+    // Grab the light near its right edge.
+    let (x0, y0) = project(&scene, SVector3::new(1.9, 3.0, -2.5));
+    let mut drag = LightDrag::begin(&scene, x0, y0).unwrap();
+    match *drag.target() {
+        DragTarget::Emitter(ref triangles) => assert_eq!(triangles.len(), 8),
+        ref other => panic!("expected the emitter to be picked, got {:?}", other),
+    }
+
+    // Beyond the original edge there is no light yet.
+    let (x1, y1) = project(&scene, SVector3::new(2.7, 3.0, -2.5));
+    assert!(scene.pick_emitter(&scene.camera().get_pick_ray(x1, y1)).is_none());
+
+    // Drag it to the right. All triangles move by the offset, and the grabbed
+    // point stays under the cursor, which requires the BVH to be refitted.
+    let before = barycenters(&scene);
+    let offset = drag.update(&mut scene, x1, y1);
+    let after = barycenters(&scene);
+    assert!(offset.x > 0.5);
+    for (b, a) in before.iter().zip(after.iter()) {
+        assert!((*a - (*b + offset)).norm_squared() < 1e-8);
+    }
+    let ray = scene.camera().get_pick_ray(x1, y1);
+    let (_, distance) = scene.pick_emitter(&ray).unwrap();
+    assert!((ray.origin + ray.direction * distance - drag.position()).norm_squared() < 1e-6);
+}
+
+#[test]
+fn alpha_mask_lets_rays_through_transparent_half() {
+    use bench;
+    use material::SMaterial;
+
+    // A textured quad at z = -2 in front of a wall at z = -4. The left half
+    // of the texture is transparent.
+    let v = SVector3::new;
+    let cutout = ([v(-1.0, -1.0, -2.0), v(1.0, -1.0, -2.0), v(1.0, 1.0, -2.0), v(-1.0, 1.0, -2.0)],
+                  SMaterial::white().with_texture(1));
+    let wall = ([v(-2.0, -2.0, -4.0), v(2.0, -2.0, -4.0), v(2.0, 2.0, -4.0), v(-2.0, 2.0, -4.0)],
+                SMaterial::white());
+    let mut mesh = bench::mesh_from_quads(&[cutout, wall]);
+    mesh.tex_coords = vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+    mesh.triangles[0].tex_coords = Some((0, 1, 2));
+    mesh.triangles[1].tex_coords = Some((0, 2, 3));
+
+    let mut scene = Scene::from_meshes(&[mesh]);
+    scene.set_alpha_mask(1, AlphaMask::new(2, 1, vec![0, 255], 128));
+
+    let ray = MRay {
+        origin: MVector3::zero(),
+        direction: MVector3::generate(|i| {
+            let x = if i < 4 { -0.5 } else { 0.5 };
+            v(x * 0.5, (i % 4) as f32 * 0.1 - 0.15, -1.0).normalized()
+        }),
+        active: Mf32::zero(),
+    };
+    let isect = scene.intersect_nearest(&ray);
+    for i in 0..8 {
+        let z = isect.position.z.get_coord(i);
+        let expected = if i < 4 { -4.0 } else { -2.0 };
+        assert!((z - expected).abs() < 1e-3, "lane {} hit at z = {}, expected {}", i, z, expected);
+        let distance = isect.distance.get_coord(i);
+        assert!((distance * ray.direction.z.get_coord(i) - z).abs() < 1e-2);
+    }
+}
+
+#[test]
+fn height_map_tilts_normals_of_textured_floor() {
+    use bench;
+    use material::SMaterial;
+
+    // A textured floor at y = -1 that spans two units along u.
+    let v = SVector3::new;
+    let floor = ([v(-1.0, -1.0, -1.0), v(1.0, -1.0, -1.0), v(1.0, -1.0, -3.0), v(-1.0, -1.0, -3.0)],
+                 SMaterial::white().with_texture(1));
+    let mut mesh = bench::mesh_from_quads(&[floor]);
+    mesh.tex_coords = vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+    mesh.triangles[0].tex_coords = Some((0, 1, 2));
+    mesh.triangles[1].tex_coords = Some((0, 2, 3));
+    let mut scene = Scene::from_meshes(&[mesh]);
+
+    let ray = MRay {
+        origin: MVector3::zero(),
+        direction: MVector3::generate(|i| v(i as f32 * 0.1 - 0.35, -1.0, -2.0).normalized()),
+        active: Mf32::zero(),
+    };
+    let flat = scene.apply_height_maps(scene.intersect_nearest(&ray));
+
+    // A ramp that rises by 0.1 per texel along u, 8 texels per two units, so
+    // the displaced floor has slope 0.4 along x.
+    let heights = (0..64).map(|i| (i % 8) as f32 * 0.1).collect();
+    scene.set_height_map(1, HeightMap::new(8, 8, heights));
+    let bumped = scene.apply_height_maps(scene.intersect_nearest(&ray));
+
+    let tilt = 0.4 / (1.0f32 + 0.16).sqrt();
+    for i in 0..8 {
+        assert!(flat.normal.x.get_coord(i).abs() < 1e-6);
+        let nx = bumped.normal.x.get_coord(i);
+        assert!((nx.abs() - tilt).abs() < 1e-2, "lane {} normal x is {}, expected {}", i, nx, tilt);
+        assert!(bumped.normal.z.get_coord(i).abs() < 1e-4);
+    }
+}
+
+#[test]
+fn switching_active_camera_changes_primary_ray() {
+    use bench;
+
+    let mut scene = bench::caustic_scene();
+    let mut side = Camera::new();
+    side.set_position(SVector3::new(4.0, 1.0, 0.0), SVector3::zero());
+    side.set_rotation(PI * 0.5, 0.0);
+    let index = scene.add_camera(side);
+    assert_eq!(index, 1);
+    assert_eq!(scene.num_cameras(), 2);
+
+    let front_ray = scene.camera().get_pick_ray(0.0, 0.0);
+    scene.set_active_camera(index);
+    let side_ray = scene.camera().get_pick_ray(0.0, 0.0);
+    assert_eq!(side_ray.origin, SVector3::new(4.0, 1.0, 0.0));
+    assert!(front_ray.origin != side_ray.origin);
+    assert!(front_ray.direction.dot(side_ray.direction).abs() < 1e-3,
+            "the cameras look in perpendicular directions");
+
+    // Switching back restores the original view.
+    scene.set_active_camera(0);
+    assert_eq!(scene.camera().get_pick_ray(0.0, 0.0).origin, front_ray.origin);
+}
+
+#[test]
+fn load_environment_hdr_replaces_sky() {
+    use bench;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    let path = env::temp_dir().join("convector_load_environment_hdr.hdr");
+    {
+        let mut file = fs::File::create(&path).unwrap();
+        file.write_all(b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 1\n").unwrap();
+        file.write_all(&[128, 64, 32, 130]).unwrap();
+    }
+    let mut scene = bench::caustic_scene();
+    let result = scene.load_environment_hdr(&path);
+    fs::remove_file(&path).unwrap();
+    result.unwrap();
+
+    let up = MVector3::new(Mf32::zero(), Mf32::zero(), Mf32::one());
+    let sky = scene.sky_intensity(up);
+    assert_eq!((sky.x.0, sky.y.0, sky.z.0), (2.0, 1.0, 0.5));
+
+    let missing = env::temp_dir().join("convector_missing_environment.hdr");
+    assert!(scene.load_environment_hdr(&missing).is_err());
+}
+
+#[test]
+fn set_sky_replaces_gradient() {
+    use bench;
+    let mut scene = bench::caustic_scene();
+    let up = MVector3::new(Mf32::zero(), Mf32::zero(), Mf32::one());
+    let gradient = scene.sky_intensity(up);
+    scene.set_sky(SVector3::new(0.0, 1.0, 1.0), 3.0);
+    let sky = scene.sky_intensity(up);
+    assert!(sky.x.0 != gradient.x.0);
+
+    // Looking straight into the sun is much brighter than the zenith.
+    let sun = MVector3::broadcast(SVector3::new(0.0, 1.0, 1.0).normalized());
+    assert!(scene.sky_intensity(sun).y.0 > 100.0 * sky.y.0);
+}
+
+#[test]
+fn stochastic_alpha_mask_converges_to_blend() {
+    use bench;
+    use material::SMaterial;
+
+    // A half-transparent textured quad at z = -2 in front of a wall at z = -4.
+    let v = SVector3::new;
+    let cutout = ([v(-1.0, -1.0, -2.0), v(1.0, -1.0, -2.0), v(1.0, 1.0, -2.0), v(-1.0, 1.0, -2.0)],
+                  SMaterial::white().with_texture(1));
+    let wall = ([v(-2.0, -2.0, -4.0), v(2.0, -2.0, -4.0), v(2.0, 2.0, -4.0), v(-2.0, 2.0, -4.0)],
+                SMaterial::white());
+    let mut mesh = bench::mesh_from_quads(&[cutout, wall]);
+    mesh.tex_coords = vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+    mesh.triangles[0].tex_coords = Some((0, 1, 2));
+    mesh.triangles[1].tex_coords = Some((0, 2, 3));
+
+    let mut scene = Scene::from_meshes(&[mesh]);
+    scene.set_alpha_mask(1, AlphaMask::stochastic(1, 1, vec![128]));
+    let alpha = 128.0 / 255.0;
+
+    // Shade the quad white and the wall black, and average over many samples.
+    let ray = MRay {
+        origin: MVector3::zero(),
+        direction: MVector3::generate(|i| v((i as f32 - 3.5) * 0.05, 0.1, -1.0).normalized()),
+        active: Mf32::zero(),
+    };
+    let mut rng = Rng::with_seed(2, 5, 7);
+    let mean_error = |n: u32, rng: &mut Rng| {
+        let mut sum = 0.0;
+        for _ in 0..n {
+            let isect = scene.intersect_nearest_stochastic(&ray, rng);
+            for i in 0..8 {
+                let z = isect.position.z.get_coord(i);
+                assert!((z + 2.0).abs() < 1e-3 || (z + 4.0).abs() < 1e-3, "unexpected hit at z = {}", z);
+                if (z + 2.0).abs() < 1e-3 {
+                    sum += 1.0;
+                }
+            }
+        }
+        (sum / (n * 8) as f32 - alpha).abs()
+    };
+
+    assert!(mean_error(4096, &mut rng) < 0.01);
+
+    // Without random numbers, the half-opaque surface is opaque.
+    let isect = scene.intersect_nearest(&ray);
+    assert!((isect.position.z.get_coord(0) + 2.0).abs() < 1e-3);
+}
+
+#[test]
+fn closest_point_above_triangle_is_projection() {
+    use bench;
+    use material::SMaterial;
+
+    // The first triangle of the quad lies in the plane y = 0. The second
+    // one is there only because the BVH needs two triangles, it slopes down
+    // and away from the query points.
+    let v = SVector3::new;
+    let quads = [([v(0.0, 0.0, 0.0), v(3.0, 0.0, 0.0), v(0.0, 0.0, -3.0), v(3.0, -10.0, -3.0)], SMaterial::white())];
+    let scene = Scene::from_meshes(&[bench::mesh_from_quads(&quads)]);
+    let triangle = Triangle::new(v(0.0, 0.0, 0.0), v(3.0, 0.0, 0.0), v(0.0, 0.0, -3.0), SMaterial::white());
+    let centroid = triangle.barycenter();
+    let normal = triangle.normal();
+    assert_eq!(normal, v(0.0, 1.0, 0.0));
+
+    // Above the centroid, the closest point is the centroid.
+    let (point, n, distance) = scene.closest_point(centroid + normal * 2.0);
+    assert!((point - centroid).norm_squared() < 1e-10, "closest point is {:?}", point);
+    assert_eq!(n, normal);
+    assert!((distance - 2.0).abs() < 1e-5);
+
+    // Beyond a vertex, the closest point is the vertex.
+    let (point, _, distance) = scene.closest_point(v(-1.0, 1.0, 1.0));
+    assert_eq!(point, v(0.0, 0.0, 0.0));
+    assert!((distance - 3.0f32.sqrt()).abs() < 1e-5);
 }
@@ -9,6 +9,12 @@
 //!
 //! Note: compile with AVX and FMA target features to use this to the full
 //! extent.
+//!
+//! With the `portable-simd` feature enabled, the x86-specific intrinsics are
+//! replaced by implementations in terms of the generic SIMD intrinsics and
+//! scalar code, so the crate can be built for targets without AVX. The results
+//! are identical, except for `recip_fast()` and `rsqrt()`, which are exact
+//! rather than approximations. This is a lot slower, of course.
 
 use std::f32::consts;
 use std::ops::{Add, BitAnd, BitOr, BitXor, Div, Mul, Neg, Sub};
@@ -30,6 +36,17 @@ pub struct Mu64(pub u64, pub u64, pub u64, pub u64);
 
 pub type Mask = Mf32;
 
+/// Four f32 values, for packets of rays that are too sparse to fill an mf32.
+#[repr(simd)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Nf32(pub f32, pub f32, pub f32, pub f32);
+
+#[repr(simd)]
+#[derive(Copy, Clone)]
+struct Ni32(i32, i32, i32, i32);
+
+pub type NMask = Nf32;
+
 impl Mf32 {
     pub fn zero() -> Mf32 {
         Mf32::broadcast(0.0)
@@ -249,6 +266,83 @@ impl Mf32 {
         (b * self).mul_add(self.abs(), a * self)
     }
 
+    /// Approximates the base 2 logarithm of self. Self must be positive.
+    ///
+    /// The exponent of the float is extracted exactly, and the logarithm of the
+    /// mantissa is approximated by a polynomial of degree 5. The script to find
+    /// the coefficients is in tools/approx_log2_exp2.py.
+    ///
+    /// The absolute error is at most 0.00005.
+    #[inline(always)]
+    pub fn log2(self) -> Mf32 {
+        use std::mem::transmute;
+
+        let a = Mf32::broadcast(1.442418578542117);
+        let b = Mf32::broadcast(-0.7119965934909929);
+        let c = Mf32::broadcast(0.4201112115476533);
+        let d = Mf32::broadcast(-0.19540715186201255);
+        let e = Mf32::broadcast(0.04487395526323496);
+
+        unsafe {
+            let bits: Mi32 = transmute(self);
+
+            // The biased exponent is in bits 23 through 30. For positive
+            // numbers the sign bit is 0, so the shift leaves only the exponent.
+            let exponent = simd_sub(simd_shr(bits, Mi32::broadcast(23)), Mi32::broadcast(127));
+
+            // Replace the exponent with 0 (biased 127) to get the mantissa as a
+            // number in [1, 2).
+            let mantissa_bits = simd_or(simd_and(bits, Mi32::broadcast(0x007f_ffff)),
+                                        Mi32::broadcast(0x3f80_0000));
+            let mantissa: Mf32 = transmute(mantissa_bits);
+
+            let t = mantissa - Mf32::one();
+            let p = t.mul_add(e, d).mul_add(t, c).mul_add(t, b).mul_add(t, a);
+            t.mul_add(p, exponent.into_mf32())
+        }
+    }
+
+    /// Approximates 2 to the power self.
+    ///
+    /// Self is rounded to the nearest integer n, and 2^n is constructed
+    /// directly as a float. The remaining fraction lies in [-1/2, 1/2], where
+    /// 2 to that power is approximated by a polynomial of degree 5. The script
+    /// to find the coefficients is in tools/approx_log2_exp2.py. Exponents
+    /// are clamped to the range of normal floats.
+    ///
+    /// The relative error is at most 0.00003%.
+    #[inline(always)]
+    pub fn exp2(self) -> Mf32 {
+        use std::mem::transmute;
+
+        let a = Mf32::broadcast(1.0000000754548972);
+        let b = Mf32::broadcast(0.6931471880262285);
+        let c = Mf32::broadcast(0.2402210748530821);
+        let d = Mf32::broadcast(0.05550357114219461);
+        let e = Mf32::broadcast(0.009676031918326564);
+        let f = Mf32::broadcast(0.0013390863364533504);
+
+        let x = self.max(Mf32::broadcast(-126.0)).min(Mf32::broadcast(127.0));
+        let n = x.into_mi32();
+        let t = x - n.into_mf32();
+        let p = t.mul_add(f, e).mul_add(t, d).mul_add(t, c).mul_add(t, b).mul_add(t, a);
+
+        unsafe {
+            let pow2_n: Mf32 = transmute(simd_shl(simd_add(n, Mi32::broadcast(127)), Mi32::broadcast(23)));
+            p * pow2_n
+        }
+    }
+
+    /// Approximates self to the power exponent. Self must be positive.
+    ///
+    /// This is computed as `exp2(exponent * log2(self))`, so the relative error
+    /// grows with the magnitude of the result in the exponent. For the range
+    /// of exponents used for glossy reflections it is well below 0.1%.
+    #[inline(always)]
+    pub fn pow(self, exponent: Mf32) -> Mf32 {
+        (exponent * self.log2()).exp2()
+    }
+
     /// Approximates 1 / self. Precision is poor but it is fast.
     #[inline(always)]
     pub fn recip_fast(self) -> Mf32 {
@@ -298,6 +392,22 @@ impl Mf32 {
         Mf32::broadcast(mask) & self
     }
 
+    /// Returns the magnitude of self with the sign bit of `sign_src`.
+    ///
+    /// This copies the sign bit, so the sign of zeros and NaNs carries over
+    /// too.
+    pub fn copysign(self, sign_src: Mf32) -> Mf32 {
+        use std::mem::transmute;
+        let sign_bit: f32 = unsafe { transmute(0x80_00_00_00_u32) };
+        self.abs() | (Mf32::broadcast(sign_bit) & sign_src)
+    }
+
+    /// Returns 1.0 where the sign bit of self is 0, and -1.0 where it is 1,
+    /// like `f32::signum()`. Zero yields 1.0 and negative zero -1.0.
+    pub fn signum(self) -> Mf32 {
+        Mf32::one().copysign(self)
+    }
+
     /// Computes self / denom with best precision.
     #[inline(always)]
     pub fn div(self, denom: Mf32) -> Mf32 {
@@ -383,6 +493,37 @@ impl Mf32 {
         unsafe { x86_mm256_cvtps_epi32(self) }
     }
 
+    /// Clamps to [0, 1], scales to [0, 255], and rounds to the nearest
+    /// integer, for quantizing a color channel.
+    ///
+    /// Scaling before clamping gives exactly the same result as clamping
+    /// first, but it saves a broadcast in the hot path.
+    #[inline(always)]
+    pub fn clamp_scale_to_u8(self) -> Mi32 {
+        let range = Mf32::broadcast(255.0);
+        Mf32::zero().max(range.min(self * range)).into_mi32()
+    }
+
+    /// Returns the sum of the components.
+    ///
+    /// The components are added pairwise, which is more precise than adding
+    /// them one by one. This is not fast, avoid it in the inner loop.
+    pub fn hsum(self) -> f32 {
+        ((self.0 + self.4) + (self.2 + self.6)) + ((self.1 + self.5) + (self.3 + self.7))
+    }
+
+    /// Returns the largest component. NaNs are ignored, unless all
+    /// components are NaN.
+    pub fn hmax(self) -> f32 {
+        self.0.max(self.4).max(self.2.max(self.6)).max(self.1.max(self.5).max(self.3.max(self.7)))
+    }
+
+    /// Returns the smallest component. NaNs are ignored, unless all
+    /// components are NaN.
+    pub fn hmin(self) -> f32 {
+        self.0.min(self.4).min(self.2.min(self.6)).min(self.1.min(self.5).min(self.3.min(self.7)))
+    }
+
     /// Returns whether all components are finite.
     ///
     /// This is slow, use only for diagnostic purposes.
@@ -449,6 +590,95 @@ impl Mu64 {
     }
 }
 
+impl Nf32 {
+    /// Builds an nf32 by applying the function to the numbers 0..3.
+    pub fn generate<F>(mut f: F) -> Nf32 where F: FnMut(usize) -> f32 {
+        Nf32(f(0), f(1), f(2), f(3))
+    }
+
+    #[inline(always)]
+    pub fn broadcast(x: f32) -> Nf32 {
+        Nf32(x, x, x, x)
+    }
+
+    /// Returns the i-th coordinate. Index must be in the range 0-3 (inclusive).
+    #[inline(always)]
+    pub fn get_coord(self, i: usize) -> f32 {
+        match i {
+            0 => self.0,
+            1 => self.1,
+            2 => self.2,
+            3 => self.3,
+            _ => panic!("index out of bounds"),
+        }
+    }
+
+    /// Approximates 1 / self, see `Mf32::recip_fast()`. The approximation is
+    /// the same as that of the 8-wide version.
+    #[inline(always)]
+    pub fn recip_fast(self) -> Nf32 {
+        unsafe { x86_mm_rcp_ps(self) }
+    }
+
+    #[inline(always)]
+    pub fn max(self, other: Nf32) -> Nf32 {
+        unsafe { x86_mm_max_ps(self, other) }
+    }
+
+    #[inline(always)]
+    pub fn min(self, other: Nf32) -> Nf32 {
+        unsafe { x86_mm_min_ps(self, other) }
+    }
+
+    /// Like `Mf32::geq()`, the comparison is true if either operand is NaN.
+    #[inline(always)]
+    pub fn geq(self, other: Nf32) -> NMask {
+        use std::mem::transmute;
+        unsafe {
+            let lt: Ni32 = simd_lt(self, other);
+            transmute(simd_xor(lt, Ni32(-1, -1, -1, -1)))
+        }
+    }
+
+    /// Returns whether all sign bits are positive (all sign bits are 0).
+    #[inline(always)]
+    pub fn all_sign_bits_positive(self) -> bool {
+        unsafe { x86_mm_movemask_ps(self) == 0 }
+    }
+
+    /// Returns whether any of the values for which the sign bit of the mask
+    /// is set, is positive.
+    #[inline(always)]
+    pub fn any_sign_bit_positive_masked(self, mask: NMask) -> bool {
+        unsafe { (!x86_mm_movemask_ps(self) & x86_mm_movemask_ps(mask)) != 0 }
+    }
+
+    /// Returns whether all of the values for which the sign bit of the mask
+    /// is set, are negative.
+    #[inline(always)]
+    pub fn all_sign_bits_negative_masked(self, mask: NMask) -> bool {
+        !self.any_sign_bit_positive_masked(mask)
+    }
+}
+
+impl Sub<Nf32> for Nf32 {
+    type Output = Nf32;
+
+    #[inline(always)]
+    fn sub(self, other: Nf32) -> Nf32 {
+        unsafe { simd_sub(self, other) }
+    }
+}
+
+impl Mul<Nf32> for Nf32 {
+    type Output = Nf32;
+
+    #[inline(always)]
+    fn mul(self, other: Nf32) -> Nf32 {
+        unsafe { simd_mul(self, other) }
+    }
+}
+
 impl Add<Mf32> for Mf32 {
     type Output = Mf32;
 
@@ -613,6 +843,17 @@ extern "platform-intrinsic" {
     // This is `_mm256_xor_ps` when compiled for AVX.
     fn simd_xor<T>(x: T, y: T) -> T;
 
+    // These are `_mm256_slli_epi32` and `_mm256_srai_epi32` with AVX2. With
+    // only AVX they are split into two 128-bit shifts.
+    fn simd_shl<T>(x: T, y: T) -> T;
+    fn simd_shr<T>(x: T, y: T) -> T;
+
+    // This is `_mm_cmplt_ps`, but the result is an integer vector.
+    fn simd_lt<T, U>(x: T, y: T) -> U;
+}
+
+#[cfg(not(feature = "portable-simd"))]
+extern "platform-intrinsic" {
     fn x86_mm256_blendv_ps(x: Mf32, y: Mf32, mask: Mask) -> Mf32;
     fn x86_mm256_cmp_ps(x: Mf32, y: Mf32, op: i8) -> Mask;
     fn x86_mm256_cvtepi32_ps(x: Mi32) -> Mf32;
@@ -624,9 +865,14 @@ extern "platform-intrinsic" {
     fn x86_mm256_rsqrt_ps(x: Mf32) -> Mf32;
     fn x86_mm256_sqrt_ps(x: Mf32) -> Mf32;
     fn x86_mm256_testc_ps(x: Mf32, y: Mf32) -> i32;
+
+    fn x86_mm_max_ps(x: Nf32, y: Nf32) -> Nf32;
+    fn x86_mm_min_ps(x: Nf32, y: Nf32) -> Nf32;
+    fn x86_mm_movemask_ps(x: Nf32) -> i32;
+    fn x86_mm_rcp_ps(x: Nf32) -> Nf32;
 }
 
-#[cfg(target_feature = "fma")]
+#[cfg(all(target_feature = "fma", not(feature = "portable-simd")))]
 extern "platform-intrinsic" {
     fn x86_mm256_fmadd_ps(x: Mf32, y: Mf32, z: Mf32) -> Mf32;
     fn x86_mm256_fmsub_ps(x: Mf32, y: Mf32, z: Mf32) -> Mf32;
@@ -635,21 +881,129 @@ extern "platform-intrinsic" {
 
 // If the FMA instructions are not enabled, fall back to a separate mul and add
 // or sub. These still use the AVX intrinsics.
-#[cfg(not(target_feature = "fma"))]
+#[cfg(any(not(target_feature = "fma"), feature = "portable-simd"))]
 unsafe fn x86_mm256_fmadd_ps(x: Mf32, y: Mf32, z: Mf32) -> Mf32 {
     x * y + z
 }
 
-#[cfg(not(target_feature = "fma"))]
+#[cfg(any(not(target_feature = "fma"), feature = "portable-simd"))]
 unsafe fn x86_mm256_fmsub_ps(x: Mf32, y: Mf32, z: Mf32) -> Mf32 {
     x * y - z
 }
 
-#[cfg(not(target_feature = "fma"))]
+#[cfg(any(not(target_feature = "fma"), feature = "portable-simd"))]
 unsafe fn x86_mm256_fnmadd_ps(x: Mf32, y: Mf32, z: Mf32) -> Mf32 {
     z - x * y
 }
 
+// Portable versions of the AVX intrinsics, for the `portable-simd` feature.
+// They keep the names of the intrinsics they replace, so the methods above do
+// not need to know which backend is in use. Note that these must reproduce
+// the exact behavior of the instructions, including for NaNs and out of range
+// values, because code relies on it.
+
+#[cfg(feature = "portable-simd")]
+fn sign_bit(x: f32) -> bool {
+    use std::mem::transmute;
+    let bits: u32 = unsafe { transmute(x) };
+    bits >> 31 == 1
+}
+
+#[cfg(feature = "portable-simd")]
+unsafe fn x86_mm256_blendv_ps(x: Mf32, y: Mf32, mask: Mask) -> Mf32 {
+    Mf32::generate(|i| if sign_bit(mask.get_coord(i)) { y.get_coord(i) } else { x.get_coord(i) })
+}
+
+#[cfg(feature = "portable-simd")]
+unsafe fn x86_mm256_cmp_ps(x: Mf32, y: Mf32, op: i8) -> Mask {
+    // Only operation 21 (not less than, unordered) is used. It is true if
+    // either operand is NaN.
+    use std::mem::transmute;
+    debug_assert_eq!(op, 21);
+    let all_ones: f32 = transmute(-1_i32);
+    Mf32::generate(|i| if x.get_coord(i) < y.get_coord(i) { 0.0 } else { all_ones })
+}
+
+#[cfg(feature = "portable-simd")]
+unsafe fn x86_mm256_cvtepi32_ps(x: Mi32) -> Mf32 {
+    Mf32::generate(|i| x.get_coord(i) as f32)
+}
+
+#[cfg(feature = "portable-simd")]
+unsafe fn x86_mm256_cvtps_epi32(x: Mf32) -> Mi32 {
+    // The instruction rounds to the nearest integer, with ties to even, and
+    // it returns i32::MIN if the value does not fit.
+    use std::i32;
+    let round = |f: f32| -> i32 {
+        if !(f >= -2147483648.0 && f < 2147483648.0) {
+            return i32::MIN
+        }
+        let r = f.round();
+        if (r - f).abs() == 0.5 { (2.0 * (f * 0.5).round()) as i32 } else { r as i32 }
+    };
+    Mi32(round(x.0), round(x.1), round(x.2), round(x.3),
+         round(x.4), round(x.5), round(x.6), round(x.7))
+}
+
+#[cfg(feature = "portable-simd")]
+unsafe fn x86_mm256_max_ps(x: Mf32, y: Mf32) -> Mf32 {
+    // If either operand is NaN, the second operand is returned.
+    Mf32::generate(|i| if x.get_coord(i) > y.get_coord(i) { x.get_coord(i) } else { y.get_coord(i) })
+}
+
+#[cfg(feature = "portable-simd")]
+unsafe fn x86_mm256_min_ps(x: Mf32, y: Mf32) -> Mf32 {
+    // If either operand is NaN, the second operand is returned.
+    Mf32::generate(|i| if x.get_coord(i) < y.get_coord(i) { x.get_coord(i) } else { y.get_coord(i) })
+}
+
+#[cfg(feature = "portable-simd")]
+unsafe fn x86_mm256_movemask_ps(x: Mf32) -> i32 {
+    (0..8).fold(0, |acc, i| acc | ((sign_bit(x.get_coord(i)) as i32) << i))
+}
+
+#[cfg(feature = "portable-simd")]
+unsafe fn x86_mm256_rcp_ps(x: Mf32) -> Mf32 {
+    simd_div(Mf32::one(), x)
+}
+
+#[cfg(feature = "portable-simd")]
+unsafe fn x86_mm256_rsqrt_ps(x: Mf32) -> Mf32 {
+    simd_div(Mf32::one(), x86_mm256_sqrt_ps(x))
+}
+
+#[cfg(feature = "portable-simd")]
+unsafe fn x86_mm256_sqrt_ps(x: Mf32) -> Mf32 {
+    x.map(f32::sqrt)
+}
+
+#[cfg(feature = "portable-simd")]
+unsafe fn x86_mm256_testc_ps(x: Mf32, y: Mf32) -> i32 {
+    // Returns 1 if the sign bits of `(not x) and y` are all 0.
+    let any = (0..8).any(|i| !sign_bit(x.get_coord(i)) && sign_bit(y.get_coord(i)));
+    (!any) as i32
+}
+
+#[cfg(feature = "portable-simd")]
+unsafe fn x86_mm_max_ps(x: Nf32, y: Nf32) -> Nf32 {
+    Nf32::generate(|i| if x.get_coord(i) > y.get_coord(i) { x.get_coord(i) } else { y.get_coord(i) })
+}
+
+#[cfg(feature = "portable-simd")]
+unsafe fn x86_mm_min_ps(x: Nf32, y: Nf32) -> Nf32 {
+    Nf32::generate(|i| if x.get_coord(i) < y.get_coord(i) { x.get_coord(i) } else { y.get_coord(i) })
+}
+
+#[cfg(feature = "portable-simd")]
+unsafe fn x86_mm_movemask_ps(x: Nf32) -> i32 {
+    (0..4).fold(0, |acc, i| acc | ((sign_bit(x.get_coord(i)) as i32) << i))
+}
+
+#[cfg(feature = "portable-simd")]
+unsafe fn x86_mm_rcp_ps(x: Nf32) -> Nf32 {
+    simd_div(Nf32::broadcast(1.0), x)
+}
+
 #[test]
 fn mf32_add_ps() {
     let a = Mf32(0.0, 0.0, 0.0, 0.0, 1.0, 2.0, 3.0, 4.0);
@@ -685,6 +1039,48 @@ fn mf32_fnmadd_ps() {
     assert_eq!(a.neg_mul_add(b, c), d);
 }
 
+#[test]
+fn mf32_copysign_takes_sign_bit_per_lane() {
+    let a = Mf32(1.0, 1.0, -2.0, -2.0, 0.0, 0.0, -0.0, 3.0);
+    let b = Mf32(5.0, -5.0, 5.0, -5.0, -1.0, 0.0, 0.0, -0.0);
+    let c = a.copysign(b);
+    assert_eq!(c, Mf32(1.0, -1.0, 2.0, -2.0, -0.0, 0.0, 0.0, -3.0));
+    for i in 0..8 {
+        let expected = a.get_coord(i).abs() * if b.get_coord(i).is_sign_negative() { -1.0 } else { 1.0 };
+        assert_eq!(c.get_coord(i).is_sign_negative(), expected.is_sign_negative());
+    }
+}
+
+#[test]
+fn mf32_signum_is_one_with_sign_of_input() {
+    let a = Mf32(2.0, -2.0, 0.0, -0.0, 1e-30, -1e30, 0.5, -0.5);
+    assert_eq!(a.signum(), Mf32(1.0, -1.0, 1.0, -1.0, 1.0, -1.0, 1.0, -1.0));
+    for i in 0..8 {
+        assert_eq!(a.signum().get_coord(i), a.get_coord(i).signum());
+    }
+}
+
+#[test]
+fn mf32_clamp_scale_to_u8_matches_separate_steps() {
+    let separate = |x: Mf32| (Mf32::one().min(x).max(Mf32::zero()) * Mf32::broadcast(255.0)).into_mi32();
+    let check = |x: Mf32| {
+        let (fused, expected) = (x.clamp_scale_to_u8(), separate(x));
+        for j in 0..8 {
+            assert_eq!(fused.get_coord(j), expected.get_coord(j), "mismatch for {}", x.get_coord(j));
+        }
+    };
+    for i in -64..320 {
+        check(Mf32::generate(|j| (i * 8 + j as i32) as f32 * (1.0 / 2048.0) - 0.01));
+    }
+    let extremes = Mf32(-1.0e30, -1.0, -0.0, 0.0, 0.5, 1.0, 2.0, 1.0e30);
+    check(extremes);
+    let quantized = extremes.clamp_scale_to_u8();
+    let expected = [0, 0, 0, 0, 128, 255, 255, 255];
+    for j in 0..8 {
+        assert_eq!(quantized.get_coord(j), expected[j]);
+    }
+}
+
 #[test]
 fn mf32_broadcast_ps() {
     let a = Mf32::broadcast(7.0);
@@ -810,6 +1206,162 @@ fn mf32_acos() {
     }
 }
 
+#[test]
+fn mf32_log2() {
+    let xs = bench::mf32_unit(4096);
+    for &x in &xs {
+        // Cover a few orders of magnitude, and avoid zero.
+        let y = (x * Mf32::broadcast(20.0) - Mf32::broadcast(10.0)).map(|e| e.exp2());
+        let approx = y.log2();
+        let serial = y.map(|yi| yi.log2());
+        let abs_error = (approx - serial).abs();
+
+        // The absolute error should not be greater than 0.00005.
+        assert!((Mf32::broadcast(0.00005) - abs_error).all_sign_bits_positive(),
+                "Error should be small but it is {:?} for the input {:?}", abs_error, y);
+    }
+}
+
+#[test]
+fn mf32_exp2() {
+    let xs = bench::mf32_biunit(4096);
+    for &x in &xs {
+        let y = x * Mf32::broadcast(100.0);
+        let approx = y.exp2();
+        let serial = y.map(|yi| yi.exp2());
+        let rel_error = (Mf32::one() - approx / serial).abs();
+
+        // The relative error should not be greater than 0.00003%.
+        assert!((Mf32::broadcast(3e-7) - rel_error).all_sign_bits_positive(),
+                "Error should be small but it is {:?} for the input {:?}", rel_error, y);
+    }
+}
+
+#[test]
+fn mf32_pow() {
+    let xs = bench::mf32_unit(4096);
+    let ns = bench::mf32_unit(4096);
+    for (&x, &n) in xs.iter().zip(ns.iter()) {
+        // Bases and exponents as used for glossy lobes.
+        let base = x.max(Mf32::broadcast(0.01));
+        let exponent = n * Mf32::broadcast(16.0);
+        let approx = base.pow(exponent);
+        let serial = Mf32::generate(|i| base.get_coord(i).powf(exponent.get_coord(i)));
+        let rel_error = (Mf32::one() - approx / serial).abs();
+
+        assert!((Mf32::broadcast(0.001) - rel_error).all_sign_bits_positive(),
+                "Error should be small but it is {:?} for {:?} ^ {:?}", rel_error, base, exponent);
+    }
+}
+
+#[test]
+fn mf32_max_min() {
+    use std::f32;
+    let nan = f32::NAN;
+    let a = Mf32(0.0, 1.0, -2.0, 3.0, nan, 1.0, -0.0, 5.0);
+    let b = Mf32(1.0, 0.0, -3.0, 3.0, 1.0, nan, 0.0, -5.0);
+    let max = a.max(b);
+    let min = a.min(b);
+
+    // If either operand is NaN, the second operand is returned.
+    for &(i, mx, mn) in &[(0, 1.0, 0.0), (1, 1.0, 0.0), (2, -2.0, -3.0), (3, 3.0, 3.0), (4, 1.0, 1.0), (7, 5.0, -5.0)] {
+        assert_eq!(max.get_coord(i), mx);
+        assert_eq!(min.get_coord(i), mn);
+    }
+    assert!(max.get_coord(5).is_nan());
+    assert!(min.get_coord(5).is_nan());
+}
+
+#[test]
+fn mf32_geq() {
+    use std::f32;
+    let nan = f32::NAN;
+    let a = Mf32(0.0, 1.0, 1.0, -1.0, nan, 0.0, -0.0, 2.0);
+    let b = Mf32(0.0, 0.0, 2.0, -2.0, 0.0, nan, 0.0, 3.0);
+    let expected = [true, true, false, true, true, true, true, false];
+    let geq = a.geq(b);
+    for i in 0..8 {
+        // The result is a mask, so only the sign bit is relevant.
+        let m = Mf32::broadcast(1.0).pick(Mf32::broadcast(-1.0), geq).get_coord(i);
+        assert_eq!(m < 0.0, expected[i], "lane {}", i);
+    }
+}
+
+#[test]
+fn mf32_pick() {
+    let a = Mf32(1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0);
+    let b = Mf32(-1.0, -2.0, -3.0, -4.0, -5.0, -6.0, -7.0, -8.0);
+    let mask = Mf32(0.0, -0.0, 1.0, -1.0, -2.0, 2.0, -0.5, 0.5);
+    assert_eq!(a.pick(b, mask), Mf32(1.0, -2.0, 3.0, -4.0, -5.0, 6.0, -7.0, 8.0));
+}
+
+#[test]
+fn mf32_all_sign_bits() {
+    let pos = Mf32(0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0);
+    let neg = Mf32(-0.0, -1.0, -2.0, -3.0, -4.0, -5.0, -6.0, -7.0);
+    let mixed = Mf32(0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, -7.0);
+    assert!(pos.all_sign_bits_positive());
+    assert!(!pos.all_sign_bits_negative());
+    assert!(neg.all_sign_bits_negative());
+    assert!(!neg.all_sign_bits_positive());
+    assert!(!mixed.all_sign_bits_positive());
+    assert!(!mixed.all_sign_bits_negative());
+    assert!(mixed.all_sign_bits_negative_masked(Mf32(0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, -1.0)));
+    assert!(!mixed.all_sign_bits_negative_masked(Mf32(-1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, -1.0)));
+}
+
+#[test]
+fn mf32_into_mi32_rounds_to_nearest_even() {
+    let x = Mf32(0.4, 0.5, 1.5, 2.5, -0.5, -1.5, -2.6, 1e10);
+    let i = x.into_mi32();
+    let expected = [0, 0, 2, 2, 0, -2, -3, i32::min_value()];
+    for k in 0..8 {
+        assert_eq!(i.get_coord(k), expected[k], "lane {}", k);
+    }
+}
+
+#[test]
+fn mi32_into_mf32() {
+    let i = Mi32(0, 1, -1, 7, -8, 1 << 24, i32::max_value(), i32::min_value());
+    let x = i.into_mf32();
+    for k in 0..8 {
+        assert_eq!(x.get_coord(k), i.get_coord(k) as f32);
+    }
+}
+
+#[test]
+fn mf32_sqrt_rsqrt() {
+    let x = Mf32(0.25, 1.0, 2.0, 4.0, 9.0, 10.0, 100.0, 1e-4);
+    let sqrt = x.sqrt();
+    let rsqrt = x.rsqrt();
+    for i in 0..8 {
+        let expected = x.get_coord(i).sqrt();
+        assert_eq!(sqrt.get_coord(i), expected);
+        // The reciprocal square root is an approximation, its relative error
+        // is at most 1.5 * 2^-12.
+        assert!((rsqrt.get_coord(i) * expected - 1.0).abs() < 0.0004);
+    }
+}
+
+#[test]
+fn mf32_hsum_hmax_hmin() {
+    use std::f32;
+    let x = Mf32(3.0, -1.0, 4.0, 1.5, -5.0, 9.0, 2.0, -6.5);
+    assert_eq!(x.hsum(), 7.0);
+    assert_eq!(x.hmax(), 9.0);
+    assert_eq!(x.hmin(), -6.5);
+
+    let y = Mf32::broadcast(0.25);
+    assert_eq!(y.hsum(), 2.0);
+    assert_eq!(y.hmax(), 0.25);
+    assert_eq!(y.hmin(), 0.25);
+
+    let z = Mf32(f32::NAN, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0);
+    assert!(z.hsum().is_nan());
+    assert_eq!(z.hmax(), 7.0);
+    assert_eq!(z.hmin(), 1.0);
+}
+
 #[test]
 fn verify_abs() {
     let x = Mf32(1.0, -1.0, 0.0, -0.0, 2.0, 3.0, 5.0, -7.0);
@@ -0,0 +1,186 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! An analytic model of the daylight sky, for outdoor scenes without an
+//! environment map.
+//!
+//! This implements the model of Preetham, Shirley and Smits, "A Practical
+//! Analytic Model for Daylight" (1999). The Hosek-Wilkie model is more
+//! accurate near the horizon, but it needs large tables of fitted
+//! coefficients, and for a background the difference is hardly visible.
+
+use color::blackbody;
+use std::f32::consts;
+use vector3::{MVector3, SVector3};
+
+#[cfg(test)]
+use simd::Mf32;
+
+/// The cosine of the angular radius of the sun disk. The real sun has a radius
+/// of about 0.27 degrees, this is one degree, so paths that bounce off a
+/// diffuse surface hit the sun often enough to converge.
+const SUN_COS_RADIUS: f32 = 0.99985;
+
+/// The radiance of the sun disk, relative to the sky at the zenith.
+const SUN_RADIANCE: f32 = 500.0;
+
+/// Scale from the luminance in kcd/m² that the model produces, to the
+/// radiance units of the renderer. With this scale the zenith is roughly as
+/// bright as the built-in gradient sky.
+const LUMINANCE_SCALE: f32 = 0.15;
+
+/// The Perez distribution coefficients A through E of one channel.
+struct Perez([f32; 5]);
+
+impl Perez {
+    /// Evaluates the relative luminance for a view direction at zenith angle
+    /// theta (given as its cosine), at angle gamma from the sun.
+    fn eval(&self, cos_theta: f32, gamma: f32, cos_gamma: f32) -> f32 {
+        let (a, b, c, d, e) = (self.0[0], self.0[1], self.0[2], self.0[3], self.0[4]);
+        (1.0 + a * (b / cos_theta).exp()) *
+        (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+    }
+}
+
+/// The sky radiance as a function of the direction, for a given position of
+/// the sun. The z-axis points up.
+pub struct PhysicalSky {
+    sun_direction: SVector3,
+
+    /// Perez coefficients for the luminance Y and the chromaticities x and y.
+    perez: [Perez; 3],
+
+    /// Values of Y, x and y at the zenith, divided by the Perez function at
+    /// the zenith, so multiplying by the Perez function gives the value.
+    zenith: [f32; 3],
+
+    /// Radiance of the sun disk.
+    sun_radiance: SVector3,
+}
+
+/// Converts luminance Y and chromaticity (x, y) to linear sRGB.
+fn yxy_to_rgb(lum: f32, x: f32, y: f32) -> SVector3 {
+    let (cx, cy, cz) = (x / y * lum, lum, (1.0 - x - y) / y * lum);
+    let r = 3.2404542 * cx - 1.5371385 * cy - 0.4985314 * cz;
+    let g = -0.9692660 * cx + 1.8760108 * cy + 0.0415560 * cz;
+    let b = 0.0556434 * cx - 0.2040259 * cy + 1.0572252 * cz;
+    SVector3::new(r.max(0.0), g.max(0.0), b.max(0.0))
+}
+
+impl PhysicalSky {
+    /// Creates a sky lit by a sun in the given direction.
+    ///
+    /// The turbidity describes the haziness of the atmosphere. A value of 2 is
+    /// a very clear sky, 10 is hazy. The model is fitted for values from 2 to
+    /// 10, the turbidity is clamped to that range. The sun must be above the
+    /// horizon.
+    pub fn new(sun_direction: SVector3, turbidity: f32) -> PhysicalSky {
+        let sun = sun_direction.normalized();
+        assert!(sun.z > 0.0, "the sun must be above the horizon");
+
+        let t = turbidity.max(2.0).min(10.0);
+        let theta_s = sun.z.min(1.0).acos();
+
+        let perez_y = Perez([0.1787 * t - 1.4630,
+                             -0.3554 * t + 0.4275,
+                             -0.0227 * t + 5.3251,
+                             0.1206 * t - 2.5771,
+                             -0.0670 * t + 0.3703]);
+        let perez_cx = Perez([-0.0193 * t - 0.2592,
+                              -0.0665 * t + 0.0008,
+                              -0.0004 * t + 0.2125,
+                              -0.0641 * t - 0.8989,
+                              -0.0033 * t + 0.0452]);
+        let perez_cy = Perez([-0.0167 * t - 0.2608,
+                              -0.0950 * t + 0.0092,
+                              -0.0079 * t + 0.2102,
+                              -0.0441 * t - 1.6537,
+                              -0.0109 * t + 0.0529]);
+
+        // Luminance (in kcd/m²) and chromaticity at the zenith.
+        let chi = (4.0 / 9.0 - t / 120.0) * (consts::PI - 2.0 * theta_s);
+        let zenith_y = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let (th, th2, th3) = (theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let t2 = t * t;
+        let zenith_cx = t2 * (0.00166 * th3 - 0.00375 * th2 + 0.00209 * th) +
+                        t * (-0.02903 * th3 + 0.06377 * th2 - 0.03202 * th + 0.00394) +
+                        (0.11693 * th3 - 0.21196 * th2 + 0.06052 * th + 0.25886);
+        let zenith_cy = t2 * (0.00275 * th3 - 0.00610 * th2 + 0.00317 * th) +
+                        t * (-0.04214 * th3 + 0.08970 * th2 - 0.04153 * th + 0.00516) +
+                        (0.15346 * th3 - 0.26756 * th2 + 0.06670 * th + 0.26688);
+
+        // The Perez function for the zenith itself, which is theta_s away from
+        // the sun.
+        let cos_s = theta_s.cos();
+        let zenith = [
+            zenith_y * LUMINANCE_SCALE / perez_y.eval(1.0, theta_s, cos_s),
+            zenith_cx / perez_cx.eval(1.0, theta_s, cos_s),
+            zenith_cy / perez_cy.eval(1.0, theta_s, cos_s),
+        ];
+
+        PhysicalSky {
+            sun_direction: sun,
+            perez: [perez_y, perez_cx, perez_cy],
+            zenith: zenith,
+            sun_radiance: blackbody(5778.0) * (zenith_y * LUMINANCE_SCALE * SUN_RADIANCE),
+        }
+    }
+
+    /// Returns the radiance of the sky in one direction, which must be
+    /// normalized.
+    pub fn radiance(&self, direction: SVector3) -> SVector3 {
+        let cos_gamma = direction.dot(self.sun_direction).max(-1.0).min(1.0);
+        if cos_gamma > SUN_COS_RADIUS {
+            return self.sun_radiance
+        }
+
+        // The model is not defined below the horizon, there the sky continues
+        // as it is just above the horizon.
+        let cos_theta = direction.z.max(0.01);
+        let gamma = cos_gamma.acos();
+        let lum = self.zenith[0] * self.perez[0].eval(cos_theta, gamma, cos_gamma);
+        let x = self.zenith[1] * self.perez[1].eval(cos_theta, gamma, cos_gamma);
+        let y = self.zenith[2] * self.perez[2].eval(cos_theta, gamma, cos_gamma);
+        yxy_to_rgb(lum, x, y)
+    }
+
+    /// Returns the radiance of the sky in 8 directions.
+    pub fn radiance_8(&self, directions: MVector3) -> MVector3 {
+        MVector3::generate(|i| {
+            let d = SVector3::new(directions.x.get_coord(i),
+                                  directions.y.get_coord(i),
+                                  directions.z.get_coord(i));
+            self.radiance(d)
+        })
+    }
+}
+
+#[test]
+fn physical_sky_is_brightest_near_the_sun() {
+    let sun = SVector3::new(0.0, 1.0, 1.0).normalized();
+    let sky = PhysicalSky::new(sun, 3.0);
+    let lum = |c: SVector3| 0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z;
+
+    let near_sun = lum(sky.radiance(SVector3::new(0.0, 1.0, 1.02).normalized()));
+    let opposite_horizon = lum(sky.radiance(SVector3::new(0.0, -1.0, 0.05).normalized()));
+    let zenith = lum(sky.radiance(SVector3::new(0.0, 0.0, 1.0)));
+    let sun_disk = lum(sky.radiance(sun));
+
+    assert!(opposite_horizon > 0.0);
+    assert!(near_sun > 5.0 * opposite_horizon,
+            "near sun: {}, opposite horizon: {}", near_sun, opposite_horizon);
+    assert!(sun_disk > 100.0 * zenith);
+}
+
+#[test]
+fn physical_sky_is_blue_at_the_zenith() {
+    let sky = PhysicalSky::new(SVector3::new(1.0, 0.0, 1.0), 2.5);
+    let zenith = sky.radiance_8(MVector3::new(Mf32::zero(), Mf32::zero(), Mf32::one()));
+    for i in 0..8 {
+        assert!(zenith.z.get_coord(i) > zenith.x.get_coord(i));
+    }
+}
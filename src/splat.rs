@@ -0,0 +1,288 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! Implements point clouds, rendered as splats.
+//!
+//! A splat is a small disk with a position, an orientation, and a color, as
+//! produced by 3D scanners. Splats are diffuse white surfaces, their color is
+//! carried by the intersection as a vertex color. They live in a bounding
+//! volume hierarchy of their own, next to the one of the triangles.
+
+use aabb::Aabb;
+use filebuffer::FileBuffer;
+use material::{MMaterial, SMaterial};
+use ray::{MIntersection, MRay, NO_TRIANGLE, triangle_bits};
+use simd::{Mask, Mf32};
+use std::cmp::Ordering;
+use std::path::Path;
+use std::str::{FromStr, from_utf8};
+use vector3::{Axis, MVector3, SVector3};
+
+/// The maximum number of splats in a leaf of the hierarchy.
+const MAX_SPLATS_PER_LEAF: usize = 4;
+
+/// An oriented disk.
+#[derive(Clone, Debug)]
+pub struct Splat {
+    pub center: SVector3,
+
+    /// The normal of the disk, of unit length.
+    pub normal: SVector3,
+
+    pub radius: f32,
+    pub color: SVector3,
+}
+
+struct SplatNode {
+    aabb: Aabb,
+
+    /// For leaves, the index of the first splat, and the number of splats.
+    /// For internal nodes the length is zero, and the index is that of the
+    /// first child. The second child is at `index + 1`.
+    index: u32,
+    len: u32,
+}
+
+/// A bounding volume hierarchy over splats.
+pub struct SplatBvh {
+    nodes: Vec<SplatNode>,
+
+    /// The splats, in the order of the leaves.
+    splats: Vec<Splat>,
+}
+
+impl Splat {
+    pub fn new(center: SVector3, normal: SVector3, radius: f32, color: SVector3) -> Splat {
+        Splat {
+            center: center,
+            normal: normal.normalized(),
+            radius: radius,
+            color: color,
+        }
+    }
+
+    /// Returns the bounding box of the disk.
+    pub fn aabb(&self) -> Aabb {
+        // Along an axis, the disk extends by the radius times the sine of the
+        // angle between the axis and the normal.
+        let n = self.normal;
+        let extent = |c: f32| self.radius * (1.0 - c * c).max(0.0).sqrt();
+        let half = SVector3::new(extent(n.x), extent(n.y), extent(n.z));
+        Aabb::new(self.center - half, self.center + half)
+    }
+
+    /// Returns the nearest intersection of the disk and the ray, or the
+    /// provided intersection if it is closer.
+    pub fn intersect(&self, ray: &MRay, isect: MIntersection) -> MIntersection {
+        let center = MVector3::broadcast(self.center);
+        let normal = MVector3::broadcast(self.normal);
+
+        // Intersect the plane of the disk. Rays parallel to the plane get an
+        // infinite or NaN distance. The comparison with the current distance
+        // is true for those, so they are discarded.
+        let denom = ray.direction.dot(normal);
+        let t = (center - ray.origin).dot(normal) / denom;
+        let position = ray.direction.mul_add(t, ray.origin);
+        let to_center = position - center;
+        let r2 = Mf32::broadcast(self.radius * self.radius);
+
+        // Sign bit 1 means discard the intersection, like for triangles.
+        let mask_outside = r2 - to_center.norm_squared();
+        let mask_behind = t;
+        let mask_closer = t.geq(isect.distance);
+
+        let white = SMaterial::white();
+        let new_isect = MIntersection {
+            position: position,
+            normal: normal,
+            distance: t,
+            material: MMaterial::broadcast_material(white),
+            tex_coords: (Mf32::zero(), Mf32::zero()),
+            front_face: denom,
+            light_links: Mask::ones(),
+            barycentric: (Mf32::zero(), Mf32::zero()),
+            vertex_color: MVector3::broadcast(self.color),
+            triangle: triangle_bits(NO_TRIANGLE),
+        };
+
+        new_isect.pick(&isect, (mask_outside | mask_behind) | (mask_closer | ray.active))
+    }
+
+    /// Loads a point cloud from a text file, with one point per line as nine
+    /// numbers: the position, the normal, and the color in the range [0, 1].
+    /// Every point becomes a splat with the given radius.
+    pub fn load_points<P: AsRef<Path>>(path: P, radius: f32) -> Vec<Splat> {
+        let fbuffer = FileBuffer::open(path).expect("failed to open file");
+        let input = from_utf8(&fbuffer[..]).expect("point cloud must be valid utf-8");
+        let mut splats = Vec::new();
+
+        for (line, line_nr) in input.lines().zip(1u32..) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let values: Vec<f32> = line.split_whitespace()
+                .map(|v| f32::from_str(v).expect("invalid number in point cloud"))
+                .collect();
+            assert!(values.len() == 9, "expected 9 numbers on line {}", line_nr);
+            let v = |i: usize| SVector3::new(values[i], values[i + 1], values[i + 2]);
+            splats.push(Splat::new(v(0), v(3), radius, v(6)));
+        }
+
+        splats
+    }
+}
+
+impl SplatBvh {
+    /// Builds the hierarchy by splitting at the median along the longest axis
+    /// of the centers, until the leaves are small enough.
+    pub fn build(splats: Vec<Splat>) -> SplatBvh {
+        let mut bvh = SplatBvh {
+            nodes: Vec::new(),
+            splats: splats,
+        };
+
+        if !bvh.splats.is_empty() {
+            let n = bvh.splats.len();
+            let aabbs: Vec<Aabb> = bvh.splats.iter().map(|s| s.aabb()).collect();
+            let aabb = Aabb::enclose_aabbs(&aabbs);
+            bvh.nodes.push(SplatNode { aabb: aabb, index: 0, len: n as u32 });
+            bvh.split(0, 0, n);
+        }
+
+        bvh
+    }
+
+    /// Returns a hierarchy without splats.
+    pub fn empty() -> SplatBvh {
+        SplatBvh::build(Vec::new())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.splats.is_empty()
+    }
+
+    pub fn splats(&self) -> &[Splat] {
+        &self.splats
+    }
+
+    /// Returns the bounding box of all splats. There must be at least one.
+    pub fn bounds(&self) -> Aabb {
+        self.nodes[0].aabb.clone()
+    }
+
+    fn split(&mut self, node: usize, first: usize, len: usize) {
+        if len <= MAX_SPLATS_PER_LEAF {
+            return
+        }
+
+        let axis = {
+            let centers: Vec<SVector3> = self.splats[first..first + len].iter().map(|s| s.center).collect();
+            let size = Aabb::enclose_points(&centers).size();
+            if size.x >= size.y && size.x >= size.z {
+                Axis::X
+            } else if size.y >= size.z {
+                Axis::Y
+            } else {
+                Axis::Z
+            }
+        };
+        let key = |s: &Splat| s.center.get_coord(axis);
+        self.splats[first..first + len].sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap_or(Ordering::Equal));
+
+        let mid = len / 2;
+        let child = self.nodes.len();
+        for &(f, l) in &[(first, mid), (first + mid, len - mid)] {
+            let aabbs: Vec<Aabb> = self.splats[f..f + l].iter().map(|s| s.aabb()).collect();
+            self.nodes.push(SplatNode { aabb: Aabb::enclose_aabbs(&aabbs), index: f as u32, len: l as u32 });
+        }
+        self.nodes[node].index = child as u32;
+        self.nodes[node].len = 0;
+
+        self.split(child + 0, first, mid);
+        self.split(child + 1, first + mid, len - mid);
+    }
+
+    /// Returns the nearest intersection closer than the provided intersection.
+    pub fn intersect_nearest(&self, ray: &MRay, mut isect: MIntersection) -> MIntersection {
+        if self.nodes.is_empty() {
+            return isect
+        }
+
+        let mut stack = Vec::with_capacity(32);
+        let root_isect = self.nodes[0].aabb.intersect(ray);
+        if root_isect.any_masked(ray.active) {
+            stack.push((root_isect, &self.nodes[0]));
+        }
+
+        while let Some((aabb_isect, node)) = stack.pop() {
+            if aabb_isect.is_further_away_than(isect.distance, ray.active) {
+                continue;
+            }
+
+            if node.len == 0 {
+                for child in &self.nodes[node.index as usize..node.index as usize + 2] {
+                    let child_isect = child.aabb.intersect(ray);
+                    if child_isect.any_masked(ray.active) {
+                        stack.push((child_isect, child));
+                    }
+                }
+            } else {
+                for splat in &self.splats[node.index as usize..(node.index + node.len) as usize] {
+                    isect = splat.intersect(ray, isect);
+                }
+            }
+        }
+
+        isect
+    }
+}
+
+#[test]
+fn splat_is_hit_within_radius_only() {
+    let splat = Splat::new(SVector3::new(0.0, 0.0, -3.0),
+                           SVector3::new(0.0, 0.0, 1.0),
+                           0.5,
+                           SVector3::new(1.0, 0.5, 0.25));
+    let bvh = SplatBvh::build(vec![splat]);
+
+    // Lanes 0-3 aim inside the disk, lanes 4-7 just outside of it.
+    let offsets = [0.0, 0.2, -0.3, 0.45, 0.55, -0.6, 1.0, 2.0];
+    let ray = MRay {
+        origin: MVector3::generate(|i| SVector3::new(offsets[i], 0.0, 0.0)),
+        direction: MVector3::generate(|_| SVector3::new(0.0, 0.0, -1.0)),
+        active: Mf32::zero(),
+    };
+    let isect = bvh.intersect_nearest(&ray, MIntersection::with_max_distance(1.0e5));
+
+    for i in 0..4 {
+        assert!((isect.distance.get_coord(i) - 3.0).abs() < 1e-5, "lane {} missed the splat", i);
+        assert!((isect.position.z.get_coord(i) + 3.0).abs() < 1e-5);
+        assert_eq!(isect.normal.z.get_coord(i), 1.0);
+        assert_eq!(isect.vertex_color.y.get_coord(i), 0.5);
+    }
+    for i in 4..8 {
+        assert_eq!(isect.distance.get_coord(i), 1.0e5, "lane {} hit beyond the radius", i);
+    }
+}
+
+#[test]
+fn splat_bvh_finds_nearest_of_many() {
+    // A row of splats along the z-axis, facing the camera. The nearest one is
+    // the one at z = -2.
+    let splats: Vec<Splat> = (2..20).rev().map(|i| {
+        Splat::new(SVector3::new(0.0, 0.0, -(i as f32)), SVector3::new(0.0, 0.0, 1.0), 0.5, SVector3::zero())
+    }).collect();
+    let bvh = SplatBvh::build(splats);
+    let ray = MRay {
+        origin: MVector3::zero(),
+        direction: MVector3::generate(|_| SVector3::new(0.0, 0.0, -1.0)),
+        active: Mf32::zero(),
+    };
+    let isect = bvh.intersect_nearest(&ray, MIntersection::with_max_distance(1.0e5));
+    assert!((isect.distance.get_coord(0) - 2.0).abs() < 1e-5);
+}
@@ -0,0 +1,151 @@
+// Convector -- An interactive CPU path tracer
+// Copyright 2016 Ruud van Asseldonk
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License version 3. A copy
+// of the License is available in the root of the repository.
+
+//! Implements affine transformations of R3.
+//!
+//! Transformations are only applied at scene construction time, so there is no
+//! SIMD version; everything here operates on single vectors.
+
+use quaternion::SQuaternion;
+use vector3::SVector3;
+
+#[cfg(test)]
+use bench;
+
+/// An affine transformation: a linear map followed by a translation.
+///
+/// The linear part is stored as the images of the three basis vectors, so
+/// applying it to a point (x, y, z) is `x * self.x + y * self.y + z * self.z`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SAffine {
+    pub x: SVector3,
+    pub y: SVector3,
+    pub z: SVector3,
+    pub translation: SVector3,
+}
+
+impl SAffine {
+    pub fn identity() -> SAffine {
+        SAffine {
+            x: SVector3::new(1.0, 0.0, 0.0),
+            y: SVector3::new(0.0, 1.0, 0.0),
+            z: SVector3::new(0.0, 0.0, 1.0),
+            translation: SVector3::zero(),
+        }
+    }
+
+    /// Returns the transformation that translates by the given offset.
+    pub fn translation(offset: SVector3) -> SAffine {
+        SAffine { translation: offset, ..SAffine::identity() }
+    }
+
+    /// Returns the transformation that scales uniformly about the origin.
+    pub fn scale(factor: f32) -> SAffine {
+        SAffine {
+            x: SVector3::new(factor, 0.0, 0.0),
+            y: SVector3::new(0.0, factor, 0.0),
+            z: SVector3::new(0.0, 0.0, factor),
+            translation: SVector3::zero(),
+        }
+    }
+
+    /// Returns the rotation about the origin described by a unit quaternion.
+    pub fn rotation(q: SQuaternion) -> SAffine {
+        // These are the columns of the well-known rotation matrix for the
+        // quaternion a + b*i + c*j + d*k.
+        let (a, b, c, d) = (q.a, q.b, q.c, q.d);
+        SAffine {
+            x: SVector3::new(1.0 - 2.0 * (c * c + d * d),
+                             2.0 * (b * c + a * d),
+                             2.0 * (b * d - a * c)),
+            y: SVector3::new(2.0 * (b * c - a * d),
+                             1.0 - 2.0 * (b * b + d * d),
+                             2.0 * (c * d + a * b)),
+            z: SVector3::new(2.0 * (b * d + a * c),
+                             2.0 * (c * d - a * b),
+                             1.0 - 2.0 * (b * b + c * c)),
+            translation: SVector3::zero(),
+        }
+    }
+
+    /// Applies only the linear part, appropriate for directions.
+    pub fn apply_vector(&self, v: SVector3) -> SVector3 {
+        self.x * v.x + self.y * v.y + self.z * v.z
+    }
+
+    /// Applies the full transformation, appropriate for positions.
+    pub fn apply_point(&self, p: SVector3) -> SVector3 {
+        self.apply_vector(p) + self.translation
+    }
+
+    /// Returns the transformation that first applies `inner` and then self.
+    pub fn compose(&self, inner: &SAffine) -> SAffine {
+        SAffine {
+            x: self.apply_vector(inner.x),
+            y: self.apply_vector(inner.y),
+            z: self.apply_vector(inner.z),
+            translation: self.apply_point(inner.translation),
+        }
+    }
+
+    /// Returns the inverse transformation. The linear part must be
+    /// invertible.
+    pub fn inverse(&self) -> SAffine {
+        // The rows of the inverse of a 3x3 matrix are the cross products of
+        // its columns, divided by the determinant.
+        let rdet = 1.0 / self.x.dot(self.y.cross(self.z));
+        let r0 = self.y.cross(self.z) * rdet;
+        let r1 = self.z.cross(self.x) * rdet;
+        let r2 = self.x.cross(self.y) * rdet;
+        let linear = SAffine {
+            x: SVector3::new(r0.x, r1.x, r2.x),
+            y: SVector3::new(r0.y, r1.y, r2.y),
+            z: SVector3::new(r0.z, r1.z, r2.z),
+            translation: SVector3::zero(),
+        };
+        SAffine { translation: -linear.apply_vector(self.translation), ..linear }
+    }
+}
+
+#[test]
+fn affine_compose_applies_inner_first() {
+    let half_sqrt_2 = 0.5 * 2.0_f32.sqrt();
+    let rotate_z = SAffine::rotation(SQuaternion::new(half_sqrt_2, 0.0, 0.0, half_sqrt_2));
+    let translate_x = SAffine::translation(SVector3::new(1.0, 0.0, 0.0));
+
+    // Translating (0, 0, 0) along x and then rotating by pi/2 radians around
+    // the z-axis ends up at (0, 1, 0). The other order stays at (1, 0, 0).
+    let p = SVector3::zero();
+    let p_tr = rotate_z.compose(&translate_x).apply_point(p);
+    let p_rt = translate_x.compose(&rotate_z).apply_point(p);
+    assert!((p_tr - SVector3::new(0.0, 1.0, 0.0)).norm_squared() < 1e-10);
+    assert!((p_rt - SVector3::new(1.0, 0.0, 0.0)).norm_squared() < 1e-10);
+}
+
+#[test]
+fn affine_inverse_undoes_transform() {
+    let q = bench::unit_squaternions(1)[0];
+    let t = SAffine::translation(SVector3::new(1.0, -2.0, 0.5))
+        .compose(&SAffine::rotation(q))
+        .compose(&SAffine::scale(3.0));
+    let p = SVector3::new(0.3, 0.7, -1.1);
+    let back = t.inverse().apply_point(t.apply_point(p));
+    assert!((back - p).norm_squared() < 1e-10, "expected {}, got {}", p, back);
+}
+
+#[test]
+fn affine_rotation_is_orthogonal() {
+    for q in bench::unit_squaternions(64) {
+        let r = SAffine::rotation(q);
+        assert!((r.x.norm_squared() - 1.0).abs() < 1e-5);
+        assert!((r.y.norm_squared() - 1.0).abs() < 1e-5);
+        assert!((r.z.norm_squared() - 1.0).abs() < 1e-5);
+        assert!(r.x.dot(r.y).abs() < 1e-5);
+        assert!(r.y.dot(r.z).abs() < 1e-5);
+        assert!(r.z.dot(r.x).abs() < 1e-5);
+    }
+}
@@ -13,13 +13,39 @@
 //! intersection code to be inlined.
 
 use material::{SMaterial, MMaterial};
-use ray::{MIntersection, MRay};
+use ray::{MIntersection, MRay, NO_TRIANGLE, triangle_bits};
 use simd::Mf32;
+use std::ops::Range;
+use std::{f32, u32};
 use vector3::{MVector3, SVector3};
 
 #[cfg(test)]
 use {bench, test};
 
+/// Determinants smaller than this, relative to the product of the edge lengths,
+/// are treated as zero. The ratio is the sine of the angle between the edges
+/// times the cosine of the angle between the ray and the normal, so this
+/// discards triangles with (nearly) collinear vertices, and rays that graze a
+/// triangle within about a microradian.
+const DEGENERATE_EPSILON: f32 = 1.0e-6;
+
+/// Returns the mask that has the sign bit set for the lanes where the
+/// determinant is too small to divide by, given the triangle's precomputed
+/// `degenerate_bound`.
+#[inline(always)]
+fn mask_degenerate(det: Mf32, degenerate_bound: Mf32) -> Mf32 {
+    degenerate_bound.geq(det * det)
+}
+
+/// Returns the bound on the squared determinant below which a ray-triangle
+/// test is considered degenerate: the squared epsilon times the product of
+/// the squared edge lengths.
+fn degenerate_bound(v0: SVector3, v1: SVector3, v2: SVector3) -> f32 {
+    let e1 = v0 - v2;
+    let e2 = v1 - v0;
+    DEGENERATE_EPSILON * DEGENERATE_EPSILON * e1.norm_squared() * e2.norm_squared()
+}
+
 #[derive(Clone, Debug)]
 pub struct Triangle {
     pub v0: SVector3,
@@ -28,7 +54,18 @@ pub struct Triangle {
     pub uv0: (f32, f32),
     pub uv1: (f32, f32),
     pub uv2: (f32, f32),
+    pub color0: SVector3,
+    pub color1: SVector3,
+    pub color2: SVector3,
     pub material: SMaterial,
+
+    /// Light linking bits, see `Mesh::light_links`.
+    pub light_links: u32,
+
+    /// Squared determinants below this bound are treated as zero, see
+    /// `DEGENERATE_EPSILON`. It is precomputed so the intersection tests need
+    /// not compute the edge lengths. Call `update_degenerate_bound()` after moving the vertices.
+    pub degenerate_bound: f32,
 }
 
 /// The result of intersecting a triangle to compute a probability density.
@@ -48,14 +85,121 @@ impl Triangle {
             uv0: (0.0, 0.0),
             uv1: (0.0, 0.0),
             uv2: (0.0, 0.0),
+            color0: SVector3::one(),
+            color1: SVector3::one(),
+            color2: SVector3::one(),
             material: mat,
+            light_links: u32::MAX,
+            degenerate_bound: degenerate_bound(v0, v1, v2),
         }
     }
 
+    /// Recomputes the bound used to discard degenerate intersections, after
+    /// the vertices have changed.
+    pub fn update_degenerate_bound(&mut self) {
+        self.degenerate_bound = degenerate_bound(self.v0, self.v1, self.v2);
+    }
+
+    /// Returns the light linking bits as the bit pattern of a float, in the
+    /// same way that materials are stored.
+    #[inline(always)]
+    fn broadcast_light_links(&self) -> Mf32 {
+        use std::mem::transmute;
+        let links: f32 = unsafe { transmute(self.light_links) };
+        Mf32::broadcast(links)
+    }
+
+    /// Interpolates the vertex colors at the point with barycentric weights
+    /// `b1` and `b2` for the second and third vertex, as in
+    /// `MIntersection::barycentric`.
+    pub fn color_at(&self, b1: f32, b2: f32) -> SVector3 {
+        self.color0 * (1.0 - b1 - b2) + self.color1 * b1 + self.color2 * b2
+    }
+
     pub fn barycenter(&self) -> SVector3 {
         (self.v0 + self.v1 + self.v2) * 3.0f32.recip()
     }
 
+    /// Returns the geometric normal, oriented like the normal that
+    /// `intersect()` returns.
+    pub fn normal(&self) -> SVector3 {
+        (self.v0 - self.v2).cross(self.v1 - self.v0).normalized()
+    }
+
+    /// Returns the derivatives of the position with respect to the texture
+    /// coordinates u and v, or `None` if the texture coordinates of the
+    /// vertices do not span an area.
+    pub fn position_derivatives(&self) -> Option<(SVector3, SVector3)> {
+        let (e1, e2) = (self.v1 - self.v0, self.v2 - self.v0);
+        let (du1, dv1) = (self.uv1.0 - self.uv0.0, self.uv1.1 - self.uv0.1);
+        let (du2, dv2) = (self.uv2.0 - self.uv0.0, self.uv2.1 - self.uv0.1);
+        let det = du1 * dv2 - du2 * dv1;
+        if det == 0.0 {
+            return None
+        }
+        let rdet = 1.0 / det;
+        let dp_du = (e1 * dv2 - e2 * dv1) * rdet;
+        let dp_dv = (e2 * du1 - e1 * du2) * rdet;
+        Some((dp_du, dp_dv))
+    }
+
+    /// Returns the point on the triangle closest to the given point.
+    ///
+    /// This is the method from section 5.1.5 of "Real-Time Collision
+    /// Detection" by Christer Ericson: find the Voronoi region of the
+    /// triangle that contains the point, and project onto that feature.
+    pub fn closest_point(&self, p: SVector3) -> SVector3 {
+        let (a, b, c) = (self.v0, self.v1, self.v2);
+        let ab = b - a;
+        let ac = c - a;
+
+        // Vertex region of a.
+        let ap = p - a;
+        let d1 = ab.dot(ap);
+        let d2 = ac.dot(ap);
+        if d1 <= 0.0 && d2 <= 0.0 {
+            return a
+        }
+
+        // Vertex region of b.
+        let bp = p - b;
+        let d3 = ab.dot(bp);
+        let d4 = ac.dot(bp);
+        if d3 >= 0.0 && d4 <= d3 {
+            return b
+        }
+
+        // Edge region of ab.
+        let vc = d1 * d4 - d3 * d2;
+        if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+            return a + ab * (d1 / (d1 - d3))
+        }
+
+        // Vertex region of c.
+        let cp = p - c;
+        let d5 = ab.dot(cp);
+        let d6 = ac.dot(cp);
+        if d6 >= 0.0 && d5 <= d6 {
+            return c
+        }
+
+        // Edge region of ac.
+        let vb = d5 * d2 - d1 * d6;
+        if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+            return a + ac * (d2 / (d2 - d6))
+        }
+
+        // Edge region of bc.
+        let va = d3 * d6 - d5 * d4;
+        if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+            return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)))
+        }
+
+        // Inside the face, project onto the plane.
+        let denom = 1.0 / (va + vb + vc);
+        a + ab * (vb * denom) + ac * (vc * denom)
+    }
+
     pub fn intersect(&self, ray: &MRay, isect: MIntersection) -> MIntersection {
         // One would expect that if the triangle were represented as
         // (v0, e1, e2) instead of (v0, v1, v2), that would be faster because we
@@ -80,6 +224,14 @@ impl Triangle {
         let normal_denorm = e1.cross(e2);
         let from_ray = v0 - ray.origin;
 
+        // If the vertices are collinear, the normal is zero, and for rays
+        // parallel to the plane the determinant is zero too. Dividing by a
+        // (nearly) zero determinant produces NaNs or huge values that can pass
+        // the tests below as spurious hits, so those lanes divide by one
+        // instead, and are discarded.
+        let det = ray.direction.dot(normal_denorm);
+        let mask_degenerate = mask_degenerate(det, Mf32::broadcast(self.degenerate_bound));
+
         // Use a true division (_mm256_div_ps), not the reciprocal approximation
         // (_mm256_rcp_ps) because the approximation is too inaccurate and
         // causes visual artifacts. The alternative is to use the approximation
@@ -87,7 +239,7 @@ impl Triangle {
         // doing the division. (Even though the microbenchmarks show that
         // `recip_precise` is faster than the division, when used in this
         // method, the division is faster.)
-        let denom = Mf32::one() / ray.direction.dot(normal_denorm);
+        let denom = Mf32::one() / det.pick(Mf32::one(), mask_degenerate);
         let t = from_ray.dot(normal_denorm) * denom;
 
         // If the potential intersection is further away than the current
@@ -133,18 +285,159 @@ impl Triangle {
         let tex_x = tx0x.mul_add(w, tx1x.mul_add(v, tx2x * u));
         let tex_y = tx0y.mul_add(w, tx1y.mul_add(v, tx2y * u));
 
+        // Compute the position from the barycentric coordinates rather than
+        // as origin + t * direction. The error of t grows with the distance
+        // to the ray origin, and far from the world origin the sum loses the
+        // low bits of t. The interpolated position lies on the triangle up to
+        // rounding of the vertex coordinates.
         let new_isect = MIntersection {
-            position: ray.direction.mul_add(t, ray.origin),
+            position: e2.mul_add(v, e1.neg_mul_add(u, v0)),
             normal: normal_denorm.normalized(),
             distance: t,
             material: MMaterial::broadcast_material(self.material),
             tex_coords: (tex_x, tex_y),
+            // The sign of denom is the sign of the cosine between the ray
+            // and the normal, which is negative for the front face.
+            front_face: denom,
+            light_links: self.broadcast_light_links(),
+            barycentric: (v, u),
+            // Vertex colors are interpolated only for the nearest hit, see
+            // `Scene::interpolate_vertex_colors()`.
+            vertex_color: MVector3::new(Mf32::one(), Mf32::one(), Mf32::one()),
+            triangle: triangle_bits(NO_TRIANGLE),
         };
 
         // Per ray, pick the new intersection if it is closer and if it was
         // indeed an intersection of the triangle, or pick the previous
         // intersection otherwise.
-        new_isect.pick(&isect, mask_positive | (ray.active | mask_closer))
+        new_isect.pick(&isect, (mask_positive | mask_degenerate) | (ray.active | mask_closer))
+    }
+
+    /// Intersects the triangle with a watertight algorithm.
+    ///
+    /// The default intersection (`intersect()`) can miss a ray that passes
+    /// exactly through the edge shared by two triangles, or hit both of them.
+    /// This version is the algorithm from "Watertight Ray/Triangle
+    /// Intersection" by Woop, Benthin, and Wald (2013). It transforms the
+    /// vertices into a space where the ray is the z-axis, and there it
+    /// computes the edge functions in a way that is exactly antisymmetric for
+    /// a shared edge. For the rare case where the ray hits an edge exactly, a
+    /// top-left rule picks one of the two triangles. It is slower than
+    /// `intersect()`. To use it in the BVH, enable the `watertight` feature.
+    pub fn intersect_watertight(&self, ray: &MRay, isect: MIntersection) -> MIntersection {
+        let d = ray.direction;
+        let (ax, ay, az) = (d.x.abs(), d.y.abs(), d.z.abs());
+
+        // Pick the dimension where the ray direction is largest as the z-axis
+        // (kz), and the next two as the x and y axes. The sign bit of the mask
+        // is set for the lanes where the x or y coordinate is largest.
+        let kz_x = ax.geq(ay) & ax.geq(az);
+        let kz_y = kz_x.neg_xor() & ay.geq(az);
+        let permute = |v: MVector3| {
+            let x = v.x.pick(v.z, kz_y).pick(v.y, kz_x);
+            let y = v.y.pick(v.x, kz_y).pick(v.z, kz_x);
+            let z = v.z.pick(v.y, kz_y).pick(v.x, kz_x);
+            (x, y, z)
+        };
+
+        // Swap the x and y axes if the z-component is negative, to preserve
+        // the winding direction of the triangle.
+        let (dx, dy, dz) = permute(d);
+        let (dx, dy) = (dx.pick(dy, dz), dy.pick(dx, dz));
+        let swap = |(x, y, z): (Mf32, Mf32, Mf32)| (x.pick(y, dz), y.pick(x, dz), z);
+
+        // Shear constants that map the ray direction onto the z-axis.
+        let rdz = Mf32::one() / dz;
+        let sx = dx * rdz;
+        let sy = dy * rdz;
+
+        // Transform the vertices relative to the ray origin. These must not
+        // use fused multiply-add: the products must be the same for every
+        // triangle that shares the vertex.
+        let transform = |v: SVector3| {
+            let (x, y, z) = swap(permute(MVector3::broadcast(v) - ray.origin));
+            (x - sx * z, y - sy * z, z * rdz)
+        };
+        let (ax, ay, az) = transform(self.v0);
+        let (bx, by, bz) = transform(self.v1);
+        let (cx, cy, cz) = transform(self.v2);
+
+        // The edge functions. For an edge shared with another triangle, the
+        // other triangle computes exactly the negation.
+        let u = cx * by - cy * bx;
+        let v = ax * cy - ay * cx;
+        let w = bx * ay - by * ax;
+
+        // If an edge function is exactly zero, the ray hits the edge. Count it
+        // as positive if the edge is a top-left edge, and as negative
+        // otherwise. The neighboring triangle traverses the edge in the other
+        // direction, so exactly one of the two has a hit.
+        let zero = Mf32::zero();
+        let is_zero = |e: Mf32| e.geq(zero) & zero.geq(e);
+        let greater = |a: Mf32, b: Mf32| b.geq(a).neg_xor();
+        let tie_break = |e: Mf32, (px, py): (Mf32, Mf32), (qx, qy): (Mf32, Mf32)| {
+            let (ex, ey) = (qx - px, qy - py);
+            let top_left = greater(ey, zero) | (is_zero(ey) & greater(zero, ex));
+            let tie = Mf32::broadcast(-1.0).pick(Mf32::one(), top_left);
+            e.pick(tie, is_zero(e))
+        };
+        let u = tie_break(u, (bx, by), (cx, cy));
+        let v = tie_break(v, (cx, cy), (ax, ay));
+        let w = tie_break(w, (ax, ay), (bx, by));
+
+        // The ray hits the triangle if all edge functions have the same sign.
+        // Sign bit 1 means discard, like in `intersect()`.
+        let not_all_positive = u | v | w;
+        let not_all_negative = (u & v & w).neg_xor();
+        let mask_edges = not_all_positive & not_all_negative;
+
+        // Scale the barycentric coordinates to compute the distance. The
+        // signs of t_scaled and det are equal if the hit is in front of the
+        // ray origin.
+        let det = u + v + w;
+        let t_scaled = u * az + v * bz + w * cz;
+        let rdet = Mf32::one() / det;
+        let t = t_scaled * rdet;
+        let (b0, b1, b2) = (u * rdet, v * rdet, w * rdet);
+
+        let mask_closer = t.geq(isect.distance);
+
+        // For a degenerate triangle, or a ray parallel to the plane of the
+        // triangle, the projected triangle has (nearly) zero area, and the
+        // edge functions are dominated by rounding errors, so they can all
+        // have the same sign. Discard those lanes with the same test as in
+        // `intersect()`.
+        let e1 = MVector3::broadcast(self.v0) - MVector3::broadcast(self.v2);
+        let e2 = MVector3::broadcast(self.v1) - MVector3::broadcast(self.v0);
+        let normal_denorm = e1.cross(e2);
+        let mask_degenerate = mask_degenerate(d.dot(normal_denorm), Mf32::broadcast(self.degenerate_bound));
+
+        let (tx0x, tx0y) = (Mf32::broadcast(self.uv0.0), Mf32::broadcast(self.uv0.1));
+        let (tx1x, tx1y) = (Mf32::broadcast(self.uv1.0), Mf32::broadcast(self.uv1.1));
+        let (tx2x, tx2y) = (Mf32::broadcast(self.uv2.0), Mf32::broadcast(self.uv2.1));
+        let tex_x = tx0x.mul_add(b0, tx1x.mul_add(b1, tx2x * b2));
+        let tex_y = tx0y.mul_add(b0, tx1y.mul_add(b1, tx2y * b2));
+
+        // As in `intersect()`, interpolate the position on the triangle.
+        let new_isect = MIntersection {
+            position: e2.mul_add(b1, e1.neg_mul_add(b2, MVector3::broadcast(self.v0))),
+            normal: normal_denorm.normalized(),
+            distance: t,
+            material: MMaterial::broadcast_material(self.material),
+            tex_coords: (tex_x, tex_y),
+            front_face: d.dot(normal_denorm),
+            light_links: self.broadcast_light_links(),
+            barycentric: (b1, b2),
+            // Vertex colors are interpolated only for the nearest hit, see
+            // `Scene::interpolate_vertex_colors()`.
+            vertex_color: MVector3::new(Mf32::one(), Mf32::one(), Mf32::one()),
+            triangle: triangle_bits(NO_TRIANGLE),
+        };
+
+        // A NaN distance (when det is zero) has an arbitrary sign bit, but
+        // then the comparison with the current distance fails, because geq is
+        // true for unordered operands.
+        new_isect.pick(&isect, ((mask_edges | mask_degenerate) | t) | (ray.active | mask_closer))
     }
 
     /// Intersects the triangle to determine the probability density for the
@@ -185,6 +478,146 @@ impl Triangle {
     }
 }
 
+/// Triangle vertices in structure-of-arrays form, eight triangles per group.
+///
+/// `Triangle::intersect()` broadcasts one triangle and intersects it with the
+/// eight rays of a packet. Here the vertices of eight triangles are stored in
+/// the lanes of an `MVector3` instead, so the vertex data of a group is loaded
+/// as contiguous `Mf32`s, and every ray of a packet is intersected with eight
+/// triangles at once. The BVH stores the triangles of its leaves in this form,
+/// every leaf starting at a new group.
+pub struct TriangleSoa {
+    v0: Vec<MVector3>,
+    v1: Vec<MVector3>,
+    v2: Vec<MVector3>,
+
+    /// Per group, the `degenerate_bound` of the triangles. Lanes that do not
+    /// contain a triangle have an infinite bound, so they are never hit.
+    degenerate_bound: Vec<Mf32>,
+}
+
+impl TriangleSoa {
+    pub fn new() -> TriangleSoa {
+        TriangleSoa {
+            v0: Vec::new(),
+            v1: Vec::new(),
+            v2: Vec::new(),
+            degenerate_bound: Vec::new(),
+        }
+    }
+
+    pub fn from_triangles(triangles: &[Triangle]) -> TriangleSoa {
+        let mut soa = TriangleSoa::new();
+        soa.push_triangles(triangles);
+        soa
+    }
+
+    /// Appends the triangles in new groups, and returns the index of the
+    /// first one. The last group is padded if the number of triangles is not
+    /// a multiple of eight.
+    pub fn push_triangles(&mut self, triangles: &[Triangle]) -> u32 {
+        let first_group = self.v0.len() as u32;
+
+        for group in triangles.chunks(8) {
+            let n = group.len();
+            let vertex = |i: usize, f: &Fn(&Triangle) -> SVector3| {
+                if i < n { f(&group[i]) } else { SVector3::zero() }
+            };
+            self.v0.push(MVector3::generate(|i| vertex(i, &|t| t.v0)));
+            self.v1.push(MVector3::generate(|i| vertex(i, &|t| t.v1)));
+            self.v2.push(MVector3::generate(|i| vertex(i, &|t| t.v2)));
+            self.degenerate_bound.push(Mf32::generate(|i| {
+                if i < n { group[i].degenerate_bound } else { f32::INFINITY }
+            }));
+        }
+
+        first_group
+    }
+
+    /// Returns for every active ray the index of the nearest triangle in the
+    /// given groups, relative to the first triangle of the first group, if it
+    /// is closer than the distance in the intersection.
+    #[inline(always)]
+    pub fn nearest_indices(&self, groups: Range<usize>, ray: &MRay, isect: &MIntersection) -> [Option<u32>; 8] {
+        let mut indices = [None; 8];
+
+        for k in 0..8 {
+            // Inactive rays have the sign bit set.
+            if ray.active.get_coord(k).is_sign_negative() {
+                continue
+            }
+
+            let origin = ray.origin.broadcast_lane(k);
+            let direction = ray.direction.broadcast_lane(k);
+            let mut distance = isect.distance.get_coord(k);
+
+            for g in groups.clone() {
+                let v0 = self.v0[g];
+                let v1 = self.v1[g];
+                let v2 = self.v2[g];
+
+                // This is the same computation as in `Triangle::intersect()`,
+                // with the same order of operations, so it finds the same
+                // distances.
+                let e1 = v0 - v2;
+                let e2 = v1 - v0;
+                let normal_denorm = e1.cross(e2);
+                let from_ray = v0 - origin;
+                let det = direction.dot(normal_denorm);
+                let mask_degenerate = mask_degenerate(det, self.degenerate_bound[g]);
+                let denom = Mf32::one() / det.pick(Mf32::one(), mask_degenerate);
+                let t = from_ray.dot(normal_denorm) * denom;
+                let cross = direction.cross(from_ray);
+                let u = cross.dot(e2) * denom;
+                let v = cross.dot(e1) * denom;
+                let w = (Mf32::one() - u) - v;
+
+                let mask_closer = t.geq(Mf32::broadcast(distance));
+                let mask = ((t | u) | (v | w)) | (mask_degenerate | mask_closer);
+
+                // Masks are not necessarily valid floats, so inspect the
+                // distances with the misses replaced by infinity instead.
+                // Lanes are visited in order and only strictly closer hits
+                // replace the current one, so for equal distances the
+                // triangle with the lowest index wins, as it does when the
+                // triangles are intersected one by one.
+                let t_hit = t.pick(Mf32::broadcast(f32::INFINITY), mask);
+                for i in 0..8 {
+                    if t_hit.get_coord(i) < distance {
+                        distance = t_hit.get_coord(i);
+                        indices[k] = Some(((g - groups.start) * 8 + i) as u32);
+                    }
+                }
+            }
+        }
+
+        indices
+    }
+
+    /// Returns the nearest intersection closer than the provided intersection.
+    ///
+    /// The triangles must be the ones the structure was built from. Only the
+    /// nearest triangles are intersected in full to fill in the surface
+    /// attributes, so the result is identical to intersecting all triangles
+    /// with `Triangle::intersect()`.
+    pub fn intersect_nearest(&self,
+                             triangles: &[Triangle],
+                             ray: &MRay,
+                             mut isect: MIntersection)
+                             -> MIntersection {
+        let mut indices: Vec<u32> = self.nearest_indices(0..self.v0.len(), ray, &isect)
+            .iter()
+            .filter_map(|&i| i)
+            .collect();
+        indices.sort();
+        indices.dedup();
+        for i in indices {
+            isect = triangles[i as usize].intersect(ray, isect);
+        }
+        isect
+    }
+}
+
 #[test]
 fn intersect_triangle() {
     use ray::SRay;
@@ -258,6 +691,142 @@ fn intersect_triangle_direct() {
     assert!(normal_norm.1 > 0.99);
 }
 
+#[test]
+fn intersect_watertight_agrees_with_intersect() {
+    let rays = bench::mrays_inward(4096 / 8);
+    let tris = bench::triangles(64);
+    for (ray, triangle) in rays.iter().zip(tris.iter().cycle()) {
+        let isect = triangle.intersect(ray, MIntersection::with_max_distance(1e5));
+        let isect_wt = triangle.intersect_watertight(ray, MIntersection::with_max_distance(1e5));
+        for i in 0..8 {
+            let (d, d_wt) = (isect.distance.get_coord(i), isect_wt.distance.get_coord(i));
+            // Rays that graze an edge may differ, but they are rare.
+            if (d == 1e5) == (d_wt == 1e5) {
+                assert!((d - d_wt).abs() < 1e-3 * d.max(1.0), "distance {} vs {}", d, d_wt);
+            }
+        }
+    }
+}
+
+#[test]
+fn intersect_watertight_hits_shared_edge_once() {
+    use ray::SRay;
+
+    // Two triangles that form a square in the plane z = 1, with a shared
+    // diagonal edge from (-1, -1) to (1, 1).
+    let p = |x, y| SVector3::new(x, y, 1.0);
+    let tri_a = Triangle::new(p(-1.0, -1.0), p(1.0, -1.0), p(1.0, 1.0), SMaterial::white());
+    let tri_b = Triangle::new(p(-1.0, -1.0), p(1.0, 1.0), p(-1.0, 1.0), SMaterial::white());
+
+    // Fire rays from various directions at points on the shared edge.
+    let directions = [
+        SVector3::new(0.0, 0.0, 1.0),
+        SVector3::new(0.0, 0.0, -1.0),
+        SVector3::new(0.3, -0.2, 1.0),
+        SVector3::new(-2.0, 0.5, 1.0),
+        SVector3::new(0.1, 3.0, -1.0),
+        SVector3::new(1.0, 1.0, 1.0),
+        SVector3::new(-0.7, 0.7, 0.2),
+        SVector3::new(0.25, 0.5, -0.125),
+    ];
+    for k in 0..16 {
+        let s = -0.9 + 0.113 * k as f32;
+        let ray = MRay::generate(|i| {
+            let direction = directions[i].normalized();
+            let origin = p(s, s) - direction * 2.0;
+            SRay::new(origin, direction)
+        });
+        let isect_a = tri_a.intersect_watertight(&ray, MIntersection::with_max_distance(1e5));
+        let isect_b = tri_b.intersect_watertight(&ray, MIntersection::with_max_distance(1e5));
+        for i in 0..8 {
+            let hits = (isect_a.distance.get_coord(i) < 1e5) as u32 +
+                       (isect_b.distance.get_coord(i) < 1e5) as u32;
+            assert_eq!(hits, 1, "ray {} at edge point {} hit {} triangles", i, s, hits);
+        }
+    }
+}
+
+#[test]
+fn intersect_never_hits_degenerate_triangle() {
+    use std::f32::consts;
+
+    let p = SVector3::new;
+    let degenerate = [
+        // Collinear vertices.
+        Triangle::new(p(-1.0, 0.0, 1.0), p(0.0, 0.0, 1.0), p(1.0, 0.0, 1.0), SMaterial::white()),
+        // Collinear up to rounding.
+        Triangle::new(p(0.1, 0.2, 0.3), p(0.4, 0.5, 0.6), p(0.7, 0.8, 0.9), SMaterial::white()),
+        // All vertices in the same place.
+        Triangle::new(p(0.5, 0.5, 0.5), p(0.5, 0.5, 0.5), p(0.5, 0.5, 0.5), SMaterial::white()),
+    ];
+
+    // Directions spread evenly over the sphere, on a Fibonacci spiral.
+    let golden_angle = consts::PI * (3.0 - 5.0f32.sqrt());
+    let direction = |i: u32| {
+        let z = 1.0 - (i as f32 + 0.5) / 128.0;
+        let r = (1.0 - z * z).sqrt();
+        let phi = golden_angle * i as f32;
+        p(r * phi.cos(), r * phi.sin(), z)
+    };
+
+    for triangle in &degenerate {
+        for i in 0..256 / 8 {
+            // Aim every ray at a point on the triangle itself.
+            let ds = MVector3::generate(|k| direction(i * 8 + k as u32));
+            let targets = MVector3::generate(|k| {
+                let s = k as f32 / 7.0;
+                triangle.v0 * (1.0 - s) + triangle.v2 * s
+            });
+            let origins = targets - ds * Mf32::broadcast(2.0);
+            let ray = MRay::new(origins, ds);
+            let isect = triangle.intersect(&ray, MIntersection::with_max_distance(1e5));
+            let isect_wt = triangle.intersect_watertight(&ray, MIntersection::with_max_distance(1e5));
+            for k in 0..8 {
+                assert_eq!(isect.distance.get_coord(k), 1e5, "degenerate triangle was hit");
+                assert_eq!(isect_wt.distance.get_coord(k), 1e5, "degenerate triangle was hit");
+            }
+        }
+    }
+}
+
+#[test]
+fn triangle_soa_agrees_with_intersect() {
+    let rays = bench::mrays_inward(64);
+    // Use a number of triangles that is not a multiple of 8, so the last
+    // group contains padding.
+    let tris = bench::triangles(61);
+    let soa = TriangleSoa::from_triangles(&tris);
+    for ray in &rays {
+        let mut expected = MIntersection::with_max_distance(1e5);
+        for triangle in &tris {
+            expected = triangle.intersect(ray, expected);
+        }
+        let actual = soa.intersect_nearest(&tris, ray, MIntersection::with_max_distance(1e5));
+        for i in 0..8 {
+            assert_eq!(expected.distance.get_coord(i), actual.distance.get_coord(i));
+            assert_eq!(expected.normal.x.get_coord(i), actual.normal.x.get_coord(i));
+            assert_eq!(expected.tex_coords.0.get_coord(i), actual.tex_coords.0.get_coord(i));
+        }
+    }
+}
+
+#[bench]
+fn bench_intersect_watertight_8_tris_per_mray(b: &mut test::Bencher) {
+    let rays = bench::mrays_inward(4096 / 8);
+    let tris = bench::triangles(4096);
+    let mut rays_it = rays.iter().cycle();
+    let mut tris_it = tris.iter().cycle();
+    b.iter(|| {
+        let ray = rays_it.next().unwrap();
+        let mut isect = MIntersection::with_max_distance(1e5);
+        for _ in 0..8 {
+            let triangle = tris_it.next().unwrap();
+            isect = triangle.intersect_watertight(&ray, isect);
+        }
+        test::black_box(isect);
+    });
+}
+
 #[bench]
 fn bench_intersect_8_mrays_per_tri(b: &mut test::Bencher) {
     let rays = bench::mrays_inward(4096 / 8);
@@ -303,3 +872,31 @@ fn bench_intersect_direct_8_tris_per_mray(b: &mut test::Bencher) {
         }
     });
 }
+
+#[bench]
+fn bench_intersect_64_tris_per_mray(b: &mut test::Bencher) {
+    let rays = bench::mrays_inward(4096 / 8);
+    let tris = bench::triangles(64);
+    let mut rays_it = rays.iter().cycle();
+    b.iter(|| {
+        let ray = rays_it.next().unwrap();
+        let mut isect = MIntersection::with_max_distance(1e5);
+        for triangle in &tris {
+            isect = triangle.intersect(&ray, isect);
+        }
+        test::black_box(isect);
+    });
+}
+
+#[bench]
+fn bench_intersect_soa_64_tris_per_mray(b: &mut test::Bencher) {
+    let rays = bench::mrays_inward(4096 / 8);
+    let tris = bench::triangles(64);
+    let soa = TriangleSoa::from_triangles(&tris);
+    let mut rays_it = rays.iter().cycle();
+    b.iter(|| {
+        let ray = rays_it.next().unwrap();
+        let isect = MIntersection::with_max_distance(1e5);
+        test::black_box(soa.intersect_nearest(&tris, &ray, isect));
+    });
+}
@@ -12,7 +12,7 @@ use filebuffer::FileBuffer;
 use glium::{DisplayBuild, Program, Surface, VertexBuffer};
 use glium::backend::Facade;
 use glium::backend::glutin_backend::GlutinFacade;
-use glium::glutin::{Event, WindowBuilder};
+use glium::glutin::{ElementState, Event, MouseButton, WindowBuilder};
 use glium::index::{NoIndices, PrimitiveType};
 use glium::texture::{MipmapsOption, RawImage2d, SrgbTexture2d, Texture2d};
 use stats::GlobalStats;
@@ -216,13 +216,27 @@ pub struct Window {
     enable_median: bool,
     width: u32,
     height: u32,
+
+    /// The last known position of the mouse cursor in pixels, from the top
+    /// left of the window.
+    mouse_position: (i32, i32),
+
+    /// Whether the left mouse button is being held down.
+    dragging: bool,
 }
 
 pub enum Action {
+    /// The left mouse button was pressed at the given screen coordinates, in
+    /// the range used by `Camera::get_ray()`.
+    BeginDrag(f32, f32),
+    /// The mouse moved to the given screen coordinates with the button held.
+    Drag(f32, f32),
     DumpTrace,
+    EndDrag,
     None,
     PrintStats,
     Quit,
+    SaveScreenshot,
     ToggleDebugView,
     ToggleRealtime,
 }
@@ -270,6 +284,8 @@ impl Window {
             enable_median: true,
             width: width,
             height: height,
+            mouse_position: (0, 0),
+            dragging: false,
         };
 
         let f0 = window.upload_frame(black_bitmap(width, height));
@@ -363,6 +379,15 @@ impl Window {
         stats.draw_vsync_us.insert_time_us(begin_draw.to(end_draw));
     }
 
+    /// Converts a position in pixels from the top left of the window into
+    /// screen coordinates, where x ranges from -1 to 1 and y points up.
+    fn screen_coords(&self) -> (f32, f32) {
+        let (px, py) = self.mouse_position;
+        let w = self.width as f32;
+        let h = self.height as f32;
+        ((2.0 * px as f32 - w) / w, (h - 2.0 * py as f32) / w)
+    }
+
     /// Handles all window events and returns an action to be performed.
     pub fn handle_events(&mut self) -> Action {
         for ev in self.display.poll_events() {
@@ -375,6 +400,8 @@ impl Window {
                 Event::ReceivedCharacter('d') => return Action::ToggleDebugView,
                 // The user pressed 'm' to toggle the median filter.
                 Event::ReceivedCharacter('m') => self.enable_median = !self.enable_median,
+                // The user pressed 'p' for picture.
+                Event::ReceivedCharacter('p') => return Action::SaveScreenshot,
                 // The user pressed 'q' for quit.
                 Event::ReceivedCharacter('q') => return Action::Quit,
                 // The user pressed 'r' to toggle the render mode.
@@ -383,6 +410,23 @@ impl Window {
                 Event::ReceivedCharacter('s') => return Action::PrintStats,
                 // The user pressed 't' for trace.
                 Event::ReceivedCharacter('t') => return Action::DumpTrace,
+                // The user drags with the left mouse button to move lights.
+                Event::MouseInput(ElementState::Pressed, MouseButton::Left) => {
+                    self.dragging = true;
+                    let (x, y) = self.screen_coords();
+                    return Action::BeginDrag(x, y);
+                }
+                Event::MouseInput(ElementState::Released, MouseButton::Left) => {
+                    self.dragging = false;
+                    return Action::EndDrag;
+                }
+                Event::MouseMoved(x, y) => {
+                    self.mouse_position = (x, y);
+                    if self.dragging {
+                        let (x, y) = self.screen_coords();
+                        return Action::Drag(x, y);
+                    }
+                }
                 // Something else.
                 _ => (),
             }
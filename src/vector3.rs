@@ -7,10 +7,10 @@
 
 //! Implements vectors in R3.
 
-use simd::{Mask, Mf32};
+use simd::{Mask, Mf32, Mi32};
 use std::f32;
 use std::fmt;
-use std::ops::{Add, Sub, Neg, Mul};
+use std::ops::{Add, AddAssign, Sub, SubAssign, Neg, Mul, MulAssign, Div};
 
 #[cfg(test)]
 use {bench, test};
@@ -36,6 +36,15 @@ pub enum Axis {
     Z,
 }
 
+/// The order of the bytes of a pixel in the 8-bit output.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChannelOrder {
+    Rgba,
+
+    /// Red and blue swapped, the native order of many display surfaces.
+    Bgra,
+}
+
 impl SVector3 {
     pub fn new(x: f32, y: f32, z: f32) -> SVector3 {
         SVector3 { x: x, y: y, z: z }
@@ -101,6 +110,16 @@ impl SVector3 {
         self.dot(self)
     }
 
+    /// Returns the distance between the points self and other.
+    pub fn distance(self, other: SVector3) -> f32 {
+        self.distance_squared(other).sqrt()
+    }
+
+    /// Returns the squared distance between the points self and other.
+    pub fn distance_squared(self, other: SVector3) -> f32 {
+        (other - self).norm_squared()
+    }
+
     pub fn normalized(self) -> SVector3 {
         let norm_squared = self.norm_squared();
         if norm_squared == 0.0 {
@@ -140,6 +159,13 @@ impl SVector3 {
             z: f32::max(self.z, other.z),
         }
     }
+
+    /// Returns whether every coordinate differs by at most `tol`.
+    pub fn approx_eq(&self, other: SVector3, tol: f32) -> bool {
+        (self.x - other.x).abs() <= tol &&
+        (self.y - other.y).abs() <= tol &&
+        (self.z - other.z).abs() <= tol
+    }
 }
 
 impl MVector3 {
@@ -159,6 +185,18 @@ impl MVector3 {
         }
     }
 
+    /// Returns an mvector with lane k of self in all lanes.
+    ///
+    /// This is useful for debugging, to trace one lane of a packet in
+    /// isolation.
+    pub fn broadcast_lane(&self, k: usize) -> MVector3 {
+        MVector3 {
+            x: Mf32::broadcast(self.x.get_coord(k)),
+            y: Mf32::broadcast(self.y.get_coord(k)),
+            z: Mf32::broadcast(self.z.get_coord(k)),
+        }
+    }
+
     /// Builds an mvector by applying the function to the numbers 0..7.
     ///
     /// Note: this is essentially a transpose, avoid in hot code.
@@ -250,6 +288,68 @@ impl MVector3 {
         result.pick(-result, normal.z)
     }
 
+    /// Returns the component of self perpendicular to the reference vector,
+    /// normalized. The reference vector must have unit length.
+    ///
+    /// This is one step of the Gram-Schmidt process, useful to build a tangent
+    /// frame around a normal. If self is (nearly) parallel to the reference,
+    /// there is no well-defined perpendicular component, and an arbitrary unit
+    /// vector perpendicular to the reference is returned instead.
+    pub fn orthonormalize(self, reference: MVector3) -> MVector3 {
+        let perp = reference.neg_mul_add(self.dot(reference), self);
+
+        // For the fallback, cross the reference with a coordinate axis that is
+        // not close to parallel to it. If |x| < 0.5 the x-axis is fine,
+        // otherwise the y-axis is.
+        let x_axis = MVector3::new(Mf32::one(), Mf32::zero(), Mf32::zero());
+        let y_axis = MVector3::new(Mf32::zero(), Mf32::one(), Mf32::zero());
+        let axis = y_axis.pick(x_axis, reference.x.abs() - Mf32::broadcast(0.5));
+        let fallback = reference.cross(axis);
+
+        // The sign bit is 1 if the perpendicular part is too short relative to
+        // the length of self, or if self is zero.
+        let threshold = self.norm_squared().mul_add(Mf32::broadcast(1e-6), Mf32::broadcast(1e-30));
+        let degenerate = perp.norm_squared() - threshold;
+
+        perp.pick(fallback, degenerate).normalized()
+    }
+
+    /// Returns the angle between self and other in radians, in [0, pi].
+    ///
+    /// The vectors need not have unit length. This uses the approximate
+    /// `Mf32::acos()`, so the angle is off by at most 0.017 radians.
+    pub fn angle_between(self, other: MVector3) -> Mf32 {
+        // Normalization is approximate, so the cosine can exceed 1 slightly.
+        let cos = self.normalized().dot(other.normalized());
+        cos.max(-Mf32::one()).min(Mf32::one()).acos()
+    }
+
+    /// Returns the projection of self onto the line spanned by other, which
+    /// must not be zero.
+    pub fn project_onto(self, other: MVector3) -> MVector3 {
+        other * (self.dot(other) / other.norm_squared())
+    }
+
+    /// Returns self, negated in the lanes where it points into the same
+    /// hemisphere as the reference (where the dot product is non-negative).
+    ///
+    /// With the incoming ray direction as reference, this orients a normal
+    /// towards the side of the surface that the ray came from.
+    pub fn faceforward(self, reference: MVector3) -> MVector3 {
+        // The sign bit of the dot product is 1 where self already faces away
+        // from the reference.
+        (-self).pick(self, self.dot(reference))
+    }
+
+    /// Returns a mask with the sign bit set in the lanes where every
+    /// coordinate differs by at most `tol`, like the result of `Mf32::geq()`.
+    pub fn approx_eq(&self, other: MVector3, tol: Mf32) -> Mask {
+        let within_x = tol.geq((self.x - other.x).abs());
+        let within_y = tol.geq((self.y - other.y).abs());
+        let within_z = tol.geq((self.z - other.z).abs());
+        within_x & within_y & within_z
+    }
+
     /// Scalar multiplication and vector add using fused multiply-add.
     pub fn mul_add(self, factor: Mf32, other: MVector3) -> MVector3 {
         MVector3 {
@@ -291,6 +391,16 @@ impl MVector3 {
         self.dot(self)
     }
 
+    /// Returns the distance between the points self and other.
+    pub fn distance(self, other: MVector3) -> Mf32 {
+        self.distance_squared(other).sqrt()
+    }
+
+    /// Returns the squared distance between the points self and other.
+    pub fn distance_squared(self, other: MVector3) -> Mf32 {
+        (other - self).norm_squared()
+    }
+
     /// Returns 1 / ||self||.
     pub fn rnorm(self) -> Mf32 {
         self.norm_squared().rsqrt()
@@ -305,6 +415,43 @@ impl MVector3 {
         }
     }
 
+    /// Converts colors to 32-bit pixels as stored in a `RenderBuffer`.
+    ///
+    /// Every channel is clamped to [0, 1], scaled to [0, 255], and rounded to
+    /// the nearest integer. Red goes in the least significant byte, then green
+    /// and blue, and the most significant byte is the alpha, which is 255. In
+    /// memory on a little-endian machine, the bytes are in RGBA order.
+    pub fn pack_rgba8(self) -> Mi32 {
+        self.pack_rgba8_with(Mf32::one(), ChannelOrder::Rgba)
+    }
+
+    /// Converts colors to 32-bit pixels as `pack_rgba8()` does, but with the
+    /// given alpha, and with red and blue swapped for `ChannelOrder::Bgra`.
+    /// The alpha is clamped and scaled like the color channels. The renderer
+    /// stores the coverage of the pixel there.
+    pub fn pack_rgba8_with(self, alpha: Mf32, order: ChannelOrder) -> Mi32 {
+        let (first, third) = match order {
+            ChannelOrder::Rgba => (self.x, self.z),
+            ChannelOrder::Bgra => (self.z, self.x),
+        };
+        let r = first.clamp_scale_to_u8();
+        let g = self.y.clamp_scale_to_u8().map(|x| x << 8);
+        let b = third.clamp_scale_to_u8().map(|x| x << 16);
+        let a = alpha.clamp_scale_to_u8().map(|x| x << 24);
+        (r | g) | (b | a)
+    }
+
+    /// Converts 32-bit pixels to colors in [0, 1], the inverse of
+    /// `pack_rgba8()`. The alpha channel is ignored.
+    pub fn unpack_rgba8(rgba: Mi32) -> MVector3 {
+        let mask = Mi32::broadcast(0xff);
+        let r = rgba & mask;
+        let g = rgba.map(|x| x >> 8) & mask;
+        let b = rgba.map(|x| x >> 16) & mask;
+        let rgb = MVector3::new(r.into_mf32(), g.into_mf32(), b.into_mf32());
+        rgb * Mf32::broadcast(1.0 / 255.0)
+    }
+
     /// Clamps every coordinate to 1.0 if it exceeds 1.0.
     pub fn clamp_one(self) -> MVector3 {
         MVector3 {
@@ -427,6 +574,58 @@ impl Mul<Mf32> for MVector3 {
     }
 }
 
+impl Mul<SVector3> for f32 {
+    type Output = SVector3;
+
+    fn mul(self, v: SVector3) -> SVector3 {
+        v * self
+    }
+}
+
+impl Div<f32> for SVector3 {
+    type Output = SVector3;
+
+    fn div(self, a: f32) -> SVector3 {
+        self * (1.0 / a)
+    }
+}
+
+impl AddAssign for SVector3 {
+    fn add_assign(&mut self, other: SVector3) {
+        *self = *self + other;
+    }
+}
+
+impl AddAssign for MVector3 {
+    fn add_assign(&mut self, other: MVector3) {
+        *self = *self + other;
+    }
+}
+
+impl SubAssign for SVector3 {
+    fn sub_assign(&mut self, other: SVector3) {
+        *self = *self - other;
+    }
+}
+
+impl SubAssign for MVector3 {
+    fn sub_assign(&mut self, other: MVector3) {
+        *self = *self - other;
+    }
+}
+
+impl MulAssign<f32> for SVector3 {
+    fn mul_assign(&mut self, a: f32) {
+        *self = *self * a;
+    }
+}
+
+impl MulAssign<Mf32> for MVector3 {
+    fn mul_assign(&mut self, a: Mf32) {
+        *self = *self * a;
+    }
+}
+
 impl fmt::Display for SVector3 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "({}, {}, {})", self.x, self.y, self.z)
@@ -442,6 +641,118 @@ fn assert_mvectors_equal(expected: MVector3, computed: MVector3, margin: f32) {
             "expected: {:?}, computed: {:?}", expected, computed);
 }
 
+#[test]
+fn svector3_operators() {
+    let a = SVector3::new(1.0, -2.0, 3.0);
+    let b = SVector3::new(0.5, 4.0, -1.0);
+
+    assert_eq!(a + b, SVector3::new(1.5, 2.0, 2.0));
+    assert_eq!(a - b, SVector3::new(0.5, -6.0, 4.0));
+    assert_eq!(-a, SVector3::new(-1.0, 2.0, -3.0));
+    assert_eq!(a * 2.0, SVector3::new(2.0, -4.0, 6.0));
+    assert_eq!(2.0 * a, SVector3::new(2.0, -4.0, 6.0));
+    assert_eq!(a / 2.0, SVector3::new(0.5, -1.0, 1.5));
+    assert_eq!(a.dot(b), 0.5 - 8.0 - 3.0);
+
+    let mut c = a;
+    c += b;
+    assert_eq!(c, SVector3::new(1.5, 2.0, 2.0));
+    c -= a;
+    assert_eq!(c, b);
+    c *= -2.0;
+    assert_eq!(c, SVector3::new(-1.0, -8.0, 2.0));
+}
+
+#[test]
+fn mvector3_broadcast_lane() {
+    let a = MVector3::generate(|i| SVector3::new(i as f32, -(i as f32), 10.0 + i as f32));
+    for k in 0..8 {
+        let b = a.broadcast_lane(k);
+        for i in 0..8 {
+            assert_eq!(b.x.get_coord(i), k as f32);
+            assert_eq!(b.y.get_coord(i), -(k as f32));
+            assert_eq!(b.z.get_coord(i), 10.0 + k as f32);
+        }
+    }
+}
+
+#[test]
+fn mvector3_operators() {
+    let a = MVector3::broadcast(SVector3::new(1.0, -2.0, 3.0));
+    let b = MVector3::broadcast(SVector3::new(0.5, 4.0, -1.0));
+    let two = Mf32::broadcast(2.0);
+
+    assert_eq!(a + b, MVector3::broadcast(SVector3::new(1.5, 2.0, 2.0)));
+    assert_eq!(a - b, MVector3::broadcast(SVector3::new(0.5, -6.0, 4.0)));
+    assert_eq!(-a, MVector3::broadcast(SVector3::new(-1.0, 2.0, -3.0)));
+    assert_eq!(a * two, MVector3::broadcast(SVector3::new(2.0, -4.0, 6.0)));
+    assert_eq!(a.dot(b), Mf32::broadcast(0.5 - 8.0 - 3.0));
+
+    let mut c = a;
+    c += b;
+    assert_eq!(c, MVector3::broadcast(SVector3::new(1.5, 2.0, 2.0)));
+    c -= a;
+    assert_eq!(c, b);
+    c *= two;
+    assert_eq!(c, MVector3::broadcast(SVector3::new(1.0, 8.0, -2.0)));
+}
+
+#[test]
+fn svector3_distance() {
+    let a = SVector3::new(1.0, 2.0, 3.0);
+    let b = SVector3::new(4.0, -2.0, 3.0);
+    assert_eq!(a.distance_squared(b), 25.0);
+    assert_eq!(a.distance(b), 5.0);
+    assert_eq!(b.distance(a), 5.0);
+    assert_eq!(a.distance(a), 0.0);
+    assert_eq!(SVector3::zero().distance(SVector3::new(2.0, 3.0, 6.0)), 7.0);
+}
+
+#[test]
+fn mvector3_distance() {
+    let a = MVector3::generate(|i| SVector3::new(i as f32, 0.0, 1.0));
+    let b = MVector3::generate(|i| SVector3::new(i as f32 + 3.0, 4.0, 1.0));
+    assert_eq!(a.distance_squared(b), Mf32::broadcast(25.0));
+    assert_eq!(b.distance_squared(a), Mf32::broadcast(25.0));
+    assert_mvectors_equal(MVector3::broadcast(SVector3::new(5.0, 5.0, 5.0)),
+                          MVector3::new(a.distance(b), b.distance(a), a.distance(b)),
+                          1e-5);
+    assert_eq!(a.distance_squared(a), Mf32::zero());
+}
+
+#[test]
+fn mvector3_pack_rgba8_round_trip() {
+    let colors = MVector3::generate(|i| {
+        let f = i as f32 / 7.0;
+        SVector3::new(f, 1.0 - f, (f * 5.3).fract())
+    });
+    let rgba = colors.pack_rgba8();
+    for i in 0..8 {
+        assert_eq!((rgba.get_coord(i) >> 24) & 0xff, 0xff, "alpha must be opaque");
+    }
+    assert_mvectors_equal(colors, MVector3::unpack_rgba8(rgba), 0.5 / 255.0 * 3.0f32.sqrt());
+
+    // The layout matches the render buffer: red in the low byte.
+    let orange = MVector3::broadcast(SVector3::new(1.0, 0.5, 0.0));
+    assert_eq!(orange.pack_rgba8().get_coord(0) as u32, 0xff_00_80_ff);
+
+    // Values outside of [0, 1] are clamped.
+    let out_of_range = MVector3::broadcast(SVector3::new(-1.0, 2.0, 0.0));
+    assert_mvectors_equal(MVector3::broadcast(SVector3::new(0.0, 1.0, 0.0)),
+                          MVector3::unpack_rgba8(out_of_range.pack_rgba8()),
+                          1e-6);
+}
+
+#[test]
+fn mvector3_pack_rgba8_with_alpha_and_order() {
+    let orange = MVector3::broadcast(SVector3::new(1.0, 0.5, 0.0));
+    let half = Mf32::broadcast(0.5);
+    assert_eq!(orange.pack_rgba8_with(half, ChannelOrder::Rgba).get_coord(0) as u32, 0x80_00_80_ff);
+    assert_eq!(orange.pack_rgba8_with(half, ChannelOrder::Bgra).get_coord(0) as u32, 0x80_ff_80_00);
+    assert_eq!(orange.pack_rgba8_with(Mf32::one(), ChannelOrder::Rgba).get_coord(0),
+               orange.pack_rgba8().get_coord(0));
+}
+
 #[test]
 fn verify_rotate_hemisphere() {
     let x = MVector3::new(Mf32::one(), Mf32::zero(), Mf32::zero());
@@ -539,6 +850,100 @@ fn rotate_hemisphere_random() {
     assert!(had_positive_z);
 }
 
+#[test]
+fn orthonormalize_is_unit_and_orthogonal() {
+    let refs = bench::mvectors_on_unit_sphere(4096);
+    let vs = bench::mvectors_on_unit_sphere(4096);
+
+    // Include the degenerate cases: parallel, anti-parallel, and zero.
+    let special = [MVector3::zero(), refs[0], -refs[0], refs[0] * Mf32::broadcast(3.0)];
+    let cases = vs.iter().cloned().zip(refs.iter().cloned())
+        .chain(special.iter().map(|&v| (v, refs[0])));
+
+    for (v, r) in cases {
+        let t = v.orthonormalize(r);
+        let norm = t.norm_squared().sqrt();
+        assert!((Mf32::broadcast(1e-3) - (norm - Mf32::one()).abs()).all_sign_bits_positive(),
+                "expected unit length, got {:?} for {:?} against {:?}", norm, v, r);
+        assert!((Mf32::broadcast(1e-3) - t.dot(r).abs()).all_sign_bits_positive(),
+                "expected orthogonal, got dot {:?} for {:?} against {:?}", t.dot(r), v, r);
+    }
+}
+
+#[test]
+fn angle_between_extrema() {
+    let x = MVector3::new(Mf32::broadcast(2.0), Mf32::zero(), Mf32::zero());
+    let y = MVector3::new(Mf32::zero(), Mf32::broadcast(0.5), Mf32::zero());
+    let pi = Mf32::broadcast(f32::consts::PI);
+    let tolerance = Mf32::broadcast(0.02);
+    let close = |a: Mf32, b: Mf32| (tolerance - (a - b).abs()).all_sign_bits_positive();
+    assert!(close(x.angle_between(y), pi * Mf32::broadcast(0.5)));
+    assert!(close(x.angle_between(x * Mf32::broadcast(3.0)), Mf32::zero()));
+    assert!(close(x.angle_between(-x), pi));
+}
+
+#[test]
+fn project_onto_axis_gives_component() {
+    let v = MVector3::new(Mf32::broadcast(1.0), Mf32::broadcast(-2.0), Mf32::broadcast(3.0));
+    let axis = MVector3::new(Mf32::zero(), Mf32::broadcast(4.0), Mf32::zero());
+    let p = v.project_onto(axis);
+    assert_eq!(p.x, Mf32::zero());
+    assert_eq!(p.y, Mf32::broadcast(-2.0));
+    assert_eq!(p.z, Mf32::zero());
+}
+
+#[test]
+fn faceforward_flips_only_lanes_facing_reference() {
+    let normal = MVector3::new(Mf32::zero(), Mf32::one(), Mf32::zero());
+    // Rays going down hit the front of an upward normal, rays going up hit
+    // the back.
+    let ys = Mf32(-1.0, -0.5, -0.1, -2.0, 1.0, 0.5, 0.1, 2.0);
+    let reference = MVector3::new(Mf32::broadcast(0.3), ys, Mf32::zero());
+    let n = normal.faceforward(reference);
+    for i in 0..4 {
+        assert_eq!(n.y.get_coord(i), 1.0, "lane {} already faces away", i);
+    }
+    for i in 4..8 {
+        assert_eq!(n.y.get_coord(i), -1.0, "lane {} should be flipped", i);
+    }
+}
+
+#[test]
+fn svector3_approx_eq_respects_tolerance() {
+    let a = SVector3::new(1.0, -2.0, 3.0);
+    assert!(a.approx_eq(a, 0.0));
+    assert!(a.approx_eq(SVector3::new(1.0, -2.0, 3.0625), 0.125));
+    assert!(a.approx_eq(SVector3::new(1.0, -2.125, 3.0), 0.125));
+    assert!(!a.approx_eq(SVector3::new(1.0, -2.0, 3.25), 0.125));
+    assert!(!a.approx_eq(SVector3::new(1.25, -2.0, 3.0), 0.125));
+}
+
+#[test]
+fn mvector3_approx_eq_is_per_lane() {
+    let a = MVector3::broadcast(SVector3::new(1.0, -2.0, 3.0));
+    // Lanes 0-3 are within the tolerance of 0.125 in one coordinate, lanes
+    // 4-7 are just outside of it.
+    let offsets = [0.0, 0.0625, 0.1, 0.125, 0.126, 0.25, 1.0, 100.0];
+    let b = MVector3::generate(|i| {
+        let d = offsets[i];
+        match i % 3 {
+            0 => SVector3::new(1.0 + d, -2.0, 3.0),
+            1 => SVector3::new(1.0, -2.0 - d, 3.0),
+            _ => SVector3::new(1.0, -2.0, 3.0 + d),
+        }
+    });
+    let mask = a.approx_eq(b, Mf32::broadcast(0.125));
+
+    // Inspect the mask by picking with it, mask lanes are not valid floats.
+    let equal = Mf32::zero().pick(Mf32::one(), mask);
+    for i in 0..4 {
+        assert_eq!(equal.get_coord(i), 1.0, "lane {} should be equal", i);
+    }
+    for i in 4..8 {
+        assert_eq!(equal.get_coord(i), 0.0, "lane {} should differ", i);
+    }
+}
+
 macro_rules! unroll_10 {
     { $x: block } => {
         $x $x $x $x $x $x $x $x $x $x
@@ -13,8 +13,10 @@ use material::SMaterial;
 use std::collections::HashMap;
 use std::path::Path;
 use std::str::{FromStr, from_utf8};
+use std::u32;
 use vector3::SVector3;
 
+#[derive(Clone)]
 pub struct Triangle {
     pub vertices: (u32, u32, u32),
     pub tex_coords: Option<(u32, u32, u32)>,
@@ -24,7 +26,17 @@ pub struct Triangle {
 pub struct Mesh {
     pub vertices: Vec<SVector3>,
     pub tex_coords: Vec<(f32, f32)>,
+
+    /// Linear RGB colors, one per vertex, or empty if the mesh has no vertex
+    /// colors. Vertex colors multiply the albedo of the material.
+    pub colors: Vec<SVector3>,
+
     pub triangles: Vec<Triangle>,
+
+    /// Light linking bits of all triangles in the mesh. An emissive surface
+    /// only lights a surface directly if their bits have one in common. By
+    /// default all bits are set, so every light illuminates everything.
+    pub light_links: u32,
 }
 
 fn assert_nondegenerate(vertices: &[SVector3], line: u32, i0: u32, i1: u32, i2: u32) {
@@ -45,6 +57,21 @@ fn assert_nondegenerate(vertices: &[SVector3], line: u32, i0: u32, i1: u32, i2:
     }
 }
 
+/// Returns the position of a vertex from the components of a `v` line, and
+/// its color if there is one. The position may be followed by a weight, which
+/// is ignored, or by an RGB color, which some exporters append.
+fn parse_vertex(components: &[f32], line: u32) -> (SVector3, Option<SVector3>) {
+    if components.len() < 3 {
+        panic!("line {}: vertex has {} coordinates, expected at least 3", line, components.len());
+    }
+    let vertex = SVector3::new(components[0], components[1], components[2]);
+    match components.len() {
+        3 | 4 => (vertex, None),
+        6 => (vertex, Some(SVector3::new(components[3], components[4], components[5]))),
+        n => panic!("line {}: vertex has {} components, expected 3, 4, or 6", line, n),
+    }
+}
+
 /// Returns the vertex index, and the texture coordinate index if there is one.
 fn parse_vertex_index(index: &str) -> (u32, Option<u32>) {
     let mut parts = index.split('/').map(|i| u32::from_str(i).unwrap());
@@ -88,6 +115,7 @@ impl Mesh {
 
         let mut vertices = Vec::new();
         let mut tex_coords = Vec::new();
+        let mut colors = Vec::new();
         let mut triangles = Vec::new();
         let mut material = SMaterial::white(); // The default material.
 
@@ -99,13 +127,14 @@ impl Mesh {
             let mut pieces = line.split_whitespace();
             match pieces.next() {
                 Some("v") => {
-                    let mut coords = pieces.map(|v| f32::from_str(v).unwrap());
-                    let vertex = SVector3 {
-                        x: coords.next().expect("missing x coordinate"),
-                        y: coords.next().expect("missing y coordinate"),
-                        z: coords.next().expect("missing z coordinate"),
-                    };
+                    let components: Vec<f32> = pieces.map(|v| f32::from_str(v).unwrap()).collect();
+                    let (vertex, color) = parse_vertex(&components, line_nr);
                     vertices.push(vertex);
+
+                    // If one vertex has a color, all of them must have one.
+                    if let Some(color) = color {
+                        colors.push(color);
+                    }
                 }
                 Some("vt") => {
                     let mut coords = pieces.map(|v| f32::from_str(v).unwrap());
@@ -144,12 +173,135 @@ impl Mesh {
             }
         }
 
+        assert!(colors.is_empty() || colors.len() == vertices.len(),
+                "either all vertices or none must have a color");
+
         Mesh {
             vertices: vertices,
             triangles: triangles,
             tex_coords: tex_coords,
+            colors: colors,
+            light_links: u32::MAX,
         }
     }
+
+    /// Merges vertices that lie within `epsilon` of each other, and updates
+    /// the triangles to refer to the remaining vertices.
+    ///
+    /// Vertices with different colors are never merged. Triangles that become
+    /// degenerate because two of their vertices were merged are removed. The
+    /// first vertex of a group of close vertices is the one that is kept.
+    pub fn deduplicate_vertices(&mut self, epsilon: f32) {
+        // Put the vertices in a grid with cells of size epsilon. Vertices
+        // within epsilon of each other are then in the same or an adjacent
+        // cell.
+        let cell_size = if epsilon > 0.0 { epsilon } else { 1.0 };
+        let cell_of = |v: SVector3| {
+            ((v.x / cell_size).floor() as i64,
+             (v.y / cell_size).floor() as i64,
+             (v.z / cell_size).floor() as i64)
+        };
+
+        let has_colors = !self.colors.is_empty();
+        let mut grid: HashMap<(i64, i64, i64), Vec<u32>> = HashMap::new();
+        let mut vertices: Vec<SVector3> = Vec::new();
+        let mut colors: Vec<SVector3> = Vec::new();
+        let mut remap = Vec::with_capacity(self.vertices.len());
+
+        for (i, &v) in self.vertices.iter().enumerate() {
+            let color = if has_colors { self.colors[i] } else { SVector3::zero() };
+            let (cx, cy, cz) = cell_of(v);
+            let mut found = None;
+            'search: for dx in -1..2 {
+                for dy in -1..2 {
+                    for dz in -1..2 {
+                        if let Some(candidates) = grid.get(&(cx + dx, cy + dy, cz + dz)) {
+                            for &j in candidates {
+                                let same_color = !has_colors || colors[j as usize] == color;
+                                if same_color && (vertices[j as usize] - v).norm_squared() <= epsilon * epsilon {
+                                    found = Some(j);
+                                    break 'search;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            let index = match found {
+                Some(j) => j,
+                None => {
+                    let j = vertices.len() as u32;
+                    vertices.push(v);
+                    if has_colors {
+                        colors.push(color);
+                    }
+                    grid.entry((cx, cy, cz)).or_insert_with(Vec::new).push(j);
+                    j
+                }
+            };
+            remap.push(index);
+        }
+
+        for triangle in &mut self.triangles {
+            let (i0, i1, i2) = triangle.vertices;
+            triangle.vertices = (remap[i0 as usize], remap[i1 as usize], remap[i2 as usize]);
+        }
+        self.triangles.retain(|t| {
+            let (i0, i1, i2) = t.vertices;
+            i0 != i1 && i1 != i2 && i2 != i0
+        });
+
+        self.vertices = vertices;
+        self.colors = colors;
+    }
+}
+
+#[test]
+fn deduplicate_vertices_welds_box_corners() {
+    use bench;
+
+    // A box built from quads has four vertices per face, but only eight
+    // unique corners.
+    let quads = bench::box_quads(SVector3::zero(), SVector3::one(), SMaterial::white());
+    let mut mesh = bench::mesh_from_quads(&quads);
+    assert_eq!(mesh.vertices.len(), 24);
+
+    // Nudge one vertex by less than epsilon, it should still be merged.
+    mesh.vertices[5].x += 1e-6;
+
+    let positions = |mesh: &Mesh| -> Vec<[SVector3; 3]> {
+        mesh.triangles.iter().map(|t| {
+            let (i0, i1, i2) = t.vertices;
+            [mesh.vertices[i0 as usize], mesh.vertices[i1 as usize], mesh.vertices[i2 as usize]]
+        }).collect()
+    };
+    let before = positions(&mesh);
+    mesh.deduplicate_vertices(1e-4);
+    let after = positions(&mesh);
+
+    assert_eq!(mesh.vertices.len(), 8);
+    assert_eq!(after.len(), before.len());
+    for (a, b) in before.iter().zip(after.iter()) {
+        for k in 0..3 {
+            assert!((a[k] - b[k]).norm_squared() < 1e-8);
+        }
+    }
+}
+
+#[test]
+fn parse_vertex_accepts_weight_and_color() {
+    let v = SVector3::new;
+    assert_eq!(parse_vertex(&[1.0, 2.0, 3.0], 1), (v(1.0, 2.0, 3.0), None));
+    assert_eq!(parse_vertex(&[1.0, 2.0, 3.0, 1.0], 1), (v(1.0, 2.0, 3.0), None));
+    assert_eq!(parse_vertex(&[1.0, 2.0, 3.0, 0.5, 0.25, 1.0], 1),
+               (v(1.0, 2.0, 3.0), Some(v(0.5, 0.25, 1.0))));
+}
+
+#[test]
+#[should_panic]
+fn parse_vertex_rejects_partial_color() {
+    parse_vertex(&[1.0, 2.0, 3.0, 0.5, 0.25], 1);
 }
 
 // The loader should be able to load all of these files without crashing. The
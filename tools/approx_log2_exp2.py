@@ -0,0 +1,74 @@
+#!/usr/bin/env python3
+
+# Convector -- An interactive CPU path tracer
+# Copyright 2016 Ruud van Asseldonk
+
+# This program is free software: you can redistribute it and/or modify
+# it under the terms of the GNU General Public License version 3. A copy
+# of the License is available in the root of the repository.
+
+# The goal is to approximate log2(m) on the domain [1, 2) and 2^f on the domain
+# [-1/2, 1/2] with polynomials. The exponent of a float is handled exactly with
+# bit manipulation, so these are the only parts that need an approximation. The
+# polynomials are found by interpolating at the Chebyshev nodes, which is close
+# to optimal in the worst case. For log2 the polynomial is in t = m - 1, and I
+# impose f(0) = 0 and f(1) = 1 so that the approximation is continuous at
+# powers of two.
+
+from math import cos, log2, pi
+
+def solve(a, b):
+    """Solves the linear system a * x = b with Gaussian elimination."""
+    n = len(b)
+    m = [row[:] + [bi] for row, bi in zip(a, b)]
+    for i in range(n):
+        p = max(range(i, n), key=lambda r: abs(m[r][i]))
+        m[i], m[p] = m[p], m[i]
+        for r in range(i + 1, n):
+            f = m[r][i] / m[i][i]
+            m[r] = [x - f * y for x, y in zip(m[r], m[i])]
+    x = [0.0] * n
+    for i in reversed(range(n)):
+        x[i] = (m[i][n] - sum(m[i][j] * x[j] for j in range(i + 1, n))) / m[i][i]
+    return x
+
+def chebyshev_nodes(lo, hi, n):
+    return [(lo + hi) / 2 + (hi - lo) / 2 * cos((2 * k + 1) * pi / (2 * n)) for k in range(n)]
+
+def eval_poly(coefs, x):
+    return sum(c * x**i for i, c in enumerate(coefs))
+
+# log2(1 + t) = t * (c1 + c2 t + ... ), with the constraint that the
+# coefficients sum to 1, so that log2(2) = 1 exactly.
+def fit_log2(degree):
+    # Substitute c1 = 1 - (c2 + ... + cn) and fit the remaining coefficients.
+    ts = chebyshev_nodes(0.0, 1.0, degree - 1)
+    a = [[t**k - t for k in range(2, degree + 1)] for t in ts]
+    b = [log2(1.0 + t) - t for t in ts]
+    rest = solve(a, b)
+    return [0.0, 1.0 - sum(rest)] + rest
+
+def fit_exp2(degree):
+    xs = chebyshev_nodes(-0.5, 0.5, degree + 1)
+    a = [[x**k for k in range(0, degree + 1)] for x in xs]
+    b = [2.0**x for x in xs]
+    return solve(a, b)
+
+log2_coefs = fit_log2(5)
+exp2_coefs = fit_exp2(5)
+
+samples = [i / 4096 for i in range(0, 4097)]
+log2_err = max(abs(eval_poly(log2_coefs, t) - log2(1.0 + t)) for t in samples)
+exp2_err = max(abs(eval_poly(exp2_coefs, t - 0.5) / 2.0**(t - 0.5) - 1.0) for t in samples)
+
+print('log2 coefficients:', log2_coefs)
+print('log2 max absolute error:', log2_err)
+print('exp2 coefficients:', exp2_coefs)
+print('exp2 max relative error:', exp2_err)
+
+# Output:
+#
+#     log2 coefficients: [0.0, 1.442418578542117, -0.7119965934909929, 0.4201112115476533, -0.19540715186201255, 0.04487395526323496]
+#     log2 max absolute error: 4.920762536386736e-05
+#     exp2 coefficients: [1.0000000754548972, 0.6931471880262285, 0.2402210748530821, 0.05550357114219461, 0.009676031918326564, 0.0013390863364533504]
+#     exp2 max relative error: 1.0164997588102409e-07